@@ -0,0 +1,35 @@
+//! Generates the C header from the declarations in `src/ffi.rs` of the main crate
+//! into `OUT_DIR` and warns if the committed `include/indexed_file_storage.h`
+//! differs from it. The committed header is regenerated explicitly with
+//! `cbindgen --config ffi/cbindgen.toml --output include/indexed_file_storage.h src/ffi.rs`
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn main() {
+    let dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let root = dir.parent().unwrap();
+    let source = root.join("src/ffi.rs");
+    let committed = root.join("include/indexed_file_storage.h");
+    println!("cargo:rerun-if-changed={}", source.display());
+    println!("cargo:rerun-if-changed={}", committed.display());
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    let config =
+        cbindgen::Config::from_file(dir.join("cbindgen.toml")).expect("invalid cbindgen.toml");
+    let generated = PathBuf::from(env::var("OUT_DIR").unwrap()).join("indexed_file_storage.h");
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(source)
+        .generate()
+        .expect("the C header can't be generated from src/ffi.rs")
+        .write_to_file(&generated);
+
+    if fs::read(&generated).ok() != fs::read(&committed).ok() {
+        println!(
+            "cargo:warning=include/indexed_file_storage.h is out of date, regenerate it with \
+             cbindgen --config ffi/cbindgen.toml --output include/indexed_file_storage.h src/ffi.rs"
+        );
+    }
+}
@@ -0,0 +1,4 @@
+//! Builds the C interface of [indexed_file_storage::ffi] as `libindexed_file_storage_ffi`.
+//! The declarations are in `include/indexed_file_storage.h`
+
+pub use indexed_file_storage::ffi::*;
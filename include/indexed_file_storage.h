@@ -0,0 +1,128 @@
+/* C interface of indexed-file-storage. The shared and static library
+ * libindexed_file_storage_ffi are built by the ffi crate with
+ * cargo build --release -p indexed-file-storage-ffi
+ * Generated from src/ffi.rs with ffi/cbindgen.toml, don't edit */
+
+#ifndef INDEXED_FILE_STORAGE_H
+#define INDEXED_FILE_STORAGE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define IFS_OK 0
+
+#define IFS_ERROR_INVALID_ARGUMENT 1
+
+#define IFS_ERROR_NOT_FOUND 2
+
+#define IFS_ERROR_ALREADY_EXISTS 3
+
+#define IFS_ERROR_NOT_A_DIRECTORY 4
+
+#define IFS_ERROR_IS_A_DIRECTORY 5
+
+#define IFS_ERROR_DIRECTORY_NOT_EMPTY 6
+
+#define IFS_ERROR_LOCKED 7
+
+#define IFS_ERROR_READ_ONLY 8
+
+#define IFS_ERROR_QUOTA_EXCEEDED 9
+
+#define IFS_ERROR_CORRUPT 10
+
+#define IFS_ERROR_IO 11
+
+// The names of the entries of a directory
+typedef struct IfsList IfsList;
+
+// An open storage
+typedef struct IfsStorage IfsStorage;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Opens or creates the storage in the directory at the path and writes its
+// handle to `out`
+//
+// # Safety
+//
+// `path` has to be a null terminated string and `out` a valid pointer
+int ifs_open(const char *path, IfsStorage **out);
+
+// Closes the storage. A null handle is ignored
+//
+// # Safety
+//
+// `storage` has to be a handle returned by [ifs_open] that isn't used afterwards
+void ifs_close(IfsStorage *storage);
+
+// Stores `length` bytes at `data` in the file at the path and creates the
+// missing parent directories
+//
+// # Safety
+//
+// `storage` has to be an open handle, `path` a null terminated string and `data`
+// valid for `length` bytes
+int ifs_store(const IfsStorage *storage, const char *path, const uint8_t *data, size_t length);
+
+// Reads the file at the path into a new buffer and writes it to `data` and its
+// length to `length`. The buffer is freed with [ifs_free_buffer]
+//
+// # Safety
+//
+// `storage` has to be an open handle, `path` a null terminated string and `data`
+// and `length` valid pointers
+int ifs_get(const IfsStorage *storage, const char *path, uint8_t **data, size_t *length);
+
+// Frees a buffer returned by [ifs_get]. A null buffer is ignored
+//
+// # Safety
+//
+// `data` and `length` have to be the buffer and length returned by [ifs_get]
+void ifs_free_buffer(uint8_t *data, size_t length);
+
+// Writes a list of the names of the entries of the directory at the path to
+// `out`. The list is freed with [ifs_list_free]
+//
+// # Safety
+//
+// `storage` has to be an open handle, `path` a null terminated string and `out`
+// a valid pointer
+int ifs_list(const IfsStorage *storage, const char *path, IfsList **out);
+
+// Returns the number of names in the list
+//
+// # Safety
+//
+// `list` has to be a list returned by [ifs_list]
+size_t ifs_list_len(const IfsList *list);
+
+// Returns the name at the index in the list or null if it's out of range. The
+// name is valid until the list is freed
+//
+// # Safety
+//
+// `list` has to be a list returned by [ifs_list]
+const char *ifs_list_name(const IfsList *list, size_t index);
+
+// Frees a list returned by [ifs_list]. A null list is ignored
+//
+// # Safety
+//
+// `list` has to be a list returned by [ifs_list] that isn't used afterwards
+void ifs_list_free(IfsList *list);
+
+// Deletes the file or empty directory at the path
+//
+// # Safety
+//
+// `storage` has to be an open handle and `path` a null terminated string
+int ifs_delete(const IfsStorage *storage, const char *path);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* INDEXED_FILE_STORAGE_H */
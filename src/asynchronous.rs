@@ -0,0 +1,190 @@
+use crate::dirtreefile::{CopyStats, DirEntry, DirTreeFile};
+use crate::error::{Error, Result};
+use crate::storage::Storage;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Runs an operation on the shared value in the blocking thread pool of tokio
+async fn blocking<S, T, F>(inner: &Arc<Mutex<S>>, operation: F) -> Result<T>
+where
+    S: Send + 'static,
+    T: Send + 'static,
+    F: FnOnce(&mut S) -> Result<T> + Send + 'static,
+{
+    let inner = Arc::clone(inner);
+    tokio::task::spawn_blocking(move || {
+        let mut guard = inner
+            .lock()
+            .map_err(|_| io::Error::other("lock poisoned"))?;
+        operation(&mut guard)
+    })
+    .await
+    .map_err(|e| Error::Io(io::Error::other(e)))?
+}
+
+/// Runs an operation on the shared storage in the blocking thread pool of tokio.
+/// The storage does its own locking so concurrent reads aren't serialized
+async fn blocking_storage<T, F>(inner: &Arc<Storage>, operation: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Storage) -> Result<T> + Send + 'static,
+{
+    let inner = Arc::clone(inner);
+    tokio::task::spawn_blocking(move || operation(&inner))
+        .await
+        .map_err(|e| Error::Io(io::Error::other(e)))?
+}
+
+/// A storage that can be used from async code without blocking the executor
+#[derive(Clone)]
+pub struct AsyncStorage {
+    inner: Arc<Storage>,
+}
+
+impl AsyncStorage {
+    /// Opens the storage in the given directory and creates it if it doesn't exist
+    pub async fn open(path: PathBuf) -> Result<Self> {
+        let storage = tokio::task::spawn_blocking(move || Storage::open(path))
+            .await
+            .map_err(|e| Error::Io(io::Error::other(e)))??;
+
+        Ok(Self::from_storage(storage))
+    }
+
+    pub fn from_storage(storage: Storage) -> Self {
+        Self::from_shared(Arc::new(storage))
+    }
+
+    /// Wraps a storage that is also used by synchronous code
+    pub fn from_shared(storage: Arc<Storage>) -> Self {
+        Self { inner: storage }
+    }
+
+    /// Stores the data at the given path replacing an existing file
+    pub async fn store(&self, path: &str, data: Vec<u8>) -> Result<u64> {
+        let path = path.to_string();
+        blocking_storage(&self.inner, move |s| s.store(&path, &data[..])).await
+    }
+
+    /// Returns the content of the file at the given path
+    pub async fn get(&self, path: &str) -> Result<Vec<u8>> {
+        let path = path.to_string();
+        blocking_storage(&self.inner, move |s| {
+            let mut data = Vec::new();
+            s.get(&path)?.read_to_end(&mut data)?;
+            Ok(data)
+        })
+        .await
+    }
+
+    /// Returns the entries of the directory at the given path
+    pub async fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>> {
+        let path = path.to_string();
+        blocking_storage(&self.inner, move |s| s.read_dir(&path)).await
+    }
+
+    /// Creates a directory. The parent directory must already exist
+    pub async fn create_dir(&self, path: &str) -> Result<()> {
+        let path = path.to_string();
+        blocking_storage(&self.inner, move |s| s.create_dir(&path)).await
+    }
+
+    /// Creates the directory and all its missing parents
+    pub async fn create_dir_all(&self, path: &str) -> Result<()> {
+        let path = path.to_string();
+        blocking_storage(&self.inner, move |s| s.create_dir_all(&path)).await
+    }
+
+    /// Deletes the file or empty directory at the given path
+    pub async fn delete(&self, path: &str) -> Result<()> {
+        let path = path.to_string();
+        blocking_storage(&self.inner, move |s| s.delete(&path)).await
+    }
+}
+
+/// A dir tree file that can be used from async code without blocking the executor
+#[derive(Clone)]
+pub struct AsyncDirTreeFile {
+    inner: Arc<Mutex<DirTreeFile>>,
+}
+
+impl AsyncDirTreeFile {
+    /// Opens the dir tree file at the given path and creates it if it doesn't exist
+    pub async fn open(path: PathBuf) -> Result<Self> {
+        let tree = tokio::task::spawn_blocking(move || DirTreeFile::open(path))
+            .await
+            .map_err(|e| Error::Io(io::Error::other(e)))??;
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(tree)),
+        })
+    }
+
+    /// Returns the current directory
+    pub async fn dir(&self) -> Result<String> {
+        blocking(&self.inner, |t| Ok(t.dir())).await
+    }
+
+    /// Reads all entries in the current dir
+    pub async fn entries(&self) -> Result<Vec<DirEntry>> {
+        blocking(&self.inner, |t| t.entries()).await
+    }
+
+    /// Resolves a path to its entry without changing the current directory
+    pub async fn lookup(&self, path: &str) -> Result<Option<DirEntry>> {
+        let path = path.to_string();
+        blocking(&self.inner, move |t| t.lookup(&path)).await
+    }
+
+    /// Changes the virtual directory to <dir>
+    pub async fn cd(&self, dir: &str) -> Result<()> {
+        let dir = dir.to_string();
+        blocking(&self.inner, move |t| t.cd(&dir)).await
+    }
+
+    /// Create a new entry in the current directory
+    pub async fn create_entry(&self, name: &str, dir: bool) -> Result<()> {
+        let name = name.to_string();
+        blocking(&self.inner, move |t| t.create_entry(&name, dir)).await
+    }
+
+    /// Creates the directory and all its missing parents
+    pub async fn create_dir_all(&self, path: &str) -> Result<()> {
+        let path = path.to_string();
+        blocking(&self.inner, move |t| t.create_dir_all(&path)).await
+    }
+
+    /// Deletes an entry in the current directory
+    pub async fn delete_entry(&self, name: &str) -> Result<bool> {
+        let name = name.to_string();
+        blocking(&self.inner, move |t| t.delete_entry(&name)).await
+    }
+
+    /// Deletes an entry in the current directory together with all its descendants
+    pub async fn delete_recursive(&self, name: &str) -> Result<bool> {
+        let name = name.to_string();
+        blocking(&self.inner, move |t| t.delete_recursive(&name)).await
+    }
+
+    /// Renames an entry in the current directory
+    pub async fn rename_entry(&self, name: &str, new_name: &str) -> Result<()> {
+        let name = name.to_string();
+        let new_name = new_name.to_string();
+        blocking(&self.inner, move |t| t.rename_entry(&name, &new_name)).await
+    }
+
+    /// Moves an entry into another directory
+    pub async fn move_entry(&self, src_path: &str, dest_dir: &str) -> Result<()> {
+        let src_path = src_path.to_string();
+        let dest_dir = dest_dir.to_string();
+        blocking(&self.inner, move |t| t.move_entry(&src_path, &dest_dir)).await
+    }
+
+    /// Copies an entry and all its descendants to another path
+    pub async fn copy_entry(&self, src_path: &str, dest_path: &str) -> Result<CopyStats> {
+        let src_path = src_path.to_string();
+        let dest_path = dest_path.to_string();
+        blocking(&self.inner, move |t| t.copy_entry(&src_path, &dest_path)).await
+    }
+}
@@ -0,0 +1,255 @@
+//! An append-only log of the changes made through a storage, enabled with
+//! [Storage::enable_audit_log] and read with [Storage::audit_log]
+//!
+//! [Storage::enable_audit_log]: crate::storage::Storage::enable_audit_log
+//! [Storage::audit_log]: crate::storage::Storage::audit_log
+
+use crate::error::{Error, Result};
+use crate::storage::ChangeEvent;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The name of the log in the storage directory
+pub const AUDIT_FILE_NAME: &str = "audit.log";
+
+const CREATED: u8 = 0;
+const MODIFIED: u8 = 1;
+const DELETED: u8 = 2;
+const MOVED: u8 = 3;
+
+/// A change with the time it was made and the actor that made it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub time: SystemTime,
+    pub actor: String,
+    pub change: ChangeEvent,
+}
+
+/// Appends a record for every change. The actor is asked for the caller
+/// on each change, e.g. from a thread local of a request handler.
+/// After a failed write the log may end with an incomplete record, so it
+/// only collects the following records until they're written to a reopened log
+pub(crate) struct AuditLog {
+    writer: Box<dyn Write + Send>,
+    actor: Box<dyn Fn() -> String + Send + Sync>,
+    unwritten: Vec<u8>,
+    failure: Option<String>,
+}
+
+impl AuditLog {
+    /// Opens the log at the path. Fails if a record in it is corrupted
+    pub fn open(path: &Path, actor: Box<dyn Fn() -> String + Send + Sync>) -> Result<Self> {
+        let mut records = AuditRecords::open(path.to_path_buf())?;
+        while records.read_record()?.is_some() {}
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        // the incomplete record of an interrupted write is dropped so that the
+        // following records can be read
+        if file.metadata()?.len() > records.offset {
+            file.set_len(records.offset)?;
+        }
+
+        Ok(Self::new(Box::new(file), actor))
+    }
+
+    /// Creates a log that appends the records to the writer
+    pub fn new(
+        writer: Box<dyn Write + Send>,
+        actor: Box<dyn Fn() -> String + Send + Sync>,
+    ) -> Self {
+        Self {
+            writer,
+            actor,
+            unwritten: Vec::new(),
+            failure: None,
+        }
+    }
+
+    /// Returns an error if a record couldn't be written
+    pub fn check(&self) -> Result<()> {
+        match &self.failure {
+            Some(reason) => Err(Error::AuditLogFailed {
+                reason: reason.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the records that weren't written because of a failure
+    pub fn unwritten(&self) -> &[u8] {
+        &self.unwritten
+    }
+
+    /// Appends the records another log couldn't write
+    pub fn write_unwritten(&mut self, records: &[u8]) -> Result<()> {
+        self.writer.write_all(records)?;
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Appends the change with a length in front and a checksum after it in a
+    /// single write, so an interrupted write only leaves an incomplete last record.
+    /// A record that can't be written is kept and an error is returned
+    pub fn record(&mut self, change: &ChangeEvent) -> Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let mut payload = Vec::new();
+        payload.write_u64::<BigEndian>(time)?;
+        write_string(&mut payload, &(self.actor)())?;
+        match change {
+            ChangeEvent::Created { path } => {
+                payload.write_u8(CREATED)?;
+                write_string(&mut payload, path)?;
+            }
+            ChangeEvent::Modified { path } => {
+                payload.write_u8(MODIFIED)?;
+                write_string(&mut payload, path)?;
+            }
+            ChangeEvent::Deleted { path } => {
+                payload.write_u8(DELETED)?;
+                write_string(&mut payload, path)?;
+            }
+            ChangeEvent::Moved { from, to } => {
+                payload.write_u8(MOVED)?;
+                write_string(&mut payload, from)?;
+                write_string(&mut payload, to)?;
+            }
+        }
+        let mut record = Vec::with_capacity(payload.len() + 8);
+        record.write_u32::<BigEndian>(payload.len() as u32)?;
+        record.write_all(&payload)?;
+        record.write_u32::<BigEndian>(crc32fast::hash(&payload))?;
+        if self.failure.is_none() {
+            match self
+                .writer
+                .write_all(&record)
+                .and_then(|_| self.writer.flush())
+            {
+                Ok(()) => return Ok(()),
+                Err(e) => self.failure = Some(e.to_string()),
+            }
+        }
+        self.unwritten.extend_from_slice(&record);
+
+        self.check()
+    }
+}
+
+/// Iterates over the records of an audit log from the oldest one
+pub struct AuditRecords {
+    reader: Option<BufReader<File>>,
+    path: PathBuf,
+    offset: u64,
+}
+
+impl AuditRecords {
+    /// Opens the log at the path. A missing log has no records
+    pub(crate) fn open(path: PathBuf) -> Result<Self> {
+        let reader = match File::open(&path) {
+            Ok(file) => Some(BufReader::new(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            reader,
+            path,
+            offset: 0,
+        })
+    }
+
+    /// Reads the next record. Returns None at the end of the log or of its last
+    /// complete record
+    fn read_record(&mut self) -> Result<Option<AuditRecord>> {
+        let reader = match &mut self.reader {
+            Some(reader) => reader,
+            None => return Ok(None),
+        };
+        let length = match reader.read_u32::<BigEndian>() {
+            Ok(length) => length as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // the length of an incomplete record may be garbage, so it isn't allocated up front
+        let mut payload = Vec::new();
+        reader.take(length as u64).read_to_end(&mut payload)?;
+        if payload.len() < length {
+            return Ok(None);
+        }
+        let checksum = match reader.read_u32::<BigEndian>() {
+            Ok(checksum) => checksum,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let corrupt = |offset| Error::corrupt(offset, "invalid audit record").in_file(&self.path);
+        if checksum != crc32fast::hash(&payload) {
+            return Err(corrupt(self.offset));
+        }
+        let record = parse_record(&payload).ok_or_else(|| corrupt(self.offset))?;
+        self.offset += 8 + length as u64;
+
+        Ok(Some(record))
+    }
+}
+
+impl Iterator for AuditRecords {
+    type Item = Result<AuditRecord>;
+
+    /// Stops after the first error
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.read_record().transpose();
+        if let Some(Err(_)) = record {
+            self.reader = None;
+        }
+
+        record
+    }
+}
+
+fn parse_record(mut payload: &[u8]) -> Option<AuditRecord> {
+    let reader = &mut payload;
+    let time = UNIX_EPOCH + Duration::from_millis(reader.read_u64::<BigEndian>().ok()?);
+    let actor = read_string(reader)?;
+    let change = match reader.read_u8().ok()? {
+        CREATED => ChangeEvent::Created {
+            path: read_string(reader)?,
+        },
+        MODIFIED => ChangeEvent::Modified {
+            path: read_string(reader)?,
+        },
+        DELETED => ChangeEvent::Deleted {
+            path: read_string(reader)?,
+        },
+        MOVED => ChangeEvent::Moved {
+            from: read_string(reader)?,
+            to: read_string(reader)?,
+        },
+        _ => return None,
+    };
+
+    Some(AuditRecord {
+        time,
+        actor,
+        change,
+    })
+}
+
+fn write_string<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    writer.write_u32::<BigEndian>(value.len() as u32)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn read_string(reader: &mut &[u8]) -> Option<String> {
+    let length = reader.read_u32::<BigEndian>().ok()? as usize;
+    if length > reader.len() {
+        return None;
+    }
+    let (value, rest) = reader.split_at(length);
+    *reader = rest;
+
+    String::from_utf8(value.to_vec()).ok()
+}
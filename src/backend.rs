@@ -0,0 +1,463 @@
+use std::alloc::{self, Layout};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::slice;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// The size of the blocks reads and writes are aligned to with direct I/O
+pub const BLOCK_SIZE: u64 = 4096;
+
+/// When written data is synced to the disk. Flushing only hands data to the
+/// operating system so it survives a crash of the process but not of the system
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync after every change
+    Always,
+    /// Sync when a journaled change or transaction is committed and after a file is stored
+    OnCommit,
+    /// Never sync and leave it to the operating system
+    #[default]
+    Never,
+}
+
+/// The medium a dir tree is stored in
+pub trait Backend: Read + Write + Seek {
+    /// Returns the size of the backend in bytes
+    fn size(&mut self) -> io::Result<u64> {
+        self.seek(SeekFrom::End(0))
+    }
+
+    /// Truncates or extends the backend to the given size
+    fn set_len(&mut self, size: u64) -> io::Result<()>;
+
+    /// Writes the data through to the storage medium
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl Backend for File {
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+impl<B: Backend + ?Sized> Backend for Box<B> {
+    fn size(&mut self) -> io::Result<u64> {
+        (**self).size()
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        (**self).set_len(size)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        (**self).sync()
+    }
+}
+
+impl Backend for Cursor<Vec<u8>> {
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.get_ref().len() as u64)
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.get_mut().resize(size as usize, 0);
+
+        Ok(())
+    }
+}
+
+/// Storage for the blob data. Data is addressed by the number of a data file
+/// and an offset inside of it so that remote backends can map data files to objects
+pub trait DataBackend: Send + Sync {
+    /// Reads data from the given file at the offset and returns the number of bytes read
+    fn read_at(&self, file: u32, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Writes all data to the given file at the offset
+    fn write_at(&self, file: u32, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Returns the size of the given file. Files that don't exist have a size of 0
+    fn len(&self, file: u32) -> io::Result<u64>;
+
+    /// Truncates or extends the file to the given size
+    fn truncate(&self, file: u32, size: u64) -> io::Result<()>;
+
+    /// Writes the data of the file through to the storage medium
+    fn sync(&self, _file: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Fills the whole buffer with data from the given file at the offset
+    fn read_exact_at(&self, file: u32, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(file, offset, buf)? {
+                0 => return Err(io::Error::from(ErrorKind::UnexpectedEof)),
+                n => {
+                    offset += n as u64;
+                    buf = &mut buf[n..];
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fills the buffers of all reads like [DataBackend::read_exact_at]. Backends
+    /// that can have multiple reads in flight submit them together
+    fn read_exact_batch(&self, reads: &mut [BatchRead<'_>]) -> io::Result<()> {
+        for read in reads {
+            self.read_exact_at(read.file, read.offset, read.buf)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A read of [DataBackend::read_exact_batch]
+pub struct BatchRead<'a> {
+    pub file: u32,
+    pub offset: u64,
+    pub buf: &'a mut [u8],
+}
+
+/// Stores data files as `data-<n>.bin` in a local directory
+pub struct LocalDataBackend {
+    dir: PathBuf,
+    handles: Mutex<HashMap<u32, File>>,
+    /// If reads and writes are done in whole aligned blocks
+    direct: bool,
+}
+
+impl LocalDataBackend {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            handles: Mutex::new(HashMap::new()),
+            direct: false,
+        }
+    }
+
+    /// Creates a backend that bypasses the page cache for deployments that do their
+    /// own caching. Data files are opened with `O_DIRECT` on Linux with the
+    /// `direct-io` feature if the file system supports it and all reads and writes
+    /// are done in whole blocks of [BLOCK_SIZE] bytes
+    pub fn with_direct_io(dir: PathBuf) -> Self {
+        Self {
+            direct: true,
+            ..Self::new(dir)
+        }
+    }
+
+    /// Returns if reads and writes are done in whole aligned blocks
+    pub fn is_direct(&self) -> bool {
+        self.direct
+    }
+
+    /// Returns the path of the data file with the given number
+    pub fn file_path(&self, file: u32) -> PathBuf {
+        self.dir.join(format!("data-{}.bin", file))
+    }
+
+    /// Runs the operation on the opened file creating the file if requested
+    pub(crate) fn with_file<T, F: FnOnce(&mut File) -> io::Result<T>>(
+        &self,
+        file: u32,
+        create: bool,
+        operation: F,
+    ) -> io::Result<T> {
+        let mut handles = self
+            .handles
+            .lock()
+            .map_err(|_| io::Error::from(ErrorKind::Other))?;
+        let handle = match handles.entry(file) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(self.open_file(file, create)?),
+        };
+
+        operation(handle)
+    }
+
+    /// Runs the operation on the opened files with the given numbers in the same order
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub(crate) fn with_files<T, F: FnOnce(&[&File]) -> io::Result<T>>(
+        &self,
+        files: &[u32],
+        operation: F,
+    ) -> io::Result<T> {
+        let mut handles = self
+            .handles
+            .lock()
+            .map_err(|_| io::Error::from(ErrorKind::Other))?;
+        for file in files {
+            if let Entry::Vacant(entry) = handles.entry(*file) {
+                entry.insert(self.open_file(*file, false)?);
+            }
+        }
+        let opened: Vec<&File> = files.iter().map(|file| &handles[file]).collect();
+
+        operation(&opened)
+    }
+
+    fn open_file(&self, file: u32, create: bool) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        options
+            .create(create)
+            .truncate(false)
+            .read(true)
+            .write(true);
+        #[cfg(all(feature = "direct-io", target_os = "linux"))]
+        if self.direct {
+            use std::os::unix::fs::OpenOptionsExt;
+            let mut direct = options.clone();
+            direct.custom_flags(libc::O_DIRECT);
+            match direct.open(self.file_path(file)) {
+                // file systems without direct I/O reject the flag
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+                result => return result,
+            }
+        }
+
+        options.open(self.file_path(file))
+    }
+}
+
+/// A zeroed buffer starting at a block boundary as required by direct I/O
+struct AlignedBuffer {
+    pointer: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    fn new(length: usize) -> Self {
+        let layout = Layout::from_size_align(length.max(1), BLOCK_SIZE as usize)
+            .expect("invalid buffer layout");
+        // the layout always has a non-zero size
+        let pointer = unsafe { alloc::alloc_zeroed(layout) };
+        if pointer.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+
+        Self { pointer, layout }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.pointer, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.pointer, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.pointer, self.layout) }
+    }
+}
+
+/// Returns the range of whole blocks covering the given range
+fn block_range(offset: u64, length: usize) -> (u64, u64) {
+    let start = offset / BLOCK_SIZE * BLOCK_SIZE;
+    let end = (offset + length as u64).div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+
+    (start, end.max(start + BLOCK_SIZE))
+}
+
+/// Reads at the offset with a single positional read on unix instead of a seek
+/// followed by a read
+#[cfg(unix)]
+fn read_file_at(f: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(f, buf, offset)
+}
+
+#[cfg(not(unix))]
+fn read_file_at(f: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    f.seek(SeekFrom::Start(offset))?;
+    f.read(buf)
+}
+
+/// Writes all data at the offset with positional writes on unix
+#[cfg(unix)]
+fn write_file_at(f: &mut File, offset: u64, data: &[u8]) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(f, data, offset)
+}
+
+#[cfg(not(unix))]
+fn write_file_at(f: &mut File, offset: u64, data: &[u8]) -> io::Result<()> {
+    f.seek(SeekFrom::Start(offset))?;
+    f.write_all(data)
+}
+
+/// Reads whole blocks at an aligned offset until the buffer is full or the end
+/// of the file is reached and returns the number of bytes read
+fn read_blocks(f: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match read_file_at(f, offset + read as u64, &mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(read)
+}
+
+fn read_direct(f: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    let (start, end) = block_range(offset, buf.len());
+    let mut blocks = AlignedBuffer::new((end - start) as usize);
+    let read = read_blocks(f, start, &mut blocks)?;
+    let skipped = (offset - start) as usize;
+    let length = read.saturating_sub(skipped).min(buf.len());
+    buf[..length].copy_from_slice(&blocks[skipped..skipped + length]);
+
+    Ok(length)
+}
+
+/// Writes the data by reading the partially covered first and last block,
+/// changing them in memory and writing all blocks back
+fn write_direct(f: &mut File, offset: u64, data: &[u8]) -> io::Result<()> {
+    let size = f.metadata()?.len();
+    let (start, end) = block_range(offset, data.len());
+    let mut blocks = AlignedBuffer::new((end - start) as usize);
+    let block = BLOCK_SIZE as usize;
+    if start < size {
+        read_blocks(f, start, &mut blocks[..block])?;
+    }
+    let last = end - BLOCK_SIZE;
+    if last > start && last < size {
+        let index = (last - start) as usize;
+        read_blocks(f, last, &mut blocks[index..])?;
+    }
+    let skipped = (offset - start) as usize;
+    blocks[skipped..skipped + data.len()].copy_from_slice(data);
+    write_file_at(f, start, &blocks)?;
+    // the padding of the last block isn't part of the file
+    if end > size {
+        f.set_len(size.max(offset + data.len() as u64))?;
+    }
+
+    Ok(())
+}
+
+impl DataBackend for LocalDataBackend {
+    fn read_at(&self, file: u32, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let direct = self.direct;
+        self.with_file(file, false, |f| {
+            if direct {
+                return read_direct(f, offset, buf);
+            }
+            read_file_at(f, offset, buf)
+        })
+    }
+
+    fn write_at(&self, file: u32, offset: u64, data: &[u8]) -> io::Result<()> {
+        let direct = self.direct;
+        self.with_file(file, true, |f| {
+            if direct {
+                return write_direct(f, offset, data);
+            }
+            write_file_at(f, offset, data)
+        })
+    }
+
+    fn len(&self, file: u32) -> io::Result<u64> {
+        match self.file_path(file).metadata() {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn truncate(&self, file: u32, size: u64) -> io::Result<()> {
+        self.with_file(file, true, |f| f.set_len(size))
+    }
+
+    fn sync(&self, file: u32) -> io::Result<()> {
+        self.with_file(file, true, |f| f.sync_data())
+    }
+}
+
+/// Keeps the data files in memory, e.g. for targets without a file system
+/// like wasm32 or for data files that were fetched over the network
+#[derive(Default)]
+pub struct MemoryDataBackend {
+    files: Mutex<HashMap<u32, Vec<u8>>>,
+}
+
+impl MemoryDataBackend {
+    /// Creates a backend with the content of the data files by their number
+    pub fn from_files(files: HashMap<u32, Vec<u8>>) -> Self {
+        Self {
+            files: Mutex::new(files),
+        }
+    }
+
+    /// Returns the content of the data files by their number
+    pub fn into_files(self) -> HashMap<u32, Vec<u8>> {
+        self.files
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn files(&self) -> MutexGuard<'_, HashMap<u32, Vec<u8>>> {
+        self.files.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl DataBackend for MemoryDataBackend {
+    fn read_at(&self, file: u32, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let files = self.files();
+        let data = files.get(&file).map_or(&[][..], |f| &f[..]);
+        let start = data.len().min(offset as usize);
+        let end = data.len().min(start + buf.len());
+        buf[..end - start].copy_from_slice(&data[start..end]);
+
+        Ok(end - start)
+    }
+
+    fn write_at(&self, file: u32, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut files = self.files();
+        let content = files.entry(file).or_default();
+        let end = offset as usize + data.len();
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[offset as usize..end].copy_from_slice(data);
+
+        Ok(())
+    }
+
+    fn len(&self, file: u32) -> io::Result<u64> {
+        Ok(self.files().get(&file).map_or(0, |f| f.len()) as u64)
+    }
+
+    fn truncate(&self, file: u32, size: u64) -> io::Result<()> {
+        self.files()
+            .entry(file)
+            .or_default()
+            .resize(size as usize, 0);
+
+        Ok(())
+    }
+}
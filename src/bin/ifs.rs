@@ -0,0 +1,202 @@
+use indexed_file_storage::error::{Error, Result};
+use indexed_file_storage::metafile::INLINE_FILE;
+use indexed_file_storage::storage::{BlobMeta, Storage};
+use indexed_file_storage::utils::{join_path, normalize_path, split_path};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Write};
+use std::path::PathBuf;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const USAGE: &str = "Usage: ifs <storage-dir> <command> [args]
+
+Commands:
+    init                   creates an empty storage
+    ls [path]              lists the entries of a directory
+    put <file> <path>      stores a local file at the given path
+    get <path> [file]      writes a stored file to a local file or stdout
+    rm <path>              removes a file or an empty directory
+    mkdir [-p] <path>      creates a directory and with -p all missing parents
+    mv <from> <to>         moves a file
+    ln <file> <link>       creates a hard link sharing the content of a file
+    stat <path>            prints information about an entry
+    du [path]              prints the number of entries and bytes below a directory
+    stats                  prints the entries and space used by the storage
+    compact                rewrites the tree and data files without unused space";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+
+    if let Err(e) = run(PathBuf::from(&args[0]), &args[1], &args[2..]) {
+        eprintln!("ifs: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(storage_path: PathBuf, command: &str, args: &[String]) -> Result<()> {
+    if command != "init" && !storage_path.exists() {
+        return Err(Error::NotFound {
+            path: storage_path.to_string_lossy().to_string(),
+        });
+    }
+    // commands that don't modify the storage can run alongside each other
+    let storage = match command {
+        "ls" | "get" | "stat" | "du" | "stats" => Storage::open_read_only(storage_path)?,
+        _ => Storage::open(storage_path)?,
+    };
+
+    match (command, args) {
+        ("init", []) => Ok(()),
+        ("ls", []) => list(&storage, "/"),
+        ("ls", [path]) => list(&storage, path),
+        ("put", [file, path]) => {
+            let length = storage.store(path, BufReader::new(File::open(file)?))?;
+            if let Some(name) = PathBuf::from(file).file_name() {
+                let blob_meta = BlobMeta {
+                    source_name: Some(name.to_string_lossy().into_owned()),
+                    created: None,
+                    modified: None,
+                    ..storage.blob_meta(path)?
+                };
+                storage.set_blob_meta(path, &blob_meta)?;
+            }
+            println!("{} bytes written to {}", length, normalize_path(path));
+            Ok(())
+        }
+        ("get", [path]) => {
+            let stdout = io::stdout();
+            io::copy(&mut storage.get(path)?, &mut stdout.lock())?;
+            Ok(())
+        }
+        ("get", [path, file]) => {
+            let mut writer = BufWriter::new(File::create(file)?);
+            io::copy(&mut storage.get(path)?, &mut writer)?;
+            writer.flush()?;
+            Ok(())
+        }
+        ("rm", [path]) => storage.delete(path),
+        ("mkdir", [path]) => storage.create_dir(path),
+        ("mkdir", [flag, path]) if flag == "-p" => storage.create_dir_all(path),
+        ("mv", [from, to]) => {
+            // moving onto a directory moves the file into it
+            let is_dir = normalize_path(to) == "/" || storage.entry(to).is_ok_and(|e| e.is_dir());
+            let to = if is_dir {
+                join_path(to, &split_path(from)?.1)
+            } else {
+                to.clone()
+            };
+            storage.rename(from, &to)
+        }
+        ("ln", [file, link]) => storage.hard_link(file, link),
+        ("stat", [path]) => stat(&storage, path),
+        ("du", []) => du(&storage, "/"),
+        ("du", [path]) => du(&storage, path),
+        ("stats", []) => stats(&storage),
+        ("compact", []) => {
+            println!("{} bytes reclaimed", storage.compact()?);
+            Ok(())
+        }
+        _ => Err(Error::Io(io::Error::new(ErrorKind::InvalidInput, USAGE))),
+    }
+}
+
+fn list(storage: &Storage, path: &str) -> Result<()> {
+    let mut entries = storage.read_dir(path)?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    for entry in entries {
+        if entry.is_dir() {
+            println!("{}/", entry.name);
+        } else {
+            println!("{}", entry.name);
+        }
+    }
+
+    Ok(())
+}
+
+fn stat(storage: &Storage, path: &str) -> Result<()> {
+    let path = normalize_path(path);
+    if path == "/" || storage.entry(&path)?.is_dir() {
+        println!("path: {}\ntype: directory", path);
+        return Ok(());
+    }
+    let (file, pointer, _) = *storage
+        .meta()
+        .get_entry(&path)
+        .ok_or_else(|| Error::NotFound { path: path.clone() })?;
+    println!(
+        "path: {}\ntype: file\nsize: {}\nlinks: {}",
+        path,
+        storage.get(&path)?.remaining(),
+        storage.link_count(&path)?,
+    );
+    if file == INLINE_FILE {
+        println!("data file: inline");
+    } else {
+        println!("data file: {}\npointer: {}", file, pointer);
+    }
+    let blob_meta = storage.blob_meta(&path)?;
+    if let Some(content_type) = blob_meta.content_type {
+        println!("content type: {}", content_type);
+    }
+    if let Some(source_name) = blob_meta.source_name {
+        println!("source name: {}", source_name);
+    }
+    if let Some(created) = blob_meta.created {
+        println!("created: {}", unix_seconds(created));
+    }
+    if let Some(modified) = blob_meta.modified {
+        println!("modified: {}", unix_seconds(modified));
+    }
+
+    Ok(())
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn stats(storage: &Storage) -> Result<()> {
+    let stats = storage.stats()?;
+    println!(
+        "files: {}\ndirectories: {}\nsymlinks: {}\nlogical bytes: {}\nphysical bytes: {}",
+        stats.files, stats.dirs, stats.symlinks, stats.logical_bytes, stats.physical_bytes
+    );
+    println!(
+        "index entries: {}\ninline entries: {}\nchunked entries: {}\ndata chunks: {}",
+        stats.index_entries, stats.inline_entries, stats.chunked_entries, stats.data_chunks
+    );
+    println!("fragmentation: {:.1}%", stats.fragmentation());
+    for file in &stats.data_files {
+        println!(
+            "data file {}: {} bytes, {:.1}% used",
+            file.file,
+            file.size,
+            file.utilization()
+        );
+    }
+    println!(
+        "tree: {} bytes, {} entries, {:.1}% free",
+        stats.tree.file_size,
+        stats.tree.entries,
+        stats.tree.fragmentation()
+    );
+
+    Ok(())
+}
+
+fn du(storage: &Storage, path: &str) -> Result<()> {
+    let stats = storage.tree().dir_stats(path)?;
+    println!(
+        "files: {}\ndirectories: {}\nsymlinks: {}\nbytes: {}",
+        stats.files, stats.dirs, stats.symlinks, stats.bytes
+    );
+
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use crate::metafile::EntryID;
+
+/// The number of bits kept for every entry the filter is sized for
+const BITS_PER_ENTRY: u64 = 10;
+/// The number of bits set for every entry
+const HASHES: u64 = 7;
+
+/// A bloom filter over entry ids so that lookups of missing ids can be
+/// answered without reading the table. Removed ids stay in the filter
+/// until it's rebuilt
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    /// Creates an empty filter sized for the given number of entries
+    pub fn with_capacity(entries: u64) -> Self {
+        let words = (entries.max(1) * BITS_PER_ENTRY).div_ceil(64);
+
+        Self {
+            bits: vec![0; words as usize],
+        }
+    }
+
+    pub fn insert(&mut self, id: &EntryID) {
+        for bit in self.bits(id) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns false if the id was never inserted
+    pub fn may_contain(&self, id: &EntryID) -> bool {
+        self.bits(id)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Returns the bits of an id. The ids are hashes already so two parts of
+    /// them are combined instead of hashing again
+    fn bits(&self, id: &EntryID) -> impl Iterator<Item = u64> {
+        let mut first = [0u8; 8];
+        let mut second = [0u8; 8];
+        first.copy_from_slice(&id[8..16]);
+        second.copy_from_slice(&id[16..24]);
+        let first = u64::from_le_bytes(first);
+        let second = u64::from_le_bytes(second) | 1;
+        let size = self.bits.len() as u64 * 64;
+
+        (0..HASHES).map(move |i| first.wrapping_add(i.wrapping_mul(second)) % size)
+    }
+}
@@ -0,0 +1,53 @@
+//! Reads a storage from in-memory images of its files, so that containers can
+//! be read without a file system, e.g. by browser apps on wasm32
+
+use crate::backend::DataBackend;
+use crate::dirtreefile::{DirEntry, DirTreeFile};
+use crate::error::{Error, Result};
+use crate::metafile::IndexedMetaFile;
+use crate::storage::BlobReader;
+use crate::utils::normalize_path;
+use std::io::Cursor;
+use std::sync::Arc;
+
+/// A read-only view of a storage whose tree and index are kept in memory and
+/// whose data files are read through a [DataBackend], e.g. a
+/// [MemoryDataBackend](crate::backend::MemoryDataBackend)
+pub struct MemoryContainer {
+    tree: DirTreeFile<Cursor<Vec<u8>>>,
+    meta: IndexedMetaFile,
+    data: Arc<dyn DataBackend>,
+}
+
+impl MemoryContainer {
+    /// Opens the container from the content of the tree and index files of a
+    /// storage directory and the backend with its data files
+    pub fn open(tree: Vec<u8>, meta: Vec<u8>, data: Arc<dyn DataBackend>) -> Result<Self> {
+        Ok(Self {
+            tree: DirTreeFile::from_backend(Cursor::new(tree))?,
+            meta: IndexedMetaFile::from_reader(&meta[..])?,
+            data,
+        })
+    }
+
+    /// Returns the entries of the directory at the given path
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>> {
+        self.tree.cd(&normalize_path(path))?;
+        self.tree.entries()
+    }
+
+    /// Returns the tree entry at the given path
+    pub fn entry(&mut self, path: &str) -> Result<DirEntry> {
+        let path = normalize_path(path);
+
+        self.tree.lookup(&path)?.ok_or(Error::NotFound { path })
+    }
+
+    /// Returns a reader for the content of the file at the given path
+    pub fn get(&self, path: &str) -> Result<BlobReader> {
+        let path = normalize_path(path);
+        let id = self.meta.hash_id(&path);
+
+        BlobReader::open(&self.meta, &self.data, &id, None)?.ok_or(Error::NotFound { path })
+    }
+}
@@ -1,59 +1,414 @@
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crate::backend::{Backend, SyncPolicy};
+use crate::error::{Error, Result};
+pub use crate::format::tree::DirEntry;
+use crate::format::tree::{
+    ChunkHeader, TreeHeader, BLOB_ID_ATTRIBUTE, CASE_INSENSITIVE_FLAG, CHECKSUM_FLAG,
+    DEFAULT_CHUNK_SIZE, EXTENDED_FLAG, FORMAT_VERSION, HEADER_SIZE, INDEX_FLAG, LENGTH_MASK, MAGIC,
+    MAX_CHUNK_ENTRIES, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE, OFFSET_SIZE, SORTED_FLAG, SYMLINK_ATTRIBUTE,
+};
+use crate::journal::JournalBackend;
+use crate::json::Json;
+use crate::lru::LruCache;
+use crate::metafile::EntryID;
+use crate::metrics::{Metrics, NoMetrics, TREE_FRAGMENTATION, TREE_LOOKUP_SECONDS};
+use crate::progress::{OperationOptions, Progress};
+use crate::trace::{event, span};
+use crate::utils::{glob_match, is_glob, join_path, normalize_path, split_path};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use std::borrow::Cow;
 use std::cmp::Ordering;
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::mem;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const CHUNK_SIZE: u64 = 1024;
+/// The location of the pointer to the first free chunk in the header of all versions
+const FREE_HEAD_OFFSET: u64 = 8;
+/// The location of the pointer to a journal of an unfinished transaction in the
+/// current header. It's 0 if there's none
+const JOURNAL_OFFSET: u64 = 20;
+/// Names reserved for devices on Windows regardless of their extension
+const WINDOWS_RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
 
+/// Rules the names of new entries have to follow. Names are never allowed to be
+/// empty or to contain a slash
+#[derive(Clone, Default)]
+pub enum NamePolicy {
+    /// Allows every other name
+    #[default]
+    Permissive,
+    /// Only allows the portable filename characters `A-Za-z0-9._-` without a leading hyphen
+    Posix,
+    /// Rejects names that can't be created on Windows
+    Windows,
+    /// Allows the names the function returns true for
+    Custom(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+impl NamePolicy {
+    /// Returns if the policy allows the name
+    pub fn allows(&self, name: &str) -> bool {
+        if name.is_empty() || name.contains('/') {
+            return false;
+        }
+        match self {
+            NamePolicy::Permissive => true,
+            NamePolicy::Posix => {
+                !name.starts_with('-')
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+            }
+            NamePolicy::Windows => {
+                let stem = name.split('.').next().unwrap_or(name);
+                !name.ends_with(['.', ' '])
+                    && !name
+                        .chars()
+                        .any(|c| c.is_control() || "<>:\"\\|?*".contains(c))
+                    && !WINDOWS_RESERVED
+                        .iter()
+                        .any(|r| r.eq_ignore_ascii_case(stem.trim_end()))
+            }
+            NamePolicy::Custom(allows) => allows(name),
+        }
+    }
+}
+
+impl fmt::Debug for NamePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NamePolicy::Permissive => write!(f, "Permissive"),
+            NamePolicy::Posix => write!(f, "Posix"),
+            NamePolicy::Windows => write!(f, "Windows"),
+            NamePolicy::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// Options for creating a new dir tree
 #[derive(Clone, Debug)]
-pub struct DirEntry {
-    pub name: String,
-    pub(crate) child_pointer: u64,
+pub struct TreeOptions {
+    /// The number of bytes available for entries in each directory chunk
+    pub chunk_size: u32,
+    /// If names are compared ignoring case. Names keep the case they were created
+    /// with and entries that only differ by case are rejected
+    pub case_insensitive: bool,
+    /// If a CRC32 of the header and payload is stored after every chunk
+    pub checksums: bool,
 }
 
-impl DirEntry {
-    pub fn new(name: String, child_pointer: u64) -> Self {
+impl Default for TreeOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            case_insensitive: false,
+            checksums: false,
+        }
+    }
+}
+
+const METADATA_ATTRIBUTE: u8 = 1;
+/// Attribute containing the key length, the key and the value of an xattr
+const XATTR_ATTRIBUTE: u8 = 2;
+/// The maximum number of symlinks followed while resolving a single path
+const MAX_SYMLINK_DEPTH: usize = 40;
+/// The number of recently read chunks that are cached by default
+pub const DEFAULT_CHUNK_CACHE_CAPACITY: usize = 1024;
+
+/// Size and timestamps of an entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntryMetadata {
+    pub size: u64,
+    pub created: SystemTime,
+    pub modified: SystemTime,
+}
+
+impl EntryMetadata {
+    /// Creates metadata for an entry of the given size created now
+    pub fn new(size: u64) -> Self {
+        let now = SystemTime::now();
+
         Self {
-            name,
-            child_pointer,
+            size,
+            created: now,
+            modified: now,
         }
     }
 
-    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> io::Result<Self> {
-        let length = reader.read_u16::<BigEndian>()?;
-        if length < 8 {
-            return Err(io::Error::from(io::ErrorKind::InvalidData));
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(24);
+        data.extend_from_slice(&self.size.to_be_bytes());
+        data.extend_from_slice(&time_to_nanos(self.created).to_be_bytes());
+        data.extend_from_slice(&time_to_nanos(self.modified).to_be_bytes());
+
+        data
+    }
+
+    pub(crate) fn from_bytes(mut data: &[u8]) -> Option<Self> {
+        if data.len() != 24 {
+            return None;
         }
-        let mut name_buf = vec![0u8; (length - 8) as usize];
-        reader.read(&mut name_buf)?;
-        let name =
-            String::from_utf8(name_buf).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
-        let pointer = reader.read_u64::<BigEndian>()?;
+        let size = data.read_u64::<BigEndian>().ok()?;
+        let created = data.read_u64::<BigEndian>().ok()?;
+        let modified = data.read_u64::<BigEndian>().ok()?;
 
-        Ok(Self {
-            name,
-            child_pointer: pointer,
+        Some(Self {
+            size,
+            created: UNIX_EPOCH + Duration::from_nanos(created),
+            modified: UNIX_EPOCH + Duration::from_nanos(modified),
         })
     }
+}
 
-    pub fn write<W: Write + Seek>(&self, writer: &mut W) -> io::Result<usize> {
+fn time_to_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+impl DirEntry {
+    pub fn write<W: Write + Seek>(&self, writer: &mut W) -> Result<usize> {
         let name_raw = self.name.as_bytes();
-        writer.write_u16::<BigEndian>(name_raw.len() as u16 + 8)?;
-        writer.write(&name_raw)?;
-        writer.write_u64::<BigEndian>(self.child_pointer)?;
+        let mut length = name_raw.len() as u16 + 8;
+        if !self.attributes.is_empty() {
+            length |= EXTENDED_FLAG;
+        }
+        // the record is assembled first so that it is written with a single call
+        let mut record = Vec::with_capacity(self.size());
+        record.write_u16::<BigEndian>(length)?;
+        record.write_all(name_raw)?;
+        record.write_u64::<BigEndian>(self.child_pointer)?;
 
-        Ok((name_raw.len() as u16 + 18) as usize)
+        if !self.attributes.is_empty() {
+            record.write_u16::<BigEndian>(self.attributes_size() as u16)?;
+            for (tag, data) in &self.attributes {
+                record.write_u8(*tag)?;
+                record.write_u16::<BigEndian>(data.len() as u16)?;
+                record.write_all(data)?;
+            }
+        }
+        writer.write_all(&record)?;
+
+        Ok(record.len())
     }
 
-    /// Returns the required size for the entry
-    pub fn size(&self) -> usize {
-        self.name.as_bytes().len() + 10
+    /// Returns the size and timestamps of the entry if they were recorded
+    pub fn metadata(&self) -> Option<EntryMetadata> {
+        self.attributes
+            .iter()
+            .find(|(tag, _)| *tag == METADATA_ATTRIBUTE)
+            .and_then(|(_, data)| EntryMetadata::from_bytes(data))
+    }
+
+    /// Sets the size and timestamps of the entry. The change is persisted
+    /// with [DirTreeFile::set_metadata]
+    pub fn set_metadata(&mut self, metadata: EntryMetadata) {
+        self.attributes
+            .retain(|(tag, _)| *tag != METADATA_ATTRIBUTE);
+        self.attributes
+            .push((METADATA_ATTRIBUTE, metadata.to_bytes()));
+    }
+
+    /// Returns the value of an extended attribute
+    pub fn xattr(&self, key: &str) -> Option<&[u8]> {
+        self.xattrs()
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns all extended attributes of the entry
+    pub fn xattrs(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.attributes
+            .iter()
+            .filter(|(tag, _)| *tag == XATTR_ATTRIBUTE)
+            .filter_map(|(_, data)| split_xattr(data))
+    }
+
+    /// Sets an extended attribute replacing an existing value. The change is
+    /// persisted with [DirTreeFile::set_xattr]
+    pub fn set_xattr(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        if key.is_empty() || key.len() > u8::MAX as usize {
+            return Err(Error::InvalidName {
+                name: key.to_string(),
+            });
+        }
+        self.remove_xattr(key);
+        let mut data = Vec::with_capacity(key.len() + value.len() + 1);
+        data.push(key.len() as u8);
+        data.extend_from_slice(key.as_bytes());
+        data.extend_from_slice(value);
+        self.attributes.push((XATTR_ATTRIBUTE, data));
+
+        Ok(())
+    }
+
+    /// Removes an extended attribute and returns if it existed
+    pub fn remove_xattr(&mut self, key: &str) -> bool {
+        let length = self.attributes.len();
+        self.attributes.retain(|(tag, data)| {
+            *tag != XATTR_ATTRIBUTE || split_xattr(data).is_none_or(|(k, _)| k != key)
+        });
+
+        self.attributes.len() != length
+    }
+
+    /// Sets the id of the content of the file. The change is persisted
+    /// with [DirTreeFile::set_blob_id]
+    pub fn set_blob_id(&mut self, id: EntryID) {
+        self.attributes.retain(|(tag, _)| *tag != BLOB_ID_ATTRIBUTE);
+        self.attributes.push((BLOB_ID_ATTRIBUTE, id.to_vec()));
+    }
+}
+
+/// Returns the JSON representation of an entry without its children
+fn entry_to_json(entry: &DirEntry) -> Json {
+    let mut members = vec![("name".to_string(), Json::String(entry.name.clone()))];
+    let kind = if entry.is_dir() {
+        "dir"
+    } else if entry.is_symlink() {
+        "symlink"
+    } else {
+        "file"
+    };
+    members.push(("type".to_string(), Json::String(kind.to_string())));
+    if let Some(target) = entry.symlink_target() {
+        members.push(("target".to_string(), Json::String(target.to_string())));
     }
+    if let Some(id) = entry.blob_id() {
+        let hex: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+        members.push(("blob".to_string(), Json::String(hex)));
+    }
+    if let Some(metadata) = entry.metadata() {
+        let metadata = vec![
+            ("size".to_string(), Json::Number(metadata.size)),
+            (
+                "created".to_string(),
+                Json::Number(time_to_nanos(metadata.created)),
+            ),
+            (
+                "modified".to_string(),
+                Json::Number(time_to_nanos(metadata.modified)),
+            ),
+        ];
+        members.push(("metadata".to_string(), Json::Object(metadata)));
+    }
+    let xattrs: Vec<(String, Json)> = entry
+        .xattrs()
+        .map(|(key, value)| (key.to_string(), Json::bytes(value)))
+        .collect();
+    if !xattrs.is_empty() {
+        members.push(("xattrs".to_string(), Json::Object(xattrs)));
+    }
+    let unknown: Vec<Json> = entry
+        .attributes
+        .iter()
+        .filter(|(tag, _)| {
+            ![
+                METADATA_ATTRIBUTE,
+                XATTR_ATTRIBUTE,
+                SYMLINK_ATTRIBUTE,
+                BLOB_ID_ATTRIBUTE,
+            ]
+            .contains(tag)
+        })
+        .map(|(tag, data)| Json::Array(vec![Json::Number(*tag as u64), Json::bytes(data)]))
+        .collect();
+    if !unknown.is_empty() {
+        members.push(("attributes".to_string(), Json::Array(unknown)));
+    }
+
+    Json::Object(members)
+}
 
-    pub fn is_dir(&self) -> bool {
-        self.child_pointer != 0
+/// Creates an entry without a child pointer from its JSON representation and
+/// returns if it's a directory
+fn entry_from_json(json: &Json) -> Result<(DirEntry, bool)> {
+    let invalid = |reason: &str| Error::InvalidArchive {
+        reason: reason.to_string(),
+    };
+    let name = json
+        .get("name")
+        .and_then(Json::as_str)
+        .ok_or_else(|| invalid("entry without a name"))?;
+    let mut entry = DirEntry::new(name.to_string(), 0);
+    let dir = match json.get("type").and_then(Json::as_str) {
+        Some("dir") => true,
+        Some("file") => false,
+        Some("symlink") => {
+            let target = json
+                .get("target")
+                .and_then(Json::as_str)
+                .ok_or_else(|| invalid("symlink without a target"))?;
+            entry
+                .attributes
+                .push((SYMLINK_ATTRIBUTE, target.as_bytes().to_vec()));
+            false
+        }
+        _ => return Err(invalid("entry with an unknown type")),
+    };
+    if let Some(hex) = json.get("blob") {
+        let id = hex
+            .as_str()
+            .filter(|hex| hex.len() == 64 && hex.is_ascii())
+            .and_then(|hex| {
+                (0..32)
+                    .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok())
+                    .collect::<Option<Vec<u8>>>()
+            })
+            .ok_or_else(|| invalid("blob id isn't 32 bytes of hex"))?;
+        entry.attributes.push((BLOB_ID_ATTRIBUTE, id));
     }
+    if let Some(metadata) = json.get("metadata") {
+        let field = |key: &str| {
+            metadata
+                .get(key)
+                .and_then(Json::as_u64)
+                .ok_or_else(|| invalid("incomplete metadata"))
+        };
+        entry.set_metadata(EntryMetadata {
+            size: field("size")?,
+            created: UNIX_EPOCH + Duration::from_nanos(field("created")?),
+            modified: UNIX_EPOCH + Duration::from_nanos(field("modified")?),
+        });
+    }
+    for (key, value) in json.get("xattrs").and_then(Json::as_object).unwrap_or(&[]) {
+        let value = value
+            .as_bytes()
+            .ok_or_else(|| invalid("xattr value isn't a byte array"))?;
+        entry.set_xattr(key, &value)?;
+    }
+    for attribute in json
+        .get("attributes")
+        .and_then(Json::as_array)
+        .unwrap_or(&[])
+    {
+        match attribute.as_array() {
+            Some([Json::Number(tag), data]) if *tag <= u8::MAX as u64 => {
+                let data = data
+                    .as_bytes()
+                    .ok_or_else(|| invalid("attribute data isn't a byte array"))?;
+                entry.attributes.push((*tag as u8, data));
+            }
+            _ => return Err(invalid("invalid attribute")),
+        }
+    }
+
+    Ok((entry, dir))
+}
+
+/// Splits the data of an xattr attribute into key and value
+fn split_xattr(data: &[u8]) -> Option<(&str, &[u8])> {
+    let key_length = *data.first()? as usize;
+    let key = std::str::from_utf8(data.get(1..key_length + 1)?).ok()?;
+
+    Some((key, &data[key_length + 1..]))
 }
 
 #[derive(Clone, Debug)]
@@ -61,44 +416,109 @@ pub struct DirChunk {
     pub location: u64,
     pub length: u32,
     pub entries: u16,
+    /// If the entries are sorted by name with an offset table at the end of the payload
+    pub sorted: bool,
+    /// If the chunk is the hash index of a large directory. The payload of an index
+    /// holds pointers to the buckets and the entry count is the number of buckets
+    pub index: bool,
+    /// If names are compared ignoring case. Taken from the tree header
+    pub case_insensitive: bool,
+    /// If the chunk is followed by a CRC32 of its header and payload. Taken from the tree header
+    pub checksum: bool,
+    /// If the checksum is verified when the payload is read
+    pub verify: bool,
     pub next: u64,
 }
 
+/// The number of chunks a bucket or a plain directory can grow to before it
+/// is converted into a hash index
+const INDEX_THRESHOLD: usize = 8;
 impl DirChunk {
     pub fn new(location: u64, length: u32) -> Self {
         Self {
             location,
             length,
             entries: 0,
+            sorted: false,
+            index: false,
+            case_insensitive: false,
+            checksum: false,
+            verify: false,
             next: 0,
         }
     }
 
-    pub fn from_reader<R: Read + Seek>(location: u64, reader: &mut R) -> io::Result<Self> {
-        reader.seek(SeekFrom::Start(location))?;
-        let length = reader.read_u32::<BigEndian>()?;
-        let entries = reader.read_u16::<BigEndian>()?;
-        reader.seek(SeekFrom::Current(length as i64))?;
-        let next = reader.read_u64::<BigEndian>()?;
+    pub fn from_reader<R: Read + Seek>(location: u64, reader: &mut R) -> Result<Self> {
+        let header = ChunkHeader::read(reader, location)?;
         Ok(Self {
             location,
-            length,
-            entries,
-            next,
+            length: header.length,
+            entries: header.entries,
+            sorted: header.sorted,
+            index: header.index,
+            case_insensitive: false,
+            checksum: false,
+            verify: false,
+            next: header.next,
         })
     }
 
+    /// Returns the part of the chunk that is parsed by the format core
+    fn header(&self) -> ChunkHeader {
+        ChunkHeader {
+            location: self.location,
+            length: self.length,
+            entries: self.entries,
+            sorted: self.sorted,
+            index: self.index,
+            next: self.next,
+        }
+    }
+
     /// Writes the header of the chunk
-    pub fn write_header<W: Write + Seek>(&self, writer: &mut W) -> io::Result<()> {
+    pub fn write_header<W: Write + Seek>(&self, writer: &mut W) -> Result<()> {
         writer.seek(SeekFrom::Start(self.location))?;
         writer.write_u32::<BigEndian>(self.length)?;
-        writer.write_u16::<BigEndian>(self.entries)?;
+        writer.write_u16::<BigEndian>(self.entry_field())?;
+
+        Ok(())
+    }
+
+    /// Returns the entry count with the flags of the chunk as it's stored in the header
+    fn entry_field(&self) -> u16 {
+        let mut entries = self.entries;
+        if self.sorted {
+            entries |= SORTED_FLAG;
+        }
+        if self.index {
+            entries |= INDEX_FLAG;
+        }
+
+        entries
+    }
+
+    /// Returns the CRC32 of the header and the given payload
+    fn checksum_of(&self, payload: &[u8]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.length.to_be_bytes());
+        hasher.update(&self.entry_field().to_be_bytes());
+        hasher.update(payload);
+
+        hasher.finalize()
+    }
+
+    /// Writes the checksum of the payload after the next pointer if the chunk has one
+    fn write_checksum<W: Write + Seek>(&self, payload: &[u8], writer: &mut W) -> Result<()> {
+        if self.checksum {
+            writer.seek(SeekFrom::Start(self.location + self.length as u64 + 14))?;
+            writer.write_u32::<BigEndian>(self.checksum_of(payload))?;
+        }
 
         Ok(())
     }
 
     /// Writes the pointer to the next chunk
-    pub fn write_next_pointer<W: Write + Seek>(&self, writer: &mut W) -> io::Result<()> {
+    pub fn write_next_pointer<W: Write + Seek>(&self, writer: &mut W) -> Result<()> {
         writer.seek(SeekFrom::Start(self.location + self.length as u64 + 6))?;
         writer.write_u64::<BigEndian>(self.next)?;
 
@@ -106,346 +526,2603 @@ impl DirChunk {
     }
 
     /// Writes the empty chunk to the disk
-    pub fn write_empty<W: Write + Seek>(&self, writer: &mut W) -> io::Result<()> {
+    pub fn write_empty<W: Write + Seek>(&self, writer: &mut W) -> Result<()> {
         self.write_header(writer)?;
         let empty_content = vec![0u8; self.length as usize];
-        writer.write(&empty_content[..])?;
+        writer.write_all(&empty_content[..])?;
         writer.write_u64::<BigEndian>(self.next)?;
+        self.write_checksum(&empty_content, writer)?;
 
         Ok(())
     }
 
-    /// Returns all entries in the chunk
-    pub fn entries<R: Read + Seek>(&self, reader: &mut R) -> io::Result<Vec<DirEntry>> {
-        let mut entries = Vec::new();
-        reader.seek(SeekFrom::Start(self.location + 6))?;
-        for _ in 0..self.entries {
-            entries.push(DirEntry::from_reader(reader)?);
-        }
+    /// Creates an empty index chunk with as many buckets as fit into the payload
+    pub fn new_index(location: u64, length: u32) -> Self {
+        let mut chunk = Self::new(location, length);
+        chunk.index = true;
+        chunk.entries = (length as usize / 8).min(MAX_CHUNK_ENTRIES as usize) as u16;
 
-        Ok(entries)
+        chunk
     }
 
-    /// Scans for free space and returns the amount of space as well as the pointer to the write location
-    pub fn free_space<R: Read + Seek>(&self, reader: &mut R) -> io::Result<(u32, u64)> {
-        let mut current: usize = 0;
-        reader.seek(SeekFrom::Start(self.location + 6))?;
-
-        for _ in 0..self.entries {
-            let length = reader.read_u16::<BigEndian>()?;
-            reader.seek(SeekFrom::Current(length as i64))?;
-            current += length as usize + 2;
+    /// Returns the pointers to the buckets of an index chunk. Empty buckets are 0
+    pub fn buckets<R: Read + Seek>(&self, reader: &mut R) -> Result<Vec<u64>> {
+        if !self.index {
+            return Ok(Vec::new());
         }
-        let available = self.length - current as u32;
+        let payload = self.read_payload(reader)?;
 
-        Ok((available, self.location + 6 + current as u64))
+        Ok(self.header().parse_buckets(&payload)?)
     }
 
-    /// Deletes an entry from the chunk if it's contained in it
-    pub fn delete_entry<R: Read + Seek, W: Write + Seek>(
-        &mut self,
-        name: &str,
-        reader: &mut R,
-        writer: &mut W,
-    ) -> io::Result<()> {
-        let mut current: usize = 0;
-        let mut deleted_size = 0;
-        reader.seek(SeekFrom::Start(self.location + 6))?;
-        let mut found = false;
+    /// Returns the bucket of the index that holds the name at the given depth
+    pub fn bucket(&self, name: &str, depth: usize) -> usize {
+        (name_hash(&name_key(name, self.case_insensitive), depth) % self.entries as u64) as usize
+    }
 
-        for _ in 0..self.entries {
-            let entry = DirEntry::from_reader(reader)?;
-            if entry.name == name {
-                deleted_size = entry.size();
-                found = true;
-                break;
-            }
-            current += entry.size();
-        }
-        // check if we found the entry
-        if !found {
-            return Err(io::Error::from(io::ErrorKind::NotFound));
+    /// Writes the pointer of a bucket of an index chunk
+    pub fn write_bucket<S: Read + Write + Seek>(
+        &self,
+        bucket: usize,
+        pointer: u64,
+        stream: &mut S,
+    ) -> Result<()> {
+        if self.checksum {
+            let mut payload = self.read_payload(stream)?;
+            BigEndian::write_u64(&mut payload[bucket * 8..bucket * 8 + 8], pointer);
+            self.write_header(stream)?;
+            stream.write_all(&payload)?;
+            return self.write_checksum(&payload, stream);
         }
-        writer.seek(SeekFrom::Start(current as u64 + self.location + 6))?;
-        reader.seek(SeekFrom::Start(
-            (current + deleted_size) as u64 + self.location + 6,
-        ))?;
-        let mut remaining_buf = vec![0u8; (self.length as usize) - (current + deleted_size)];
-        reader.read_exact(&mut remaining_buf)?;
-        writer.write(&remaining_buf[..])?;
-        self.entries -= 1;
-        self.write_header(writer)?;
+        stream.seek(SeekFrom::Start(self.location + 6 + bucket as u64 * 8))?;
+        stream.write_u64::<BigEndian>(pointer)?;
 
         Ok(())
     }
 
-    pub fn size(&self) -> usize {
-        self.length as usize + 8 + 6
-    }
-}
-
-pub struct DirTreeFile {
-    path: PathBuf,
-    dir: Vec<String>,
-    position: u64,
-    entries: Option<Vec<DirEntry>>,
-}
-
-impl DirTreeFile {
-    pub fn new(path: PathBuf) -> Self {
-        Self {
-            path,
-            dir: Vec::new(),
-            position: 0,
-            entries: None,
+    /// Returns the chunks that continue the directory which are the next chunk
+    /// for plain chunks and the non empty buckets for index chunks
+    pub fn links<R: Read + Seek>(&self, reader: &mut R) -> Result<Vec<u64>> {
+        if self.index {
+            Ok(self
+                .buckets(reader)?
+                .into_iter()
+                .filter(|b| *b != 0)
+                .collect())
+        } else if self.next != 0 {
+            Ok(vec![self.next])
+        } else {
+            Ok(Vec::new())
         }
     }
 
-    pub fn init(&self) -> io::Result<()> {
-        if !self.path.exists() || self.get_size()? == 0 {
-            let mut writer = self.get_writer()?;
-            let chunk = DirChunk::new(self.get_size()?, CHUNK_SIZE as u32);
-            chunk.write_empty(&mut writer)?;
-            writer.flush()?;
+    /// Returns all entries in the chunk. The payload is read at once and parsed in memory
+    pub fn entries<R: Read + Seek>(&self, reader: &mut R) -> Result<Vec<DirEntry>> {
+        if self.index {
+            return Ok(Vec::new());
         }
+        let payload = self.read_payload(reader)?;
 
-        Ok(())
+        Ok(self.header().parse_entries(&payload)?)
     }
 
-    pub fn dir(&self) -> String {
-        format!("/{}", self.dir.join("/"))
-    }
+    /// Finds an entry by name. Sorted chunks are searched with a binary search
+    /// over the offset table so that only a few entries have to be parsed
+    pub fn find_entry<R: Read + Seek>(
+        &self,
+        name: &str,
+        reader: &mut R,
+    ) -> Result<Option<DirEntry>> {
+        if self.index {
+            return Ok(None);
+        }
+        if !self.sorted {
+            return Ok(self
+                .entries(reader)?
+                .into_iter()
+                .find(|e| same_name(&e.name, name, self.case_insensitive)));
+        }
+        let payload = self.read_payload(reader)?;
+        let key = name_key(name, self.case_insensitive);
+        let mut low = 0;
+        let mut high = self.entries as usize;
 
-    /// Reads all entries in the current dir
-    pub fn entries(&mut self) -> io::Result<Vec<DirEntry>> {
-        if let Some(entries) = self.entries.clone() {
-            return Ok(entries);
+        while low < high {
+            let middle = (low + high) / 2;
+            let entry = self.entry_at(&payload, self.table_offset(&payload, middle)?)?;
+            match name_key(&entry.name, self.case_insensitive).cmp(&key) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return Ok(Some(entry)),
+            }
         }
-        let mut reader = self.get_reader()?;
-        reader.seek(SeekFrom::Start(self.position))?;
-        let mut entries = Vec::new();
-        let mut position = self.position;
 
-        loop {
-            let chunk = DirChunk::from_reader(position, &mut reader)?;
-            entries.append(&mut chunk.entries(&mut reader)?);
+        Ok(None)
+    }
 
-            if chunk.next == 0 {
-                break;
+    fn read_payload<R: Read + Seek>(&self, reader: &mut R) -> Result<Vec<u8>> {
+        let mut payload = vec![0u8; self.length as usize];
+        reader.seek(SeekFrom::Start(self.location + 6))?;
+        reader.read_exact(&mut payload)?;
+        if self.checksum && self.verify {
+            reader.seek(SeekFrom::Current(8))?;
+            if reader.read_u32::<BigEndian>()? != self.checksum_of(&payload) {
+                return Err(Error::corrupt(self.location, "checksum mismatch"));
             }
-            position = chunk.next;
         }
-        self.entries = Some(entries.clone());
 
-        Ok(entries)
+        Ok(payload)
     }
 
-    /// Changes the virtual directory to <dir>
-    pub fn cd(&mut self, mut dir: &str) -> io::Result<()> {
-        if dir.starts_with('/') {
-            self.position = 0;
-            self.dir.clear();
-            self.entries = None;
-            dir = dir.trim_start_matches('/');
-        }
-        if dir.len() > 0 {
-            let parts = dir.split('/');
+    /// Returns the offset of the i-th entry from the table of a sorted chunk
+    fn table_offset(&self, payload: &[u8], index: usize) -> Result<usize> {
+        Ok(self.header().table_offset(payload, index)?)
+    }
 
-            for part in parts {
-                if part == ".." {
-                    self.dir.pop();
-                    self.cd(self.dir().as_str())?;
-                } else {
-                    let entries = self.entries()?;
-                    let entry = entries.iter().find(|e| e.name == part);
+    /// Parses the entry at the given offset of the payload
+    fn entry_at(&self, payload: &[u8], offset: usize) -> Result<DirEntry> {
+        Ok(self.header().entry_at(payload, offset)?)
+    }
 
-                    if let Some(entry) = entry {
-                        if entry.child_pointer == 0 {
-                            return Err(io::Error::from(ErrorKind::NotFound));
-                        }
-                        self.position = entry.child_pointer;
-                        self.dir.push(part.to_string());
-                        self.entries = None;
-                    } else {
-                        return Err(io::Error::from(ErrorKind::NotFound));
-                    }
-                }
-            }
+    /// Returns the number of bytes that are available for a new entry. The offset
+    /// table isn't counted as the chunk falls back to unsorted entries when it's full
+    pub fn free_space<R: Read + Seek>(&self, reader: &mut R) -> Result<u32> {
+        if self.index || self.entries >= MAX_CHUNK_ENTRIES {
+            return Ok(0);
         }
+        let used: usize = self.entries(reader)?.iter().map(|e| e.size()).sum();
 
-        Ok(())
+        Ok((self.length as usize).saturating_sub(used) as u32)
     }
 
-    pub fn has_entry(&mut self, name: &str) -> io::Result<bool> {
-        Ok(self.entries()?.iter().find(|e| e.name == name).is_some())
+    /// Returns if the entries fit into the chunk
+    fn fits(&self, entries: &[DirEntry]) -> bool {
+        let used: usize = entries.iter().map(|e| e.size()).sum();
+        used <= self.length as usize && entries.len() <= MAX_CHUNK_ENTRIES as usize
     }
 
-    /// Create a new entry in the current directory
-    pub fn create_entry(&mut self, name: &str, dir: bool) -> io::Result<()> {
-        if name.contains('/') || name.len() == 0 {
-            return Err(io::Error::from(ErrorKind::InvalidData));
-        }
-        if self.has_entry(name)? {
-            return Err(io::Error::from(ErrorKind::AlreadyExists));
+    /// Adds an entry to the chunk. The entry must fit into the chunk
+    pub fn insert_entry<S: Read + Write + Seek>(
+        &mut self,
+        entry: DirEntry,
+        stream: &mut S,
+    ) -> Result<()> {
+        let mut entries = self.entries(stream)?;
+        entries.push(entry);
+
+        self.write_entries(&entries, stream)
+    }
+
+    /// Deletes an entry from the chunk if it's contained in it
+    pub fn delete_entry<S: Read + Write + Seek>(
+        &mut self,
+        name: &str,
+        stream: &mut S,
+    ) -> Result<()> {
+        let mut entries = self.entries(stream)?;
+        let length = entries.len();
+        entries.retain(|e| !same_name(&e.name, name, self.case_insensitive));
+        if entries.len() == length {
+            return Err(Error::NotFound {
+                path: name.to_string(),
+            });
         }
-        self.create_dir_entry(name, dir)
+
+        self.write_entries(&entries, stream)
     }
 
-    /// Deletes an entry in the current directory
-    pub fn delete_entry(&mut self, name: &str) -> io::Result<bool> {
-        let mut reader = self.get_reader()?;
-        let mut chunk = DirChunk::from_reader(self.position, &mut reader)?;
-        let mut found = false;
+    /// Replaces the entries of the chunk. The entries are stored sorted with an
+    /// offset table if the table fits and in the given order otherwise
+    pub fn write_entries<W: Write + Seek>(
+        &mut self,
+        entries: &[DirEntry],
+        writer: &mut W,
+    ) -> Result<()> {
+        if !self.fits(entries) {
+            return Err(Error::corrupt(self.location, "entries exceed the chunk"));
+        }
+        let mut sorted: Vec<&DirEntry> = entries.iter().collect();
+        sorted.sort_by_cached_key(|e| name_key(&e.name, self.case_insensitive));
+        let used: usize = entries.iter().map(|e| e.size()).sum();
+        let mut payload = Cursor::new(Vec::with_capacity(self.length as usize));
+        self.sorted = used + entries.len() * OFFSET_SIZE <= self.length as usize;
 
-        loop {
-            if let Some(_) = chunk.entries(&mut reader)?.iter().find(|e| e.name == name) {
-                found = true;
-                break;
+        if self.sorted {
+            let mut offsets = Vec::with_capacity(entries.len());
+            for entry in sorted {
+                offsets.push(payload.position() as u32);
+                entry.write(&mut payload)?;
             }
-            if chunk.next == 0 {
-                break;
+            let mut payload = payload.into_inner();
+            payload.resize(self.length as usize - offsets.len() * OFFSET_SIZE, 0);
+            for offset in offsets {
+                payload.write_u32::<BigEndian>(offset)?;
             }
-            chunk = DirChunk::from_reader(chunk.next, &mut reader)?;
-        }
-        if found {
-            let mut writer = self.get_writer()?;
-            chunk.delete_entry(name, &mut reader, &mut writer)?;
-            writer.flush()?;
+            self.write_payload(&payload, entries.len(), writer)
+        } else {
+            for entry in entries {
+                entry.write(&mut payload)?;
+            }
+            let mut payload = payload.into_inner();
+            payload.resize(self.length as usize, 0);
+            self.write_payload(&payload, entries.len(), writer)
         }
+    }
 
-        Ok(found)
+    fn write_payload<W: Write + Seek>(
+        &mut self,
+        payload: &[u8],
+        entries: usize,
+        writer: &mut W,
+    ) -> Result<()> {
+        self.entries = entries as u16;
+        self.write_header(writer)?;
+        writer.write_all(payload)?;
+        self.write_checksum(payload, writer)?;
+
+        Ok(())
+    }
+
+    pub fn size(&self) -> usize {
+        if self.checksum {
+            self.length as usize + 8 + 6 + 4
+        } else {
+            self.length as usize + 8 + 6
+        }
     }
+}
 
-    fn get_reader(&self) -> io::Result<BufReader<File>> {
-        Ok(BufReader::new(File::open(&self.path)?))
+/// Returns the key names are sorted and hashed by
+fn name_key(name: &str, case_insensitive: bool) -> Cow<'_, str> {
+    if case_insensitive {
+        Cow::Owned(name.to_lowercase())
+    } else {
+        Cow::Borrowed(name)
     }
+}
 
-    fn get_writer(&self) -> io::Result<BufWriter<File>> {
-        Ok(BufWriter::new(
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(&self.path)?,
-        ))
+/// Returns if two names refer to the same entry
+fn same_name(a: &str, b: &str, case_insensitive: bool) -> bool {
+    name_key(a, case_insensitive) == name_key(b, case_insensitive)
+}
+
+/// Hashes a name with FNV-1a seeded with the depth of the index so that names
+/// sharing a bucket are spread over the buckets of the next level
+fn name_hash(name: &str, depth: usize) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in std::iter::once(depth as u8).chain(name.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
     }
 
-    /// Creates a new dir entry without the name check
-    fn create_dir_entry(&mut self, name: &str, dir: bool) -> io::Result<()> {
-        let mut reader = self.get_reader()?;
-        let mut writer = self.get_writer()?;
+    hash
+}
 
-        let pointer = if dir {
-            self.new_chunk(&mut writer)?.location
+/// The number of entries created by a copy
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CopyStats {
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+}
+
+impl CopyStats {
+    fn count(&mut self, entry: &DirEntry) {
+        if entry.is_dir() {
+            self.dirs += 1;
+        } else if entry.is_symlink() {
+            self.symlinks += 1;
         } else {
-            0
-        };
-        let entry = DirEntry::new(name.to_string(), pointer);
-        let (mut chunk, write_pointer) = self.find_free_space(entry.size() as u32, &mut reader)?;
-        let mut writer = self.get_writer()?;
-        writer.seek(SeekFrom::Start(write_pointer))?;
-        entry.write(&mut writer)?;
-        chunk.entries += 1;
-        chunk.write_header(&mut writer)?;
-        writer.flush()?;
-        if let Some(entries) = &mut self.entries {
-            entries.push(entry);
+            self.files += 1;
         }
+    }
+}
 
-        Ok(())
+/// The number of entries and bytes below a directory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DirStats {
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    /// The sum of the sizes in the metadata of all files. Files without metadata count as empty
+    pub bytes: u64,
+}
+
+/// The space used by a dir tree file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TreeStats {
+    /// The size of the tree file in bytes
+    pub file_size: u64,
+    /// The number of chunks reachable from the root
+    pub chunks: u64,
+    /// The number of chunks in the free list
+    pub free_chunks: u64,
+    pub entries: u64,
+    /// The bytes taken by the entries in the chunks
+    pub entry_bytes: u64,
+    /// The bytes of the file that hold neither entries nor chunk headers, e.g.
+    /// unused space in chunks and free chunks
+    pub free_bytes: u64,
+}
+
+impl TreeStats {
+    /// Returns the percentage of the file that is free space
+    pub fn fragmentation(&self) -> f64 {
+        match self.file_size {
+            0 => 0.0,
+            size => self.free_bytes as f64 * 100.0 / size as f64,
+        }
     }
+}
 
-    /// Finds free space to write an entry to
-    fn find_free_space<R: Read + Seek>(
-        &self,
-        amount: u32,
-        reader: &mut R,
-    ) -> io::Result<(DirChunk, u64)> {
-        let write_pointer;
-        let mut chunk = DirChunk::from_reader(self.position, reader)?;
+/// The result of a structural check of a dir tree file
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TreeCheck {
+    /// Ranges of the file that don't belong to any reachable chunk
+    pub unreachable: Vec<(u64, u64)>,
+    /// Locations of chunks that overlap with another chunk or are referenced twice
+    pub overlapping: Vec<u64>,
+    /// Locations of chunks with an invalid length or entries that don't fit into the chunk
+    pub bad_lengths: Vec<u64>,
+    /// Paths of all file entries in the tree
+    pub files: Vec<String>,
+}
 
+impl TreeCheck {
+    /// Returns if no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.unreachable.is_empty() && self.overlapping.is_empty() && self.bad_lengths.is_empty()
+    }
+}
+
+/// A depth-first iterator over the entries below a directory
+pub struct Walk<'a, B: Backend> {
+    tree: &'a mut DirTreeFile<B>,
+    /// The depth, path and remaining entries in reverse order of each open directory
+    stack: Vec<(usize, String, Vec<DirEntry>)>,
+    /// The directories that were entered to detect cycles in corrupt trees
+    visited: HashSet<u64>,
+}
+
+impl<B: Backend> Iterator for Walk<'_, B> {
+    type Item = Result<(usize, String, DirEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
         loop {
-            let (free_amount, pointer) = chunk.free_space(reader)?;
-            if free_amount > amount {
-                write_pointer = pointer;
-                break;
+            let (depth, dir, entries) = self.stack.last_mut()?;
+            let entry = match entries.pop() {
+                Some(entry) => entry,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            let depth = *depth;
+            let path = format!("{}/{}", dir, entry.name);
+
+            if entry.is_dir() {
+                if let Err(e) = self.tree.visit(&mut self.visited, entry.child_pointer) {
+                    self.stack.clear();
+                    return Some(Err(e));
+                }
+                match self.tree.read_entries(entry.child_pointer) {
+                    Ok(mut children) => {
+                        children.reverse();
+                        self.stack.push((depth + 1, path.clone(), children));
+                    }
+                    Err(e) => return Some(Err(e)),
+                }
             }
 
-            let next = chunk.next;
-            if next == 0 {
-                let mut writer = self.get_writer()?;
-                let new_chunk = self.new_chunk(&mut writer)?;
-                write_pointer = new_chunk.location + 6;
-                chunk.next = new_chunk.location;
-                chunk.write_next_pointer(&mut writer)?;
-                writer.flush()?;
-                chunk = new_chunk;
-                break;
+            return Some(Ok((depth, path, entry)));
+        }
+    }
+}
+
+/// A lazy search for entries below a directory that match a predicate
+pub struct Find<'a, B: Backend, P> {
+    walk: Walk<'a, B>,
+    predicate: P,
+}
+
+impl<B: Backend, P: FnMut(&DirEntry) -> bool> Iterator for Find<'_, B, P> {
+    type Item = Result<(String, DirEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for item in &mut self.walk {
+            match item {
+                Ok((_, path, entry)) if (self.predicate)(&entry) => return Some(Ok((path, entry))),
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
             }
-            chunk = DirChunk::from_reader(next, reader)?;
         }
 
-        Ok((chunk, write_pointer))
+        None
     }
+}
 
-    fn memory_layout<R: Read + Seek>(
-        &self,
-        location: u64,
-        reader: &mut R,
-    ) -> io::Result<Vec<(u64, u64)>> {
-        let mut layout = Vec::new();
-        let chunk = DirChunk::from_reader(location, reader)?;
-        layout.push((chunk.location, chunk.location + chunk.size() as u64));
+/// A batch of mutations started with [DirTreeFile::transaction]. Relative paths
+/// are resolved against the current directory of the tree when the transaction started
+pub struct Transaction<'a, B: Backend> {
+    tree: &'a mut DirTreeFile<B>,
+    base: String,
+}
 
-        if chunk.next != 0 {
-            layout.append(&mut self.memory_layout(chunk.next, reader)?);
-        }
-        for child in chunk.entries(reader)? {
-            if child.child_pointer != 0 {
-                layout.append(&mut self.memory_layout(child.child_pointer, reader)?);
-            }
+impl<B: Backend> Transaction<'_, B> {
+    /// Runs the operation in the parent directory of the path with the name of the entry
+    fn in_parent<T, F>(&mut self, path: &str, operation: F) -> Result<T>
+    where
+        F: FnOnce(&mut DirTreeFile<B>, &str) -> Result<T>,
+    {
+        let (parent, name) = split_path(&self.resolve(path))?;
+        self.tree.cd(&parent)?;
+
+        operation(self.tree, &name)
+    }
+
+    fn resolve(&self, path: &str) -> String {
+        if path.starts_with('/') {
+            normalize_path(path)
+        } else {
+            join_path(&self.base, path)
         }
+    }
 
-        Ok(layout)
+    /// Returns the entry at the path including the changes of the transaction
+    pub fn lookup(&mut self, path: &str) -> Result<Option<DirEntry>> {
+        let path = self.resolve(path);
+        self.tree.lookup(&path)
     }
 
-    /// Creates a new chunk at the end of the file
-    fn new_chunk(&self, writer: &mut BufWriter<File>) -> io::Result<DirChunk> {
-        let chunk = DirChunk::new(
-            self.next_chunk_location(CHUNK_SIZE as u64)?,
-            CHUNK_SIZE as u32,
-        );
-        chunk.write_empty(writer)?;
+    /// Creates a file or directory
+    pub fn create(&mut self, path: &str, dir: bool) -> Result<()> {
+        self.in_parent(path, |tree, name| tree.create_entry(name, dir))
+    }
 
-        Ok(chunk)
+    /// Creates the directory and all its missing parents
+    pub fn create_dir_all(&mut self, path: &str) -> Result<()> {
+        let path = self.resolve(path);
+        self.tree.create_dir_all(&path)
     }
 
-    /// Returns the size of the file in bytes
-    pub fn get_size(&self) -> io::Result<u64> {
-        self.path.metadata().map(|m| m.len())
+    /// Creates a symlink pointing to the target
+    pub fn create_symlink(&mut self, path: &str, target: &str) -> Result<()> {
+        self.in_parent(path, |tree, name| tree.create_symlink(name, target))
     }
 
-    /// Returns the next available chunk location
-    fn next_chunk_location(&self, size: u64) -> io::Result<u64> {
-        let mut reader = self.get_reader()?;
-        let mut layout = self.memory_layout(0, &mut reader)?;
-        layout.sort_by(|(a, _), (b, _)| {
-            if a > b {
-                Ordering::Greater
-            } else if a < b {
-                Ordering::Less
+    /// Deletes a file or an empty directory and returns if it existed
+    pub fn delete(&mut self, path: &str) -> Result<bool> {
+        self.in_parent(path, |tree, name| tree.delete_entry(name))
+    }
+
+    /// Deletes an entry with all its descendants and returns if it existed
+    pub fn delete_recursive(&mut self, path: &str) -> Result<bool> {
+        self.in_parent(path, |tree, name| tree.delete_recursive(name))
+    }
+
+    /// Renames the entry at the path within its directory
+    pub fn rename(&mut self, path: &str, new_name: &str) -> Result<()> {
+        self.in_parent(path, |tree, name| tree.rename_entry(name, new_name))
+    }
+
+    /// Moves the entry at `src_path` into the directory `dest_dir`
+    pub fn move_entry(&mut self, src_path: &str, dest_dir: &str) -> Result<()> {
+        let src = self.resolve(src_path);
+        let dest = self.resolve(dest_dir);
+        self.tree.move_entry(&src, &dest)
+    }
+
+    /// Sets the size and timestamps of an entry
+    pub fn set_metadata(&mut self, path: &str, metadata: EntryMetadata) -> Result<()> {
+        self.in_parent(path, |tree, name| tree.set_metadata(name, metadata))
+    }
+
+    /// Sets an extended attribute of an entry
+    pub fn set_xattr(&mut self, path: &str, key: &str, value: &[u8]) -> Result<()> {
+        self.in_parent(path, |tree, name| tree.set_xattr(name, key, value))
+    }
+
+    /// Removes an extended attribute of an entry and returns if it existed
+    pub fn remove_xattr(&mut self, path: &str, key: &str) -> Result<bool> {
+        self.in_parent(path, |tree, name| tree.remove_xattr(name, key))
+    }
+}
+
+/// An open directory of a tree. Handles don't borrow the tree so several of them
+/// can be used at the same time, each with its own cache of entries
+#[derive(Clone, Debug)]
+pub struct DirHandle {
+    dir: Vec<String>,
+    position: u64,
+    entries: Option<Vec<DirEntry>>,
+    /// The modification count of the tree the cached entries belong to
+    generation: u64,
+}
+
+impl DirHandle {
+    fn new(position: u64) -> Self {
+        Self {
+            dir: Vec::new(),
+            position,
+            entries: None,
+            generation: 0,
+        }
+    }
+
+    /// Returns the path of the directory
+    pub fn path(&self) -> String {
+        format!("/{}", self.dir.join("/"))
+    }
+
+    /// Opens a directory relative to this one
+    pub fn open_dir<B: Backend>(&mut self, tree: &mut DirTreeFile<B>, path: &str) -> Result<Self> {
+        let path = join_path(&self.path(), path);
+        tree.open_dir(&path)
+    }
+
+    /// Reads all entries in the directory
+    pub fn entries<B: Backend>(&mut self, tree: &mut DirTreeFile<B>) -> Result<Vec<DirEntry>> {
+        tree.with_dir(self, |t| t.entries())
+    }
+
+    /// Returns if the directory contains an entry with the name
+    pub fn has_entry<B: Backend>(&mut self, tree: &mut DirTreeFile<B>, name: &str) -> Result<bool> {
+        tree.with_dir(self, |t| t.has_entry(name))
+    }
+
+    /// Creates a new entry in the directory
+    pub fn create_entry<B: Backend>(
+        &mut self,
+        tree: &mut DirTreeFile<B>,
+        name: &str,
+        dir: bool,
+    ) -> Result<()> {
+        tree.with_dir(self, |t| t.create_entry(name, dir))
+    }
+
+    /// Deletes an entry in the directory
+    pub fn delete_entry<B: Backend>(
+        &mut self,
+        tree: &mut DirTreeFile<B>,
+        name: &str,
+    ) -> Result<bool> {
+        tree.with_dir(self, |t| t.delete_entry(name))
+    }
+
+    /// Renames an entry in the directory
+    pub fn rename_entry<B: Backend>(
+        &mut self,
+        tree: &mut DirTreeFile<B>,
+        name: &str,
+        new_name: &str,
+    ) -> Result<()> {
+        tree.with_dir(self, |t| t.rename_entry(name, new_name))
+    }
+}
+
+/// A chunk with enough space for a new entry together with the bucket it belongs to
+struct FreeSpace {
+    chunk: DirChunk,
+    /// The first chunk of the bucket or of the directory if it isn't indexed
+    bucket: u64,
+    /// The number of indexes above the bucket
+    depth: usize,
+    /// The number of chunks in the bucket up to the chunk
+    length: usize,
+}
+
+/// A virtual directory tree stored in a backend which is a file by default
+pub struct DirTreeFile<B: Backend = File> {
+    backend: JournalBackend<B>,
+    path: PathBuf,
+    /// The current directory used by the path and name based methods
+    cursor: DirHandle,
+    /// Counts modifications of directories so that cached entries of other handles
+    /// can be detected as outdated
+    generation: u64,
+    /// Locations of unused chunks in files without a header. Scanned from the
+    /// file on the first allocation
+    free: Option<BTreeSet<u64>>,
+    /// The first chunk of the free list stored in the header. Free chunks are
+    /// linked with their next pointer
+    free_head: u64,
+    chunk_size: u32,
+    case_insensitive: bool,
+    /// If chunks are followed by a checksum
+    checksums: bool,
+    /// If checksums are verified when chunks are read. Not stored in the file
+    verify_checksums: bool,
+    /// If single mutations are written through the journal. Not stored in the file
+    journaled: bool,
+    /// The headers and parsed entries of recently read chunks by their location.
+    /// Chunks are dropped when any of their bytes are written
+    chunk_cache: LruCache<CachedChunk>,
+    /// The rules for new names. Not stored in the file
+    name_policy: NamePolicy,
+    metrics: Arc<dyn Metrics>,
+    /// The location of the root chunk after the header
+    root: u64,
+    /// The state from before the current batch started
+    batch: Option<Snapshot>,
+}
+
+/// What's known of a recently read chunk
+struct CachedChunk {
+    header: Option<DirChunk>,
+    entries: Option<Vec<DirEntry>>,
+}
+
+/// The state of a tree that isn't stored in the backend
+struct Snapshot {
+    cursor: DirHandle,
+    free: Option<BTreeSet<u64>>,
+    free_head: u64,
+    root: u64,
+}
+
+impl DirTreeFile<File> {
+    /// Opens the dir tree file at the given path and creates it if it doesn't exist.
+    /// Waits until no other process holds a lock on the file
+    pub fn open(path: PathBuf) -> Result<Self> {
+        Self::open_locked(path, false, true)
+    }
+
+    /// Opens the dir tree file like [DirTreeFile::open] but fails with
+    /// [Error::Locked] instead of waiting if another process holds a lock
+    pub fn try_open(path: PathBuf) -> Result<Self> {
+        Self::open_locked(path, false, false)
+    }
+
+    /// Opens an existing dir tree file for reading. The file is locked shared
+    /// so that other readers can open it at the same time
+    pub fn open_read_only(path: PathBuf) -> Result<Self> {
+        Self::open_locked(path, true, true)
+    }
+
+    /// Opens the dir tree file like [DirTreeFile::open_read_only] but fails with
+    /// [Error::Locked] instead of waiting if another process holds a lock
+    pub fn try_open_read_only(path: PathBuf) -> Result<Self> {
+        Self::open_locked(path, true, false)
+    }
+
+    /// Creates a new dir tree file with the given options. Fails if the file already exists
+    pub fn create_with_options(path: PathBuf, options: TreeOptions) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        file.lock()?;
+        let mut tree = Self::from_backend_with_options(file, options)?;
+        tree.path = path;
+
+        Ok(tree)
+    }
+
+    fn open_locked(path: PathBuf, read_only: bool, wait: bool) -> Result<Self> {
+        Self::open_locked_with(path, read_only, wait, Ok)
+    }
+}
+
+impl<B: Backend> DirTreeFile<B> {
+    /// Opens the file and takes an advisory lock that is released when the tree is
+    /// dropped. The tree is stored in the backend created from the locked file
+    pub(crate) fn open_locked_with<F: FnOnce(File) -> io::Result<B>>(
+        path: PathBuf,
+        read_only: bool,
+        wait: bool,
+        backend: F,
+    ) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(!read_only)
+            .truncate(false)
+            .read(true)
+            .write(!read_only)
+            .open(&path)?;
+        let locked = match (read_only, wait) {
+            (false, true) => file.lock().map(|_| true),
+            (true, true) => file.lock_shared().map(|_| true),
+            (false, false) => try_lock_result(file.try_lock()),
+            (true, false) => try_lock_result(file.try_lock_shared()),
+        }?;
+        if !locked {
+            return Err(Error::Locked { path });
+        }
+        let mut tree = Self::from_backend(backend(file)?)?;
+        tree.path = path;
+
+        Ok(tree)
+    }
+}
+
+/// Returns false if the lock is held by someone else
+fn try_lock_result(result: std::result::Result<(), TryLockError>) -> io::Result<bool> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+impl<B: Backend> DirTreeFile<B> {
+    /// Creates a dir tree stored in the given backend. An empty backend
+    /// gets initialized with an empty root directory
+    pub fn from_backend(backend: B) -> Result<Self> {
+        Self::from_backend_with_options(backend, TreeOptions::default())
+    }
+
+    /// Creates a dir tree stored in the given backend. An empty backend gets
+    /// initialized with the options, existing trees keep their chunk size
+    pub fn from_backend_with_options(backend: B, options: TreeOptions) -> Result<Self> {
+        if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&options.chunk_size) {
+            return Err(Error::invalid_argument(format!(
+                "chunk size must be between {} and {}",
+                MIN_CHUNK_SIZE, MAX_CHUNK_SIZE
+            )));
+        }
+        let mut tree = Self {
+            backend: JournalBackend::new(backend),
+            path: PathBuf::new(),
+            cursor: DirHandle::new(HEADER_SIZE),
+            generation: 0,
+            free: None,
+            free_head: 0,
+            chunk_size: options.chunk_size,
+            case_insensitive: options.case_insensitive,
+            checksums: options.checksums,
+            verify_checksums: true,
+            journaled: true,
+            chunk_cache: LruCache::new(DEFAULT_CHUNK_CACHE_CAPACITY),
+            name_policy: NamePolicy::default(),
+            metrics: Arc::new(NoMetrics),
+            root: HEADER_SIZE,
+            batch: None,
+        };
+        tree.init()?;
+
+        Ok(tree)
+    }
+
+    /// Returns the backend the tree is stored in
+    pub fn into_inner(self) -> B {
+        self.backend.into_inner()
+    }
+
+    /// Returns the number of bytes available for entries in each directory chunk
+    pub fn chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+
+    /// Returns if names are compared ignoring case
+    pub fn is_case_insensitive(&self) -> bool {
+        self.case_insensitive
+    }
+
+    /// Returns if chunks are followed by a checksum
+    pub fn has_checksums(&self) -> bool {
+        self.checksums
+    }
+
+    /// Sets if checksums are verified when chunks are read. Verification is on by
+    /// default and can be turned off to read as much as possible from a damaged tree
+    pub fn set_verify_checksums(&mut self, verify: bool) {
+        self.verify_checksums = verify;
+        self.chunk_cache.clear();
+    }
+
+    /// Sets the number of recently read chunks that are kept in memory. The least
+    /// recently used chunks are dropped when the cache is full and 0 disables it
+    pub fn set_chunk_cache_capacity(&mut self, chunks: usize) {
+        self.chunk_cache.set_capacity(chunks);
+    }
+
+    /// Returns the number of chunks the cache can hold
+    pub fn chunk_cache_capacity(&self) -> usize {
+        self.chunk_cache.capacity()
+    }
+
+    /// Returns the number of chunks that are currently cached
+    pub fn cached_chunks(&self) -> usize {
+        self.chunk_cache.len()
+    }
+
+    /// Sets the rules names of new entries have to follow
+    pub fn set_name_policy(&mut self, policy: NamePolicy) {
+        self.name_policy = policy;
+    }
+
+    /// Returns the rules names of new entries have to follow
+    pub fn name_policy(&self) -> &NamePolicy {
+        &self.name_policy
+    }
+
+    /// Sets where lookup latencies and the fragmentation of the tree are reported
+    pub fn set_metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// Writes the header and the root chunk of an empty file or reads the header
+    fn init(&mut self) -> Result<()> {
+        if self.get_size()? == 0 {
+            let mut flags = 0;
+            if self.case_insensitive {
+                flags |= CASE_INSENSITIVE_FLAG;
+            }
+            if self.checksums {
+                flags |= CHECKSUM_FLAG;
+            }
+            let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+            header.write_all(&MAGIC)?;
+            header.write_u16::<BigEndian>(FORMAT_VERSION)?;
+            header.write_u16::<BigEndian>(flags)?;
+            header.write_u64::<BigEndian>(0)?;
+            header.write_u32::<BigEndian>(self.chunk_size)?;
+            header.resize(HEADER_SIZE as usize, 0);
+            self.backend.seek(SeekFrom::Start(0))?;
+            self.backend.write_all(&header)?;
+            let chunk = self.blank_chunk(self.root);
+            chunk.write_empty(&mut self.backend)?;
+            self.backend.flush()?;
+
+            return Ok(());
+        }
+        let header =
+            TreeHeader::read(&mut self.backend).map_err(|e| Error::from(e).in_file(&self.path))?;
+        self.case_insensitive = header.case_insensitive;
+        self.checksums = header.checksums;
+        self.free_head = header.free_head;
+        self.root = header.root;
+        self.chunk_size = header.chunk_size;
+        if header.version == FORMAT_VERSION {
+            let replayed = self
+                .backend
+                .replay(JOURNAL_OFFSET)
+                .map_err(|e| e.in_file(&self.path))?;
+            if replayed {
+                event!(
+                    Info,
+                    "completed an interrupted commit in {}",
+                    self.path.display()
+                );
+            }
+            self.backend.seek(SeekFrom::Start(FREE_HEAD_OFFSET))?;
+            self.free_head = self.backend.read_u64::<BigEndian>()?;
+        }
+        self.cursor.position = self.root;
+
+        Ok(())
+    }
+
+    pub fn dir(&self) -> String {
+        self.cursor.path()
+    }
+
+    /// Opens the directory at the path as a handle that is independent of the
+    /// current directory. Relative paths are resolved against the current directory
+    pub fn open_dir(&mut self, path: &str) -> Result<DirHandle> {
+        let path = self.resolve_path(path);
+        let mut handle = DirHandle::new(self.root);
+        self.with_dir(&mut handle, |tree| tree.cd(&path))?;
+
+        Ok(handle)
+    }
+
+    /// Runs the operation with the handle as the current directory. Changing the
+    /// directory inside the operation moves the handle
+    pub fn with_dir<T, F>(&mut self, handle: &mut DirHandle, operation: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        mem::swap(&mut self.cursor, handle);
+        self.sync_cursor();
+        let result = operation(self);
+        mem::swap(&mut self.cursor, handle);
+        self.sync_cursor();
+
+        result
+    }
+
+    /// Drops the cached entries of the current directory if another handle
+    /// modified the tree since they were read
+    fn sync_cursor(&mut self) {
+        if self.cursor.generation != self.generation {
+            self.cursor.entries = None;
+            self.cursor.generation = self.generation;
+        }
+    }
+
+    /// Records a modification. The cache of the current directory is updated by
+    /// the caller while other handles drop theirs
+    fn modified(&mut self) {
+        self.generation += 1;
+        self.cursor.generation = self.generation;
+    }
+
+    /// Returns the full path of an entry in the current directory
+    fn entry_path(&self, name: &str) -> String {
+        if self.cursor.dir.is_empty() {
+            format!("/{}", name)
+        } else {
+            format!("{}/{}", self.dir(), name)
+        }
+    }
+
+    /// Reads all entries in the current dir
+    pub fn entries(&mut self) -> Result<Vec<DirEntry>> {
+        if let Some(entries) = self.cursor.entries.clone() {
+            return Ok(entries);
+        }
+        let entries = self.read_entries(self.cursor.position)?;
+        self.cursor.entries = Some(entries.clone());
+
+        Ok(entries)
+    }
+
+    /// Resolves a path to its entry without changing the current directory.
+    /// Relative paths are resolved against the current directory
+    pub fn lookup(&mut self, path: &str) -> Result<Option<DirEntry>> {
+        let start = Instant::now();
+        let path = self.resolve_path(path);
+        let entry = self.lookup_resolving(&path, 0);
+        self.metrics
+            .histogram(TREE_LOOKUP_SECONDS, start.elapsed().as_secs_f64());
+
+        entry
+    }
+
+    /// Looks up an absolute path following symlinks in all but the last component
+    fn lookup_resolving(&mut self, path: &str, depth: usize) -> Result<Option<DirEntry>> {
+        let (parent, name) = split_path(path)?;
+        let parts: Vec<&str> = parent.split('/').filter(|p| !p.is_empty()).collect();
+        let mut location = self.root;
+        let mut current = String::from("/");
+
+        for (i, part) in parts.iter().enumerate() {
+            let entry = match self.find_in_dir(location, part)? {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+            let entry_path = join_path(&current, part);
+
+            if let Some(target) = entry.symlink_target() {
+                if depth >= MAX_SYMLINK_DEPTH {
+                    return Err(Error::SymlinkLoop { path: entry_path });
+                }
+                let rest = join_path(&parts[i + 1..].join("/"), &name);
+                let target = if target.starts_with('/') {
+                    normalize_path(target)
+                } else {
+                    join_path(&current, target)
+                };
+                let resolved = join_path(&target, &rest);
+                return self.lookup_resolving(&resolved, depth + 1);
+            }
+            if !entry.is_dir() {
+                return Err(Error::NotADirectory { path: entry_path });
+            }
+            location = entry.child_pointer;
+            current = entry_path;
+        }
+
+        self.find_in_dir(location, &name)
+    }
+
+    /// Returns an iterator over all entries below the directory at the given path
+    /// yielding the depth, the path and the entry depth-first. The iterator reads
+    /// the chunks directly so the current directory isn't changed
+    pub fn walk(&mut self, path: &str) -> Result<Walk<'_, B>> {
+        let path = self.resolve_path(path);
+        let location = if path == "/" {
+            self.root
+        } else {
+            match self.lookup(&path)? {
+                Some(entry) if entry.is_dir() => entry.child_pointer,
+                Some(_) => return Err(Error::NotADirectory { path }),
+                None => return Err(Error::NotFound { path }),
+            }
+        };
+        let mut entries = self.read_entries(location)?;
+        entries.reverse();
+        let base = if path == "/" { String::new() } else { path };
+
+        Ok(Walk {
+            tree: self,
+            stack: vec![(1, base, entries)],
+            visited: std::iter::once(location).collect(),
+        })
+    }
+
+    /// Returns an iterator over the paths and entries below the directory at the
+    /// given path the predicate returns true for. Directories are only read when
+    /// the search reaches them
+    pub fn find<P: FnMut(&DirEntry) -> bool>(
+        &mut self,
+        path: &str,
+        predicate: P,
+    ) -> Result<Find<'_, B, P>> {
+        Ok(Find {
+            walk: self.walk(path)?,
+            predicate,
+        })
+    }
+
+    /// Counts the files, directories and symlinks below the directory at the given
+    /// path and sums up the sizes of the files in a single traversal
+    pub fn dir_stats(&mut self, path: &str) -> Result<DirStats> {
+        let mut stats = DirStats::default();
+
+        for item in self.walk(path)? {
+            let (_, _, entry) = item?;
+            if entry.is_dir() {
+                stats.dirs += 1;
+            } else if entry.is_symlink() {
+                stats.symlinks += 1;
+            } else {
+                stats.files += 1;
+                stats.bytes += entry.metadata().map_or(0, |m| m.size);
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Returns the number of chunks and entries in the tree and how much of the file
+    /// they use
+    pub fn stats(&mut self) -> Result<TreeStats> {
+        let file_size = self.get_size()?;
+        let mut stats = TreeStats {
+            file_size,
+            ..Default::default()
+        };
+        // the header before the root chunk counts as used
+        let mut used = self.root;
+        let mut visited = HashSet::new();
+        let mut stack = vec![self.root];
+        while let Some(location) = stack.pop() {
+            if !visited.insert(location) {
+                continue;
+            }
+            let (chunk, entries) = self.check_chunk(location, file_size)?;
+            stack.extend(chunk.links(&mut self.backend)?);
+            stats.chunks += 1;
+            stats.entries += entries.len() as u64;
+            let entry_bytes: u64 = entries.iter().map(|e| e.size() as u64).sum();
+            stats.entry_bytes += entry_bytes;
+            used += match chunk.index {
+                true => chunk.size() as u64,
+                false => (chunk.size() - chunk.length as usize) as u64 + entry_bytes,
+            };
+            stack.extend(
+                entries
+                    .iter()
+                    .filter(|e| e.is_dir())
+                    .map(|e| e.child_pointer),
+            );
+        }
+        let mut free = self.free_head;
+        while free != 0 && visited.insert(free) {
+            stats.free_chunks += 1;
+            free = self.read_chunk(free)?.next;
+        }
+        stats.free_bytes = file_size.saturating_sub(used);
+        self.metrics
+            .gauge(TREE_FRAGMENTATION, stats.fragmentation());
+
+        Ok(stats)
+    }
+
+    /// Returns the paths of all entries matching the glob pattern. Relative patterns
+    /// are resolved against the current directory
+    pub fn glob(&mut self, pattern: &str) -> Result<Vec<String>> {
+        let pattern = self.resolve_path(pattern);
+        let base: Vec<&str> = pattern
+            .split('/')
+            .filter(|p| !p.is_empty())
+            .take_while(|p| !is_glob(p))
+            .collect();
+        let base = format!("/{}", base.join("/"));
+        if !is_glob(&pattern) {
+            return Ok(match self.lookup(&pattern) {
+                Ok(Some(_)) => vec![pattern],
+                _ => Vec::new(),
+            });
+        }
+        let walk = match self.walk(&base) {
+            Ok(walk) => walk,
+            Err(Error::NotFound { .. }) | Err(Error::NotADirectory { .. }) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut paths = Vec::new();
+
+        for item in walk {
+            let (_, path, _) = item?;
+            if glob_match(&pattern, &path) {
+                paths.push(path);
+            }
+        }
+
+        Ok(paths)
+    }
+
+    /// Reads the entries of the directory starting at the given chunk
+    fn read_entries(&mut self, location: u64) -> Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        let mut visited = HashSet::new();
+        let mut stack = vec![location];
+
+        while let Some(location) = stack.pop() {
+            self.visit(&mut visited, location)?;
+            let chunk = self.read_chunk(location)?;
+            entries.append(&mut self.chunk_entries(&chunk)?);
+            let mut links = chunk
+                .links(&mut self.backend)
+                .map_err(|e| e.in_file(&self.path))?;
+            links.reverse();
+            stack.append(&mut links);
+        }
+
+        Ok(entries)
+    }
+
+    /// Returns the entries of a chunk from the cache or reads and caches them
+    fn chunk_entries(&mut self, chunk: &DirChunk) -> Result<Vec<DirEntry>> {
+        self.drop_written_chunks();
+        if let Some(entries) = self.cached_entries(chunk.location) {
+            event!(
+                Trace,
+                "chunk cache hit for the entries at {}",
+                chunk.location
+            );
+            return Ok(entries.clone());
+        }
+        event!(
+            Trace,
+            "chunk cache miss for the entries at {}",
+            chunk.location
+        );
+        let entries = chunk
+            .entries(&mut self.backend)
+            .map_err(|e| e.in_file(&self.path))?;
+        match self.chunk_cache.get_mut(chunk.location) {
+            Some(cached) => cached.entries = Some(entries.clone()),
+            None => self.chunk_cache.insert(
+                chunk.location,
+                CachedChunk {
+                    header: None,
+                    entries: Some(entries.clone()),
+                },
+            ),
+        }
+
+        Ok(entries)
+    }
+
+    /// Returns the cached entries of a chunk and marks it as recently used
+    fn cached_entries(&mut self, location: u64) -> Option<&Vec<DirEntry>> {
+        self.chunk_cache.get_mut(location)?.entries.as_ref()
+    }
+
+    /// Drops the cached entries of all chunks that were written since the last call
+    fn drop_written_chunks(&mut self) {
+        let chunk_size = self.blank_chunk(0).size() as u64;
+        for (start, end) in self.backend.take_written() {
+            let first = (start + 1).saturating_sub(chunk_size);
+            self.chunk_cache.remove_range(first..end);
+        }
+    }
+
+    /// Finds an entry by name in the directory starting at the given chunk
+    fn find_in_dir(&mut self, location: u64, name: &str) -> Result<Option<DirEntry>> {
+        Ok(self.find_in_chunks(location, name)?.map(|(_, entry)| entry))
+    }
+
+    /// Finds an entry by name and returns it together with the chunk containing it
+    fn find_in_chunks(
+        &mut self,
+        mut location: u64,
+        name: &str,
+    ) -> Result<Option<(DirChunk, DirEntry)>> {
+        let mut depth = 0;
+        let mut visited = HashSet::new();
+
+        loop {
+            self.visit(&mut visited, location)?;
+            let chunk = self.read_chunk(location)?;
+            if chunk.index {
+                let buckets = chunk
+                    .buckets(&mut self.backend)
+                    .map_err(|e| e.in_file(&self.path))?;
+                location = buckets[chunk.bucket(name, depth)];
+                if location == 0 {
+                    return Ok(None);
+                }
+                depth += 1;
+                continue;
+            }
+            self.drop_written_chunks();
+            let case_insensitive = self.case_insensitive;
+            let entry = match self.cached_entries(location) {
+                Some(entries) => entries
+                    .iter()
+                    .find(|e| same_name(&e.name, name, case_insensitive))
+                    .cloned(),
+                None => chunk
+                    .find_entry(name, &mut self.backend)
+                    .map_err(|e| e.in_file(&self.path))?,
+            };
+            if let Some(entry) = entry {
+                return Ok(Some((chunk, entry)));
+            }
+            if chunk.next == 0 {
+                return Ok(None);
+            }
+            location = chunk.next;
+        }
+    }
+
+    /// Finds an entry in the current directory using the cache if it's populated
+    fn current_entry_named(&mut self, name: &str) -> Result<Option<DirEntry>> {
+        match &self.cursor.entries {
+            Some(entries) => Ok(entries
+                .iter()
+                .find(|e| same_name(&e.name, name, self.case_insensitive))
+                .cloned()),
+            None => self.find_in_dir(self.cursor.position, name),
+        }
+    }
+
+    /// Changes the virtual directory to <dir>
+    pub fn cd(&mut self, dir: &str) -> Result<()> {
+        self.cd_resolving(dir, 0)
+    }
+
+    /// Changes the directory following symlinks up to the maximum depth
+    fn cd_resolving(&mut self, mut dir: &str, depth: usize) -> Result<()> {
+        if dir.starts_with('/') {
+            self.cursor.position = self.root;
+            self.cursor.dir.clear();
+            self.cursor.entries = None;
+            dir = dir.trim_start_matches('/');
+        }
+        if !dir.is_empty() {
+            let parts = dir.split('/');
+
+            for part in parts {
+                if part == ".." {
+                    self.cursor.dir.pop();
+                    self.cd(self.dir().as_str())?;
+                } else {
+                    let entry = self.current_entry_named(part)?;
+
+                    if let Some(entry) = entry {
+                        if let Some(target) = entry.symlink_target() {
+                            if depth >= MAX_SYMLINK_DEPTH {
+                                return Err(Error::SymlinkLoop {
+                                    path: self.entry_path(part),
+                                });
+                            }
+                            self.cd_resolving(target, depth + 1)?;
+                            continue;
+                        }
+                        if entry.child_pointer == 0 {
+                            return Err(Error::NotADirectory {
+                                path: self.entry_path(part),
+                            });
+                        }
+                        self.cursor.position = entry.child_pointer;
+                        self.cursor.dir.push(entry.name.clone());
+                        self.cursor.entries = None;
+                    } else {
+                        return Err(Error::NotFound {
+                            path: self.entry_path(part),
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn has_entry(&mut self, name: &str) -> Result<bool> {
+        Ok(self.current_entry_named(name)?.is_some())
+    }
+
+    /// Create a new entry in the current directory
+    pub fn create_entry(&mut self, name: &str, dir: bool) -> Result<()> {
+        self.journaled(|tree| {
+            tree.check_new_name(name)?;
+            let path = tree.path.clone();
+            tree.create_dir_entry(name, dir)
+                .map_err(|e| e.in_file(&path))
+        })
+    }
+
+    /// Creates a symlink in the current directory pointing to the target path.
+    /// Relative targets are resolved against the directory containing the link
+    pub fn create_symlink(&mut self, name: &str, target: &str) -> Result<()> {
+        self.journaled(|tree| {
+            tree.check_new_name(name)?;
+            let mut entry = DirEntry::new(name.to_string(), 0);
+            entry
+                .attributes
+                .push((SYMLINK_ATTRIBUTE, target.as_bytes().to_vec()));
+            if entry.size() > tree.chunk_size as usize {
+                return Err(Error::EntryTooLarge {
+                    path: tree.entry_path(name),
+                    size: entry.size(),
+                    max: tree.chunk_size as usize,
+                });
+            }
+
+            tree.insert_entry(entry)
+        })
+    }
+
+    /// Creates a file entry in the current directory that refers to its content
+    /// in the meta file with the given id
+    pub fn create_file_entry(&mut self, name: &str, id: EntryID) -> Result<()> {
+        self.journaled(|tree| {
+            tree.check_new_name(name)?;
+            let mut entry = DirEntry::new(name.to_string(), 0);
+            entry.set_blob_id(id);
+
+            tree.insert_entry(entry)
+        })
+    }
+
+    /// Creates the directory and all its missing parents without changing the
+    /// current directory. Succeeds if the directory already exists
+    pub fn create_dir_all(&mut self, path: &str) -> Result<()> {
+        self.journaled(|tree| {
+            let path = tree.resolve_path(path);
+            let current = tree.dir();
+            tree.cd("/")?;
+
+            for part in path.split('/').filter(|p| !p.is_empty()) {
+                if !tree.has_entry(part)? {
+                    tree.create_entry(part, true)?;
+                }
+                tree.cd(part)?;
+            }
+
+            tree.cd(&current)
+        })
+    }
+
+    /// Deletes an entry in the current directory. The chunks of a deleted
+    /// directory and all its descendants are freed for reuse
+    pub fn delete_entry(&mut self, name: &str) -> Result<bool> {
+        self.journaled(|tree| {
+            let entry = match tree
+                .entries()?
+                .into_iter()
+                .find(|e| same_name(&e.name, name, tree.case_insensitive))
+            {
+                Some(entry) => entry,
+                None => return Ok(false),
+            };
+            let chunks = if entry.is_dir() {
+                tree.subtree_chunks(entry.child_pointer)?
             } else {
-                Ordering::Equal
+                Vec::new()
+            };
+            tree.remove_entry(name)?;
+            tree.free_chunks(chunks)?;
+
+            Ok(true)
+        })
+    }
+
+    /// Removes the record of an entry without freeing the chunks it points to
+    fn remove_entry(&mut self, name: &str) -> Result<bool> {
+        let case_insensitive = self.case_insensitive;
+        match self.find_entry_chunk(name)? {
+            Some(mut chunk) => {
+                chunk.delete_entry(name, &mut self.backend)?;
+                self.backend.flush()?;
+                self.modified();
+                if let Some(entries) = &mut self.cursor.entries {
+                    entries.retain(|e| !same_name(&e.name, name, case_insensitive));
+                }
+
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Deletes an entry in the current directory together with all its descendants
+    /// and frees the chunks of the deleted directories
+    pub fn delete_recursive(&mut self, name: &str) -> Result<bool> {
+        self.journaled(|tree| tree.delete_entry(name))
+    }
+
+    /// Renames an entry in the current directory. The entry is rewritten in place
+    /// if the new name fits into its chunk and moved to another chunk otherwise.
+    /// The child pointer is kept so the contents of directories stay intact
+    pub fn rename_entry(&mut self, name: &str, new_name: &str) -> Result<()> {
+        self.journaled(|tree| {
+            if name == new_name {
+                return match tree.has_entry(name)? {
+                    true => Ok(()),
+                    false => Err(Error::NotFound {
+                        path: tree.entry_path(name),
+                    }),
+                };
+            }
+            // changing only the case keeps the name of the same entry
+            if !same_name(name, new_name, tree.case_insensitive) {
+                tree.check_new_name(new_name)?;
+            }
+            tree.update_entry(name, |entry| entry.name = new_name.to_string())
+        })
+    }
+
+    /// Sets the size and timestamps of an entry in the current directory
+    pub fn set_metadata(&mut self, name: &str, metadata: EntryMetadata) -> Result<()> {
+        self.journaled(|tree| tree.update_entry(name, |entry| entry.set_metadata(metadata)))
+    }
+
+    /// Sets the id of the content of a file in the current directory
+    pub fn set_blob_id(&mut self, name: &str, id: EntryID) -> Result<()> {
+        self.journaled(|tree| tree.update_entry(name, |entry| entry.set_blob_id(id)))
+    }
+
+    /// Sets an extended attribute of an entry in the current directory
+    pub fn set_xattr(&mut self, name: &str, key: &str, value: &[u8]) -> Result<()> {
+        self.journaled(|tree| {
+            let mut result = Ok(());
+            tree.update_entry(name, |entry| result = entry.set_xattr(key, value))?;
+
+            result
+        })
+    }
+
+    /// Returns the value of an extended attribute of an entry in the current directory
+    pub fn get_xattr(&mut self, name: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.current_entry(name)?.xattr(key).map(|v| v.to_vec()))
+    }
+
+    /// Returns the keys of all extended attributes of an entry in the current directory
+    pub fn list_xattrs(&mut self, name: &str) -> Result<Vec<String>> {
+        Ok(self
+            .current_entry(name)?
+            .xattrs()
+            .map(|(key, _)| key.to_string())
+            .collect())
+    }
+
+    /// Removes an extended attribute of an entry in the current directory
+    /// and returns if it existed
+    pub fn remove_xattr(&mut self, name: &str, key: &str) -> Result<bool> {
+        self.journaled(|tree| {
+            if tree.current_entry(name)?.xattr(key).is_none() {
+                return Ok(false);
+            }
+            tree.update_entry(name, |entry| {
+                entry.remove_xattr(key);
+            })?;
+
+            Ok(true)
+        })
+    }
+
+    /// Returns the entry with the given name in the current directory
+    fn current_entry(&mut self, name: &str) -> Result<DirEntry> {
+        self.entries()?
+            .into_iter()
+            .find(|e| same_name(&e.name, name, self.case_insensitive))
+            .ok_or_else(|| Error::NotFound {
+                path: self.entry_path(name),
+            })
+    }
+
+    /// Changes an entry in the current directory. The entry is rewritten in place
+    /// if it still fits into its chunk and moved to another chunk otherwise
+    fn update_entry<F: FnOnce(&mut DirEntry)>(&mut self, name: &str, update: F) -> Result<()> {
+        let mut chunk = self
+            .find_entry_chunk(name)?
+            .ok_or_else(|| Error::NotFound {
+                path: self.entry_path(name),
+            })?;
+        let mut chunk_entries = chunk.entries(&mut self.backend)?;
+        let index = chunk_entries
+            .iter()
+            .position(|e| same_name(&e.name, name, self.case_insensitive))
+            .unwrap();
+        let mut entry = chunk_entries[index].clone();
+        update(&mut entry);
+        if entry.size() > self.chunk_size as usize {
+            return Err(Error::EntryTooLarge {
+                path: self.entry_path(&entry.name),
+                size: entry.size(),
+                max: self.chunk_size as usize,
+            });
+        }
+        let free_amount = chunk.free_space(&mut self.backend)?;
+        let case_insensitive = self.case_insensitive;
+        // a renamed entry can belong to another bucket of an indexed directory
+        let fits = entry.size() <= free_amount as usize + chunk_entries[index].size();
+
+        if fits && entry.name == chunk_entries[index].name {
+            chunk_entries[index] = entry.clone();
+            chunk.write_entries(&chunk_entries, &mut self.backend)?;
+            self.backend.flush()?;
+            self.modified();
+            if let Some(entries) = &mut self.cursor.entries {
+                entries.retain(|e| !same_name(&e.name, name, case_insensitive));
+                entries.push(entry);
+            }
+        } else {
+            self.remove_entry(name)?;
+            self.insert_entry(entry)?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the entry at `src_path` into the directory `dest_dir`. Only the entry
+    /// record is moved so directories keep their contents without copying them.
+    /// Relative paths are resolved against the current directory
+    pub fn move_entry(&mut self, src_path: &str, dest_dir: &str) -> Result<()> {
+        self.journaled(|tree| {
+            let src = tree.resolve_path(src_path);
+            let dest = tree.resolve_path(dest_dir);
+            let (parent, name) = split_path(&src)?;
+            let current = tree.dir();
+
+            if dest == src || dest.starts_with(&format!("{}/", src)) {
+                return Err(Error::InvalidName { name: dest });
+            }
+            tree.cd(&parent)?;
+            let parent_location = tree.cursor.position;
+            let entry = tree
+                .entries()?
+                .into_iter()
+                .find(|e| same_name(&e.name, &name, tree.case_insensitive))
+                .ok_or(Error::NotFound { path: src.clone() })?;
+            tree.cd(&dest)?;
+            if tree.cursor.position != parent_location {
+                // the destination might be reached through symlinks or other cases
+                if let Err(e) = tree.check_outside(&entry, &dest) {
+                    tree.cd(&current)?;
+                    return Err(e);
+                }
+                if tree.has_entry(&name)? {
+                    tree.cd(&current)?;
+                    return Err(Error::AlreadyExists {
+                        path: join_path(&dest, &name),
+                    });
+                }
+                // the entry is inserted before it is removed so that an interruption
+                // can't lose the subtree
+                tree.insert_entry(entry)?;
+                tree.cd(&parent)?;
+                tree.remove_entry(&name)?;
+            }
+            // the current directory might have been moved with the entry
+            let moved = join_path(&dest, &name);
+            match current.strip_prefix(&src) {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                    tree.cd(&format!("{}{}", moved, rest))
+                }
+                _ => tree.cd(&current),
+            }
+        })
+    }
+
+    /// Copies the entry at `src_path` to `dest_path`. Directories are copied with all
+    /// their descendants into new chunks while symlinks are copied as they are.
+    /// Relative paths are resolved against the current directory
+    pub fn copy_entry(&mut self, src_path: &str, dest_path: &str) -> Result<CopyStats> {
+        self.journaled(|tree| {
+            let src = tree.resolve_path(src_path);
+            let dest = tree.resolve_path(dest_path);
+            let (dest_parent, dest_name) = split_path(&dest)?;
+
+            if dest == src || dest.starts_with(&format!("{}/", src)) {
+                return Err(Error::InvalidName { name: dest });
+            }
+            let entry = tree
+                .lookup(&src)?
+                .ok_or(Error::NotFound { path: src.clone() })?;
+            let current = tree.dir();
+            let result = tree
+                .cd(&dest_parent)
+                .and_then(|_| tree.check_outside(&entry, &dest))
+                .and_then(|_| tree.copy_subtree(entry, &dest_parent, &dest_name));
+            tree.cd(&current)?;
+
+            result
+        })
+    }
+
+    /// Fails with [Error::InvalidName] if the entry is a directory that contains
+    /// the current directory or is the current directory itself. The chunks are
+    /// compared so that paths through symlinks or in other cases are detected
+    fn check_outside(&mut self, entry: &DirEntry, dest: &str) -> Result<()> {
+        if !entry.is_dir() {
+            return Ok(());
+        }
+        let mut location = self.root;
+        for name in self.cursor.dir.clone() {
+            if location == entry.child_pointer {
+                break;
+            }
+            location = self
+                .find_in_dir(location, &name)?
+                .ok_or_else(|| Error::NotFound { path: self.dir() })?
+                .child_pointer;
+        }
+        if location == entry.child_pointer {
+            return Err(Error::InvalidName {
+                name: dest.to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Inserts a copy of the entry into the directory and copies its descendants
+    fn copy_subtree(&mut self, entry: DirEntry, parent: &str, name: &str) -> Result<CopyStats> {
+        let mut stats = CopyStats::default();
+        self.cd(parent)?;
+        self.check_new_name(name)?;
+        let mut visited = HashSet::new();
+        let mut stack = vec![(entry, parent.to_string(), name.to_string())];
+
+        while let Some((mut entry, parent, name)) = stack.pop() {
+            let source = entry.child_pointer;
+            if source != 0 {
+                self.visit(&mut visited, source)?;
+            }
+            self.cd(&parent)?;
+            stats.count(&entry);
+            entry.name = name;
+            entry.child_pointer = 0;
+            let copy = self.insert_new_entry(entry.clone(), source != 0)?;
+            // a copy showing up in the source means the source contains a cycle
+            if copy != 0 {
+                visited.insert(copy);
+            }
+
+            if source != 0 {
+                let path = join_path(&parent, &entry.name);
+                for child in self.read_entries(source)? {
+                    let name = child.name.clone();
+                    stack.push((child, path.clone(), name));
+                }
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Writes the whole tree as nested JSON objects. Entries are sorted by name so
+    /// that equal trees produce the same output
+    pub fn to_json<W: Write>(&mut self, writer: &mut W) -> Result<()> {
+        self.to_json_with(writer, OperationOptions::new())
+    }
+
+    /// Writes the tree like [DirTreeFile::to_json], reports every exported entry and
+    /// stops when the token of the options is cancelled. Nothing is written then
+    pub fn to_json_with<W: Write>(
+        &mut self,
+        writer: &mut W,
+        mut options: OperationOptions,
+    ) -> Result<()> {
+        let mut visited = HashSet::new();
+        let mut state = Progress::new(None);
+        let entries = self.dir_to_json(self.root, &mut visited, &mut options, &mut state)?;
+        let root = Json::Object(vec![
+            ("type".to_string(), Json::String("dir".to_string())),
+            ("entries".to_string(), entries),
+        ]);
+        root.write(writer, 0)?;
+        writeln!(writer)?;
+
+        Ok(())
+    }
+
+    /// Returns the JSON array of the entries of a directory and their descendants
+    fn dir_to_json(
+        &mut self,
+        location: u64,
+        visited: &mut HashSet<u64>,
+        options: &mut OperationOptions,
+        state: &mut Progress,
+    ) -> Result<Json> {
+        self.visit(visited, location)?;
+        let mut entries = self.read_entries(location)?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut items = Vec::with_capacity(entries.len());
+
+        for entry in entries {
+            options.check()?;
+            state.advance(entry.size() as u64);
+            options.report(*state);
+            let mut json = entry_to_json(&entry);
+            if entry.is_dir() {
+                let children = self.dir_to_json(entry.child_pointer, visited, options, state)?;
+                if let Json::Object(members) = &mut json {
+                    members.push(("entries".to_string(), children));
+                }
+            }
+            items.push(json);
+        }
+
+        Ok(Json::Array(items))
+    }
+
+    /// Creates the entries of a tree written by [DirTreeFile::to_json] in the
+    /// current directory. Fails on entries that already exist. Entries created
+    /// before an error are kept
+    pub fn from_json<R: Read>(&mut self, reader: &mut R) -> Result<CopyStats> {
+        self.from_json_with(reader, OperationOptions::new())
+    }
+
+    /// Creates the entries like [DirTreeFile::from_json], reports every imported entry
+    /// and stops when the token of the options is cancelled. The entries created
+    /// before the cancellation are kept
+    pub fn from_json_with<R: Read>(
+        &mut self,
+        reader: &mut R,
+        mut options: OperationOptions,
+    ) -> Result<CopyStats> {
+        self.journaled(|tree| {
+            let json = Json::parse(reader)?;
+            let current = tree.dir();
+            let result = tree.import_json(&json, &current, &mut options);
+            tree.cd(&current)?;
+
+            result
+        })
+    }
+
+    fn import_json(
+        &mut self,
+        json: &Json,
+        dest: &str,
+        options: &mut OperationOptions,
+    ) -> Result<CopyStats> {
+        let mut stats = CopyStats::default();
+        let mut state = Progress::new(None);
+        let mut stack = vec![(json, dest.to_string())];
+
+        while let Some((dir, parent)) = stack.pop() {
+            let entries = dir.get("entries").and_then(Json::as_array).ok_or_else(|| {
+                Error::InvalidArchive {
+                    reason: format!("directory {} without entries", parent),
+                }
+            })?;
+            self.cd(&parent)?;
+            for json in entries {
+                options.check()?;
+                let (entry, is_dir) = entry_from_json(json)?;
+                self.check_new_name(&entry.name)?;
+                if entry.size() > self.chunk_size as usize {
+                    return Err(Error::EntryTooLarge {
+                        path: self.entry_path(&entry.name),
+                        size: entry.size(),
+                        max: self.chunk_size as usize,
+                    });
+                }
+                if is_dir {
+                    stats.dirs += 1;
+                } else {
+                    stats.count(&entry);
+                }
+                let path = join_path(&parent, &entry.name);
+                state.advance(entry.size() as u64);
+                self.insert_new_entry(entry, is_dir)?;
+                options.report(state);
+                if is_dir {
+                    stack.push((json, path));
+                }
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Runs the operations of the closure as a single batch. Writes are kept in
+    /// memory until the closure succeeds and are then applied through a journal
+    /// with a single flush, so the batch is either applied completely or not at all
+    /// even if the process is interrupted. If the closure fails nothing is written.
+    /// Files without the current header are written without a journal
+    pub fn transaction<T, F>(&mut self, operations: F) -> Result<T>
+    where
+        F: FnOnce(&mut Transaction<'_, B>) -> Result<T>,
+    {
+        let current = self.dir();
+        self.staged(|tree| {
+            let mut transaction = Transaction {
+                tree,
+                base: current.clone(),
+            };
+            let value = operations(&mut transaction)?;
+            tree.cd(&current)?;
+
+            Ok(value)
+        })
+    }
+
+    /// Sets if single mutations are written through the journal. It's on by default
+    /// so that an interrupted write can't leave a chunk half written. Turning it off
+    /// makes bulk changes faster
+    pub fn set_journaled(&mut self, journaled: bool) {
+        self.journaled = journaled;
+    }
+
+    /// Sets when writes are synced to the disk. Journaled mutations and transactions
+    /// sync every step of their commit unless the policy is [SyncPolicy::Never] and
+    /// mutations without the journal are only synced with [SyncPolicy::Always]
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.backend.set_sync_policy(policy);
+    }
+
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.backend.sync_policy()
+    }
+
+    /// Syncs all written data to the disk
+    pub fn sync(&mut self) -> Result<()> {
+        self.backend.sync()?;
+
+        Ok(())
+    }
+
+    /// Runs a mutation through the journal if journaling is enabled
+    fn journaled<T, F: FnOnce(&mut Self) -> Result<T>>(&mut self, operation: F) -> Result<T> {
+        if self.journaled {
+            return self.staged(operation);
+        }
+        let value = operation(self)?;
+        if self.sync_policy() == SyncPolicy::Always {
+            self.backend.sync()?;
+        }
+
+        Ok(value)
+    }
+
+    /// Starts a batch. Writes of the following mutations are kept in memory and
+    /// applied through a single journal with one flush by [DirTreeFile::commit] so
+    /// that chunks changed several times are only written once. If a mutation in
+    /// the batch fails the whole batch is discarded. Writes of a batch that isn't
+    /// committed are lost when the tree is dropped
+    pub fn begin_batch(&mut self) -> Result<()> {
+        if self.backend.is_staging() {
+            return Err(Error::invalid_argument("a batch is already running"));
+        }
+        self.batch = Some(self.snapshot());
+        self.backend.begin()?;
+
+        Ok(())
+    }
+
+    /// Returns if a batch was started and not committed yet
+    pub fn in_batch(&self) -> bool {
+        self.batch.is_some()
+    }
+
+    /// Applies the writes of the current batch. Does nothing if there is no batch
+    pub fn commit(&mut self) -> Result<()> {
+        let snapshot = match self.batch.take() {
+            Some(snapshot) => snapshot,
+            None => return Ok(()),
+        };
+        let journal = self.journal_pointer();
+        if let Err(e) = self.backend.commit(journal) {
+            self.restore(snapshot);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Discards the writes of the current batch
+    pub fn rollback(&mut self) {
+        if let Some(snapshot) = self.batch.take() {
+            self.restore(snapshot);
+        }
+    }
+
+    /// Stages the writes of the operation and commits them if it succeeds. Nested
+    /// calls become part of the outer operation
+    fn staged<T, F: FnOnce(&mut Self) -> Result<T>>(&mut self, operation: F) -> Result<T> {
+        if self.backend.is_staging() {
+            let result = operation(self);
+            if result.is_err() {
+                self.rollback();
             }
+            return result;
+        }
+        let snapshot = self.snapshot();
+        let journal = self.journal_pointer();
+        self.backend.begin()?;
+
+        let result = operation(self).and_then(|value| {
+            self.backend.commit(journal)?;
+            Ok(value)
         });
+        if result.is_err() {
+            self.restore(snapshot);
+        }
+
+        result
+    }
+
+    /// Returns the location of the journal pointer if the file has one
+    fn journal_pointer(&self) -> Option<u64> {
+        if self.root == HEADER_SIZE {
+            Some(JOURNAL_OFFSET)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the state that has to be restored when staged writes are dropped
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            cursor: self.cursor.clone(),
+            free: self.free.clone(),
+            free_head: self.free_head,
+            root: self.root,
+        }
+    }
+
+    /// Drops the staged writes and restores the state from before they were staged
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.backend.rollback();
+        self.cursor = snapshot.cursor;
+        self.free = snapshot.free;
+        self.free_head = snapshot.free_head;
+        self.root = snapshot.root;
+        self.modified();
+    }
+
+    /// Returns the absolute normalized path for a path relative to the current directory
+    fn resolve_path(&self, path: &str) -> String {
+        if path.starts_with('/') {
+            normalize_path(path)
+        } else {
+            join_path(&self.dir(), path)
+        }
+    }
+
+    /// Adds an existing entry to the current directory
+    fn insert_entry(&mut self, entry: DirEntry) -> Result<()> {
+        let space = self.find_free_space(self.cursor.position, &entry)?;
+        self.write_entry_at(space, &entry)?;
+        if let Some(entries) = &mut self.cursor.entries {
+            entries.push(entry);
+        }
+
+        Ok(())
+    }
+
+    /// Checks if a new entry with the given name can be created in the current directory
+    fn check_new_name(&mut self, name: &str) -> Result<()> {
+        if !self.name_policy.allows(name) {
+            return Err(Error::InvalidName {
+                name: name.to_string(),
+            });
+        }
+        let max = (self.chunk_size as usize - DirEntry::new(String::new(), 0).size())
+            .min(LENGTH_MASK as usize - 8);
+        if name.len() > max {
+            return Err(Error::NameTooLong {
+                name: name.to_string(),
+                max,
+            });
+        }
+        if self.has_entry(name)? {
+            return Err(Error::AlreadyExists {
+                path: self.entry_path(name),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Returns the chunk of the current directory that contains the entry
+    fn find_entry_chunk(&mut self, name: &str) -> Result<Option<DirChunk>> {
+        Ok(self
+            .find_in_chunks(self.cursor.position, name)?
+            .map(|(chunk, _)| chunk))
+    }
+
+    /// Creates a new dir entry without the name check
+    fn create_dir_entry(&mut self, name: &str, dir: bool) -> Result<()> {
+        self.insert_new_entry(DirEntry::new(name.to_string(), 0), dir)?;
+
+        Ok(())
+    }
+
+    /// Adds the entry to the current directory and allocates an empty chunk for it
+    /// if it is a directory. Returns the child pointer of the entry
+    fn insert_new_entry(&mut self, mut entry: DirEntry, dir: bool) -> Result<u64> {
+        // the free space has to be found first so that a newly appended chunk is
+        // already reachable when the chunk for the directory gets allocated
+        let space = self.find_free_space(self.cursor.position, &entry)?;
+
+        if dir {
+            entry.child_pointer = self.new_chunk()?.location;
+        }
+        self.write_entry_at(space, &entry)?;
+        let child_pointer = entry.child_pointer;
+        if let Some(entries) = &mut self.cursor.entries {
+            entries.push(entry);
+        }
+
+        Ok(child_pointer)
+    }
+
+    /// Adds the entry to a chunk with enough free space and converts the bucket
+    /// into an index if it grew beyond the threshold
+    fn write_entry_at(&mut self, space: FreeSpace, entry: &DirEntry) -> Result<()> {
+        let FreeSpace {
+            mut chunk,
+            bucket,
+            depth,
+            length,
+        } = space;
+        chunk
+            .insert_entry(entry.clone(), &mut self.backend)
+            .map_err(|e| e.in_file(&self.path))?;
+        if length > INDEX_THRESHOLD {
+            self.convert_to_index(bucket, depth)?;
+        }
+        self.backend.flush()?;
+        self.modified();
+
+        Ok(())
+    }
+
+    /// Finds free space for an entry in the directory starting at the given chunk.
+    /// Buckets of an index that are still empty get a new chunk
+    fn find_free_space(&mut self, mut location: u64, entry: &DirEntry) -> Result<FreeSpace> {
+        let amount = entry.size() as u32;
+        let mut depth = 0;
+        let mut visited = HashSet::new();
+        self.visit(&mut visited, location)?;
+        let mut chunk = self.read_chunk(location)?;
+
+        while chunk.index {
+            let buckets = chunk
+                .buckets(&mut self.backend)
+                .map_err(|e| e.in_file(&self.path))?;
+            let bucket = chunk.bucket(&entry.name, depth);
+            depth += 1;
+            location = buckets[bucket];
+            if location == 0 {
+                let new_chunk = self.new_chunk()?;
+                chunk.write_bucket(bucket, new_chunk.location, &mut self.backend)?;
+                self.backend.flush()?;
+                return Ok(FreeSpace {
+                    bucket: new_chunk.location,
+                    chunk: new_chunk,
+                    depth,
+                    length: 1,
+                });
+            }
+            self.visit(&mut visited, location)?;
+            chunk = self.read_chunk(location)?;
+        }
+        let mut length = 1;
+
+        loop {
+            if chunk.free_space(&mut self.backend)? >= amount {
+                break;
+            }
+
+            let next = chunk.next;
+            if next == 0 {
+                let new_chunk = self.new_chunk()?;
+                chunk.next = new_chunk.location;
+                chunk.write_next_pointer(&mut self.backend)?;
+                self.backend.flush()?;
+                chunk = new_chunk;
+                length += 1;
+                break;
+            }
+            self.visit(&mut visited, next)?;
+            chunk = self.read_chunk(next)?;
+            length += 1;
+        }
+
+        Ok(FreeSpace {
+            chunk,
+            bucket: location,
+            depth,
+            length,
+        })
+    }
+
+    /// Replaces the chain of chunks at the location with an index and distributes
+    /// its entries over the buckets. The location stays the same so pointers to
+    /// the directory or the bucket remain valid
+    fn convert_to_index(&mut self, location: u64, depth: usize) -> Result<()> {
+        let mut entries = Vec::new();
+        let mut chunks = Vec::new();
+        let mut visited = HashSet::new();
+        let mut next = location;
+
+        while next != 0 {
+            self.visit(&mut visited, next)?;
+            let chunk = self.read_chunk(next)?;
+            entries.append(
+                &mut chunk
+                    .entries(&mut self.backend)
+                    .map_err(|e| e.in_file(&self.path))?,
+            );
+            if next != location {
+                chunks.push(next);
+            }
+            next = chunk.next;
+        }
+        let mut index = DirChunk::new_index(location, self.chunk_size);
+        index.case_insensitive = self.case_insensitive;
+        index.checksum = self.checksums;
+        index.verify = self.verify_checksums;
+        index.write_empty(&mut self.backend)?;
+        self.free_chunks(chunks)?;
+
+        let mut buckets: Vec<Vec<DirEntry>> = vec![Vec::new(); index.entries as usize];
+        for entry in entries {
+            buckets[index.bucket(&entry.name, depth)].push(entry);
+        }
+        for (i, bucket_entries) in buckets.into_iter().enumerate() {
+            let mut previous: Option<DirChunk> = None;
+            let mut chunk_entries = Vec::new();
+
+            for entry in bucket_entries {
+                let mut candidate = chunk_entries.clone();
+                candidate.push(entry.clone());
+                if !chunk_entries.is_empty() && !DirChunk::new(0, self.chunk_size).fits(&candidate)
+                {
+                    previous =
+                        Some(self.write_bucket_chunk(&index, i, previous, &chunk_entries)?);
+                    chunk_entries.clear();
+                }
+                chunk_entries.push(entry);
+            }
+            if !chunk_entries.is_empty() {
+                self.write_bucket_chunk(&index, i, previous, &chunk_entries)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes a new chunk with the entries and links it after the previous chunk
+    /// of the bucket or into the index
+    fn write_bucket_chunk(
+        &mut self,
+        index: &DirChunk,
+        bucket: usize,
+        previous: Option<DirChunk>,
+        entries: &[DirEntry],
+    ) -> Result<DirChunk> {
+        let mut chunk = self.new_chunk()?;
+        chunk.write_entries(entries, &mut self.backend)?;
+        match previous {
+            Some(mut previous) => {
+                previous.next = chunk.location;
+                previous.write_next_pointer(&mut self.backend)?;
+            }
+            None => index.write_bucket(bucket, chunk.location, &mut self.backend)?,
+        }
+
+        Ok(chunk)
+    }
+
+    /// Checks the structure of the whole tree without trusting any pointer or length
+    pub fn check(&mut self) -> Result<TreeCheck> {
+        self.check_with(OperationOptions::new())
+    }
+
+    /// Checks the tree like [DirTreeFile::check], reports every checked chunk and
+    /// stops when the token of the options is cancelled
+    pub fn check_with(&mut self, mut options: OperationOptions) -> Result<TreeCheck> {
+        let mut state = Progress::new(None);
+        let file_size = self.get_size()?;
+        let mut report = TreeCheck::default();
+        let mut ranges = Vec::new();
+        let mut visited = HashSet::new();
+        let mut stack = vec![(self.root, String::new())];
+
+        while let Some((location, path)) = stack.pop() {
+            options.check()?;
+            if !visited.insert(location) {
+                report.overlapping.push(location);
+                continue;
+            }
+            let (chunk, entries) = match self.check_chunk(location, file_size) {
+                Ok(chunk) => chunk,
+                Err(_) => {
+                    report.bad_lengths.push(location);
+                    continue;
+                }
+            };
+            ranges.push((location, location + chunk.size() as u64));
+            state.advance(chunk.size() as u64);
+            options.report(state);
+            match chunk.links(&mut self.backend) {
+                Ok(links) => stack.extend(links.into_iter().map(|l| (l, path.clone()))),
+                Err(_) => report.bad_lengths.push(location),
+            }
+            for entry in entries {
+                let entry_path = format!("{}/{}", path, entry.name);
+                if entry.is_dir() {
+                    stack.push((entry.child_pointer, entry_path));
+                } else if !entry.is_symlink() {
+                    report.files.push(entry_path);
+                }
+            }
+        }
+        let mut free = self.free_head;
+        while free != 0 {
+            if !visited.insert(free) {
+                report.overlapping.push(free);
+                break;
+            }
+            match self.check_chunk(free, file_size) {
+                Ok((chunk, _)) => {
+                    ranges.push((free, free + chunk.size() as u64));
+                    free = chunk.next;
+                }
+                Err(_) => {
+                    report.bad_lengths.push(free);
+                    break;
+                }
+            }
+        }
+        ranges.push((0, self.root));
+        ranges.sort_unstable();
+        let mut previous = 0;
+
+        for (start, end) in ranges {
+            if start < previous {
+                report.overlapping.push(start);
+            } else if start > previous {
+                report.unreachable.push((previous, start));
+            }
+            previous = previous.max(end);
+        }
+        if previous < file_size {
+            report.unreachable.push((previous, file_size));
+        }
+
+        Ok(report)
+    }
+
+    /// Truncates the file to the end of the last reachable chunk
+    pub fn truncate_unreachable(&mut self) -> Result<()> {
+        self.journaled(|tree| {
+            let check = tree.check()?;
+            let size = tree.get_size()?;
+
+            if let Some((start, end)) = check.unreachable.last() {
+                if *end == size && *start > 0 {
+                    tree.backend.set_len(*start)?;
+                    tree.free = None;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Returns chunks that are neither reachable from the root nor part of the free
+    /// list to the free list. Such chunks are leaked by operations that were
+    /// interrupted. Fails on trees with overlapping or broken chunks as their
+    /// reachability can't be trusted. Returns the number of reclaimed chunks
+    pub fn reclaim_orphans(&mut self) -> Result<usize> {
+        self.journaled(|tree| {
+            let check = tree.check()?;
+            if let Some(location) = check.overlapping.first().or(check.bad_lengths.first()) {
+                return Err(
+                    Error::corrupt(*location, "can't reclaim chunks of a broken tree")
+                        .in_file(&tree.path),
+                );
+            }
+            let chunk_size = tree.blank_chunk(0).size() as u64;
+            let mut orphans = Vec::new();
+
+            for (start, end) in check.unreachable {
+                let mut location = start;
+                while location + chunk_size <= end {
+                    orphans.push(location);
+                    location += chunk_size;
+                }
+            }
+            let count = orphans.len();
+            if count > 0 {
+                tree.free_chunks(orphans)?;
+            }
+
+            Ok(count)
+        })
+    }
+
+    /// Rewrites the tree so that every directory uses as few chunks as possible and
+    /// truncates the file. Files without a header are upgraded to the current
+    /// format. Returns the number of bytes reclaimed. Handles other than the
+    /// current directory point to old locations afterwards and have to be reopened
+    pub fn compact(&mut self) -> Result<u64> {
+        self.compact_with(OperationOptions::new())
+    }
+
+    /// Compacts the tree like [DirTreeFile::compact], reports every copied entry and
+    /// stops when the token of the options is cancelled. The tree is only replaced
+    /// after all entries were copied, so a cancelled compaction leaves it unchanged
+    pub fn compact_with(&mut self, mut options: OperationOptions) -> Result<u64> {
+        let _span = span!("tree compaction");
+        let mut state = Progress::new(None);
+        self.journaled(|tree| {
+            let size = tree.get_size()?;
+            let current = tree.dir();
+            let tree_options = TreeOptions {
+                chunk_size: tree.chunk_size,
+                case_insensitive: tree.case_insensitive,
+                checksums: tree.checksums,
+            };
+            let mut compacted =
+                DirTreeFile::from_backend_with_options(Cursor::new(Vec::new()), tree_options)?;
+            let mut visited = HashSet::new();
+            let mut stack = vec![(tree.root, compacted.root)];
+
+            while let Some((source, dest)) = stack.pop() {
+                options.check()?;
+                tree.visit(&mut visited, source)?;
+                compacted.cursor = DirHandle::new(dest);
+                for mut entry in tree.read_entries(source)? {
+                    let child = entry.child_pointer;
+                    entry.child_pointer = 0;
+                    state.advance(entry.size() as u64);
+                    options.report(state);
+                    let copy = compacted.insert_new_entry(entry, child != 0)?;
+                    if child != 0 {
+                        stack.push((child, copy));
+                    }
+                }
+            }
+            let data = compacted.into_inner().into_inner();
+            tree.backend.seek(SeekFrom::Start(0))?;
+            tree.backend.write_all(&data)?;
+            tree.backend.set_len(data.len() as u64)?;
+            tree.backend.flush()?;
+            tree.root = HEADER_SIZE;
+            tree.free_head = 0;
+            tree.free = None;
+            tree.cursor = DirHandle::new(tree.root);
+            tree.modified();
+            tree.cd(&current)?;
+
+            Ok(size.saturating_sub(data.len() as u64))
+        })
+    }
+
+    /// Reads a chunk and its entries validating all lengths against the file size
+    fn check_chunk(&mut self, location: u64, file_size: u64) -> Result<(DirChunk, Vec<DirEntry>)> {
+        if location + DirChunk::new(0, 0).size() as u64 > file_size {
+            return Err(Error::corrupt(location, "chunk exceeds the file"));
+        }
+        let chunk = self.read_chunk(location)?;
+        if chunk.length != self.chunk_size || location + chunk.size() as u64 > file_size {
+            return Err(Error::corrupt(location, "invalid chunk length"));
+        }
+        let entries = chunk.entries(&mut self.backend)?;
+        let used: usize = entries.iter().map(|e| e.size()).sum();
+        if used > chunk.length as usize {
+            return Err(Error::corrupt(location, "entries exceed the chunk"));
+        }
+
+        Ok((chunk, entries))
+    }
+
+    /// Returns the ranges of all chunks reachable from the location
+    fn memory_layout(&mut self, location: u64) -> Result<Vec<(u64, u64)>> {
+        let chunk_size = self.blank_chunk(0).size() as u64;
+
+        Ok(self
+            .subtree_chunks(location)?
+            .into_iter()
+            .map(|location| (location, location + chunk_size))
+            .collect())
+    }
+
+    /// Returns the locations of all chunks of the directory and its descendants.
+    /// Chunks that are reached twice are reported as corruption instead of looping
+    fn subtree_chunks(&mut self, location: u64) -> Result<Vec<u64>> {
+        let mut chunks = Vec::new();
+        let mut visited = HashSet::new();
+        let mut stack = vec![location];
+
+        while let Some(location) = stack.pop() {
+            self.visit(&mut visited, location)?;
+            let chunk = self.read_chunk(location)?;
+            chunks.push(location);
+            stack.append(&mut chunk.links(&mut self.backend)?);
+            for entry in chunk.entries(&mut self.backend)? {
+                if entry.is_dir() {
+                    stack.push(entry.child_pointer);
+                }
+            }
+        }
+
+        Ok(chunks)
+    }
+
+    /// Marks a chunk as visited by a traversal and fails if it was visited before
+    /// which means that the pointers of the tree contain a cycle
+    fn visit(&self, visited: &mut HashSet<u64>, location: u64) -> Result<()> {
+        if visited.insert(location) {
+            Ok(())
+        } else {
+            Err(Error::corrupt(location, "chunk is referenced twice").in_file(&self.path))
+        }
+    }
+
+    /// Releases chunks that are no longer referenced. Free chunks at the end of
+    /// the file are truncated and the others are reused by later allocations
+    fn free_chunks(&mut self, mut chunks: Vec<u64>) -> Result<()> {
+        let chunk_size = self.blank_chunk(0).size() as u64;
+        let size = self.get_size()?;
+        let mut end = size;
+        chunks.sort_unstable();
+
+        if self.root == 0 {
+            // without a header the free chunks are only tracked in memory
+            let scanned = self.free.is_some();
+            let mut free = self.free.take().unwrap_or_default();
+            free.extend(chunks.drain(..));
+            while let Some(&location) = free.last() {
+                if location + chunk_size != end {
+                    break;
+                }
+                free.pop_last();
+                end = location;
+            }
+            // an unscanned list would miss the gaps that existed before
+            if scanned {
+                self.free = Some(free);
+            }
+        } else {
+            while let Some(&location) = chunks.last() {
+                if location + chunk_size != end {
+                    break;
+                }
+                chunks.pop();
+                end = location;
+            }
+        }
+        if end < size {
+            self.backend.set_len(end)?;
+        }
+        for location in chunks {
+            let mut chunk = self.blank_chunk(location);
+            chunk.next = self.free_head;
+            if chunk.checksum {
+                chunk.write_empty(&mut self.backend)?;
+            } else {
+                chunk.write_header(&mut self.backend)?;
+                chunk.write_next_pointer(&mut self.backend)?;
+            }
+            self.set_free_head(location)?;
+        }
+        self.backend.flush()?;
+
+        Ok(())
+    }
+
+    fn set_free_head(&mut self, location: u64) -> Result<()> {
+        self.backend.seek(SeekFrom::Start(FREE_HEAD_OFFSET))?;
+        self.backend.write_u64::<BigEndian>(location)?;
+        self.free_head = location;
+
+        Ok(())
+    }
+
+    /// Reads the chunk at the location
+    fn read_chunk(&mut self, location: u64) -> Result<DirChunk> {
+        self.drop_written_chunks();
+        if let Some(CachedChunk {
+            header: Some(header),
+            ..
+        }) = self.chunk_cache.get_mut(location)
+        {
+            event!(Trace, "chunk cache hit for the header at {}", location);
+            return Ok(header.clone());
+        }
+        event!(Trace, "chunk cache miss for the header at {}", location);
+        let mut chunk = DirChunk::from_reader(location, &mut self.backend)?;
+        chunk.case_insensitive = self.case_insensitive;
+        chunk.checksum = self.checksums;
+        chunk.verify = self.verify_checksums;
+        match self.chunk_cache.get_mut(location) {
+            Some(cached) => cached.header = Some(chunk.clone()),
+            None => self.chunk_cache.insert(
+                location,
+                CachedChunk {
+                    header: Some(chunk.clone()),
+                    entries: None,
+                },
+            ),
+        }
+
+        Ok(chunk)
+    }
+
+    /// Returns an empty chunk with the settings of the tree
+    fn blank_chunk(&self, location: u64) -> DirChunk {
+        let mut chunk = DirChunk::new(location, self.chunk_size);
+        chunk.case_insensitive = self.case_insensitive;
+        chunk.checksum = self.checksums;
+        chunk.verify = self.verify_checksums;
+
+        chunk
+    }
+
+    /// Creates a new chunk in a free location or at the end of the file
+    fn new_chunk(&mut self) -> Result<DirChunk> {
+        let mut chunk = self.blank_chunk(0);
+        chunk.location = self.next_chunk_location(chunk.size() as u64)?;
+        chunk.write_empty(&mut self.backend)?;
+        event!(Debug, "allocated a chunk at {}", chunk.location);
+
+        Ok(chunk)
+    }
+
+    /// Returns the size of the file in bytes
+    pub fn get_size(&mut self) -> Result<u64> {
+        Ok(self.backend.size()?)
+    }
+
+    /// Returns the next available chunk location
+    fn next_chunk_location(&mut self, size: u64) -> Result<u64> {
+        if self.root != 0 {
+            if self.free_head == 0 {
+                return self.get_size();
+            }
+            let location = self.free_head;
+            let chunk = self.read_chunk(location)?;
+            self.set_free_head(chunk.next)?;
+
+            return Ok(location);
+        }
+        if self.free.is_none() {
+            self.free = Some(self.scan_free_chunks(size)?);
+        }
+        match self.free.as_mut().and_then(|free| free.pop_first()) {
+            Some(location) => Ok(location),
+            None => self.get_size(),
+        }
+    }
+
+    /// Finds the locations between the reachable chunks of a file without
+    /// a header that can hold a chunk
+    fn scan_free_chunks(&mut self, size: u64) -> Result<BTreeSet<u64>> {
+        let mut layout = self.memory_layout(self.root)?;
+        layout.push((0, self.root));
+        layout.sort_unstable();
+        let mut free = BTreeSet::new();
         let mut previous = 0;
 
-        for (a1, a2) in layout {
-            if a1 - previous > size {
-                return Ok(previous);
+        for (start, end) in layout {
+            while start >= previous + size {
+                free.insert(previous);
+                previous += size;
             }
-            previous = a2;
+            previous = previous.max(end);
         }
 
-        self.get_size()
+        Ok(free)
     }
 }
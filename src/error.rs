@@ -0,0 +1,237 @@
+use crate::format::FormatError;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The error type of all storage operations
+#[derive(Debug)]
+pub enum Error {
+    /// An io error of the underlying files
+    Io(io::Error),
+    /// There's no entry at the given path
+    NotFound { path: String },
+    /// An entry with the same name already exists
+    AlreadyExists { path: String },
+    /// A directory was expected but the entry is a file
+    NotADirectory { path: String },
+    /// A file was expected but the entry is a directory
+    IsADirectory { path: String },
+    /// The directory can't be removed because it still has entries
+    DirectoryNotEmpty { path: String },
+    /// The name can't be used for an entry
+    InvalidName { name: String },
+    /// The name doesn't fit into a single directory chunk
+    NameTooLong { name: String, max: usize },
+    /// The entry with its attributes doesn't fit into a single directory chunk
+    EntryTooLarge {
+        path: String,
+        size: usize,
+        max: usize,
+    },
+    /// Too many symlinks were followed while resolving a path
+    SymlinkLoop { path: String },
+    /// A range of a file was requested that doesn't lie within the file
+    InvalidRange {
+        path: String,
+        offset: u64,
+        length: u64,
+        size: u64,
+    },
+    /// The data in a file doesn't match the expected format
+    Corrupt {
+        file: PathBuf,
+        offset: u64,
+        reason: String,
+    },
+    /// An archive that is imported can't be read
+    InvalidArchive { reason: String },
+    /// The file is locked by another process
+    Locked { path: PathBuf },
+    /// The storage was opened read-only
+    ReadOnly { path: PathBuf },
+    /// The file was written with a format version or flags this version doesn't know
+    UnsupportedFormat {
+        file: PathBuf,
+        version: u16,
+        flags: u16,
+    },
+    /// The operation was stopped through its cancel token
+    Cancelled,
+    /// Storing the file would exceed the quota of the storage
+    QuotaExceeded { path: String },
+    /// A value, inline blob or chunk list is larger than a value can hold
+    ValueTooLarge { size: usize, max: usize },
+    /// An argument or the state of the object doesn't allow the operation
+    InvalidArgument { reason: String },
+    /// A change was made but its audit record couldn't be written. Changes are
+    /// refused until the audit log is enabled again
+    AuditLogFailed { reason: String },
+}
+
+impl Error {
+    /// Creates an error for an argument that doesn't allow the operation
+    pub fn invalid_argument<S: ToString>(reason: S) -> Self {
+        Error::InvalidArgument {
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Creates a corruption error for the given offset. The file is attached
+    /// by the owner of the reader via [Error::in_file]
+    pub fn corrupt<S: ToString>(offset: u64, reason: S) -> Self {
+        Error::Corrupt {
+            file: PathBuf::new(),
+            offset,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Attaches the file path to a corruption or format error
+    pub fn in_file(self, path: &Path) -> Self {
+        match self {
+            Error::Corrupt { offset, reason, .. } => Error::Corrupt {
+                file: path.to_path_buf(),
+                offset,
+                reason,
+            },
+            Error::UnsupportedFormat { version, flags, .. } => Error::UnsupportedFormat {
+                file: path.to_path_buf(),
+                version,
+                flags,
+            },
+            error => error,
+        }
+    }
+
+    /// Returns the io error kind that matches the error best
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Io(e) => e.kind(),
+            Error::NotFound { .. } => io::ErrorKind::NotFound,
+            Error::AlreadyExists { .. } => io::ErrorKind::AlreadyExists,
+            Error::NotADirectory { .. } => io::ErrorKind::NotADirectory,
+            Error::IsADirectory { .. } => io::ErrorKind::IsADirectory,
+            Error::DirectoryNotEmpty { .. } => io::ErrorKind::DirectoryNotEmpty,
+            Error::InvalidName { .. } | Error::NameTooLong { .. } | Error::EntryTooLarge { .. } => {
+                io::ErrorKind::InvalidInput
+            }
+            Error::Corrupt { .. } | Error::InvalidArchive { .. } => io::ErrorKind::InvalidData,
+            Error::SymlinkLoop { .. } | Error::InvalidRange { .. } => io::ErrorKind::InvalidInput,
+            Error::Locked { .. } => io::ErrorKind::WouldBlock,
+            Error::ReadOnly { .. } => io::ErrorKind::ReadOnlyFilesystem,
+            Error::UnsupportedFormat { .. } => io::ErrorKind::Unsupported,
+            Error::Cancelled => io::ErrorKind::Interrupted,
+            Error::QuotaExceeded { .. } => io::ErrorKind::QuotaExceeded,
+            Error::ValueTooLarge { .. } | Error::InvalidArgument { .. } => {
+                io::ErrorKind::InvalidInput
+            }
+            Error::AuditLogFailed { .. } => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::NotFound { path } => write!(f, "{} not found", path),
+            Error::AlreadyExists { path } => write!(f, "{} already exists", path),
+            Error::NotADirectory { path } => write!(f, "{} is not a directory", path),
+            Error::IsADirectory { path } => write!(f, "{} is a directory", path),
+            Error::DirectoryNotEmpty { path } => write!(f, "directory {} is not empty", path),
+            Error::InvalidName { name } => write!(f, "invalid entry name {:?}", name),
+            Error::NameTooLong { name, max } => {
+                write!(f, "name {:?} is longer than {} bytes", name, max)
+            }
+            Error::EntryTooLarge { path, size, max } => write!(
+                f,
+                "entry {} needs {} bytes but at most {} fit into a chunk",
+                path, size, max
+            ),
+            Error::SymlinkLoop { path } => write!(f, "too many symlinks in {}", path),
+            Error::InvalidRange {
+                path,
+                offset,
+                length,
+                size,
+            } => write!(
+                f,
+                "range of {} bytes at {} exceeds {} with {} bytes",
+                length, offset, path, size
+            ),
+            Error::Corrupt {
+                file,
+                offset,
+                reason,
+            } => write!(f, "{:?} is corrupted at {}: {}", file, offset, reason),
+            Error::InvalidArchive { reason } => write!(f, "invalid archive: {}", reason),
+            Error::Locked { path } => write!(f, "{:?} is locked by another process", path),
+            Error::ReadOnly { path } => write!(f, "{:?} is opened read-only", path),
+            Error::UnsupportedFormat {
+                file,
+                version,
+                flags,
+            } => write!(
+                f,
+                "{:?} uses the unsupported format version {} with flags {:#x}",
+                file, version, flags
+            ),
+            Error::Cancelled => write!(f, "the operation was cancelled"),
+            Error::QuotaExceeded { path } => {
+                write!(f, "storing {} would exceed the quota", path)
+            }
+            Error::ValueTooLarge { size, max } => {
+                write!(
+                    f,
+                    "a value of {} bytes exceeds the limit of {} bytes",
+                    size, max
+                )
+            }
+            Error::InvalidArgument { reason } => write!(f, "invalid argument: {}", reason),
+            Error::AuditLogFailed { reason } => {
+                write!(f, "the audit log can't be written: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<FormatError> for Error {
+    fn from(error: FormatError) -> Self {
+        match error {
+            FormatError::Io(e) => Error::Io(e),
+            FormatError::UnexpectedEnd => Error::Io(io::ErrorKind::UnexpectedEof.into()),
+            FormatError::Corrupt { offset, reason } => Error::corrupt(offset, reason),
+            FormatError::UnsupportedFormat { version, flags } => Error::UnsupportedFormat {
+                file: PathBuf::new(),
+                version,
+                flags,
+            },
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Io(e) => e,
+            error => io::Error::new(error.kind(), error),
+        }
+    }
+}
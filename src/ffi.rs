@@ -0,0 +1,248 @@
+//! A C interface to a storage with opaque handles. Functions return [IFS_OK]
+//! or one of the error codes. The `ffi` crate of the workspace builds the
+//! functions as a C library. Their declarations in `include/indexed_file_storage.h`
+//! are generated from this module with cbindgen
+
+use crate::error::{Error, Result};
+use crate::storage::Storage;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::path::PathBuf;
+use std::{ptr, slice};
+
+pub const IFS_OK: c_int = 0;
+pub const IFS_ERROR_INVALID_ARGUMENT: c_int = 1;
+pub const IFS_ERROR_NOT_FOUND: c_int = 2;
+pub const IFS_ERROR_ALREADY_EXISTS: c_int = 3;
+pub const IFS_ERROR_NOT_A_DIRECTORY: c_int = 4;
+pub const IFS_ERROR_IS_A_DIRECTORY: c_int = 5;
+pub const IFS_ERROR_DIRECTORY_NOT_EMPTY: c_int = 6;
+pub const IFS_ERROR_LOCKED: c_int = 7;
+pub const IFS_ERROR_READ_ONLY: c_int = 8;
+pub const IFS_ERROR_QUOTA_EXCEEDED: c_int = 9;
+pub const IFS_ERROR_CORRUPT: c_int = 10;
+pub const IFS_ERROR_IO: c_int = 11;
+
+/// An open storage
+pub struct IfsStorage(Storage);
+
+/// The names of the entries of a directory
+pub struct IfsList(Vec<CString>);
+
+fn error_code(error: &Error) -> c_int {
+    match error {
+        Error::NotFound { .. } => IFS_ERROR_NOT_FOUND,
+        Error::AlreadyExists { .. } => IFS_ERROR_ALREADY_EXISTS,
+        Error::NotADirectory { .. } => IFS_ERROR_NOT_A_DIRECTORY,
+        Error::IsADirectory { .. } => IFS_ERROR_IS_A_DIRECTORY,
+        Error::DirectoryNotEmpty { .. } => IFS_ERROR_DIRECTORY_NOT_EMPTY,
+        Error::InvalidName { .. }
+        | Error::NameTooLong { .. }
+        | Error::EntryTooLarge { .. }
+        | Error::InvalidRange { .. }
+        | Error::ValueTooLarge { .. }
+        | Error::InvalidArgument { .. } => IFS_ERROR_INVALID_ARGUMENT,
+        Error::Locked { .. } => IFS_ERROR_LOCKED,
+        Error::ReadOnly { .. } => IFS_ERROR_READ_ONLY,
+        Error::QuotaExceeded { .. } => IFS_ERROR_QUOTA_EXCEEDED,
+        Error::Corrupt { .. } => IFS_ERROR_CORRUPT,
+        _ => IFS_ERROR_IO,
+    }
+}
+
+fn code(result: Result<()>) -> c_int {
+    match result {
+        Ok(()) => IFS_OK,
+        Err(e) => error_code(&e),
+    }
+}
+
+/// Returns the string at the pointer or None if it's null or not UTF-8
+unsafe fn to_str<'a>(value: *const c_char) -> Option<&'a str> {
+    if value.is_null() {
+        return None;
+    }
+    CStr::from_ptr(value).to_str().ok()
+}
+
+/// Opens or creates the storage in the directory at the path and writes its
+/// handle to `out`
+///
+/// # Safety
+///
+/// `path` has to be a null terminated string and `out` a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn ifs_open(path: *const c_char, out: *mut *mut IfsStorage) -> c_int {
+    let path = match to_str(path) {
+        Some(path) if !out.is_null() => path,
+        _ => return IFS_ERROR_INVALID_ARGUMENT,
+    };
+    match Storage::open(PathBuf::from(path)) {
+        Ok(storage) => {
+            *out = Box::into_raw(Box::new(IfsStorage(storage)));
+            IFS_OK
+        }
+        Err(e) => error_code(&e),
+    }
+}
+
+/// Closes the storage. A null handle is ignored
+///
+/// # Safety
+///
+/// `storage` has to be a handle returned by [ifs_open] that isn't used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn ifs_close(storage: *mut IfsStorage) {
+    if !storage.is_null() {
+        drop(Box::from_raw(storage));
+    }
+}
+
+/// Stores `length` bytes at `data` in the file at the path and creates the
+/// missing parent directories
+///
+/// # Safety
+///
+/// `storage` has to be an open handle, `path` a null terminated string and `data`
+/// valid for `length` bytes
+#[no_mangle]
+pub unsafe extern "C" fn ifs_store(
+    storage: *const IfsStorage,
+    path: *const c_char,
+    data: *const u8,
+    length: usize,
+) -> c_int {
+    let (storage, path) = match (storage.as_ref(), to_str(path)) {
+        (Some(storage), Some(path)) => (storage, path),
+        _ => return IFS_ERROR_INVALID_ARGUMENT,
+    };
+    let content = match (data.is_null(), length) {
+        (_, 0) => &[][..],
+        (false, length) => slice::from_raw_parts(data, length),
+        (true, _) => return IFS_ERROR_INVALID_ARGUMENT,
+    };
+
+    code(storage.0.store_at(path, content).map(|_| ()))
+}
+
+/// Reads the file at the path into a new buffer and writes it to `data` and its
+/// length to `length`. The buffer is freed with [ifs_free_buffer]
+///
+/// # Safety
+///
+/// `storage` has to be an open handle, `path` a null terminated string and `data`
+/// and `length` valid pointers
+#[no_mangle]
+pub unsafe extern "C" fn ifs_get(
+    storage: *const IfsStorage,
+    path: *const c_char,
+    data: *mut *mut u8,
+    length: *mut usize,
+) -> c_int {
+    let (storage, path) = match (storage.as_ref(), to_str(path)) {
+        (Some(storage), Some(path)) if !data.is_null() && !length.is_null() => (storage, path),
+        _ => return IFS_ERROR_INVALID_ARGUMENT,
+    };
+    match storage.0.read_path(path) {
+        Ok(content) => {
+            let content = content.into_boxed_slice();
+            *length = content.len();
+            *data = Box::into_raw(content) as *mut u8;
+            IFS_OK
+        }
+        Err(e) => error_code(&e),
+    }
+}
+
+/// Frees a buffer returned by [ifs_get]. A null buffer is ignored
+///
+/// # Safety
+///
+/// `data` and `length` have to be the buffer and length returned by [ifs_get]
+#[no_mangle]
+pub unsafe extern "C" fn ifs_free_buffer(data: *mut u8, length: usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(data, length)));
+    }
+}
+
+/// Writes a list of the names of the entries of the directory at the path to
+/// `out`. The list is freed with [ifs_list_free]
+///
+/// # Safety
+///
+/// `storage` has to be an open handle, `path` a null terminated string and `out`
+/// a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn ifs_list(
+    storage: *const IfsStorage,
+    path: *const c_char,
+    out: *mut *mut IfsList,
+) -> c_int {
+    let (storage, path) = match (storage.as_ref(), to_str(path)) {
+        (Some(storage), Some(path)) if !out.is_null() => (storage, path),
+        _ => return IFS_ERROR_INVALID_ARGUMENT,
+    };
+    let entries = match storage.0.read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => return error_code(&e),
+    };
+    let mut names = Vec::with_capacity(entries.len());
+    for entry in entries {
+        match CString::new(entry.name) {
+            Ok(name) => names.push(name),
+            Err(_) => return IFS_ERROR_CORRUPT,
+        }
+    }
+    *out = Box::into_raw(Box::new(IfsList(names)));
+
+    IFS_OK
+}
+
+/// Returns the number of names in the list
+///
+/// # Safety
+///
+/// `list` has to be a list returned by [ifs_list]
+#[no_mangle]
+pub unsafe extern "C" fn ifs_list_len(list: *const IfsList) -> usize {
+    list.as_ref().map_or(0, |list| list.0.len())
+}
+
+/// Returns the name at the index in the list or null if it's out of range. The
+/// name is valid until the list is freed
+///
+/// # Safety
+///
+/// `list` has to be a list returned by [ifs_list]
+#[no_mangle]
+pub unsafe extern "C" fn ifs_list_name(list: *const IfsList, index: usize) -> *const c_char {
+    list.as_ref()
+        .and_then(|list| list.0.get(index))
+        .map_or(ptr::null(), |name| name.as_ptr())
+}
+
+/// Frees a list returned by [ifs_list]. A null list is ignored
+///
+/// # Safety
+///
+/// `list` has to be a list returned by [ifs_list] that isn't used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn ifs_list_free(list: *mut IfsList) {
+    if !list.is_null() {
+        drop(Box::from_raw(list));
+    }
+}
+
+/// Deletes the file or empty directory at the path
+///
+/// # Safety
+///
+/// `storage` has to be an open handle and `path` a null terminated string
+#[no_mangle]
+pub unsafe extern "C" fn ifs_delete(storage: *const IfsStorage, path: *const c_char) -> c_int {
+    match (storage.as_ref(), to_str(path)) {
+        (Some(storage), Some(path)) => code(storage.0.delete(path)),
+        _ => IFS_ERROR_INVALID_ARGUMENT,
+    }
+}
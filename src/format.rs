@@ -0,0 +1,88 @@
+//! Parsers for the on-disk formats of the dir tree and the meta file that only
+//! need `core` and `alloc`. They are available without the default `std`
+//! feature, so that embedded targets can read containers produced on the
+//! desktop. Data is read through [FormatRead], which is implemented for byte
+//! slices by [SliceReader] and for every seekable reader with `std`
+
+pub mod meta;
+pub mod tree;
+
+use alloc::string::String;
+use core::convert::TryFrom;
+use core::fmt;
+
+/// An error while reading one of the formats
+#[derive(Debug)]
+pub enum FormatError {
+    #[cfg(feature = "std")]
+    Io(std::io::Error),
+    /// The data ended before the structure was complete
+    UnexpectedEnd,
+    /// The data doesn't match the format
+    Corrupt { offset: u64, reason: String },
+    /// The file has a version or flags this version doesn't understand
+    UnsupportedFormat { version: u16, flags: u16 },
+}
+
+impl FormatError {
+    pub(crate) fn corrupt<S: Into<String>>(offset: u64, reason: S) -> Self {
+        FormatError::Corrupt {
+            offset,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            FormatError::Io(e) => write!(f, "{}", e),
+            FormatError::UnexpectedEnd => write!(f, "unexpected end of data"),
+            FormatError::Corrupt { offset, reason } => {
+                write!(f, "corrupted at offset {}: {}", offset, reason)
+            }
+            FormatError::UnsupportedFormat { version, flags } => write!(
+                f,
+                "unsupported format version {} with flags {:#06x}",
+                version, flags
+            ),
+        }
+    }
+}
+
+/// Reads data at an offset of a file or memory region
+pub trait FormatRead {
+    /// Fills the whole buffer with the data at the offset. Fails with
+    /// [FormatError::UnexpectedEnd] if the data ends before
+    fn read_exact_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FormatError>;
+}
+
+/// Reads from a byte slice, e.g. a file that was loaded or memory mapped flash
+pub struct SliceReader<'a>(pub &'a [u8]);
+
+impl FormatRead for SliceReader<'_> {
+    fn read_exact_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FormatError> {
+        let data = usize::try_from(offset)
+            .ok()
+            .and_then(|start| self.0.get(start..)?.get(..buf.len()))
+            .ok_or(FormatError::UnexpectedEnd)?;
+        buf.copy_from_slice(data);
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read + std::io::Seek> FormatRead for R {
+    fn read_exact_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FormatError> {
+        use std::io::{ErrorKind, SeekFrom};
+
+        self.seek(SeekFrom::Start(offset))
+            .and_then(|_| self.read_exact(buf))
+            .map_err(|e| match e.kind() {
+                ErrorKind::UnexpectedEof => FormatError::UnexpectedEnd,
+                _ => FormatError::Io(e),
+            })
+    }
+}
@@ -0,0 +1,284 @@
+//! The meta file: a table of the entries followed by appended log records
+
+use super::FormatError;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder};
+
+pub(crate) const HASH_SIZE: usize = 256 / 8;
+/// Marks a meta file with a header. Files without one start with the number of entries
+pub(crate) const MAGIC: [u8; 4] = *b"IFSM";
+/// The version of the meta file format. Entries of version 1 have no length,
+/// entries before version 3 have no values and tables and log records before
+/// version 4 have no checksum
+pub(crate) const FORMAT_VERSION: u16 = 4;
+/// Marks an appended record that adds or replaces an entry
+pub(crate) const LOG_INSERT: u8 = 1;
+/// Marks an appended record that removes an entry
+pub(crate) const LOG_REMOVE: u8 = 2;
+/// Marks an appended record that replaces the values of an entry
+pub(crate) const LOG_VALUES: u8 = 3;
+/// The maximum length of a single value
+pub const MAX_VALUE_LENGTH: usize = u16::MAX as usize;
+/// The number of the last known hash algorithm in the header
+const MAX_ALGORITHM: u8 = 3;
+
+pub type EntryID = [u8; HASH_SIZE];
+/// The data file, the offset of the blob in it and the length of the blob
+pub type MetaEntry = (u32, u64, u64);
+/// Values stored next to an entry by their tag
+pub type Values = BTreeMap<u8, Vec<u8>>;
+/// The length of entries read from files of version 1 which don't record it
+pub const UNKNOWN_LENGTH: u64 = u64::MAX;
+
+/// The entries and values of a meta file with its appended records applied
+#[derive(Clone, Debug, Default)]
+pub struct MetaTable {
+    /// The number of the algorithm ids are hashed with
+    pub algorithm: u8,
+    pub version: u16,
+    pub entries: BTreeMap<EntryID, MetaEntry>,
+    pub values: BTreeMap<EntryID, Values>,
+    /// The number of appended records
+    pub logged: usize,
+    /// If the file ends with an incomplete record or has an older version, so
+    /// that it should be rewritten before records are appended
+    pub rewrite: bool,
+}
+
+impl MetaTable {
+    /// Parses the content of a meta file. Fails with [FormatError::Corrupt] if
+    /// the table has more than the given number of entries
+    pub fn parse(data: &[u8], max_entries: u64) -> Result<Self, FormatError> {
+        let mut reader = Reader { data, position: 0 };
+        let truncated = |e| content_error(e, 0);
+        let start = reader.take(8).map_err(truncated)?;
+        let mut table = MetaTable::default();
+        let table_size = if start[..4] == MAGIC {
+            let version = BigEndian::read_u16(&start[4..6]);
+            if !(1..=FORMAT_VERSION).contains(&version) || start[6] > MAX_ALGORITHM || start[7] != 0
+            {
+                return Err(FormatError::UnsupportedFormat {
+                    version,
+                    flags: BigEndian::read_u16(&start[6..8]),
+                });
+            }
+            table.algorithm = start[6];
+            table.version = version;
+            reader.u64().map_err(|e| content_error(e, 8))?
+        } else {
+            table.version = 1;
+            BigEndian::read_u64(start)
+        };
+        let version = table.version;
+        if table_size > max_entries {
+            return Err(FormatError::corrupt(
+                8,
+                format!("the table has {} entries", table_size),
+            ));
+        }
+        for _ in 0..table_size {
+            let offset = reader.position;
+            let (id, entry) = reader
+                .id()
+                .and_then(|id| Ok((id, reader.entry(version)?)))
+                .map_err(|e| content_error(e, offset))?;
+            table.entries.insert(id, entry);
+            if version >= 3 {
+                let block = reader.block().map_err(|e| content_error(e, offset))?;
+                let values = decode_values(block)
+                    .ok_or_else(|| FormatError::corrupt(offset, "invalid values"))?;
+                set_values(&mut table.values, id, values);
+            }
+        }
+        if version >= 4 {
+            let offset = reader.position;
+            let checksum = crc32fast::hash(&data[..offset as usize]);
+            let stored = reader.u32().map_err(|e| content_error(e, offset))?;
+            if stored != checksum {
+                return Err(FormatError::corrupt(offset, "table checksum mismatch"));
+            }
+        }
+        // a record that was cut off while appending is dropped with the next write
+        // and older versions are upgraded so that new records can be appended
+        table.rewrite = version < FORMAT_VERSION;
+        while reader.position < data.len() as u64 {
+            let offset = reader.position;
+            let record = match reader.record(version) {
+                Ok(record) => record,
+                Err(FormatError::UnexpectedEnd) => {
+                    table.rewrite = true;
+                    break;
+                }
+                Err(e) => return Err(at_offset(e, offset)),
+            };
+            if version >= 4 {
+                let checksum = crc32fast::hash(&data[offset as usize..reader.position as usize]);
+                match reader.u32() {
+                    Ok(stored) if stored == checksum => {}
+                    Ok(_) => {
+                        return Err(FormatError::corrupt(offset, "log record checksum mismatch"))
+                    }
+                    Err(_) => {
+                        table.rewrite = true;
+                        break;
+                    }
+                }
+            }
+            match record {
+                (id, Change::Insert(entry)) => {
+                    table.entries.insert(id, entry);
+                    if table.entries.len() as u64 > max_entries {
+                        return Err(FormatError::corrupt(offset, "too many entries"));
+                    }
+                }
+                (id, Change::Remove) => {
+                    table.entries.remove(&id);
+                    table.values.remove(&id);
+                }
+                (id, Change::Values(block)) => {
+                    let values = decode_values(block)
+                        .ok_or_else(|| FormatError::corrupt(offset, "invalid values"))?;
+                    set_values(&mut table.values, id, values);
+                }
+            }
+            table.logged += 1;
+        }
+
+        Ok(table)
+    }
+}
+
+/// A change that is appended to the file
+enum Change<'a> {
+    Insert(MetaEntry),
+    Remove,
+    /// The encoded values of the entry
+    Values(&'a [u8]),
+}
+
+/// Reads the parts of a meta file from its content
+struct Reader<'a> {
+    data: &'a [u8],
+    position: u64,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], FormatError> {
+        let start = self.position as usize;
+        let taken = self
+            .data
+            .get(start..)
+            .and_then(|rest| rest.get(..length))
+            .ok_or(FormatError::UnexpectedEnd)?;
+        self.position += length as u64;
+
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, FormatError> {
+        Ok(BigEndian::read_u32(self.take(4)?))
+    }
+
+    fn u64(&mut self) -> Result<u64, FormatError> {
+        Ok(BigEndian::read_u64(self.take(8)?))
+    }
+
+    fn id(&mut self) -> Result<EntryID, FormatError> {
+        let mut id = [0u8; HASH_SIZE];
+        id.copy_from_slice(self.take(HASH_SIZE)?);
+
+        Ok(id)
+    }
+
+    fn entry(&mut self, version: u16) -> Result<MetaEntry, FormatError> {
+        let file = self.u32()?;
+        let pointer = self.u64()?;
+        let length = if version == 1 {
+            UNKNOWN_LENGTH
+        } else {
+            self.u64()?
+        };
+
+        Ok((file, pointer, length))
+    }
+
+    /// Reads a length prefixed block
+    fn block(&mut self) -> Result<&'a [u8], FormatError> {
+        let offset = self.position;
+        let length = self.u32()?;
+        // every tag holds at most one value
+        if length as usize > 256 * (3 + MAX_VALUE_LENGTH) {
+            return Err(FormatError::corrupt(
+                offset,
+                format!("values of {} bytes", length),
+            ));
+        }
+
+        self.take(length as usize)
+    }
+
+    /// Reads an appended record without its checksum
+    fn record(&mut self, version: u16) -> Result<(EntryID, Change<'a>), FormatError> {
+        let offset = self.position;
+        let tag = self.take(1)?[0];
+        let id = self.id()?;
+        let change = match tag {
+            LOG_INSERT => Change::Insert(self.entry(version)?),
+            LOG_REMOVE => Change::Remove,
+            LOG_VALUES if version >= 3 => Change::Values(self.block()?),
+            tag => {
+                return Err(FormatError::corrupt(
+                    offset,
+                    format!("unknown log record {}", tag),
+                ))
+            }
+        };
+
+        Ok((id, change))
+    }
+}
+
+/// Decodes a block of values. Returns None if the block is malformed
+pub(crate) fn decode_values(mut block: &[u8]) -> Option<Values> {
+    let mut values = BTreeMap::new();
+    while !block.is_empty() {
+        if block.len() < 3 {
+            return None;
+        }
+        let tag = block[0];
+        let length = u16::from_be_bytes([block[1], block[2]]) as usize;
+        if block.len() < 3 + length {
+            return None;
+        }
+        values.insert(tag, block[3..3 + length].to_vec());
+        block = &block[3 + length..];
+    }
+
+    Some(values)
+}
+
+fn set_values(all: &mut BTreeMap<EntryID, Values>, id: EntryID, values: Values) {
+    if values.is_empty() {
+        all.remove(&id);
+    } else {
+        all.insert(id, values);
+    }
+}
+
+/// Turns a table that ends early into a corruption error at the given offset
+fn content_error(error: FormatError, offset: u64) -> FormatError {
+    match error {
+        FormatError::UnexpectedEnd => FormatError::corrupt(offset, "the table is truncated"),
+        error => at_offset(error, offset),
+    }
+}
+
+/// Reports a corruption inside an entry or record at the offset of its start
+fn at_offset(error: FormatError, offset: u64) -> FormatError {
+    match error {
+        FormatError::Corrupt { reason, .. } => FormatError::Corrupt { offset, reason },
+        error => error,
+    }
+}
@@ -0,0 +1,402 @@
+//! The dir tree file: a header followed by chunks of directory entries
+
+use super::meta::EntryID;
+use super::{FormatError, FormatRead};
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder};
+use core::convert::TryInto;
+
+pub(crate) const DEFAULT_CHUNK_SIZE: u32 = 1024;
+pub(crate) const MIN_CHUNK_SIZE: u32 = 64;
+pub(crate) const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+/// Marks files that start with a versioned header
+pub(crate) const MAGIC: [u8; 4] = *b"IFSV";
+/// The format version written to new files
+pub(crate) const FORMAT_VERSION: u16 = 2;
+/// The size of the header containing the magic, the version, the flags, the
+/// free list and the chunk size. The rest is reserved
+pub(crate) const HEADER_SIZE: u64 = 32;
+/// Marks files with the first header that had no version and flags. Older files
+/// start directly with the root chunk
+pub(crate) const V1_MAGIC: [u8; 4] = *b"IFST";
+/// The size of the header of version 1 files
+pub(crate) const V1_HEADER_SIZE: u64 = 16;
+/// Set in the flags of trees that compare names ignoring case
+pub(crate) const CASE_INSENSITIVE_FLAG: u16 = 0x0001;
+/// Set in the flags of trees that store a checksum after every chunk
+pub(crate) const CHECKSUM_FLAG: u16 = 0x0002;
+/// All flags this version understands. Files with other flags are refused
+pub(crate) const KNOWN_FLAGS: u16 = CASE_INSENSITIVE_FLAG | CHECKSUM_FLAG;
+/// Set in the high byte of the chunk size of version 1 files that compare names
+/// ignoring case
+pub(crate) const V1_CASE_INSENSITIVE_FLAG: u32 = 0x0100_0000;
+
+/// Set in the length field of entries that are followed by an attribute block.
+/// Trees written before attributes existed never have it set
+pub(crate) const EXTENDED_FLAG: u16 = 0x8000;
+/// The bits of the length field that contain the length of the name and pointer
+pub(crate) const LENGTH_MASK: u16 = 0x3FFF;
+/// Attribute containing the target path of a symlink
+pub(crate) const SYMLINK_ATTRIBUTE: u8 = 3;
+/// Attribute containing the id of the content of a file in the meta file
+pub(crate) const BLOB_ID_ATTRIBUTE: u8 = 4;
+
+/// Set in the entry count of chunks whose entries are sorted by name. Chunks
+/// written before sorting existed keep their entries in insertion order
+pub(crate) const SORTED_FLAG: u16 = 0x8000;
+/// Set in the entry count of index chunks
+pub(crate) const INDEX_FLAG: u16 = 0x4000;
+/// The maximum number of entries in a single chunk
+pub(crate) const MAX_CHUNK_ENTRIES: u16 = 0x3FFF;
+/// The size of an offset in the table of a sorted chunk
+pub(crate) const OFFSET_SIZE: usize = 4;
+
+/// The header of a dir tree file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TreeHeader {
+    /// The format version. Files without a header have version 0
+    pub version: u16,
+    /// The location of the first chunk of the root directory
+    pub root: u64,
+    /// The number of bytes available for entries in each chunk
+    pub chunk_size: u32,
+    /// The location of the first free chunk or 0
+    pub free_head: u64,
+    /// If names are compared ignoring case
+    pub case_insensitive: bool,
+    /// If every chunk is followed by a checksum
+    pub checksums: bool,
+}
+
+impl TreeHeader {
+    /// Reads the header at the start of the file. A journal of an unfinished
+    /// transaction isn't applied, so the tree is read as it was before it
+    pub fn read<R: FormatRead + ?Sized>(reader: &mut R) -> Result<Self, FormatError> {
+        let mut start = [0u8; 20];
+        reader.read_exact_at(0, &mut start[..4])?;
+        if start[..4] == MAGIC || start[..4] == V1_MAGIC {
+            reader.read_exact_at(4, &mut start[4..])?;
+        }
+        let header = if start[..4] == MAGIC {
+            let version = BigEndian::read_u16(&start[4..6]);
+            let flags = BigEndian::read_u16(&start[6..8]);
+            if version != FORMAT_VERSION || flags & !KNOWN_FLAGS != 0 {
+                return Err(FormatError::UnsupportedFormat { version, flags });
+            }
+            TreeHeader {
+                version,
+                root: HEADER_SIZE,
+                chunk_size: BigEndian::read_u32(&start[16..20]),
+                free_head: BigEndian::read_u64(&start[8..16]),
+                case_insensitive: flags & CASE_INSENSITIVE_FLAG != 0,
+                checksums: flags & CHECKSUM_FLAG != 0,
+            }
+        } else if start[..4] == V1_MAGIC {
+            let chunk_size = BigEndian::read_u32(&start[4..8]);
+            TreeHeader {
+                version: 1,
+                root: V1_HEADER_SIZE,
+                chunk_size: chunk_size & !V1_CASE_INSENSITIVE_FLAG,
+                free_head: BigEndian::read_u64(&start[8..16]),
+                case_insensitive: chunk_size & V1_CASE_INSENSITIVE_FLAG != 0,
+                checksums: false,
+            }
+        } else if BigEndian::read_u32(&start[..4]) == DEFAULT_CHUNK_SIZE {
+            // files without a header start with the root chunk
+            TreeHeader {
+                version: 0,
+                root: 0,
+                chunk_size: DEFAULT_CHUNK_SIZE,
+                free_head: 0,
+                case_insensitive: false,
+                checksums: false,
+            }
+        } else {
+            return Err(FormatError::corrupt(0, "not a dir tree file"));
+        };
+        if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&header.chunk_size) {
+            return Err(FormatError::corrupt(4, "invalid chunk size"));
+        }
+
+        Ok(header)
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DirEntry {
+    pub name: String,
+    pub(crate) child_pointer: u64,
+    /// Tagged attributes stored after the pointer. Unknown tags are kept as they are
+    pub(crate) attributes: Vec<(u8, Vec<u8>)>,
+}
+
+impl DirEntry {
+    pub fn new(name: String, child_pointer: u64) -> Self {
+        Self {
+            name,
+            child_pointer,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Parses the entry at the offset of the data and returns it with the offset
+    /// after it. Corruption is reported at the offset of the entry
+    pub fn parse(data: &[u8], offset: usize) -> Result<(Self, usize), FormatError> {
+        let corrupt = |reason| FormatError::corrupt(offset as u64, reason);
+        let mut reader = data.get(offset..).ok_or(FormatError::UnexpectedEnd)?;
+        let raw_length = BigEndian::read_u16(take(&mut reader, 2)?);
+        let length = raw_length & LENGTH_MASK;
+        if length < 8 {
+            return Err(corrupt("entry length is too short"));
+        }
+        let name = take(&mut reader, (length - 8) as usize)?;
+        let name = String::from_utf8(name.to_vec())
+            .map_err(|_| corrupt("entry name is not valid utf-8"))?;
+        let child_pointer = BigEndian::read_u64(take(&mut reader, 8)?);
+        let mut attributes = Vec::new();
+
+        if raw_length & EXTENDED_FLAG != 0 {
+            let block_length = BigEndian::read_u16(take(&mut reader, 2)?) as usize;
+            let mut block = take(&mut reader, block_length)?;
+
+            while !block.is_empty() {
+                let header = take(&mut block, 3).map_err(|_| corrupt("truncated attribute"))?;
+                let length = BigEndian::read_u16(&header[1..]) as usize;
+                let value =
+                    take(&mut block, length).map_err(|_| corrupt("attribute exceeds the entry"))?;
+                attributes.push((header[0], value.to_vec()));
+            }
+        }
+        let end = data.len() - reader.len();
+
+        Ok((
+            Self {
+                name,
+                child_pointer,
+                attributes,
+            },
+            end,
+        ))
+    }
+
+    /// Returns the required size for the entry
+    pub fn size(&self) -> usize {
+        if self.attributes.is_empty() {
+            self.name.len() + 10
+        } else {
+            self.name.len() + 12 + self.attributes_size()
+        }
+    }
+
+    pub(crate) fn attributes_size(&self) -> usize {
+        self.attributes.iter().map(|(_, data)| data.len() + 3).sum()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.child_pointer != 0
+    }
+
+    /// Returns the location of the first chunk of the directory or 0 for files
+    pub fn child_pointer(&self) -> u64 {
+        self.child_pointer
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.symlink_target().is_some()
+    }
+
+    /// Returns the path the entry points to if it is a symlink
+    pub fn symlink_target(&self) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(tag, _)| *tag == SYMLINK_ATTRIBUTE)
+            .and_then(|(_, data)| core::str::from_utf8(data).ok())
+    }
+
+    /// Returns the id of the content of the file in the meta file if it's
+    /// different from the hash of its path
+    pub fn blob_id(&self) -> Option<EntryID> {
+        self.attributes
+            .iter()
+            .find(|(tag, _)| *tag == BLOB_ID_ATTRIBUTE)
+            .and_then(|(_, data)| data.as_slice().try_into().ok())
+    }
+}
+
+/// The header of a chunk of directory entries
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkHeader {
+    pub location: u64,
+    /// The length of the payload
+    pub length: u32,
+    /// The number of entries, or buckets of an index
+    pub entries: u16,
+    /// If the entries are sorted by name with an offset table at the end of the payload
+    pub sorted: bool,
+    /// If the chunk is the hash index of a large directory
+    pub index: bool,
+    /// The location of the chunk that continues the directory or 0
+    pub next: u64,
+}
+
+impl ChunkHeader {
+    /// Reads the header and the pointer to the next chunk of the chunk at the location
+    pub fn read<R: FormatRead + ?Sized>(
+        reader: &mut R,
+        location: u64,
+    ) -> Result<Self, FormatError> {
+        let mut header = [0u8; 6];
+        reader.read_exact_at(location, &mut header)?;
+        let length = BigEndian::read_u32(&header[..4]);
+        let entries = BigEndian::read_u16(&header[4..]);
+        let mut next = [0u8; 8];
+        reader.read_exact_at(location + 6 + length as u64, &mut next)?;
+
+        Ok(Self {
+            location,
+            length,
+            entries: entries & MAX_CHUNK_ENTRIES,
+            sorted: entries & SORTED_FLAG != 0,
+            index: entries & INDEX_FLAG != 0,
+            next: BigEndian::read_u64(&next),
+        })
+    }
+
+    /// Reads the payload of the chunk without verifying a checksum
+    pub fn payload<R: FormatRead + ?Sized>(&self, reader: &mut R) -> Result<Vec<u8>, FormatError> {
+        let mut payload = vec![0u8; self.length as usize];
+        reader.read_exact_at(self.location + 6, &mut payload)?;
+
+        Ok(payload)
+    }
+
+    /// Returns the entries of the chunk. Index chunks have none
+    pub fn entries<R: FormatRead + ?Sized>(
+        &self,
+        reader: &mut R,
+    ) -> Result<Vec<DirEntry>, FormatError> {
+        if self.index {
+            return Ok(Vec::new());
+        }
+
+        self.parse_entries(&self.payload(reader)?)
+    }
+
+    /// Returns the chunks that continue the directory which are the next chunk
+    /// for plain chunks and the non empty buckets for index chunks
+    pub fn links<R: FormatRead + ?Sized>(&self, reader: &mut R) -> Result<Vec<u64>, FormatError> {
+        if self.index {
+            Ok(self
+                .parse_buckets(&self.payload(reader)?)?
+                .into_iter()
+                .filter(|b| *b != 0)
+                .collect())
+        } else if self.next != 0 {
+            Ok(vec![self.next])
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    /// Parses the entries of the payload of a plain or sorted chunk
+    pub(crate) fn parse_entries(&self, payload: &[u8]) -> Result<Vec<DirEntry>, FormatError> {
+        let mut entries = Vec::with_capacity(self.entries as usize);
+        if self.sorted {
+            for i in 0..self.entries as usize {
+                entries.push(self.entry_at(payload, self.table_offset(payload, i)?)?);
+            }
+        } else {
+            let mut offset = 0;
+            for _ in 0..self.entries {
+                let entry = self.entry_at(payload, offset)?;
+                offset += entry.size();
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Parses the pointers to the buckets of the payload of an index chunk. Empty
+    /// buckets are 0
+    pub(crate) fn parse_buckets(&self, payload: &[u8]) -> Result<Vec<u64>, FormatError> {
+        if self.entries as usize * 8 > self.length as usize || self.entries == 0 {
+            return Err(FormatError::corrupt(
+                self.location,
+                "buckets exceed the chunk",
+            ));
+        }
+
+        Ok(payload
+            .chunks_exact(8)
+            .take(self.entries as usize)
+            .map(BigEndian::read_u64)
+            .collect())
+    }
+
+    /// Returns the offset of the i-th entry from the table of a sorted chunk
+    pub(crate) fn table_offset(&self, payload: &[u8], index: usize) -> Result<usize, FormatError> {
+        let table_start = payload
+            .len()
+            .checked_sub(self.entries as usize * OFFSET_SIZE)
+            .ok_or_else(|| FormatError::corrupt(self.location, "offset table exceeds the chunk"))?;
+        let position = table_start + index * OFFSET_SIZE;
+
+        Ok(BigEndian::read_u32(&payload[position..position + OFFSET_SIZE]) as usize)
+    }
+
+    /// Parses the entry at the given offset of the payload
+    pub(crate) fn entry_at(&self, payload: &[u8], offset: usize) -> Result<DirEntry, FormatError> {
+        match DirEntry::parse(payload, offset) {
+            Ok((entry, _)) => Ok(entry),
+            Err(FormatError::Corrupt { offset, reason }) => Err(FormatError::Corrupt {
+                offset: self.location + 6 + offset,
+                reason,
+            }),
+            Err(FormatError::UnexpectedEnd) => Err(FormatError::corrupt(
+                self.location,
+                "entries exceed the chunk",
+            )),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Returns the entries of the directory whose first chunk is at the location,
+/// e.g. [TreeHeader::root] or [DirEntry::child_pointer]
+pub fn read_dir<R: FormatRead + ?Sized>(
+    reader: &mut R,
+    location: u64,
+) -> Result<Vec<DirEntry>, FormatError> {
+    let mut entries = Vec::new();
+    let mut visited = BTreeSet::new();
+    let mut stack = vec![location];
+
+    while let Some(location) = stack.pop() {
+        if !visited.insert(location) {
+            return Err(FormatError::corrupt(location, "the chunks form a cycle"));
+        }
+        let chunk = ChunkHeader::read(reader, location)?;
+        entries.append(&mut chunk.entries(reader)?);
+        let mut links = chunk.links(reader)?;
+        links.reverse();
+        stack.append(&mut links);
+    }
+
+    Ok(entries)
+}
+
+/// Splits the first bytes off the data
+fn take<'a>(data: &mut &'a [u8], length: usize) -> Result<&'a [u8], FormatError> {
+    if data.len() < length {
+        return Err(FormatError::UnexpectedEnd);
+    }
+    let (taken, rest) = data.split_at(length);
+    *data = rest;
+
+    Ok(taken)
+}
@@ -0,0 +1,455 @@
+use crate::error::{Error, Result};
+use crate::storage::{BlobMeta, Storage};
+use crate::utils::join_path;
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyWrite, ReplyXattr, Request, TimeOrNow, FUSE_ROOT_ID,
+};
+use libc::{
+    E2BIG, EAGAIN, EDQUOT, EEXIST, EINVAL, EIO, EISDIR, ELOOP, ENAMETOOLONG, ENODATA, ENOENT,
+    ENOTDIR, ENOTEMPTY, ERANGE, EROFS,
+};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+const TTL: Duration = Duration::from_secs(1);
+const BLOCK_SIZE: u32 = 512;
+/// The extended attribute exposing the content type of a file
+const CONTENT_TYPE_XATTR: &str = "user.content_type";
+/// The extended attribute exposing the source name of a file
+const SOURCE_NAME_XATTR: &str = "user.source_name";
+
+/// Mounts the storage at the given mountpoint and blocks until it is unmounted
+pub fn mount<P: AsRef<Path>>(storage: Storage, mountpoint: P) -> io::Result<()> {
+    let options = [
+        MountOption::FSName("indexed-file-storage".to_string()),
+        MountOption::DefaultPermissions,
+    ];
+
+    fuser::mount2(StorageFs::new(storage), mountpoint, &options)
+}
+
+/// Exposes a storage as a fuse filesystem by assigning inode numbers to paths
+pub struct StorageFs {
+    storage: Storage,
+    paths: Vec<String>,
+    inodes: HashMap<String, u64>,
+}
+
+impl StorageFs {
+    pub fn new(storage: Storage) -> Self {
+        let mut inodes = HashMap::new();
+        inodes.insert("/".to_string(), FUSE_ROOT_ID);
+
+        Self {
+            storage,
+            paths: vec!["/".to_string()],
+            inodes,
+        }
+    }
+
+    /// Returns the inode for a path and assigns a new one if it doesn't have one yet
+    fn inode(&mut self, path: &str) -> u64 {
+        if let Some(ino) = self.inodes.get(path) {
+            return *ino;
+        }
+        self.paths.push(path.to_string());
+        let ino = self.paths.len() as u64;
+        self.inodes.insert(path.to_string(), ino);
+
+        ino
+    }
+
+    fn path(&self, ino: u64) -> Option<String> {
+        self.paths.get(ino.checked_sub(1)? as usize).cloned()
+    }
+
+    fn child_path(&self, parent: u64, name: &OsStr) -> Option<String> {
+        Some(join_path(&self.path(parent)?, name.to_str()?))
+    }
+
+    /// Returns the attributes of the entry at the given path
+    fn attr(&mut self, path: &str) -> Result<FileAttr> {
+        let now = SystemTime::now();
+        let (kind, size, created, modified) = if path == "/" {
+            (FileType::Directory, 0, now, now)
+        } else {
+            let entry = self.storage.entry(path)?;
+            let (created, modified) = entry
+                .metadata()
+                .map_or((now, now), |m| (m.created, m.modified));
+            if entry.is_dir() {
+                (FileType::Directory, 0, created, modified)
+            } else if let Some(target) = entry.symlink_target() {
+                (FileType::Symlink, target.len() as u64, created, modified)
+            } else {
+                let size = match entry.metadata() {
+                    Some(metadata) => metadata.size,
+                    None => self.storage.get(path)?.remaining(),
+                };
+                (FileType::RegularFile, size, created, modified)
+            }
+        };
+
+        Ok(FileAttr {
+            ino: self.inode(path),
+            size,
+            blocks: size.div_ceil(BLOCK_SIZE as u64),
+            atime: modified,
+            mtime: modified,
+            ctime: modified,
+            crtime: created,
+            kind,
+            perm: if kind == FileType::Directory {
+                0o755
+            } else {
+                0o644
+            },
+            nlink: 1,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            rdev: 0,
+            blksize: BLOCK_SIZE,
+            flags: 0,
+        })
+    }
+
+    /// Reads the whole content of a file
+    fn read_all(&self, path: &str) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.storage.get(path)?.read_to_end(&mut data)?;
+
+        Ok(data)
+    }
+}
+
+/// Replies with the size of the value if the caller asks for it with a size
+/// of 0 or with the value if it fits
+fn reply_xattr(value: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+fn errno(error: &Error) -> i32 {
+    match error {
+        Error::NotFound { .. } => ENOENT,
+        Error::AlreadyExists { .. } => EEXIST,
+        Error::NotADirectory { .. } => ENOTDIR,
+        Error::IsADirectory { .. } => EISDIR,
+        Error::DirectoryNotEmpty { .. } => ENOTEMPTY,
+        Error::InvalidName { .. } => EINVAL,
+        Error::NameTooLong { .. } => ENAMETOOLONG,
+        Error::EntryTooLarge { .. } => E2BIG,
+        Error::SymlinkLoop { .. } => ELOOP,
+        Error::Locked { .. } => EAGAIN,
+        Error::ReadOnly { .. } => EROFS,
+        Error::QuotaExceeded { .. } => EDQUOT,
+        _ => EIO,
+    }
+}
+
+impl Filesystem for StorageFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let path = match self.child_path(parent, name) {
+            Some(path) => path,
+            None => return reply.error(ENOENT),
+        };
+        match self.attr(&path) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        let path = match self.path(ino) {
+            Some(path) => path,
+            None => return reply.error(ENOENT),
+        };
+        match self.attr(&path) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let path = match self.path(ino) {
+            Some(path) => path,
+            None => return reply.error(ENOENT),
+        };
+        let result = (|| {
+            if let Some(size) = size {
+                let mut data = self.read_all(&path)?;
+                data.resize(size as usize, 0);
+                self.storage.store(&path, &data[..])?;
+            }
+            if mtime.is_some() || crtime.is_some() {
+                let blob_meta = self.storage.blob_meta(&path)?;
+                self.storage.set_blob_meta(
+                    &path,
+                    &BlobMeta {
+                        created: crtime,
+                        modified: mtime.map(|time| match time {
+                            TimeOrNow::SpecificTime(time) => time,
+                            TimeOrNow::Now => SystemTime::now(),
+                        }),
+                        ..blob_meta
+                    },
+                )?;
+            }
+            self.attr(&path)
+        })();
+        match result {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let path = match self.path(ino) {
+            Some(path) => path,
+            None => return reply.error(ENOENT),
+        };
+        let blob_meta = match self.storage.blob_meta(&path) {
+            Ok(blob_meta) => blob_meta,
+            Err(e) => return reply.error(errno(&e)),
+        };
+        let value = match name.to_str() {
+            Some(CONTENT_TYPE_XATTR) => blob_meta.content_type,
+            Some(SOURCE_NAME_XATTR) => blob_meta.source_name,
+            _ => None,
+        };
+        match value {
+            Some(value) => reply_xattr(value.as_bytes(), size, reply),
+            None => reply.error(ENODATA),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let path = match self.path(ino) {
+            Some(path) => path,
+            None => return reply.error(ENOENT),
+        };
+        let blob_meta = match self.storage.blob_meta(&path) {
+            Ok(blob_meta) => blob_meta,
+            Err(Error::IsADirectory { .. }) => BlobMeta::default(),
+            Err(e) => return reply.error(errno(&e)),
+        };
+        let mut names = Vec::new();
+        if blob_meta.content_type.is_some() {
+            names.extend_from_slice(CONTENT_TYPE_XATTR.as_bytes());
+            names.push(0);
+        }
+        if blob_meta.source_name.is_some() {
+            names.extend_from_slice(SOURCE_NAME_XATTR.as_bytes());
+            names.push(0);
+        }
+        reply_xattr(&names, size, reply)
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let path = match self.child_path(parent, name) {
+            Some(path) => path,
+            None => return reply.error(ENOENT),
+        };
+        match self
+            .storage
+            .create_dir(&path)
+            .and_then(|_| self.attr(&path))
+        {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let path = match self.child_path(parent, name) {
+            Some(path) => path,
+            None => return reply.error(ENOENT),
+        };
+        match self.storage.delete(&path) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.unlink(req, parent, name, reply)
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let path = match self.child_path(parent, name) {
+            Some(path) => path,
+            None => return reply.error(ENOENT),
+        };
+        match self
+            .storage
+            .store(&path, io::empty())
+            .and_then(|_| self.attr(&path))
+        {
+            Ok(attr) => reply.created(&TTL, &attr, 0, 0, 0),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let path = match self.path(ino) {
+            Some(path) => path,
+            None => return reply.error(ENOENT),
+        };
+        match self.storage.entry(&path) {
+            Ok(entry) => match entry.symlink_target() {
+                Some(target) => reply.data(target.as_bytes()),
+                None => reply.error(EINVAL),
+            },
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let path = match self.path(ino) {
+            Some(path) => path,
+            None => return reply.error(ENOENT),
+        };
+        let result = (|| {
+            let mut reader = self.storage.get(&path)?;
+            io::copy(&mut (&mut reader).take(offset as u64), &mut io::sink())?;
+            let mut data = Vec::with_capacity(size as usize);
+            reader.take(size as u64).read_to_end(&mut data)?;
+
+            Ok(data)
+        })();
+        match result {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let path = match self.path(ino) {
+            Some(path) => path,
+            None => return reply.error(ENOENT),
+        };
+        // blobs are immutable in the data files so a write rewrites the whole file
+        let result = (|| {
+            let mut content = self.read_all(&path)?;
+            let end = offset as usize + data.len();
+            if content.len() < end {
+                content.resize(end, 0);
+            }
+            content[offset as usize..end].copy_from_slice(data);
+            self.storage.store(&path, &content[..])
+        })();
+        match result {
+            Ok(_) => reply.written(data.len() as u32),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let path = match self.path(ino) {
+            Some(path) => path,
+            None => return reply.error(ENOENT),
+        };
+        let entries = match self.storage.read_dir(&path) {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(errno(&e)),
+        };
+        let mut listing = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (ino, FileType::Directory, "..".to_string()),
+        ];
+        for entry in entries {
+            let kind = if entry.is_dir() {
+                FileType::Directory
+            } else if entry.is_symlink() {
+                FileType::Symlink
+            } else {
+                FileType::RegularFile
+            };
+            listing.push((self.inode(&join_path(&path, &entry.name)), kind, entry.name));
+        }
+        for (i, (ino, kind, name)) in listing.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+}
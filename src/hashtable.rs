@@ -0,0 +1,513 @@
+use crate::backend::Backend;
+use crate::bloom::BloomFilter;
+use crate::error::{Error, Result};
+use crate::metafile::{EntryID, HashAlgorithm, IndexedMetaFile, MetaEntry};
+#[cfg(feature = "mmap")]
+use crate::mmap::Mapping;
+use crate::trace::{event, span};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// Marks a file as a hash table file
+const MAGIC: [u8; 4] = *b"IFSH";
+/// The version of the hash table format. Slots of version 1 have no blob length
+const VERSION: u16 = 2;
+/// magic, version, hash algorithm, reserved, capacity, count, used slots
+const HEADER_SIZE: u64 = 32;
+/// state, id, data file, data pointer, blob length
+const SLOT_SIZE: u64 = 1 + 32 + 4 + 8 + 8;
+/// The number of slots a new table starts with
+const MIN_CAPACITY: u64 = 64;
+/// The number of slots that are copied at once while the table grows
+const COPY_SLOTS: u64 = 64;
+
+const EMPTY: u8 = 0;
+const USED: u8 = 1;
+/// A removed entry that still continues probe sequences
+const REMOVED: u8 = 2;
+
+/// A meta file stored as an open addressing hash table so that lookups
+/// seek directly to the entry instead of loading all entries into memory.
+/// Reference counts aren't tracked as they would need all entries
+pub struct HashTableFile<B: Backend = File> {
+    backend: B,
+    /// The number of slots. Always a power of two
+    capacity: u64,
+    /// The number of entries
+    count: u64,
+    /// The number of slots that are used or removed
+    used: u64,
+    algorithm: HashAlgorithm,
+    /// Built on load so that missing ids are answered without reading slots
+    bloom: BloomFilter,
+}
+
+/// The result of probing for an id
+struct Probe {
+    /// The slot holding the id
+    found: Option<(u64, MetaEntry)>,
+    /// The first slot a new entry can be written to
+    free: Option<u64>,
+}
+
+impl HashTableFile<File> {
+    /// Opens the hash table file at the given path and creates it if it doesn't exist
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        Self::from_backend(file).map_err(|e| e.in_file(&path))
+    }
+}
+
+impl<B: Backend> HashTableFile<B> {
+    /// Creates a hash table stored in the given backend. An empty backend
+    /// gets initialized with an empty table
+    pub fn from_backend(backend: B) -> Result<Self> {
+        Self::from_backend_with_algorithm(backend, HashAlgorithm::default())
+    }
+
+    /// Creates a hash table like [HashTableFile::from_backend]. The algorithm is
+    /// used if the backend is empty, otherwise the one in the header is used
+    pub fn from_backend_with_algorithm(mut backend: B, algorithm: HashAlgorithm) -> Result<Self> {
+        if backend.size()? == 0 {
+            let mut table = Self {
+                backend,
+                capacity: MIN_CAPACITY,
+                count: 0,
+                used: 0,
+                algorithm,
+                bloom: BloomFilter::with_capacity(MIN_CAPACITY),
+            };
+            table
+                .backend
+                .set_len(HEADER_SIZE + MIN_CAPACITY * SLOT_SIZE)?;
+            table.write_header()?;
+
+            return Ok(table);
+        }
+        backend.seek(SeekFrom::Start(0))?;
+        let (algorithm, capacity, count, used) = read_header(&mut backend)?;
+        check_size(capacity, backend.size()?)?;
+
+        let mut table = Self {
+            backend,
+            capacity,
+            count,
+            used,
+            algorithm,
+            bloom: BloomFilter::with_capacity(capacity),
+        };
+        table.for_each_used(HEADER_SIZE, capacity, |table, id, _| {
+            table.bloom.insert(id);
+            Ok(())
+        })?;
+
+        Ok(table)
+    }
+
+    /// Creates a hash table in the given backend with the entries of a meta file
+    pub fn from_meta_file(backend: B, meta: &IndexedMetaFile) -> Result<Self> {
+        let mut table = Self::from_backend_with_algorithm(backend, meta.algorithm())?;
+        for (id, entry) in meta.iter() {
+            table.insert(id, *entry)?;
+        }
+
+        Ok(table)
+    }
+
+    pub fn into_inner(self) -> B {
+        self.backend
+    }
+
+    /// Returns the algorithm ids are hashed with
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Returns the number of entries
+    pub fn len(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns if the table has no entries
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns false if the table certainly doesn't contain the id. This doesn't read the table
+    pub fn may_contain<K: AsRef<[u8]> + ?Sized>(&self, id: &K) -> bool {
+        self.bloom.may_contain(&self.algorithm.hash_id(id))
+    }
+
+    /// Returns an entry by id
+    pub fn get_entry<K: AsRef<[u8]> + ?Sized>(&mut self, id: &K) -> Result<Option<MetaEntry>> {
+        self.get_entry_raw(&self.algorithm.hash_id(id))
+    }
+
+    /// Returns an entry by its hashed id as recorded in the tree entries
+    pub fn get_entry_raw(&mut self, id: &EntryID) -> Result<Option<MetaEntry>> {
+        if !self.bloom.may_contain(id) {
+            return Ok(None);
+        }
+        let probe = self.probe(HEADER_SIZE, self.capacity, id)?;
+
+        Ok(probe.found.map(|(_, entry)| entry))
+    }
+
+    /// Adds a file entry and returns the entry it replaced
+    pub fn add_entry<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        id: &K,
+        entry: MetaEntry,
+    ) -> Result<Option<MetaEntry>> {
+        self.add_entry_raw(self.algorithm.hash_id(id), entry)
+    }
+
+    /// Adds an entry by an id that is already hashed and returns the entry it replaced
+    pub fn add_entry_raw(&mut self, id: EntryID, entry: MetaEntry) -> Result<Option<MetaEntry>> {
+        self.insert(&id, entry)
+    }
+
+    /// Removes an entry from the table and returns it
+    pub fn remove_entry<K: AsRef<[u8]> + ?Sized>(&mut self, id: &K) -> Result<Option<MetaEntry>> {
+        self.remove_entry_raw(&self.algorithm.hash_id(id))
+    }
+
+    /// Removes an entry by its hashed id
+    pub fn remove_entry_raw(&mut self, id: &EntryID) -> Result<Option<MetaEntry>> {
+        if !self.bloom.may_contain(id) {
+            return Ok(None);
+        }
+        let probe = self.probe(HEADER_SIZE, self.capacity, id)?;
+        let (slot, entry) = match probe.found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        self.backend
+            .seek(SeekFrom::Start(HEADER_SIZE + slot * SLOT_SIZE))?;
+        self.backend.write_u8(REMOVED)?;
+        self.count -= 1;
+        self.write_header()?;
+
+        Ok(Some(entry))
+    }
+
+    fn insert(&mut self, id: &EntryID, entry: MetaEntry) -> Result<Option<MetaEntry>> {
+        let probe = self.probe(HEADER_SIZE, self.capacity, id)?;
+        if let Some((slot, previous)) = probe.found {
+            write_slot(&mut self.backend, HEADER_SIZE, slot, id, entry)?;
+            self.backend.flush()?;
+            return Ok(Some(previous));
+        }
+        // at most three quarters of the slots are used so that probing stays short
+        if (self.used + 1) * 4 > self.capacity * 3 {
+            self.rebuild()?;
+            return self.insert(id, entry);
+        }
+        let slot = probe
+            .free
+            .ok_or_else(|| Error::corrupt(HEADER_SIZE, "the table is full"))?;
+        if read_state(&mut self.backend, HEADER_SIZE, slot)? == EMPTY {
+            self.used += 1;
+        }
+        write_slot(&mut self.backend, HEADER_SIZE, slot, id, entry)?;
+        self.bloom.insert(id);
+        self.count += 1;
+        self.write_header()?;
+
+        Ok(None)
+    }
+
+    /// Looks up the slot of an id in the table starting at the given offset
+    fn probe(&mut self, table: u64, capacity: u64, id: &EntryID) -> Result<Probe> {
+        let mut probe = Probe {
+            found: None,
+            free: None,
+        };
+        let mut slot = first_slot(id, capacity);
+        for _ in 0..capacity {
+            let offset = table + slot * SLOT_SIZE;
+            self.backend.seek(SeekFrom::Start(offset))?;
+            let mut data = [0u8; SLOT_SIZE as usize];
+            self.backend.read_exact(&mut data)?;
+            match data[0] {
+                EMPTY => {
+                    probe.free = probe.free.or(Some(slot));
+                    return Ok(probe);
+                }
+                REMOVED => probe.free = probe.free.or(Some(slot)),
+                USED if data[1..33] == id[..] => {
+                    probe.found = Some((slot, slot_entry(&data)?));
+                    return Ok(probe);
+                }
+                USED => {}
+                state => {
+                    return Err(Error::corrupt(
+                        offset,
+                        format!("invalid slot state {}", state),
+                    ))
+                }
+            }
+            slot = (slot + 1) & (capacity - 1);
+        }
+
+        Ok(probe)
+    }
+
+    /// Rewrites the table without removed slots doubling its capacity if it's
+    /// more than half full. The new table is built after the old one and then
+    /// moved to the start so that only a few slots are kept in memory
+    fn rebuild(&mut self) -> Result<()> {
+        let _span = span!("hash table rebuild");
+        let capacity = if (self.count + 1) * 2 > self.capacity {
+            self.capacity * 2
+        } else {
+            self.capacity
+        };
+        event!(Debug, "rebuilding the hash table with {} slots", capacity);
+        let old_size = self.capacity * SLOT_SIZE;
+        let table = HEADER_SIZE + old_size;
+        self.backend.set_len(table)?;
+        self.backend.set_len(table + capacity * SLOT_SIZE)?;
+
+        // removed ids are dropped from the filter as well
+        self.bloom = BloomFilter::with_capacity(capacity);
+        self.for_each_used(HEADER_SIZE, self.capacity, |this, id, entry| {
+            let free = this.probe(table, capacity, id)?.free;
+            let free = free.ok_or_else(|| Error::corrupt(table, "the table is full"))?;
+            write_slot(&mut this.backend, table, free, id, entry)?;
+            this.bloom.insert(id);
+            Ok(())
+        })?;
+
+        // moving down in blocks no larger than the old table never overwrites unread data
+        let size = capacity * SLOT_SIZE;
+        let block = (COPY_SLOTS * SLOT_SIZE).min(old_size);
+        let mut moved = 0;
+        while moved < size {
+            let length = block.min(size - moved);
+            let mut data = vec![0u8; length as usize];
+            self.backend.seek(SeekFrom::Start(table + moved))?;
+            self.backend.read_exact(&mut data)?;
+            self.backend.seek(SeekFrom::Start(HEADER_SIZE + moved))?;
+            self.backend.write_all(&data)?;
+            moved += length;
+        }
+        self.backend.set_len(HEADER_SIZE + size)?;
+        self.capacity = capacity;
+        self.used = self.count;
+        self.write_header()
+    }
+
+    /// Calls the function with every used slot of the table at the given offset
+    /// reading a few slots at a time
+    fn for_each_used<F>(&mut self, table: u64, capacity: u64, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut Self, &EntryID, MetaEntry) -> Result<()>,
+    {
+        let mut slot = 0;
+        while slot < capacity {
+            let slots = COPY_SLOTS.min(capacity - slot);
+            let mut data = vec![0u8; (slots * SLOT_SIZE) as usize];
+            self.backend
+                .seek(SeekFrom::Start(table + slot * SLOT_SIZE))?;
+            self.backend.read_exact(&mut data)?;
+            for record in data.chunks(SLOT_SIZE as usize) {
+                if record[0] != USED {
+                    continue;
+                }
+                let mut id = [0u8; 32];
+                id.copy_from_slice(&record[1..33]);
+                f(self, &id, slot_entry(record)?)?;
+            }
+            slot += slots;
+        }
+
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        self.backend.seek(SeekFrom::Start(0))?;
+        self.backend.write_all(&MAGIC)?;
+        self.backend.write_u16::<BigEndian>(VERSION)?;
+        self.backend.write_u8(self.algorithm.to_u8())?;
+        self.backend.write_u8(0)?;
+        self.backend.write_u64::<BigEndian>(self.capacity)?;
+        self.backend.write_u64::<BigEndian>(self.count)?;
+        self.backend.write_u64::<BigEndian>(self.used)?;
+        self.backend.flush()?;
+
+        Ok(())
+    }
+}
+
+/// A read only view of a hash table file that resolves lookups against a
+/// memory mapping of the file instead of reading slots with system calls.
+/// The file must not be changed while it's mapped
+#[cfg(feature = "mmap")]
+pub struct MappedHashTableFile {
+    mapping: Mapping,
+    capacity: u64,
+    count: u64,
+    algorithm: HashAlgorithm,
+}
+
+#[cfg(feature = "mmap")]
+impl MappedHashTableFile {
+    /// Maps the hash table file at the given path
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let file = File::open(&path)?;
+        Self::from_file(&file).map_err(|e| e.in_file(&path))
+    }
+
+    /// Maps an open hash table file
+    pub fn from_file(file: &File) -> Result<Self> {
+        let mapping = Mapping::new(file)?;
+        let data = mapping.as_slice();
+        let (algorithm, capacity, count, _) =
+            read_header(&mut data.get(..HEADER_SIZE as usize).unwrap_or(data))?;
+        check_size(capacity, data.len() as u64)?;
+
+        Ok(Self {
+            mapping,
+            capacity,
+            count,
+            algorithm,
+        })
+    }
+
+    /// Returns the algorithm ids are hashed with
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Returns the number of entries
+    pub fn len(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns if the table has no entries
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns an entry by id
+    pub fn get_entry<K: AsRef<[u8]> + ?Sized>(&self, id: &K) -> Result<Option<MetaEntry>> {
+        self.get_entry_raw(&self.algorithm.hash_id(id))
+    }
+
+    /// Returns an entry by its hashed id as recorded in the tree entries
+    pub fn get_entry_raw(&self, id: &EntryID) -> Result<Option<MetaEntry>> {
+        let slots = &self.mapping.as_slice()[HEADER_SIZE as usize..];
+        let mut slot = first_slot(id, self.capacity);
+        for _ in 0..self.capacity {
+            let start = (slot * SLOT_SIZE) as usize;
+            let data = &slots[start..start + SLOT_SIZE as usize];
+            match data[0] {
+                EMPTY => return Ok(None),
+                USED if data[1..33] == id[..] => return slot_entry(data).map(Some),
+                USED | REMOVED => {}
+                state => {
+                    return Err(Error::corrupt(
+                        HEADER_SIZE + start as u64,
+                        format!("invalid slot state {}", state),
+                    ))
+                }
+            }
+            slot = (slot + 1) & (self.capacity - 1);
+        }
+
+        Ok(None)
+    }
+}
+
+/// Reads and validates the header returning the algorithm, capacity, count and used slots
+fn read_header<R: Read>(reader: &mut R) -> Result<(HashAlgorithm, u64, u64, u64)> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(Error::corrupt(0, "not a hash table file"));
+    }
+    let version = reader.read_u16::<BigEndian>()?;
+    let flags = reader.read_u16::<BigEndian>()?;
+    let algorithm = match HashAlgorithm::from_u8((flags >> 8) as u8) {
+        Some(algorithm) if version == VERSION && flags & 0xFF == 0 => algorithm,
+        _ => {
+            return Err(Error::UnsupportedFormat {
+                file: PathBuf::new(),
+                version,
+                flags,
+            })
+        }
+    };
+    let capacity = reader.read_u64::<BigEndian>()?;
+    let count = reader.read_u64::<BigEndian>()?;
+    let used = reader.read_u64::<BigEndian>()?;
+    if !capacity.is_power_of_two() || count > used || used > capacity {
+        return Err(Error::corrupt(8, "invalid table size"));
+    }
+
+    Ok((algorithm, capacity, count, used))
+}
+
+/// Checks that the file holds exactly the slots of the table
+fn check_size(capacity: u64, size: u64) -> Result<()> {
+    let expected = capacity
+        .checked_mul(SLOT_SIZE)
+        .and_then(|s| s.checked_add(HEADER_SIZE));
+    if expected != Some(size) {
+        return Err(Error::corrupt(8, "the table doesn't match the file size"));
+    }
+
+    Ok(())
+}
+
+/// Returns the slot probing starts at. The ids are hashes already so their
+/// first bytes are spread evenly
+fn first_slot(id: &EntryID, capacity: u64) -> u64 {
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&id[..8]);
+
+    u64::from_be_bytes(prefix) & (capacity - 1)
+}
+
+/// Returns the entry of a used slot
+fn slot_entry(data: &[u8]) -> Result<MetaEntry> {
+    let mut reader = &data[33..];
+    let file = reader.read_u32::<BigEndian>()?;
+    let pointer = reader.read_u64::<BigEndian>()?;
+    let length = reader.read_u64::<BigEndian>()?;
+
+    Ok((file, pointer, length))
+}
+
+fn read_state<B: Backend>(backend: &mut B, table: u64, slot: u64) -> Result<u8> {
+    backend.seek(SeekFrom::Start(table + slot * SLOT_SIZE))?;
+
+    Ok(backend.read_u8()?)
+}
+
+fn write_slot<B: Backend>(
+    backend: &mut B,
+    table: u64,
+    slot: u64,
+    id: &EntryID,
+    (file, pointer, length): MetaEntry,
+) -> Result<()> {
+    let mut data = Vec::with_capacity(SLOT_SIZE as usize);
+    data.write_u8(USED)?;
+    data.write_all(id)?;
+    data.write_u32::<BigEndian>(file)?;
+    data.write_u64::<BigEndian>(pointer)?;
+    data.write_u64::<BigEndian>(length)?;
+    backend.seek(SeekFrom::Start(table + slot * SLOT_SIZE))?;
+    backend.write_all(&data)?;
+
+    Ok(())
+}
@@ -0,0 +1,366 @@
+use crate::backend::{Backend, SyncPolicy};
+use crate::error::{Error, Result};
+use crate::trace::{event, span};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// The number of bytes that are copied into the staging area on the first write
+const PAGE_SIZE: u64 = 512;
+/// The number of written ranges that are kept before they are merged
+const MAX_WRITTEN_RANGES: usize = 4096;
+/// Marks the start of a journal
+const JOURNAL_MAGIC: [u8; 4] = *b"IFSJ";
+
+/// Writes that are kept in memory until they are committed
+struct Staging {
+    /// Modified pages by their index
+    pages: BTreeMap<u64, Vec<u8>>,
+    /// The size of the backend including staged writes
+    size: u64,
+    /// The smallest size the backend was truncated to. Unstaged bytes after it read as zeros
+    truncated: u64,
+}
+
+/// A backend that can stage writes in memory and apply them at once through a journal
+pub(crate) struct JournalBackend<B: Backend> {
+    inner: B,
+    staging: Option<Staging>,
+    position: u64,
+    /// The ranges written since they were last taken so that caches can drop what changed
+    written: Vec<(u64, u64)>,
+    sync_policy: SyncPolicy,
+}
+
+impl<B: Backend> JournalBackend<B> {
+    pub fn new(inner: B) -> Self {
+        Self {
+            inner,
+            staging: None,
+            position: 0,
+            written: Vec::new(),
+            sync_policy: SyncPolicy::default(),
+        }
+    }
+
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.sync_policy = policy;
+    }
+
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+
+    /// Returns if writes are currently staged
+    pub fn is_staging(&self) -> bool {
+        self.staging.is_some()
+    }
+
+    /// Starts keeping writes in memory
+    pub fn begin(&mut self) -> io::Result<()> {
+        let size = self.inner.size()?;
+        self.position = self.inner.stream_position()?;
+        self.staging = Some(Staging {
+            pages: BTreeMap::new(),
+            size,
+            truncated: size,
+        });
+
+        Ok(())
+    }
+
+    /// Drops all staged writes
+    pub fn rollback(&mut self) {
+        if self.staging.take().is_some() {
+            // everything read while staging could have been changed
+            self.record(0, u64::MAX);
+        }
+    }
+
+    /// Returns the ranges that were written since the last call
+    pub fn take_written(&mut self) -> Vec<(u64, u64)> {
+        std::mem::take(&mut self.written)
+    }
+
+    /// Records a written range. Too many ranges are merged into one covering everything
+    fn record(&mut self, start: u64, end: u64) {
+        if self.written.len() >= MAX_WRITTEN_RANGES {
+            self.written = vec![(0, u64::MAX)];
+        } else {
+            self.written.push((start, end));
+        }
+    }
+
+    /// Applies the staged writes. If a journal location is given in the header the
+    /// writes are stored in a journal after the end of the data first and the
+    /// location is recorded at that offset so that an interrupted commit can be
+    /// completed with [JournalBackend::replay]
+    pub fn commit(&mut self, journal_pointer: Option<u64>) -> Result<()> {
+        let _span = span!("commit");
+        let size = self.inner.size()?;
+        if let Some(staging) = &self.staging {
+            // old data after a truncation that is covered by the new size has to be zeroed
+            let end = size.min(staging.size);
+            let mut start = staging.truncated;
+            while start < end {
+                self.page(start / PAGE_SIZE)?;
+                start = (start / PAGE_SIZE + 1) * PAGE_SIZE;
+            }
+        }
+        let staging = match self.staging.take() {
+            Some(staging) => staging,
+            None => return Ok(()),
+        };
+        let writes: Vec<(u64, &[u8])> = staging
+            .pages
+            .iter()
+            .filter(|(index, _)| **index * PAGE_SIZE < staging.size)
+            .map(|(index, page)| {
+                let offset = index * PAGE_SIZE;
+                let length = page.len().min((staging.size - offset) as usize);
+                (offset, &page[..length])
+            })
+            .collect();
+        if writes.is_empty() && staging.size == size {
+            return Ok(());
+        }
+        event!(Debug, "committing {} staged writes", writes.len());
+
+        if let Some(pointer) = journal_pointer {
+            let location = size.max(staging.size);
+            let mut journal = Vec::new();
+            journal.write_all(&JOURNAL_MAGIC)?;
+            journal.write_u64::<BigEndian>(staging.size)?;
+            journal.write_u32::<BigEndian>(writes.len() as u32)?;
+            for (offset, data) in &writes {
+                journal.write_u64::<BigEndian>(*offset)?;
+                journal.write_u32::<BigEndian>(data.len() as u32)?;
+                journal.write_all(data)?;
+            }
+            let checksum = crc32fast::hash(&journal);
+            journal.write_u32::<BigEndian>(checksum)?;
+            self.inner.seek(SeekFrom::Start(location))?;
+            self.inner.write_all(&journal)?;
+            self.flush_inner()?;
+            // the journal is complete once its location is in the header
+            self.write_pointer(pointer, location)?;
+            apply(&mut self.inner, &writes)?;
+            self.flush_inner()?;
+            self.write_pointer(pointer, 0)?;
+        } else {
+            apply(&mut self.inner, &writes)?;
+        }
+        // truncating last keeps the journal until it's no longer needed
+        self.inner.set_len(staging.size)?;
+        self.flush_inner()?;
+        self.inner.seek(SeekFrom::Start(self.position))?;
+
+        Ok(())
+    }
+
+    /// Completes a commit that was interrupted. Returns if a journal was replayed
+    pub fn replay(&mut self, pointer: u64) -> Result<bool> {
+        self.inner.seek(SeekFrom::Start(pointer))?;
+        let location = self.inner.read_u64::<BigEndian>()?;
+        if location == 0 {
+            return Ok(false);
+        }
+        let corrupt = || Error::corrupt(location, "incomplete journal");
+        self.inner.seek(SeekFrom::Start(location))?;
+        let mut journal = Vec::new();
+        self.inner.read_to_end(&mut journal)?;
+        let mut reader = &journal[..];
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).map_err(|_| corrupt())?;
+        if magic != JOURNAL_MAGIC {
+            return Err(corrupt());
+        }
+        let size = reader.read_u64::<BigEndian>().map_err(|_| corrupt())?;
+        let count = reader.read_u32::<BigEndian>().map_err(|_| corrupt())?;
+        let mut writes = Vec::with_capacity(count.min(1024) as usize);
+        for _ in 0..count {
+            let offset = reader.read_u64::<BigEndian>().map_err(|_| corrupt())?;
+            let length = reader.read_u32::<BigEndian>().map_err(|_| corrupt())? as usize;
+            if length > reader.len() {
+                return Err(corrupt());
+            }
+            writes.push((offset, &reader[..length]));
+            reader = &reader[length..];
+        }
+        let end = journal.len() - reader.len();
+        let checksum = reader.read_u32::<BigEndian>().map_err(|_| corrupt())?;
+        if checksum != crc32fast::hash(&journal[..end]) {
+            return Err(corrupt());
+        }
+        apply(&mut self.inner, &writes)?;
+        self.flush_inner()?;
+        self.write_pointer(pointer, 0)?;
+        self.inner.set_len(size)?;
+        self.flush_inner()?;
+
+        Ok(true)
+    }
+
+    fn write_pointer(&mut self, pointer: u64, location: u64) -> io::Result<()> {
+        self.inner.seek(SeekFrom::Start(pointer))?;
+        self.inner.write_u64::<BigEndian>(location)?;
+        self.flush_inner()
+    }
+
+    /// Flushes the backend and syncs it unless syncing is disabled. Commits are
+    /// ordered by these calls so every step has to be on the disk before the next
+    fn flush_inner(&mut self) -> io::Result<()> {
+        match self.sync_policy {
+            SyncPolicy::Never => self.inner.flush(),
+            SyncPolicy::Always | SyncPolicy::OnCommit => self.inner.sync(),
+        }
+    }
+
+    /// Returns the page with staged writes creating it from the backend if needed
+    fn page(&mut self, index: u64) -> io::Result<&mut Vec<u8>> {
+        let staging = self.staging.as_mut().expect("not staging");
+        if !staging.pages.contains_key(&index) {
+            let mut page = vec![0u8; PAGE_SIZE as usize];
+            let start = index * PAGE_SIZE;
+            let end = (start + PAGE_SIZE).min(staging.truncated);
+            if start < end {
+                self.inner.seek(SeekFrom::Start(start))?;
+                self.inner.read_exact(&mut page[..(end - start) as usize])?;
+            }
+            staging.pages.insert(index, page);
+        }
+
+        Ok(staging.pages.get_mut(&index).expect("page was inserted"))
+    }
+}
+
+/// Writes the data to the backend
+fn apply<B: Backend>(backend: &mut B, writes: &[(u64, &[u8])]) -> io::Result<()> {
+    for (offset, data) in writes {
+        backend.seek(SeekFrom::Start(*offset))?;
+        backend.write_all(data)?;
+    }
+    backend.flush()
+}
+
+impl<B: Backend> Read for JournalBackend<B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (size, truncated) = match &self.staging {
+            None => return self.inner.read(buf),
+            Some(staging) => (staging.size, staging.truncated),
+        };
+        if self.position >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.position / PAGE_SIZE;
+        let offset = (self.position % PAGE_SIZE) as usize;
+        let length = buf
+            .len()
+            .min(PAGE_SIZE as usize - offset)
+            .min((size - self.position) as usize);
+        let buf = &mut buf[..length];
+
+        match self.staging.as_ref().and_then(|s| s.pages.get(&index)) {
+            Some(page) => buf.copy_from_slice(&page[offset..offset + length]),
+            None if self.position + length as u64 <= truncated => {
+                self.inner.seek(SeekFrom::Start(self.position))?;
+                self.inner.read_exact(buf)?;
+            }
+            None => self
+                .page(index)
+                .map(|page| buf.copy_from_slice(&page[offset..offset + length]))?,
+        }
+        self.position += length as u64;
+
+        Ok(length)
+    }
+}
+
+impl<B: Backend> Write for JournalBackend<B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.staging.is_none() {
+            let start = self.inner.stream_position()?;
+            let written = self.inner.write(buf)?;
+            self.record(start, start + written as u64);
+            return Ok(written);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.position / PAGE_SIZE;
+        let offset = (self.position % PAGE_SIZE) as usize;
+        let length = buf.len().min(PAGE_SIZE as usize - offset);
+        self.page(index)?[offset..offset + length].copy_from_slice(&buf[..length]);
+        self.record(self.position, self.position + length as u64);
+        self.position += length as u64;
+        let staging = self.staging.as_mut().expect("not staging");
+        staging.size = staging.size.max(self.position);
+
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.staging {
+            None => self.inner.flush(),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+impl<B: Backend> Seek for JournalBackend<B> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let size = match &self.staging {
+            None => return self.inner.seek(position),
+            Some(staging) => staging.size,
+        };
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        Ok(self.position)
+    }
+}
+
+impl<B: Backend> Backend for JournalBackend<B> {
+    fn size(&mut self) -> io::Result<u64> {
+        match &self.staging {
+            None => self.inner.size(),
+            Some(staging) => Ok(staging.size),
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        match self.staging {
+            None => self.inner.sync(),
+            Some(_) => Ok(()),
+        }
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.record(size, u64::MAX);
+        let staging = match &mut self.staging {
+            None => return self.inner.set_len(size),
+            Some(staging) => staging,
+        };
+        staging.size = size;
+        if size < staging.truncated {
+            staging.truncated = size;
+        }
+        // bytes after the end have to read as zeros if the backend grows again
+        let last = size / PAGE_SIZE;
+        staging.pages.retain(|index, _| *index <= last);
+        if let Some(page) = staging.pages.get_mut(&last) {
+            let end = (size % PAGE_SIZE) as usize;
+            page[end..].iter_mut().for_each(|b| *b = 0);
+        }
+
+        Ok(())
+    }
+}
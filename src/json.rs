@@ -0,0 +1,311 @@
+use crate::error::{Error, Result};
+use std::io::{Read, Write};
+
+/// A parsed JSON value. Numbers are limited to unsigned integers as that's all
+/// the tree stores
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    /// The members in the order they appear in
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Returns the value of a member of an object
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, Json)]> {
+        match self {
+            Json::Object(members) => Some(members),
+            _ => None,
+        }
+    }
+
+    /// Creates an array of numbers from bytes
+    pub fn bytes(data: &[u8]) -> Self {
+        Json::Array(data.iter().map(|b| Json::Number(*b as u64)).collect())
+    }
+
+    /// Returns the bytes of an array of numbers
+    pub fn as_bytes(&self) -> Option<Vec<u8>> {
+        self.as_array()?
+            .iter()
+            .map(|b| b.as_u64().filter(|b| *b <= u8::MAX as u64).map(|b| b as u8))
+            .collect()
+    }
+
+    /// Writes the value indented with two spaces per level
+    pub fn write<W: Write>(&self, writer: &mut W, indent: usize) -> Result<()> {
+        match self {
+            Json::Null => write!(writer, "null")?,
+            Json::Bool(b) => write!(writer, "{}", b)?,
+            Json::Number(n) => write!(writer, "{}", n)?,
+            Json::String(s) => write_string(writer, s)?,
+            Json::Array(items) if items.iter().all(|i| matches!(i, Json::Number(_))) => {
+                let numbers: Vec<String> = items
+                    .iter()
+                    .filter_map(Json::as_u64)
+                    .map(|n| n.to_string())
+                    .collect();
+                write!(writer, "[{}]", numbers.join(", "))?;
+            }
+            Json::Array(items) => {
+                writeln!(writer, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    write!(writer, "{:1$}", "", (indent + 1) * 2)?;
+                    item.write(writer, indent + 1)?;
+                    writeln!(writer, "{}", if i + 1 < items.len() { "," } else { "" })?;
+                }
+                write!(writer, "{:1$}]", "", indent * 2)?;
+            }
+            Json::Object(members) if members.is_empty() => write!(writer, "{{}}")?,
+            Json::Object(members) => {
+                writeln!(writer, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    write!(writer, "{:1$}", "", (indent + 1) * 2)?;
+                    write_string(writer, key)?;
+                    write!(writer, ": ")?;
+                    value.write(writer, indent + 1)?;
+                    writeln!(writer, "{}", if i + 1 < members.len() { "," } else { "" })?;
+                }
+                write!(writer, "{:1$}}}", "", indent * 2)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a complete document
+    pub fn parse<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+        let mut parser = Parser {
+            chars: text.char_indices().peekable(),
+            length: text.len(),
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        match parser.chars.next() {
+            None => Ok(value),
+            Some((position, _)) => Err(invalid(position, "trailing characters")),
+        }
+    }
+}
+
+fn write_string<W: Write>(writer: &mut W, s: &str) -> Result<()> {
+    write!(writer, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(writer, "\\\"")?,
+            '\\' => write!(writer, "\\\\")?,
+            '\n' => write!(writer, "\\n")?,
+            '\r' => write!(writer, "\\r")?,
+            '\t' => write!(writer, "\\t")?,
+            c if c.is_control() => write!(writer, "\\u{:04x}", c as u32)?,
+            c => write!(writer, "{}", c)?,
+        }
+    }
+    write!(writer, "\"")?;
+
+    Ok(())
+}
+
+fn invalid(position: usize, reason: &str) -> Error {
+    Error::InvalidArchive {
+        reason: format!("{} at byte {}", reason, position),
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    length: usize,
+}
+
+impl Parser<'_> {
+    fn unexpected_end(&self) -> Error {
+        invalid(self.length, "unexpected end")
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some((_, c)) if c == expected => Ok(()),
+            Some((position, _)) => Err(invalid(position, &format!("expected {:?}", expected))),
+            None => Err(self.unexpected_end()),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        self.skip_whitespace();
+        let (position, c) = match self.chars.peek() {
+            Some(next) => *next,
+            None => return Err(self.unexpected_end()),
+        };
+        match c {
+            '{' => self.object(),
+            '[' => self.array(),
+            '"' => Ok(Json::String(self.string()?)),
+            '0'..='9' => self.number(),
+            _ => {
+                let mut word = String::new();
+                while let Some((_, c)) = self.chars.next_if(|(_, c)| c.is_ascii_alphabetic()) {
+                    word.push(c);
+                }
+                match word.as_str() {
+                    "null" => Ok(Json::Null),
+                    "true" => Ok(Json::Bool(true)),
+                    "false" => Ok(Json::Bool(false)),
+                    _ => Err(invalid(position, "unexpected value")),
+                }
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json> {
+        self.expect('{')?;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.chars.next_if(|(_, c)| *c == '}').is_some() {
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(':')?;
+            members.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some((_, ',')) => {}
+                Some((_, '}')) => return Ok(Json::Object(members)),
+                Some((position, _)) => return Err(invalid(position, "expected ',' or '}'")),
+                None => return Err(self.unexpected_end()),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.chars.next_if(|(_, c)| *c == ']').is_some() {
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some((_, ',')) => {}
+                Some((_, ']')) => return Ok(Json::Array(items)),
+                Some((position, _)) => return Err(invalid(position, "expected ',' or ']'")),
+                None => return Err(self.unexpected_end()),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let mut number: u64 = 0;
+        let position = self.chars.peek().map_or(0, |(p, _)| *p);
+        while let Some((_, c)) = self.chars.next_if(|(_, c)| c.is_ascii_digit()) {
+            number = number
+                .checked_mul(10)
+                .and_then(|n| n.checked_add(c as u64 - '0' as u64))
+                .ok_or_else(|| invalid(position, "number too large"))?;
+        }
+        if self
+            .chars
+            .next_if(|(_, c)| matches!(c, '.' | 'e' | 'E'))
+            .is_some()
+        {
+            return Err(invalid(position, "only unsigned integers are supported"));
+        }
+
+        Ok(Json::Number(number))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => return Ok(s),
+                Some((position, '\\')) => match self.chars.next() {
+                    Some((_, '"')) => s.push('"'),
+                    Some((_, '\\')) => s.push('\\'),
+                    Some((_, '/')) => s.push('/'),
+                    Some((_, 'n')) => s.push('\n'),
+                    Some((_, 'r')) => s.push('\r'),
+                    Some((_, 't')) => s.push('\t'),
+                    Some((_, 'b')) => s.push('\u{8}'),
+                    Some((_, 'f')) => s.push('\u{c}'),
+                    Some((_, 'u')) => {
+                        let mut code = self.hex(position)?;
+                        // characters outside the basic plane are written as surrogate pairs
+                        if (0xD800..0xDC00).contains(&code) {
+                            self.expect('\\')?;
+                            self.expect('u')?;
+                            let low = self.hex(position)?;
+                            code = 0x10000
+                                + ((code - 0xD800) << 10)
+                                + (low.wrapping_sub(0xDC00) & 0x3FF);
+                        }
+                        s.push(
+                            char::from_u32(code)
+                                .ok_or_else(|| invalid(position, "invalid escape"))?,
+                        );
+                    }
+                    _ => return Err(invalid(position, "invalid escape")),
+                },
+                Some((_, c)) => s.push(c),
+                None => return Err(self.unexpected_end()),
+            }
+        }
+    }
+
+    fn hex(&mut self, position: usize) -> Result<u32> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self
+                .chars
+                .next()
+                .and_then(|(_, c)| c.to_digit(16))
+                .ok_or_else(|| invalid(position, "invalid escape"))?;
+            code = code * 16 + digit;
+        }
+
+        Ok(code)
+    }
+}
@@ -1,22 +1,111 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+extern crate alloc;
+
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+#[cfg(feature = "std")]
+pub mod audit;
+#[cfg(feature = "std")]
+pub mod backend;
+#[cfg(feature = "std")]
+mod bloom;
+#[cfg(feature = "std")]
+pub mod container;
+#[cfg(feature = "std")]
 pub mod dirtreefile;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod format;
+#[cfg(feature = "fuse")]
+pub mod fuse;
+#[cfg(feature = "std")]
+pub mod hashtable;
+#[cfg(feature = "std")]
+mod journal;
+#[cfg(feature = "std")]
+mod json;
+#[cfg(feature = "std")]
+mod lru;
+#[cfg(feature = "std")]
+pub mod lsm;
+#[cfg(feature = "std")]
 pub mod metafile;
+#[cfg(feature = "std")]
+pub mod metrics;
+#[cfg(feature = "mmap")]
+mod mmap;
+#[cfg(feature = "object_store")]
+pub mod object_store;
+#[cfg(feature = "std")]
+pub mod progress;
+#[cfg(feature = "std")]
+pub mod replication;
+#[cfg(feature = "serve")]
+pub mod serve;
+#[cfg(feature = "std")]
+pub mod sharded;
+#[cfg(feature = "std")]
 pub mod storage;
+#[cfg(feature = "std")]
+mod trace;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+#[cfg(feature = "std")]
 pub mod utils;
+#[cfg(feature = "vfs")]
+pub mod vfs;
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
-    use crate::metafile::IndexedMetaFile;
-    use std::io;
+    use crate::audit::{AuditLog, AUDIT_FILE_NAME};
+    use crate::backend::{Backend, DataBackend, MemoryDataBackend, SyncPolicy, BLOCK_SIZE};
+    use crate::container::MemoryContainer;
+    use crate::dirtreefile::{DirTreeFile, EntryMetadata, NamePolicy, TreeOptions};
+    use crate::error::Error;
+    use crate::hashtable::HashTableFile;
+    use crate::lsm::LsmMetaFile;
+    use crate::metafile::{
+        hash_id, ConflictPolicy, HashAlgorithm, IndexedMetaFile, INLINE_FILE, MARKER_FILE,
+        UNKNOWN_LENGTH,
+    };
+    use crate::metrics::{
+        Metrics, BLOBS_STORED, BYTES_WRITTEN, FRAGMENTATION, LOOKUP_SECONDS, TREE_FRAGMENTATION,
+        TREE_LOOKUP_SECONDS,
+    };
+    use crate::progress::{CancelToken, OperationOptions, Progress};
+    use crate::sharded::ShardedMetaFile;
+    use crate::storage::{
+        ArchiveFormat, BlobMeta, ChangeEvent, EvictionPolicy, EvictionStrategy, ListCursor,
+        LowSpacePolicy, Quota, Storage, NAMESPACES_DIR, TAGS_NAMESPACE, VERSIONS_NAMESPACE,
+    };
+    use crate::utils::glob_match;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn test_storage(name: &str) -> io::Result<Storage> {
+        let path = std::env::temp_dir().join(format!("ifs-test-{}", name));
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+
+        Ok(Storage::open(path)?)
+    }
 
     #[test]
     fn it_writes_meta_files() -> io::Result<()> {
         let mut meta_file = IndexedMetaFile::new()?;
-        meta_file.add_entry("./example-file.txt", 0, 1);
-        meta_file.add_entry("./example2-file.png", 2, 4);
+        meta_file.add_entry("./example-file.txt", (0, 1, 5));
+        meta_file.add_entry("./example2-file.png", (2, 4, 5));
         let mut result = Vec::with_capacity(0);
         meta_file.write(&mut result)?;
         println!("{:?}", result);
-        assert_eq!(result[4..8], [0, 0, 0, 2]);
+        assert_eq!(result[..4], *b"IFSM");
+        assert_eq!(result[12..16], [0, 0, 0, 2]);
 
         Ok(())
     }
@@ -31,8 +120,3765 @@ mod tests {
             174, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 4,
         ];
         let meta_file = IndexedMetaFile::from_reader(&data[..])?;
-        assert_eq!(meta_file.get_entry("./example-file.txt"), Some(&(0, 1)));
+        assert_eq!(
+            meta_file.get_entry("./example-file.txt"),
+            Some(&(0, 1, UNKNOWN_LENGTH))
+        );
+        assert_eq!(
+            meta_file.get_entry("./example2-file.png"),
+            Some(&(2, 4, UNKNOWN_LENGTH))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_records_the_hash_algorithm() -> io::Result<()> {
+        let mut meta_file = IndexedMetaFile::with_algorithm(HashAlgorithm::Sha512Trunc256)?;
+        meta_file.add_entry("a", (0, 1, 5));
+        assert!(meta_file.contains_raw(&HashAlgorithm::Sha512Trunc256.hash_id("a")));
+        assert!(!meta_file.contains_raw(&hash_id("a")));
+        let mut data = Vec::new();
+        meta_file.write(&mut data)?;
+        let meta_file = IndexedMetaFile::from_reader(&data[..])?;
+        assert_eq!(meta_file.algorithm(), HashAlgorithm::Sha512Trunc256);
+        assert_eq!(meta_file.get_entry("a"), Some(&(0, 1, 5)));
+
+        let mut table = HashTableFile::from_meta_file(Cursor::new(Vec::new()), &meta_file)?;
+        assert_eq!(table.get_entry("a")?, Some((0, 1, 5)));
+        let table = HashTableFile::from_backend(table.into_inner())?;
+        assert_eq!(table.algorithm(), HashAlgorithm::Sha512Trunc256);
+
+        data[6] = 9;
+        assert!(matches!(
+            IndexedMetaFile::from_reader(&data[..]),
+            Err(Error::UnsupportedFormat { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_hashes_ids_with_non_sha_algorithms() -> io::Result<()> {
+        for algorithm in [HashAlgorithm::Blake3, HashAlgorithm::Xxh3] {
+            let id = algorithm.hash_id("a");
+            assert_ne!(id, hash_id("a"));
+            assert_ne!(id, algorithm.hash_id("b"));
+            assert_ne!(id[..16], id[16..]);
+
+            let mut meta_file = IndexedMetaFile::with_algorithm(algorithm)?;
+            meta_file.add_entry("a", (0, 1, 5));
+            let mut data = Vec::new();
+            meta_file.write(&mut data)?;
+            let meta_file = IndexedMetaFile::from_reader(&data[..])?;
+            assert_eq!(meta_file.algorithm(), algorithm);
+            assert!(meta_file.contains_raw(&id));
+        }
+        assert_eq!(
+            HashAlgorithm::Blake3.hash_id(""),
+            *blake3::hash(b"").as_bytes()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_saves_meta_files_to_their_path() -> io::Result<()> {
+        let path = std::env::temp_dir().join("ifs-test-meta-open.meta");
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        let mut meta_file = IndexedMetaFile::open(&path)?;
+        assert!(!meta_file.is_dirty());
+        meta_file.flush()?;
+        assert!(!path.exists());
+        meta_file.add_entry("a", (0, 1, 5));
+        assert!(meta_file.is_dirty());
+        meta_file.flush()?;
+        assert!(!meta_file.is_dirty());
+        assert_eq!(
+            IndexedMetaFile::open(&path)?.get_entry("a"),
+            Some(&(0, 1, 5))
+        );
+
+        meta_file.set_autosave(true);
+        meta_file.add_entry("b", (2, 4, 5));
+        drop(meta_file);
+        let meta_file = IndexedMetaFile::open(&path)?;
+        assert_eq!(meta_file.get_entry("b"), Some(&(2, 4, 5)));
+        assert!(IndexedMetaFile::new()?.save().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_counts_meta_file_entries() -> io::Result<()> {
+        let mut meta_file = IndexedMetaFile::new()?;
+        assert!(meta_file.is_empty());
+        meta_file.add_entry("a", (0, 1, 5));
+        meta_file.add_entry("a", (0, 2, 5));
+        meta_file.add_entry("b", (0, 2, 5));
+        assert_eq!(meta_file.len(), 2);
+        assert!(meta_file.contains("a"));
+        assert!(meta_file.contains_raw(&hash_id("b")));
+        meta_file.remove_entry("a");
+        assert!(!meta_file.contains("a"));
+        assert!(!meta_file.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_counts_references_per_extent() -> io::Result<()> {
+        let mut meta_file = IndexedMetaFile::new()?;
+        meta_file.add_entry("a", (0, 8, 5));
+        meta_file.add_entry("b", (0, 8, 12));
+        meta_file.add_entry("c", (0, 8, UNKNOWN_LENGTH));
+        meta_file.add_entry("d", (0, 40, 5));
+        assert_eq!(meta_file.ref_count(&(0, 8, 5)), 3);
+        assert_eq!(meta_file.extent_refs(0, 40), 1);
+        assert_eq!(
+            meta_file.shared_extents().collect::<Vec<_>>(),
+            [((0, 8), 3)]
+        );
+
+        meta_file.add_entry("b", (0, 8, 20));
+        assert_eq!(meta_file.extent_refs(0, 8), 3);
+        meta_file.remove_entry("a");
+        meta_file.remove_entry("c");
+        assert_eq!(meta_file.ref_count(&(0, 8, 20)), 1);
+        assert_eq!(meta_file.shared_extents().count(), 0);
+        meta_file.remove_entry("b");
+        assert_eq!(meta_file.extent_refs(0, 8), 0);
+
+        let mut data = Vec::new();
+        meta_file.add_entry("e", (0, 40, 5));
+        meta_file.write(&mut data)?;
+        let loaded = IndexedMetaFile::from_reader(&data[..])?;
+        assert_eq!(loaded.extent_refs(0, 40), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_adds_raw_meta_file_entries() -> io::Result<()> {
+        let mut meta_file = IndexedMetaFile::new()?;
+        let id = [7u8; 32];
+        assert_eq!(meta_file.add_entry_raw(id, (0, 1, 5)), None);
+        assert_eq!(meta_file.add_entry_raw(id, (0, 2, 5)), Some((0, 1, 5)));
+        assert_eq!(meta_file.get_entry_raw(&id), Some(&(0, 2, 5)));
+        assert_eq!(meta_file.ref_count(&(0, 1, 5)), 0);
+        assert_eq!(meta_file.remove_entry_raw(&id), Some((0, 2, 5)));
+
+        let mut table = HashTableFile::from_backend(Cursor::new(Vec::new()))?;
+        table.add_entry_raw(id, (1, 1, 5))?;
+        assert_eq!(table.get_entry_raw(&id)?, Some((1, 1, 5)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_accepts_binary_keys() -> io::Result<()> {
+        let mut meta_file = IndexedMetaFile::new()?;
+        let uuid = [0x12u8; 16];
+        meta_file.add_entry(&uuid, (0, 1, 5));
+        meta_file.add_entry(&vec![0xFFu8, 0xFE], (0, 2, 5));
+        meta_file.add_entry("a", (0, 3, 5));
+        assert_eq!(meta_file.get_entry(&uuid[..]), Some(&(0, 1, 5)));
+        assert!(meta_file.contains(&[0xFFu8, 0xFE]));
+        assert_eq!(meta_file.get_entry(b"a"), Some(&(0, 3, 5)));
+        assert_eq!(meta_file.remove_entry(&uuid), Some((0, 1, 5)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_stores_values_next_to_meta_entries() -> io::Result<()> {
+        let path = std::env::temp_dir().join("ifs-test-meta-values.meta");
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        let mut meta_file = IndexedMetaFile::open(&path)?;
+        meta_file.add_entry("a", (0, 1, 5));
+        meta_file.add_entry("b", (0, 2, 5));
+        assert!(matches!(
+            meta_file.set_meta("c", 1, b"x"),
+            Err(Error::NotFound { .. })
+        ));
+        meta_file.set_meta("a", 1, b"text/plain")?;
+        meta_file.set_meta("b", 2, &[1])?;
+        meta_file.flush()?;
+        meta_file.set_meta("a", 2, &[0, 1])?;
+        assert_eq!(meta_file.remove_meta("b", 2)?, Some(vec![1]));
+        meta_file.add_entry("a", (0, 3, 5));
+        meta_file.flush()?;
+        assert_eq!(meta_file.log_length(), 3);
+
+        let mut reopened = IndexedMetaFile::open(&path)?;
+        assert_eq!(reopened.get_meta("a", 1), Some(&b"text/plain"[..]));
+        assert_eq!(reopened.get_meta("a", 2), Some(&[0, 1][..]));
+        assert_eq!(reopened.meta_tags("b"), Vec::<u8>::new());
+        reopened.compact()?;
+        let mut reopened = IndexedMetaFile::open(&path)?;
+        assert_eq!(reopened.meta_tags("a"), vec![1, 2]);
+        reopened.remove_entry("a");
+        assert_eq!(reopened.get_meta("a", 1), None);
+        assert!(reopened.set_meta("b", 1, &vec![0; 70000]).is_err());
+
+        let storage = test_storage("meta-values")?;
+        storage.store("/a.txt", &b"hello"[..])?;
+        storage.meta_mut().set_meta("/a.txt", 1, b"text/plain")?;
+        storage.rename("/a.txt", "/b.txt")?;
+        assert_eq!(
+            storage.meta().get_meta("/b.txt", 1),
+            Some(&b"text/plain"[..])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_keeps_entries_in_namespaces() -> io::Result<()> {
+        let mut meta_file = IndexedMetaFile::new()?;
+        meta_file.add_entry("a", (0, 1, 5));
+        assert_eq!(meta_file.add_entry_in("thumbnails", "a", (0, 2, 5))?, None);
+        meta_file.add_entry_in("thumbnails", "b", (0, 3, 5))?;
+        meta_file.add_entry_in("previews", "a", (0, 4, 5))?;
+        assert_eq!(meta_file.get_entry("a"), Some(&(0, 1, 5)));
+        assert_eq!(meta_file.get_entry_in("thumbnails", "a"), Some(&(0, 2, 5)));
+        assert_eq!(meta_file.get_entry_in("previews", "b"), None);
+        assert_eq!(meta_file.namespace_entries("thumbnails").count(), 2);
+        assert_eq!(meta_file.namespaces(), vec!["previews", "thumbnails"]);
+        // a plain key spelling out the namespace and its length stays separate
+        meta_file.add_entry("\0\x0athumbnailsa", (0, 5, 5));
+        assert_eq!(meta_file.get_entry_in("thumbnails", "a"), Some(&(0, 2, 5)));
+
+        let mut data = Vec::new();
+        meta_file.write(&mut data)?;
+        let mut meta_file = IndexedMetaFile::from_reader(&data[..])?;
+        assert_eq!(meta_file.remove_entry_in("previews", "a"), Some((0, 4, 5)));
+        let mut removed = meta_file.remove_namespace("thumbnails");
+        removed.sort_unstable();
+        assert_eq!(removed, vec![(0, 2, 5), (0, 3, 5)]);
+        assert!(meta_file.namespaces().is_empty());
+        assert_eq!(meta_file.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_finds_entries_by_location() -> io::Result<()> {
+        let mut meta_file = IndexedMetaFile::new()?;
+        meta_file.add_entry("a", (0, 20, 5));
+        meta_file.add_entry("b", (0, 10, 5));
+        meta_file.add_entry("c", (1, 0, 5));
+        meta_file.add_entry("link", (0, 20, 5));
+        let in_file: Vec<_> = meta_file.entries_in_file(0).map(|(_, e)| e.1).collect();
+        assert_eq!(in_file, vec![10, 20, 20]);
+        let (entry, ids) = meta_file.entry_at(0, 20).unwrap();
+        assert_eq!(*entry, (0, 20, 5));
+        assert_eq!(ids, [hash_id("a"), hash_id("link")]);
+
+        meta_file.add_entry("a", (1, 30, 5));
+        meta_file.remove_entry("link");
+        assert!(meta_file.entry_at(0, 20).is_none());
+        assert_eq!(meta_file.entries_in_file(1).count(), 2);
+
+        let mut data = Vec::new();
+        meta_file.write(&mut data)?;
+        let meta_file = IndexedMetaFile::from_reader(&data[..])?;
+        assert_eq!(meta_file.entry_at(1, 30).unwrap().1, [hash_id("a")]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_merges_meta_files() -> io::Result<()> {
+        let mut base = IndexedMetaFile::new()?;
+        base.add_entry("a", (0, 1, 5));
+        base.add_entry("b", (0, 2, 5));
+        let mut other = IndexedMetaFile::new()?;
+        other.add_entry("b", (1, 2, 5));
+        other.add_entry("c", (1, 3, 5));
+        other.set_meta("c", 1, b"value")?;
+
+        let mut merged = IndexedMetaFile::new()?;
+        merged.merge(&base, ConflictPolicy::Error)?;
+        assert!(matches!(
+            merged.merge(&other, ConflictPolicy::Error),
+            Err(Error::AlreadyExists { .. })
+        ));
+        assert!(!merged.contains("c"));
+        assert_eq!(merged.merge(&other, ConflictPolicy::KeepSelf)?, 1);
+        assert_eq!(merged.get_entry("b"), Some(&(0, 2, 5)));
+        assert_eq!(merged.get_meta("c", 1), Some(&b"value"[..]));
+        assert_eq!(merged.merge(&other, ConflictPolicy::KeepOther)?, 1);
+        assert_eq!(merged.get_entry("b"), Some(&(1, 2, 5)));
+        assert_eq!(merged.ref_count(&(0, 2, 5)), 0);
+        assert_eq!(merged.len(), 3);
+
+        let sha512 = IndexedMetaFile::with_algorithm(HashAlgorithm::Sha512Trunc256)?;
+        assert!(merged.merge(&sha512, ConflictPolicy::KeepOther).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_iterates_meta_file_entries() -> io::Result<()> {
+        let mut meta_file = IndexedMetaFile::new()?;
+        meta_file.add_entry("a", (0, 1, 5));
+        meta_file.add_entry("b", (0, 2, 5));
+        meta_file.add_entry("c", (1, 1, 5));
+        meta_file.remove_entry("b");
+        let mut entries: Vec<_> = meta_file.iter().map(|(id, entry)| (*id, *entry)).collect();
+        entries.sort();
+        let mut expected = vec![(hash_id("a"), (0, 1, 5)), (hash_id("c"), (1, 1, 5))];
+        expected.sort();
+        assert_eq!(entries, expected);
+        assert_eq!(meta_file.ids().count(), 2);
+        assert!(meta_file.ids().any(|id| *id == hash_id("c")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_appends_meta_file_changes() -> io::Result<()> {
+        let path = std::env::temp_dir().join("ifs-test-meta-log.meta");
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        let mut meta_file = IndexedMetaFile::open(&path)?;
+        meta_file.set_compact_threshold(4);
+        meta_file.add_entry("a", (0, 1, 5));
+        meta_file.add_entry("b", (0, 2, 5));
+        meta_file.flush()?;
+        let table_size = fs::metadata(&path)?.len();
+        meta_file.add_entry("c", (0, 3, 5));
+        meta_file.remove_entry("a");
+        meta_file.flush()?;
+        assert_eq!(meta_file.log_length(), 2);
+        assert!(fs::metadata(&path)?.len() > table_size);
+
+        let mut reopened = IndexedMetaFile::open(&path)?;
+        assert_eq!(reopened.log_length(), 2);
+        assert_eq!(reopened.get_entry("a"), None);
+        assert_eq!(reopened.get_entry("c"), Some(&(0, 3, 5)));
+        reopened.compact()?;
+        assert_eq!(reopened.log_length(), 0);
+        assert_eq!(
+            IndexedMetaFile::open(&path)?.get_entry("b"),
+            Some(&(0, 2, 5))
+        );
+
+        // passing the threshold rewrites the whole table
+        reopened.set_compact_threshold(4);
+        for i in 0..5 {
+            reopened.add_entry("b", (1, i, 5));
+        }
+        reopened.flush()?;
+        assert_eq!(reopened.log_length(), 0);
+
+        // a record cut off while appending is dropped
+        reopened.add_entry("d", (0, 4, 5));
+        reopened.flush()?;
+        let length = fs::metadata(&path)?.len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(length - 3)?;
+        let mut torn = IndexedMetaFile::open(&path)?;
+        assert_eq!(torn.get_entry("d"), None);
+        assert!(torn.is_dirty());
+        torn.flush()?;
+        assert_eq!(
+            IndexedMetaFile::open(&path)?.get_entry("b"),
+            Some(&(1, 4, 5))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_detects_corrupt_meta_files() -> io::Result<()> {
+        let mut meta_file = IndexedMetaFile::new()?;
+        meta_file.add_entry("a", (0, 1, 5));
+        meta_file.add_entry("b", (0, 2, 5));
+        let mut data = Vec::new();
+        meta_file.write(&mut data)?;
+        assert!(IndexedMetaFile::from_reader(&data[..]).is_ok());
+
+        let mut flipped = data.clone();
+        flipped[20] ^= 1;
+        assert!(matches!(
+            IndexedMetaFile::from_reader(&flipped[..]),
+            Err(Error::Corrupt { .. })
+        ));
+        assert!(matches!(
+            IndexedMetaFile::from_reader(&data[..data.len() - 30]),
+            Err(Error::Corrupt { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_limits_the_entries_of_untrusted_meta_files() -> io::Result<()> {
+        let mut meta_file = IndexedMetaFile::new()?;
+        for i in 0..11 {
+            meta_file.add_entry(&i.to_string(), (0, i, 5));
+        }
+        let mut data = Vec::new();
+        meta_file.write(&mut data)?;
+        assert!(IndexedMetaFile::from_reader_limited(&data[..], 11).is_ok());
+        assert!(matches!(
+            IndexedMetaFile::from_reader_limited(&data[..], 10),
+            Err(Error::Corrupt { offset: 8, .. })
+        ));
+
+        // a huge entry count in a small file fails before anything is read
+        let path = std::env::temp_dir().join("ifs-test-meta-untrusted.meta");
+        let mut forged = data[..16].to_vec();
+        forged[8..16].copy_from_slice(&(1u64 << 40).to_be_bytes());
+        forged.extend_from_slice(&[0u8; 64]);
+        fs::write(&path, &forged)?;
+        assert!(matches!(
+            IndexedMetaFile::open(&path),
+            Err(Error::Corrupt { offset: 8, .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_looks_up_entries_in_hash_table_files() -> io::Result<()> {
+        let path = std::env::temp_dir().join("ifs-test-hash-table.meta");
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        let mut table = HashTableFile::open(path.clone())?;
+        for i in 0..200 {
+            assert_eq!(table.add_entry(&i.to_string(), (0, i, 5))?, None);
+        }
+        for i in (0..200).step_by(2) {
+            assert_eq!(table.remove_entry(&i.to_string())?, Some((0, i, 5)));
+        }
+        assert_eq!(table.add_entry("1", (1, 1, 5))?, Some((0, 1, 5)));
+        drop(table);
+
+        let mut table = HashTableFile::open(path)?;
+        assert_eq!(table.len(), 100);
+        assert_eq!(table.get_entry("1")?, Some((1, 1, 5)));
+        assert_eq!(table.get_entry("2")?, None);
+        for i in (3..200).step_by(2) {
+            assert_eq!(table.get_entry(&i.to_string())?, Some((0, i, 5)));
+        }
+
+        let mut meta_file = IndexedMetaFile::new()?;
+        meta_file.add_entry("a", (2, 4, 5));
+        let mut table = HashTableFile::from_meta_file(Cursor::new(Vec::new()), &meta_file)?;
+        assert_eq!(table.get_entry_raw(&hash_id("a"))?, Some((2, 4, 5)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_skips_missing_ids_with_a_bloom_filter() -> io::Result<()> {
+        let mut table = HashTableFile::from_backend(Cursor::new(Vec::new()))?;
+        for i in 0..100 {
+            table.add_entry(&i.to_string(), (0, i, 5))?;
+        }
+        let mut table = HashTableFile::from_backend(table.into_inner())?;
+        assert!((0..100).all(|i| table.may_contain(&i.to_string())));
+        let false_positives = (100..1100)
+            .filter(|i| table.may_contain(&i.to_string()))
+            .count();
+        assert!(false_positives < 50);
+        assert_eq!(table.get_entry("100")?, None);
+        assert_eq!(table.get_entry("99")?, Some((0, 99, 5)));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn it_looks_up_entries_in_mapped_hash_table_files() -> io::Result<()> {
+        use crate::hashtable::MappedHashTableFile;
+
+        let path = std::env::temp_dir().join("ifs-test-mapped-hash-table.meta");
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        let mut table = HashTableFile::open(path.clone())?;
+        for i in 0..100 {
+            table.add_entry(&i.to_string(), (0, i, 5))?;
+        }
+        table.remove_entry("5")?;
+        drop(table);
+
+        let table = MappedHashTableFile::open(path)?;
+        assert_eq!(table.len(), 99);
+        assert_eq!(table.get_entry("42")?, Some((0, 42, 5)));
+        assert_eq!(table.get_entry("5")?, None);
+        assert_eq!(table.get_entry("100")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_keeps_lsm_meta_files_in_sorted_runs() -> io::Result<()> {
+        let path = std::env::temp_dir().join("ifs-test-lsm");
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        let mut meta_file = LsmMetaFile::open(&path)?;
+        meta_file.set_memtable_limit(10);
+        meta_file.set_max_runs(4);
+        for i in 0..35 {
+            meta_file.add_entry(&i.to_string(), (0, i, 5))?;
+        }
+        assert_eq!(meta_file.run_count(), 3);
+        assert_eq!(meta_file.pending(), 5);
+        meta_file.remove_entry("3")?;
+        meta_file.add_entry("4", (1, 4, 5))?;
+        assert_eq!(meta_file.get_entry("3")?, None);
+        meta_file.flush()?;
+        drop(meta_file);
+
+        let mut meta_file = LsmMetaFile::open(&path)?;
+        assert_eq!(meta_file.run_count(), 4);
+        assert_eq!(meta_file.get_entry("3")?, None);
+        assert_eq!(meta_file.get_entry("4")?, Some((1, 4, 5)));
+        assert_eq!(meta_file.get_entry("20")?, Some((0, 20, 5)));
+        assert_eq!(meta_file.get_entry("35")?, None);
+        meta_file.compact()?;
+        assert_eq!(meta_file.run_count(), 1);
+        assert_eq!(meta_file.get_entry("3")?, None);
+        assert_eq!(meta_file.get_entry("4")?, Some((1, 4, 5)));
+        assert_eq!(meta_file.get_entry("34")?, Some((0, 34, 5)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_merges_lsm_runs_in_the_background() -> io::Result<()> {
+        let path = std::env::temp_dir().join("ifs-test-lsm-merge");
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        let mut meta_file = LsmMetaFile::open(&path)?;
+        meta_file.set_memtable_limit(10);
+        meta_file.set_max_runs(2);
+        for i in 0..30 {
+            meta_file.add_entry(&i.to_string(), (0, i, 5))?;
+        }
+        assert!(meta_file.is_merging());
+        assert_eq!(meta_file.get_entry("7")?, Some((0, 7, 5)));
+        meta_file.remove_entry("3")?;
+        meta_file.add_entry("4", (1, 4, 5))?;
+        meta_file.flush()?;
+        meta_file.wait_for_merge()?;
+        assert!(!meta_file.is_merging());
+        assert_eq!(meta_file.run_count(), 2);
+        assert_eq!(meta_file.get_entry("3")?, None);
+        assert_eq!(meta_file.get_entry("4")?, Some((1, 4, 5)));
+        drop(meta_file);
+
+        let mut meta_file = LsmMetaFile::open(&path)?;
+        assert_eq!(meta_file.run_count(), 2);
+        assert_eq!(meta_file.get_entry("3")?, None);
+        assert_eq!(meta_file.get_entry("4")?, Some((1, 4, 5)));
+        assert_eq!(meta_file.get_entry("29")?, Some((0, 29, 5)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_reports_invalid_arguments_and_corrupt_runs() -> io::Result<()> {
+        let path = std::env::temp_dir().join("ifs-test-typed-errors");
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        let mut meta_file = LsmMetaFile::open(&path)?;
+        meta_file.add_entry("a", (0, 0, 5))?;
+        meta_file.flush()?;
+        drop(meta_file);
+        let run = fs::read_dir(&path)?.next().unwrap()?.path();
+        let mut data = fs::read(&run)?;
+        data[16 + 32] = 9;
+        fs::write(&run, data)?;
+        assert!(matches!(
+            LsmMetaFile::open(&path),
+            Err(Error::Corrupt { offset: 16, ref reason, .. }) if reason == "invalid record state 9"
+        ));
+
+        let mut meta_file = IndexedMetaFile::new()?;
+        assert!(matches!(
+            meta_file.add_inline_entry("a", &vec![0u8; 70_000]),
+            Err(Error::ValueTooLarge { size: 70_000, .. })
+        ));
+        let other = IndexedMetaFile::with_algorithm(HashAlgorithm::Sha512Trunc256)?;
+        assert!(matches!(
+            meta_file.merge(&other, ConflictPolicy::KeepSelf),
+            Err(Error::InvalidArgument { .. })
+        ));
+        assert!(matches!(
+            meta_file.save(),
+            Err(Error::InvalidArgument { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_splits_meta_files_into_shards() -> io::Result<()> {
+        let path = std::env::temp_dir().join("ifs-test-sharded");
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        let meta_file = ShardedMetaFile::open(&path, 4)?;
+        std::thread::scope(|scope| {
+            for thread in 0..4u64 {
+                let meta_file = &meta_file;
+                scope.spawn(move || {
+                    for i in 0..50 {
+                        meta_file.add_entry(&format!("{}-{}", thread, i), (0, thread * 50 + i, 5));
+                    }
+                });
+            }
+        });
+        assert_eq!(meta_file.remove_entry("0-0"), Some((0, 0, 5)));
+        meta_file.flush()?;
+        drop(meta_file);
+
+        assert!(ShardedMetaFile::open(&path, 8).is_err());
+        let meta_file = ShardedMetaFile::open(&path, 4)?;
+        assert_eq!(meta_file.len(), 199);
+        assert!(!meta_file.contains("0-0"));
+        assert_eq!(meta_file.get_entry("3-49"), Some((0, 199, 5)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_stores_and_reads_files() -> io::Result<()> {
+        let storage = test_storage("store")?;
+        storage.create_dir("/docs")?;
+        storage.store("/docs/a.txt", &b"hello"[..])?;
+        storage.store("/docs/b.txt", &b"world"[..])?;
+        let mut content = String::new();
+        storage.get("/docs/b.txt")?.read_to_string(&mut content)?;
+        assert_eq!(content, "world");
+        let created = storage.entry("/docs/b.txt")?.metadata().unwrap().created;
+        storage.store("/docs/b.txt", &b"world!"[..])?;
+        let metadata = storage.entry("/docs/b.txt")?.metadata().unwrap();
+        assert_eq!(metadata.size, 6);
+        assert_eq!(metadata.created, created);
+        storage.set_xattr("/docs/b.txt", "content-type", b"text/plain")?;
+        storage.set_xattr("/docs/b.txt", "flag", b"")?;
+        storage.store("/docs/b.txt", &b"world"[..])?;
+        assert_eq!(
+            storage.get_xattr("/docs/b.txt", "content-type")?,
+            Some(b"text/plain".to_vec())
+        );
+        assert!(storage.remove_xattr("/docs/b.txt", "flag")?);
+        assert_eq!(storage.list_xattrs("/docs/b.txt")?, vec!["content-type"]);
+        storage.delete("/docs/a.txt")?;
+        assert!(storage.get("/docs/a.txt").is_err());
+        storage.create_dir_all("/docs/x/y")?;
+        storage.create_dir_all("/docs/x/y")?;
+        assert!(storage.entry("/docs/x/y")?.is_dir());
+        assert!(matches!(
+            storage.create_dir_all("/docs/b.txt/z"),
+            Err(Error::NotADirectory { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_records_blob_lengths() -> io::Result<()> {
+        let storage = test_storage("blob-lengths")?;
+        storage.store("/a.txt", &b"hello"[..])?;
+        storage.store("/b.txt", &b"hello world"[..])?;
+        let (file, pointer, length) = *storage.meta().get_entry("/b.txt").unwrap();
+        assert_eq!(length, 11);
+
+        // indexes without lengths get them from the data files when they are opened
+        let mut legacy = Vec::new();
+        legacy.extend_from_slice(&(storage.meta().len() as u64).to_be_bytes());
+        for (id, (file, pointer, _)) in storage.meta().iter() {
+            legacy.extend_from_slice(id);
+            legacy.extend_from_slice(&file.to_be_bytes());
+            legacy.extend_from_slice(&pointer.to_be_bytes());
+        }
+        let path = std::env::temp_dir().join("ifs-test-blob-lengths");
+        drop(storage);
+        fs::write(path.join("index.meta"), legacy)?;
+        let storage = Storage::open(path.clone())?;
+        assert_eq!(storage.meta().get_entry("/a.txt").unwrap().2, 5);
+        assert_eq!(
+            storage.meta().get_entry("/b.txt"),
+            Some(&(file, pointer, 11))
+        );
+        assert!(storage.check()?.is_ok());
+        storage.store("/c.txt", &b"!"[..])?;
+        let mut content = String::new();
+        storage.get("/b.txt")?.read_to_string(&mut content)?;
+        assert_eq!(content, "hello world");
+        let meta_file = IndexedMetaFile::open(path.join("index.meta"))?;
+        assert_eq!(meta_file.get_entry("/a.txt").unwrap().2, 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_aligns_blobs_for_direct_io() -> io::Result<()> {
+        let path = std::env::temp_dir().join("ifs-test-direct-io");
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        let storage = Storage::open_direct(path.clone())?;
+        let large: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        storage.store("/a.txt", &b"hello"[..])?;
+        storage.store("/large.bin", &large[..])?;
+        storage.store("/b.txt", &b"world"[..])?;
+        for (path, length) in [("/a.txt", 5), ("/large.bin", 10_000), ("/b.txt", 5)] {
+            let (_, pointer, stored) = *storage.meta().get_entry(path).unwrap();
+            assert_eq!(pointer % BLOCK_SIZE, 0);
+            assert_eq!(stored, length);
+        }
+        let (_, pointer, _) = *storage.meta().get_entry("/b.txt").unwrap();
+        assert_eq!(
+            fs::metadata(path.join("data-0.bin"))?.len(),
+            pointer + 8 + 5
+        );
+        let mut content = Vec::new();
+        storage.get("/large.bin")?.read_to_end(&mut content)?;
+        assert_eq!(content, large);
+        assert!(storage.check()?.is_ok());
+
+        drop(storage);
+        let storage = Storage::open(path)?;
+        let mut content = String::new();
+        storage.get("/b.txt")?.read_to_string(&mut content)?;
+        assert_eq!(content, "world");
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    #[test]
+    fn it_stores_through_io_uring() -> io::Result<()> {
+        use crate::backend::BatchRead;
+        use crate::uring::UringDataBackend;
+
+        let path = std::env::temp_dir().join("ifs-test-io-uring");
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        let storage = Storage::open_uring(path.clone())?;
+        let large: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        storage.create_dir("/dir")?;
+        for i in 0..100 {
+            storage.store(&format!("/dir/{}.txt", i), format!("file {}", i).as_bytes())?;
+        }
+        storage.store("/large.bin", &large[..])?;
+        storage.delete("/dir/0.txt")?;
+        storage.vacuum()?;
+        let mut content = Vec::new();
+        storage.get("/large.bin")?.read_to_end(&mut content)?;
+        assert_eq!(content, large);
+        assert_eq!(storage.read_dir("/dir")?.len(), 99);
+        assert!(storage.check()?.is_ok());
+
+        drop(storage);
+        let storage = Storage::open(path.clone())?;
+        let mut content = String::new();
+        storage.get("/dir/99.txt")?.read_to_string(&mut content)?;
+        assert_eq!(content, "file 99");
+        drop(storage);
+
+        // more reads than fit into the ring at once
+        let data = UringDataBackend::new(path)?;
+        let mut buffers = vec![[0u8; 4]; 200];
+        let mut reads: Vec<BatchRead<'_>> = buffers
+            .iter_mut()
+            .enumerate()
+            .map(|(i, buf)| BatchRead {
+                file: 0,
+                offset: i as u64,
+                buf,
+            })
+            .collect();
+        data.read_exact_batch(&mut reads)?;
+        drop(reads);
+        let mut expected = [0u8; 4];
+        data.read_exact_at(0, 199, &mut expected)?;
+        assert_eq!(buffers[199], expected);
+        reads = vec![BatchRead {
+            file: 0,
+            offset: data.len(0)? - 2,
+            buf: &mut expected,
+        }];
+        assert_eq!(
+            data.read_exact_batch(&mut reads).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_inlines_small_blobs() -> io::Result<()> {
+        let storage = test_storage("inline")?;
+        storage.set_inline_threshold(16);
+        storage.store("/small.txt", &b"hello"[..])?;
+        storage.store("/large.txt", &b"hello world, not inlined"[..])?;
+        assert_eq!(
+            storage.meta().get_entry("/small.txt").unwrap().0,
+            INLINE_FILE
+        );
+        assert_ne!(
+            storage.meta().get_entry("/large.txt").unwrap().0,
+            INLINE_FILE
+        );
+        let mut content = String::new();
+        storage.get("/small.txt")?.read_to_string(&mut content)?;
+        assert_eq!(content, "hello");
+
+        storage.hard_link("/small.txt", "/link.txt")?;
+        assert_eq!(storage.link_count("/link.txt")?, 2);
+        storage.delete("/small.txt")?;
+        let mut content = String::new();
+        storage.get("/link.txt")?.read_to_string(&mut content)?;
+        assert_eq!(content, "hello");
+        storage.rename("/link.txt", "/moved.txt")?;
+        storage.store("/other.txt", &b"world"[..])?;
+        assert!(storage.check()?.is_ok());
+
+        // replacing the content with a large blob drops the inline content
+        storage.store("/moved.txt", &b"a longer content in a data file"[..])?;
+        assert!(storage.meta().inline_content("/moved.txt").is_none());
+        let path = std::env::temp_dir().join("ifs-test-inline");
+        drop(storage);
+        let storage = Storage::open(path)?;
+        let mut content = String::new();
+        storage.get("/other.txt")?.read_to_string(&mut content)?;
+        assert_eq!(content, "world");
+        assert!(storage.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_splits_large_blobs_across_data_files() -> io::Result<()> {
+        let storage = test_storage("chunked")?;
+        storage.set_max_data_file_size(4096);
+        let large: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        storage.store("/large.bin", &large[..])?;
+        let chunks = storage.meta().chunks("/large.bin");
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.iter().map(|c| c.2).sum::<u64>(), 10_000);
+        assert_eq!(storage.meta().get_entry("/large.bin").unwrap().2, 10_000);
+        let mut content = Vec::new();
+        storage.get("/large.bin")?.read_to_end(&mut content)?;
+        assert_eq!(content, large);
+        assert!(storage.check()?.is_ok());
+
+        let path = std::env::temp_dir().join("ifs-test-chunked");
+        drop(storage);
+        let storage = Storage::open(path.clone())?;
+        storage.hard_link("/large.bin", "/link.bin")?;
+        assert_eq!(storage.meta().chunks("/link.bin"), chunks);
+        storage.delete("/large.bin")?;
+        let mut content = Vec::new();
+        storage.get("/link.bin")?.read_to_end(&mut content)?;
+        assert_eq!(content, large);
+        storage.delete("/link.bin")?;
+        for file in 0..3 {
+            let data = path.join(format!("data-{}.bin", file));
+            assert_eq!(fs::metadata(data)?.len(), 0);
+        }
 
         Ok(())
     }
+
+    #[test]
+    fn it_reads_ranges_of_blobs() -> io::Result<()> {
+        let storage = test_storage("ranges")?;
+        storage.set_max_data_file_size(4096);
+        storage.set_inline_threshold(16);
+        let large: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        storage.store("/large.bin", &large[..])?;
+        storage.store("/small.txt", &b"hello world"[..])?;
+        storage.store("/plain.txt", &b"a file in a single data file"[..])?;
+
+        assert_eq!(
+            storage.read_range("/large.bin", 4000, 5000)?,
+            &large[4000..9000]
+        );
+        assert_eq!(storage.read_range("/large.bin", 9999, 1)?, &large[9999..]);
+        assert_eq!(storage.read_range("/small.txt", 6, 5)?, b"world");
+        assert_eq!(storage.read_range("/plain.txt", 2, 4)?, b"file");
+        assert!(storage.read_range("/plain.txt", 0, 0)?.is_empty());
+        let mut reader = storage.get_range("/large.bin", 100, 10)?;
+        assert_eq!(reader.remaining(), 10);
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        assert_eq!(content, &large[100..110]);
+        assert!(matches!(
+            storage.read_range("/small.txt", 6, 6),
+            Err(Error::InvalidRange { size: 11, .. })
+        ));
+        assert!(matches!(
+            storage.read_range("/small.txt", u64::MAX, 2),
+            Err(Error::InvalidRange { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_appends_to_files() -> io::Result<()> {
+        let storage = test_storage("append")?;
+        storage.set_max_data_file_size(4096);
+        storage.store("/a.log", &b"one"[..])?;
+        let (file, pointer, _) = *storage.meta().get_entry("/a.log").unwrap();
+        assert_eq!(storage.append("/a.log", &b"two"[..])?, 6);
+        assert_eq!(
+            storage.meta().get_entry("/a.log"),
+            Some(&(file, pointer, 6))
+        );
+        assert_eq!(storage.entry("/a.log")?.metadata().unwrap().size, 6);
+
+        // content that isn't at the end of the data file is moved
+        storage.store("/b.txt", &b"b"[..])?;
+        storage.hard_link("/b.txt", "/c.txt")?;
+        storage.append("/a.log", &b"three"[..])?;
+        assert_ne!(storage.meta().get_entry("/a.log").unwrap().1, pointer);
+        assert_eq!(storage.read_range("/a.log", 0, 11)?, b"onetwothree");
+        storage.append("/b.txt", &b"!"[..])?;
+        assert_eq!(storage.read_range("/b.txt", 0, 2)?, b"b!");
+        assert_eq!(storage.read_range("/c.txt", 0, 1)?, b"b");
+
+        // appends continue in the next data file when the current one is full
+        let large = vec![7u8; 6000];
+        storage.append("/a.log", &large[..])?;
+        assert_eq!(storage.meta().chunks("/a.log").len(), 2);
+        let mut content = Vec::new();
+        storage.get("/a.log")?.read_to_end(&mut content)?;
+        assert_eq!(&content[..11], b"onetwothree");
+        assert_eq!(&content[11..], &large[..]);
+
+        storage.set_inline_threshold(16);
+        assert_eq!(storage.append("/new.txt", &b"new"[..])?, 3);
+        storage.append("/new.txt", &b" file"[..])?;
+        assert_eq!(storage.meta().get_entry("/new.txt").unwrap().0, INLINE_FILE);
+        assert_eq!(storage.read_range("/new.txt", 0, 8)?, b"new file");
+        assert!(storage.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_writes_inside_stored_files() -> io::Result<()> {
+        let storage = test_storage("write-at")?;
+        storage.set_max_data_file_size(4096);
+        storage.set_inline_threshold(16);
+        let records = vec![0u8; 6000];
+        storage.store("/records.bin", &records[..])?;
+        storage.store("/small.bin", &b"0000"[..])?;
+        storage.hard_link("/records.bin", "/link.bin")?;
+        let created = storage.entry("/records.bin")?.metadata().unwrap().modified;
+        {
+            let mut writer = storage.open_writer("/records.bin")?;
+            assert_eq!(writer.len(), 6000);
+            writer.seek(SeekFrom::Start(4080))?;
+            writer.write_all(b"spans two chunks")?;
+            writer.write_at(0, b"first")?;
+            writer.seek(SeekFrom::End(-4))?;
+            writer.write_all(b"last")?;
+            assert_eq!(writer.write(b"more")?, 0);
+            assert!(matches!(
+                writer.write_at(5998, b"abc"),
+                Err(Error::InvalidRange { .. })
+            ));
+        }
+        assert_eq!(storage.read_range("/records.bin", 0, 5)?, b"first");
+        assert_eq!(
+            storage.read_range("/link.bin", 4080, 16)?,
+            b"spans two chunks"
+        );
+        assert_eq!(storage.read_range("/records.bin", 5996, 4)?, b"last");
+        let metadata = storage.entry("/records.bin")?.metadata().unwrap();
+        assert_eq!(metadata.size, 6000);
+        assert!(metadata.modified >= created);
+
+        // content stored in the index is moved to a data file to be written
+        storage.open_writer("/small.bin")?.write_at(1, b"11")?;
+        assert_ne!(
+            storage.meta().get_entry("/small.bin").unwrap().0,
+            INLINE_FILE
+        );
+        assert_eq!(storage.read_range("/small.bin", 0, 4)?, b"0110");
+        assert!(storage.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_truncates_stored_files() -> io::Result<()> {
+        let storage = test_storage("truncate")?;
+        storage.set_max_data_file_size(4096);
+        let large: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        storage.store("/large.bin", &large[..])?;
+        let data_file = |n: u32| {
+            let path = std::env::temp_dir().join(format!("ifs-test-truncate/data-{}.bin", n));
+            fs::metadata(path).map(|m| m.len())
+        };
+        assert_eq!(data_file(2)?, 8 + 10_000 - 2 * 4088);
+
+        storage.truncate("/large.bin", 5000)?;
+        assert_eq!(storage.meta().chunks("/large.bin").len(), 2);
+        assert_eq!(data_file(1)?, 8 + 5000 - 4088);
+        assert_eq!(data_file(2)?, 0);
+        storage.truncate("/large.bin", 100)?;
+        assert!(storage.meta().chunks("/large.bin").is_empty());
+        assert_eq!(storage.entry("/large.bin")?.metadata().unwrap().size, 100);
+        let mut content = Vec::new();
+        storage.get("/large.bin")?.read_to_end(&mut content)?;
+        assert_eq!(content, &large[..100]);
+        assert!(storage.check()?.is_ok());
+
+        storage.store("/shared.txt", &b"hello world"[..])?;
+        storage.hard_link("/shared.txt", "/link.txt")?;
+        storage.truncate("/shared.txt", 5)?;
+        assert_eq!(storage.read_range("/shared.txt", 0, 5)?, b"hello");
+        assert_eq!(storage.get("/link.txt")?.remaining(), 11);
+        storage.truncate("/shared.txt", 8)?;
+        assert_eq!(storage.read_range("/shared.txt", 0, 8)?, b"hello\0\0\0");
+        assert!(storage.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_keeps_previous_versions() -> io::Result<()> {
+        let storage = test_storage("versions")?;
+        storage.set_version_retention(2);
+        storage.store("/doc.txt", &b"first"[..])?;
+        storage.store("/doc.txt", &b"second"[..])?;
+        storage.store("/doc.txt", &b"third"[..])?;
+        assert_eq!(storage.list_versions("/doc.txt")?, vec![1, 2, 3]);
+        let read = |version: u64| -> io::Result<Vec<u8>> {
+            let mut content = Vec::new();
+            storage
+                .get_version("/doc.txt", version)?
+                .read_to_end(&mut content)?;
+            Ok(content)
+        };
+        assert_eq!(read(1)?, b"first");
+        assert_eq!(read(3)?, b"third");
+
+        storage.store("/doc.txt", &b"fourth"[..])?;
+        assert_eq!(storage.list_versions("/doc.txt")?, vec![2, 3, 4]);
+        assert!(storage.get_version("/doc.txt", 1).is_err());
+        assert_eq!(read(2)?, b"second");
+
+        storage.rename("/doc.txt", "/moved.txt")?;
+        assert_eq!(storage.list_versions("/moved.txt")?, vec![2, 3, 4]);
+        storage.set_version_retention(1);
+        assert_eq!(storage.prune_versions()?, 1);
+        assert_eq!(storage.list_versions("/moved.txt")?, vec![3, 4]);
+        assert!(storage.check()?.is_ok());
+
+        storage.delete("/moved.txt")?;
+        assert!(storage
+            .meta()
+            .namespace_entries(VERSIONS_NAMESPACE)
+            .next()
+            .is_none());
+        assert!(storage.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_renames_directories() -> io::Result<()> {
+        let storage = test_storage("rename-dirs")?;
+        storage.set_version_retention(1);
+        storage.create_dir_all("/docs/2024")?;
+        storage.create_dir("/archive")?;
+        storage.store("/docs/a.txt", &b"first"[..])?;
+        storage.store("/docs/a.txt", &b"second"[..])?;
+        storage.store("/docs/2024/b.txt", &b"b"[..])?;
+        storage.hard_link("/docs/2024/b.txt", "/link.txt")?;
+        storage.tag("/docs/a.txt", "draft")?;
+
+        storage.rename("/docs", "/archive/old")?;
+        assert!(storage.entry("/docs").is_err());
+        let mut content = Vec::new();
+        storage
+            .get("/archive/old/a.txt")?
+            .read_to_end(&mut content)?;
+        assert_eq!(content, b"second");
+        content.clear();
+        storage
+            .get("/archive/old/2024/b.txt")?
+            .read_to_end(&mut content)?;
+        assert_eq!(content, b"b");
+        assert_eq!(storage.list_versions("/archive/old/a.txt")?, vec![1, 2]);
+        assert_eq!(storage.tags("/archive/old/a.txt")?, vec!["draft"]);
+        assert_eq!(storage.find_by_tag("draft"), vec!["/archive/old/a.txt"]);
+        assert_eq!(storage.link_count("/link.txt")?, 2);
+        assert!(storage.check()?.is_ok());
+
+        storage.create_dir("/taken")?;
+        assert!(matches!(
+            storage.rename("/archive/old", "/taken"),
+            Err(Error::AlreadyExists { .. })
+        ));
+        assert!(storage.rename("/archive", "/archive/old/inner").is_err());
+        storage.rename("/archive/old", "/old")?;
+        assert_eq!(storage.read_dir("/archive")?.len(), 0);
+        assert_eq!(storage.read_dir("/old")?.len(), 2);
+        storage.delete("/old/2024/b.txt")?;
+        assert_eq!(storage.link_count("/link.txt")?, 1);
+        assert!(storage.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_moves_deleted_files_to_the_trash() -> io::Result<()> {
+        let storage = test_storage("trash")?;
+        storage.set_trash(true);
+        storage.store("/keep.txt", &b"keep me"[..])?;
+        storage.store("/drop.txt", &b"drop me"[..])?;
+        storage.delete("/keep.txt")?;
+        storage.delete("/drop.txt")?;
+        assert!(storage.get("/keep.txt").is_err());
+        let trashed: Vec<String> = storage.list_trash().into_iter().map(|(p, _)| p).collect();
+        assert_eq!(trashed, vec!["/drop.txt", "/keep.txt"]);
+        assert!(storage.check()?.is_ok());
+
+        storage.restore("/keep.txt")?;
+        let mut content = Vec::new();
+        storage.get("/keep.txt")?.read_to_end(&mut content)?;
+        assert_eq!(content, b"keep me");
+        assert_eq!(storage.entry("/keep.txt")?.metadata().unwrap().size, 7);
+        assert!(storage.restore("/keep.txt").is_err());
+
+        assert_eq!(storage.empty_trash(Duration::from_secs(3600))?, 0);
+        assert_eq!(storage.empty_trash(Duration::from_secs(0))?, 1);
+        assert!(storage.list_trash().is_empty());
+        assert!(storage.restore("/drop.txt").is_err());
+        assert!(storage.check()?.is_ok());
+
+        storage.store("/big.txt", &vec![0u8; 1000][..])?;
+        storage.store("/last.txt", &b"last"[..])?;
+        storage.delete("/big.txt")?;
+        assert_eq!(storage.empty_trash(Duration::from_secs(0))?, 1);
+        assert!(storage.stats()?.fragmentation() > 0.0);
+        assert!(storage.vacuum()? > 0);
+        assert_eq!(storage.stats()?.fragmentation(), 0.0);
+        let mut content = Vec::new();
+        storage.get("/last.txt")?.read_to_end(&mut content)?;
+        assert_eq!(content, b"last");
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_evicts_files_by_policy() -> io::Result<()> {
+        let storage = test_storage("eviction")?;
+        let store = |path: &str| -> io::Result<()> {
+            storage.store(path, &[0u8; 100][..])?;
+            std::thread::sleep(Duration::from_millis(2));
+            Ok(())
+        };
+        store("/a")?;
+        store("/b")?;
+        store("/c")?;
+        assert_eq!(storage.enforce_policies()?, 0);
+
+        storage.set_eviction_policy(Some(EvictionPolicy {
+            max_entries: Some(2),
+            strategy: EvictionStrategy::Fifo,
+            ..Default::default()
+        }));
+        assert_eq!(storage.enforce_policies()?, 1);
+        assert!(storage.get("/a").is_err());
+
+        storage.set_eviction_policy(Some(EvictionPolicy {
+            max_size: Some(200),
+            ..Default::default()
+        }));
+        storage.get("/b")?;
+        store("/d")?;
+        assert_eq!(storage.enforce_policies()?, 1);
+        assert!(storage.get("/c").is_err());
+        assert!(storage.get("/b").is_ok());
+
+        storage.set_eviction_policy(Some(EvictionPolicy {
+            max_age: Some(Duration::MAX),
+            ..Default::default()
+        }));
+        assert_eq!(storage.enforce_policies()?, 0);
+
+        storage.set_eviction_policy(Some(EvictionPolicy {
+            max_age: Some(Duration::from_secs(0)),
+            ..Default::default()
+        }));
+        assert_eq!(storage.enforce_policies()?, 2);
+        assert!(storage.read_dir("/")?.is_empty());
+        assert!(storage.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_vacuums_data_files() -> io::Result<()> {
+        let path = std::env::temp_dir().join("ifs-test-vacuum");
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        let storage = Storage::open(path.clone())?;
+        storage.store("/big", &vec![1u8; 100_000][..])?;
+        storage.store("/small", &b"0123456789"[..])?;
+        storage.delete("/big")?;
+        storage.compact()?;
+        let stats = storage.stats()?;
+        assert_eq!(stats.logical_bytes, 10);
+        assert_eq!(stats.physical_bytes, 18);
+
+        storage.set_max_data_file_size(4096);
+        storage.store("/a", &vec![2u8; 3000][..])?;
+        storage.store("/chunked", &vec![3u8; 6000][..])?;
+        storage.hard_link("/chunked", "/link")?;
+        storage.store("/b", &vec![4u8; 100][..])?;
+        storage.delete("/a")?;
+        assert!(storage.vacuum()? > 0);
+        assert_eq!(storage.stats()?.fragmentation(), 0.0);
+        assert!(storage.check()?.is_ok());
+
+        storage.set_eviction_policy(Some(EvictionPolicy {
+            max_entries: Some(3),
+            strategy: EvictionStrategy::Fifo,
+            ..Default::default()
+        }));
+        assert_eq!(storage.enforce_policies()?, 1);
+        assert!(storage.get("/small").is_err());
+        assert!(storage.vacuum()? > 0);
+        assert_eq!(storage.stats()?.fragmentation(), 0.0);
+        drop(storage);
+
+        let storage = Storage::open(path)?;
+        let mut content = Vec::new();
+        storage.get("/link")?.read_to_end(&mut content)?;
+        assert_eq!(content, vec![3u8; 6000]);
+        content.clear();
+        storage.get("/b")?.read_to_end(&mut content)?;
+        assert_eq!(content, vec![4u8; 100]);
+        assert!(storage.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_vacuums_around_open_readers() -> io::Result<()> {
+        let storage = test_storage("vacuum-readers")?;
+        storage.store("/a", &b"AAAAAAAA"[..])?;
+        storage.store("/b", &b"BBBBBBBB"[..])?;
+        let mut reader = storage.get("/b")?;
+        storage.delete("/a")?;
+        storage.vacuum()?;
+        storage.store("/c", &b"CCCCCCCC"[..])?;
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        assert_eq!(content, b"BBBBBBBB");
+        drop(reader);
+        content.clear();
+        storage.get("/b")?.read_to_end(&mut content)?;
+        assert_eq!(content, b"BBBBBBBB");
+
+        storage.store("/d", &vec![1u8; 100][..])?;
+        let mut reader = storage.get("/d")?;
+        storage.delete("/b")?;
+        storage.delete("/c")?;
+        storage.vacuum()?;
+        storage.store("/e", &vec![2u8; 100][..])?;
+        content.clear();
+        reader.read_to_end(&mut content)?;
+        assert_eq!(content, vec![1u8; 100]);
+        drop(reader);
+
+        assert!(storage.vacuum()? > 0);
+        assert_eq!(storage.stats()?.fragmentation(), 0.0);
+        assert!(storage.check()?.is_ok());
+        content.clear();
+        storage.get("/d")?.read_to_end(&mut content)?;
+        assert_eq!(content, vec![1u8; 100]);
+        content.clear();
+        storage.get("/e")?.read_to_end(&mut content)?;
+        assert_eq!(content, vec![2u8; 100]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_keeps_pinned_files() -> io::Result<()> {
+        let storage = test_storage("pin")?;
+        storage.store("/cached.txt", &b"cached"[..])?;
+        storage.store("/keep.txt", &b"keep"[..])?;
+        storage.pin("/keep.txt")?;
+        assert!(storage.is_pinned("/keep.txt"));
+        assert!(storage.pin("/missing.txt").is_err());
+
+        storage.set_eviction_policy(Some(EvictionPolicy {
+            max_entries: Some(0),
+            ..Default::default()
+        }));
+        assert_eq!(storage.enforce_policies()?, 1);
+        assert!(storage.get("/cached.txt").is_err());
+        assert!(storage.get("/keep.txt").is_ok());
+
+        storage.store("/keep.txt", &b"replaced"[..])?;
+        assert!(storage.is_pinned("/keep.txt"));
+        storage.set_expiry("/keep.txt", Some(UNIX_EPOCH))?;
+        assert_eq!(storage.expire_now()?, 0);
+        assert!(storage.unpin("/keep.txt")?);
+        assert!(!storage.unpin("/keep.txt")?);
+        assert_eq!(storage.expire_now()?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_stores_blob_metadata() -> io::Result<()> {
+        let storage = test_storage("blob_meta")?;
+        storage.store("/image.png", &b"png"[..])?;
+        let created = storage.blob_meta("/image.png")?.created.unwrap();
+        assert_eq!(storage.blob_meta("/image.png")?.content_type, None);
+
+        let modified = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        storage.set_blob_meta(
+            "/image.png",
+            &BlobMeta {
+                content_type: Some("image/png".to_string()),
+                source_name: Some("holiday.png".to_string()),
+                created: None,
+                modified: Some(modified),
+            },
+        )?;
+        storage.store("/image.png", &b"new png"[..])?;
+        storage.rename("/image.png", "/moved.png")?;
+        let blob_meta = storage.blob_meta("/moved.png")?;
+        assert_eq!(blob_meta.content_type.as_deref(), Some("image/png"));
+        assert_eq!(blob_meta.source_name.as_deref(), Some("holiday.png"));
+        assert_eq!(blob_meta.created, Some(created));
+        assert!(blob_meta.modified.unwrap() > modified);
+
+        storage.set_blob_meta("/moved.png", &BlobMeta::default())?;
+        assert_eq!(storage.blob_meta("/moved.png")?.content_type, None);
+        storage.create_dir("/dir")?;
+        assert!(storage.blob_meta("/dir").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_lists_files_in_pages() -> io::Result<()> {
+        let storage = test_storage("list")?;
+        storage.create_dir_all("/b/c")?;
+        storage.create_dir("/empty")?;
+        for path in &["/a.txt", "/b/c/d.txt", "/b/e.txt", "/f.txt", "/b/c/a.txt"] {
+            storage.store(path, &b"content"[..])?;
+        }
+        storage.create_symlink("/link", "/a.txt")?;
+
+        let mut paths = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (files, next) = storage.list(cursor, 2)?;
+            assert!(files.len() <= 2);
+            paths.extend(files.into_iter().map(|file| file.path));
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(
+            paths,
+            ["/a.txt", "/b/c/a.txt", "/b/c/d.txt", "/b/e.txt", "/f.txt"]
+        );
+
+        let (files, next) = storage.list(Some(ListCursor::new("/b/c/d.txt")), 10)?;
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].size, 7);
+        assert_eq!(next, None);
+        let (files, next) = storage.list(None, 5)?;
+        assert_eq!(files.len(), 5);
+        assert_eq!(next, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_finds_files_by_tag() -> io::Result<()> {
+        let storage = test_storage("tags")?;
+        storage.store("/a.png", &b"a"[..])?;
+        storage.store("/b.png", &b"b"[..])?;
+        storage.store("/c.txt", &b"c"[..])?;
+        storage.tag("/a.png", "thumbnail")?;
+        storage.tag("/b.png", "thumbnail")?;
+        storage.tag("/b.png", "image")?;
+        storage.tag("/b.png", "image")?;
+        assert!(matches!(
+            storage.tag("/missing.png", "image"),
+            Err(Error::NotFound { .. })
+        ));
+        assert!(storage.tag("/c.txt", "").is_err());
+        assert_eq!(storage.tags("/b.png")?, ["thumbnail", "image"]);
+        assert_eq!(storage.find_by_tag("thumbnail"), ["/a.png", "/b.png"]);
+        assert!(storage.check()?.is_ok());
+
+        storage.store("/b.png", &b"new b"[..])?;
+        storage.rename("/b.png", "/c.txt")?;
+        assert_eq!(storage.find_by_tag("thumbnail"), ["/a.png", "/c.txt"]);
+        assert_eq!(storage.find_by_tag("image"), ["/c.txt"]);
+        assert!(storage.untag("/c.txt", "image")?);
+        assert!(!storage.untag("/c.txt", "image")?);
+        assert!(storage.find_by_tag("image").is_empty());
+
+        storage.delete("/a.png")?;
+        assert_eq!(storage.find_by_tag("thumbnail"), ["/c.txt"]);
+        assert_eq!(storage.meta().ref_count(&(MARKER_FILE, 0, 0)), 0);
+        assert!(storage.check()?.is_ok());
+
+        // the paths of a tag are kept in a few buckets
+        storage.create_dir("/many")?;
+        let paths: Vec<String> = (0..300)
+            .map(|i| format!("/many/{:03}-{}.bin", i, "x".repeat(40)))
+            .collect();
+        for path in &paths {
+            storage.store(path, &b"x"[..])?;
+            storage.tag(path, "many")?;
+        }
+        assert_eq!(storage.find_by_tag("many"), paths);
+        let buckets = storage.meta().namespace_entries(TAGS_NAMESPACE).count();
+        assert!(buckets > 3 && buckets < 10);
+        for path in &paths[..150] {
+            assert!(storage.untag(path, "many")?);
+        }
+        assert_eq!(storage.find_by_tag("many"), paths[150..]);
+        assert_eq!(storage.find_by_tag("thumbnail"), ["/c.txt"]);
+        assert!(storage.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_reports_storage_stats() -> io::Result<()> {
+        let storage = test_storage("stats")?;
+        storage.set_inline_threshold(16);
+        storage.create_dir("/dir")?;
+        storage.store("/dir/small.txt", &b"small"[..])?;
+        storage.store("/large.bin", &[1u8; 1000][..])?;
+        storage.store("/deleted.bin", &[2u8; 500][..])?;
+        storage.store("/last.bin", &[3u8; 100][..])?;
+        storage.delete("/deleted.bin")?;
+        storage.hard_link("/large.bin", "/link.bin")?;
+
+        let stats = storage.stats()?;
+        assert_eq!((stats.files, stats.dirs), (4, 1));
+        assert_eq!(stats.logical_bytes, 5 + 1000 + 1000 + 100);
+        assert_eq!((stats.index_entries, stats.inline_entries), (4, 1));
+        assert_eq!(stats.data_chunks, 2);
+        assert_eq!(stats.data_files.len(), 1);
+        assert_eq!(stats.data_files[0].size, 8 + 1000 + 8 + 500 + 8 + 100);
+        assert_eq!(stats.data_files[0].used, 8 + 1000 + 8 + 100);
+        assert_eq!(stats.physical_bytes, 5 + stats.data_files[0].size);
+        assert!(stats.fragmentation() > 31.0 && stats.fragmentation() < 32.0);
+        assert_eq!(stats.tree.entries, 5);
+        assert!(stats.tree.chunks >= 2);
+        assert!(stats.tree.free_bytes < stats.tree.file_size);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_shares_storages_between_threads() -> io::Result<()> {
+        let storage = Arc::new(test_storage("threads")?);
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let storage = Arc::clone(&storage);
+                std::thread::spawn(move || -> io::Result<()> {
+                    for j in 0..10 {
+                        let path = format!("/{}-{}.txt", i, j);
+                        storage.store(&path, path.as_bytes())?;
+                        let mut content = String::new();
+                        storage.get(&path)?.read_to_string(&mut content)?;
+                        assert_eq!(content, path);
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        assert_eq!(storage.read_dir("/")?.len(), 40);
+        assert!(storage.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_keeps_the_content_of_open_readers() -> io::Result<()> {
+        let storage = test_storage("open-readers")?;
+        storage.store("/a", &b"AAAAAAAA"[..])?;
+        storage.store("/b", &b"BBBBBBBB"[..])?;
+        let mut reader = storage.get("/b")?;
+        storage.delete("/b")?;
+        storage.store("/c", &b"CCCCCCCC"[..])?;
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        assert_eq!(content, b"BBBBBBBB");
+        content.clear();
+        storage.get("/c")?.read_to_end(&mut content)?;
+        assert_eq!(content, b"CCCCCCCC");
+
+        storage.set_max_data_file_size(4096);
+        storage.store("/chunked", &vec![1u8; 6000][..])?;
+        let mut reader = storage.get("/chunked")?;
+        storage.truncate("/chunked", 10)?;
+        storage.delete("/chunked")?;
+        storage.store("/d", &vec![2u8; 6000][..])?;
+        content.clear();
+        reader.read_to_end(&mut content)?;
+        assert_eq!(content, vec![1u8; 6000]);
+        drop(reader);
+        assert!(storage.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_counts_hard_links() -> io::Result<()> {
+        let storage = test_storage("links")?;
+        storage.store("/a.txt", &b"shared"[..])?;
+        storage.hard_link("/a.txt", "/b.txt")?;
+        assert_eq!(storage.link_count("/b.txt")?, 2);
+        let data_path = std::env::temp_dir().join("ifs-test-links/data-0.bin");
+        let size = fs::metadata(&data_path)?.len();
+
+        storage.delete("/a.txt")?;
+        assert_eq!(fs::metadata(&data_path)?.len(), size);
+        let mut content = String::new();
+        storage.get("/b.txt")?.read_to_string(&mut content)?;
+        assert_eq!(content, "shared");
+        assert_eq!(storage.link_count("/b.txt")?, 1);
+        storage.delete("/b.txt")?;
+        assert_eq!(fs::metadata(&data_path)?.len(), 0);
+        assert!(storage.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_reports_metrics() -> io::Result<()> {
+        #[derive(Default)]
+        struct Recorder(Mutex<HashMap<&'static str, Vec<f64>>>);
+
+        impl Recorder {
+            fn record(&self, name: &'static str, value: f64) {
+                self.0.lock().unwrap().entry(name).or_default().push(value);
+            }
+
+            fn values(&self, name: &str) -> Vec<f64> {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .get(name)
+                    .cloned()
+                    .unwrap_or_default()
+            }
+        }
+
+        impl Metrics for Recorder {
+            fn counter(&self, name: &'static str, value: u64) {
+                self.record(name, value as f64);
+            }
+
+            fn gauge(&self, name: &'static str, value: f64) {
+                self.record(name, value);
+            }
+
+            fn histogram(&self, name: &'static str, value: f64) {
+                self.record(name, value);
+            }
+        }
+
+        let storage = test_storage("metrics")?;
+        let recorder = Arc::new(Recorder::default());
+        storage.set_metrics(recorder.clone());
+        storage.set_inline_threshold(16);
+        storage.store("/small.txt", &b"small"[..])?;
+        storage.store("/large.bin", &[1u8; 1000][..])?;
+        assert_eq!(recorder.values(BYTES_WRITTEN).iter().sum::<f64>(), 1005.0);
+        assert_eq!(recorder.values(BLOBS_STORED).len(), 2);
+
+        storage.open_writer("/large.bin")?.write_all(b"abc")?;
+        assert_eq!(recorder.values(BYTES_WRITTEN).iter().sum::<f64>(), 1008.0);
+        let lookups = recorder.values(LOOKUP_SECONDS).len();
+        storage.get("/large.bin")?;
+        assert_eq!(recorder.values(LOOKUP_SECONDS).len(), lookups + 1);
+        storage.tree().lookup("/large.bin")?;
+        assert_eq!(recorder.values(TREE_LOOKUP_SECONDS).len(), 1);
+
+        let stats = storage.stats()?;
+        assert_eq!(recorder.values(FRAGMENTATION), vec![stats.fragmentation()]);
+        assert_eq!(
+            recorder.values(TREE_FRAGMENTATION),
+            vec![stats.tree.fragmentation()]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_notifies_subscribers() -> io::Result<()> {
+        let storage = test_storage("subscribe")?;
+        let events = storage.subscribe();
+        let path = |path: &str| path.to_string();
+
+        storage.create_dir_all("/a/b")?;
+        storage.store("/a/b/file.txt", &b"hello"[..])?;
+        storage.store("/a/b/file.txt", &b"world"[..])?;
+        storage.rename("/a/b/file.txt", "/a/file.txt")?;
+        storage.delete("/a/file.txt")?;
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                ChangeEvent::Created { path: path("/a") },
+                ChangeEvent::Created { path: path("/a/b") },
+                ChangeEvent::Created {
+                    path: path("/a/b/file.txt")
+                },
+                ChangeEvent::Modified {
+                    path: path("/a/b/file.txt")
+                },
+                ChangeEvent::Moved {
+                    from: path("/a/b/file.txt"),
+                    to: path("/a/file.txt")
+                },
+                ChangeEvent::Deleted {
+                    path: path("/a/file.txt")
+                },
+            ]
+        );
+
+        storage.begin_batch()?;
+        storage.store("/discarded.txt", &b"data"[..])?;
+        storage.rollback()?;
+        storage.begin_batch()?;
+        storage.store("/batch.txt", &b"data"[..])?;
+        assert!(events.try_recv().is_err());
+        storage.commit()?;
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![ChangeEvent::Created {
+                path: path("/batch.txt")
+            }]
+        );
+
+        drop(events);
+        storage.delete("/batch.txt")?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_enforces_quotas() -> io::Result<()> {
+        let storage = test_storage("quota")?;
+        storage.set_quota(Some(Quota {
+            max_bytes: Some(100),
+            max_entries: Some(2),
+            policy: LowSpacePolicy::Reject,
+        }));
+        storage.store("/a.bin", &[1u8; 60][..])?;
+        assert!(matches!(
+            storage.store("/b.bin", &[2u8; 60][..]),
+            Err(Error::QuotaExceeded { .. })
+        ));
+        assert!(storage.get("/b.bin").is_err());
+        storage.store("/a.bin", &[1u8; 90][..])?;
+        storage.store("/b.bin", &[2u8; 10][..])?;
+        assert!(matches!(
+            storage.store("/c.bin", &[][..]),
+            Err(Error::QuotaExceeded { .. })
+        ));
+        assert!(storage.check()?.is_ok());
+
+        storage.set_quota(Some(Quota {
+            max_bytes: Some(100),
+            max_entries: None,
+            policy: LowSpacePolicy::Evict,
+        }));
+        storage.store("/c.bin", &[3u8; 50][..])?;
+        assert!(storage.get("/a.bin").is_err());
+        assert_eq!(storage.get("/b.bin")?.remaining(), 10);
+        storage.pin("/b.bin")?;
+        storage.pin("/c.bin")?;
+        assert!(matches!(
+            storage.store("/d.bin", &[4u8; 50][..]),
+            Err(Error::QuotaExceeded { .. })
+        ));
+        assert!(storage.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_isolates_namespaces() -> io::Result<()> {
+        let storage = test_storage("namespaces")?;
+        let first = storage.namespace("user-1")?;
+        let second = storage.namespace("user-2")?;
+        first.create_dir("/docs")?;
+        first.store("/docs/a.txt", &b"first"[..])?;
+        first.store("/b.txt", &b"first"[..])?;
+        second.create_dir("/docs")?;
+        second.store("/docs/a.txt", &b"second!"[..])?;
+        assert!(second.store("/../user-1/b.txt", &b"escaped"[..]).is_err());
+
+        assert_eq!(first.get("/docs/a.txt")?.remaining(), 5);
+        assert_eq!(second.get("/docs/a.txt")?.remaining(), 7);
+        assert_eq!(first.get("/b.txt")?.remaining(), 5);
+        assert!(second.get("/b.txt").is_err());
+        assert_eq!(
+            storage
+                .get(&format!("{}/user-1/b.txt", NAMESPACES_DIR))?
+                .remaining(),
+            5
+        );
+        assert_eq!(storage.namespaces()?, vec!["user-1", "user-2"]);
+        assert!(storage.namespace("a/b").is_err());
+
+        let (files, cursor) = first.list(None, 1)?;
+        assert_eq!(files[0].path, "/b.txt");
+        let (files, cursor) = first.list(cursor, 1)?;
+        assert_eq!(files[0].path, "/docs/a.txt");
+        assert!(cursor.is_none());
+        assert_eq!((first.usage()?.files, first.usage()?.bytes), (2, 10));
+
+        second.set_quota(Some(Quota {
+            max_bytes: Some(10),
+            max_entries: None,
+            policy: LowSpacePolicy::Reject,
+        }));
+        assert!(matches!(
+            second.store("/c.txt", &b"four"[..]),
+            Err(Error::QuotaExceeded { .. })
+        ));
+        first.store("/c.txt", &b"four"[..])?;
+        second.set_quota(None);
+        second.store("/c.txt", &b"four"[..])?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_writes_an_audit_log() -> io::Result<()> {
+        let storage = test_storage("audit")?;
+        storage.store("/before.txt", &b"not recorded"[..])?;
+        let actor = Arc::new(Mutex::new(String::from("alice")));
+        let current = Arc::clone(&actor);
+        storage.enable_audit_log(move || current.lock().unwrap().clone())?;
+        storage.store("/a.txt", &b"hello"[..])?;
+        *actor.lock().unwrap() = String::from("bob");
+        storage.rename("/a.txt", "/b.txt")?;
+        storage.delete("/b.txt")?;
+
+        let records = storage.audit_log()?.collect::<Result<Vec<_>, _>>()?;
+        let changes: Vec<(&str, &ChangeEvent)> = records
+            .iter()
+            .map(|record| (record.actor.as_str(), &record.change))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    "alice",
+                    &ChangeEvent::Created {
+                        path: String::from("/a.txt")
+                    }
+                ),
+                (
+                    "bob",
+                    &ChangeEvent::Moved {
+                        from: String::from("/a.txt"),
+                        to: String::from("/b.txt")
+                    }
+                ),
+                (
+                    "bob",
+                    &ChangeEvent::Deleted {
+                        path: String::from("/b.txt")
+                    }
+                ),
+            ]
+        );
+        assert!(records[0].time <= records[2].time);
+
+        // an interrupted write leaves an incomplete record that isn't read
+        let path = std::env::temp_dir()
+            .join("ifs-test-audit")
+            .join(AUDIT_FILE_NAME);
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(&[0, 0, 0, 40, 1])?;
+        assert_eq!(storage.audit_log()?.count(), 3);
+        storage.enable_audit_log(|| String::from("carol"))?;
+        storage.store("/c.txt", &b"hello"[..])?;
+        let records = storage.audit_log()?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[3].actor, "carol");
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_refuses_changes_after_a_failed_audit_record() -> io::Result<()> {
+        struct FailingWriter;
+
+        impl Write for FailingWriter {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk full"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let storage = test_storage("audit-failure")?;
+        let events = storage.subscribe();
+        storage.enable_audit_log(|| String::from("alice"))?;
+        storage.store("/a.txt", &b"hello"[..])?;
+        storage.set_audit_log(AuditLog::new(
+            Box::new(FailingWriter),
+            Box::new(|| String::from("bob")),
+        ))?;
+
+        // the change is made and sent but reported as failed
+        let result = storage.store("/b.txt", &b"world"[..]);
+        assert!(matches!(result, Err(Error::AuditLogFailed { .. })));
+        assert_eq!(storage.read_path("/b.txt")?, b"world");
+        assert_eq!(events.try_iter().count(), 2);
+
+        // further changes are refused until the log is enabled again
+        let result = storage.store("/c.txt", &b"!"[..]);
+        assert!(matches!(result, Err(Error::AuditLogFailed { .. })));
+        assert!(storage.entry("/c.txt").is_err());
+        storage.enable_audit_log(|| String::from("carol"))?;
+        storage.store("/c.txt", &b"!"[..])?;
+        let records = storage.audit_log()?.collect::<Result<Vec<_>, _>>()?;
+        let actors: Vec<&str> = records.iter().map(|r| r.actor.as_str()).collect();
+        assert_eq!(actors, vec!["alice", "bob", "carol"]);
+        assert_eq!(
+            records[1].change,
+            ChangeEvent::Created {
+                path: String::from("/b.txt")
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_replicates_changes() -> io::Result<()> {
+        let primary = test_storage("replication-primary")?;
+        let replica = test_storage("replication-replica")?;
+        primary.enable_replication_log()?;
+        primary.create_dir_all("/docs/old")?;
+        primary.store("/docs/a.txt", &b"hello"[..])?;
+        primary.store("/docs/b.txt", &b"world"[..])?;
+        primary.create_symlink("/link", "/docs/a.txt")?;
+        primary.rename("/docs/b.txt", "/docs/c.txt")?;
+        primary.delete("/docs/old")?;
+        let first = replica.apply_replication(primary.replication_log(0)?)?;
+        assert!(first > 0);
+
+        primary.begin_batch()?;
+        primary.store("/docs/d.txt", &b"batched"[..])?;
+        primary.store("/docs/e.txt", &b"discarded"[..])?;
+        primary.rollback()?;
+        primary.begin_batch()?;
+        primary.store("/docs/d.txt", &b"batched"[..])?;
+        primary.commit()?;
+        primary.open_writer("/docs/a.txt")?.write_all(b"HELLO")?;
+        let last = replica.apply_replication(primary.replication_log(first)?)?;
+        assert_eq!(last, first + 2);
+        assert_eq!(primary.replication_log(last)?.limit(), 0);
+
+        let read = |storage: &Storage, path: &str| -> io::Result<Vec<u8>> {
+            let mut content = Vec::new();
+            storage.get(path)?.read_to_end(&mut content)?;
+            Ok(content)
+        };
+        for path in ["/docs/a.txt", "/docs/c.txt", "/docs/d.txt"] {
+            assert_eq!(read(&replica, path)?, read(&primary, path)?);
+        }
+        assert_eq!(
+            replica.entry("/link")?.symlink_target(),
+            Some("/docs/a.txt")
+        );
+        assert!(replica.entry("/docs/b.txt").is_err());
+        assert!(replica.entry("/docs/e.txt").is_err());
+        assert!(replica.entry("/docs/old").is_err());
+
+        // applying the whole log again leaves the replica unchanged
+        assert_eq!(
+            replica.apply_replication(primary.replication_log(0)?)?,
+            last
+        );
+        assert_eq!(read(&replica, "/docs/d.txt")?, b"batched");
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_stores_by_path() -> io::Result<()> {
+        let storage = test_storage("paths")?;
+        storage.store_at("/docs/2024/report.pdf", &b"report"[..])?;
+        storage.store_at("/docs/notes.txt", &b"notes"[..])?;
+        assert_eq!(storage.read_path("/docs/2024/report.pdf")?, b"report");
+        let names: Vec<String> = storage
+            .read_dir("/docs")?
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&String::from("2024")));
+
+        storage.remove_path("/docs/notes.txt")?;
+        storage.remove_path("/docs")?;
+        assert!(storage.read_dir("/")?.is_empty());
+        assert!(storage.read_path("/docs/2024/report.pdf").is_err());
+        assert!(storage.remove_path("/docs").is_err());
+        assert_eq!(storage.stats()?.index_entries, 0);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn it_emits_tracing_spans_and_events() -> io::Result<()> {
+        use std::fmt::Debug;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        #[derive(Clone, Default)]
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        impl Visit for Recorder {
+            fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+                if field.name() == "message" {
+                    self.0.lock().unwrap().push(format!("{:?}", value));
+                }
+            }
+        }
+
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut lines = self.0.lock().unwrap();
+                lines.push(format!("span {}", span.metadata().name()));
+                Id::from_u64(lines.len() as u64)
+            }
+
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                event.record(&mut self.clone());
+            }
+
+            fn enter(&self, _: &Id) {}
+
+            fn exit(&self, _: &Id) {}
+        }
+
+        let storage = test_storage("tracing")?;
+        storage.store("/big", &vec![1u8; 100_000][..])?;
+        storage.store("/small", &b"0123456789"[..])?;
+        storage.delete("/big")?;
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || storage.vacuum())?;
+
+        let lines = recorder.0.lock().unwrap();
+        assert!(lines.contains(&String::from("span vacuum")));
+        assert!(lines.contains(&String::from("vacuumed 100008 bytes of the data files")));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "vfs")]
+    #[test]
+    fn it_implements_the_vfs_file_system() -> io::Result<()> {
+        use crate::vfs::StorageVfs;
+        use ::vfs::{VfsFileType, VfsPath};
+
+        fn vfs<T>(result: ::vfs::VfsResult<T>) -> io::Result<T> {
+            result.map_err(io::Error::other)
+        }
+
+        let storage = Arc::new(test_storage("vfs")?);
+        let root = VfsPath::new(StorageVfs::new(Arc::clone(&storage)));
+        let docs = vfs(root.join("docs/2024"))?;
+        vfs(docs.create_dir_all())?;
+        let file = vfs(docs.join("a.txt"))?;
+        let mut writer = vfs(file.create_file())?;
+        writer.write_all(b"hello")?;
+        writer.flush()?;
+        assert_eq!(storage.read_path("/docs/2024/a.txt")?, b"hello");
+        drop(writer);
+        let mut writer = vfs(file.append_file())?;
+        writer.write_all(b" wor")?;
+        writer.flush()?;
+        assert_eq!(storage.read_path("/docs/2024/a.txt")?, b"hello wor");
+        writer.seek(SeekFrom::Start(0))?;
+        writer.write_all(b"ld")?;
+        drop(writer);
+
+        assert_eq!(vfs(file.read_to_string())?, "hello world");
+        assert_eq!(storage.read_path("/docs/2024/a.txt")?, b"hello world");
+        let mut reader = vfs(file.open_file())?;
+        reader.seek(SeekFrom::Start(6))?;
+        let mut content = String::new();
+        reader.read_to_string(&mut content)?;
+        assert_eq!(content, "world");
+        reader.seek(SeekFrom::End(-11))?;
+        let mut start = [0; 5];
+        reader.read_exact(&mut start)?;
+        assert_eq!(&start, b"hello");
+
+        let metadata = vfs(file.metadata())?;
+        assert_eq!(metadata.file_type, VfsFileType::File);
+        assert_eq!(metadata.len, 11);
+        assert!(vfs(docs.is_dir())?);
+        assert!(vfs(root.is_dir())?);
+        let names: Vec<String> = vfs(root.join("docs"))
+            .and_then(|dir| vfs(dir.read_dir()))?
+            .map(|path| path.filename())
+            .collect();
+        assert_eq!(names, vec!["2024"]);
+
+        let copy = vfs(root.join("copy.txt"))?;
+        vfs(file.copy_file(&copy))?;
+        let moved = vfs(root.join("moved.txt"))?;
+        vfs(copy.move_file(&moved))?;
+        assert!(!vfs(copy.exists())?);
+        assert_eq!(vfs(moved.read_to_string())?, "hello world");
+        assert!(docs.remove_dir().is_err());
+        vfs(file.remove_file())?;
+        vfs(docs.remove_dir())?;
+        assert!(!vfs(docs.exists())?);
+
+        let mut writer = vfs(moved.append_file())?;
+        writer.write_all(b"!")?;
+        storage.delete("/moved.txt")?;
+        storage.create_dir("/moved.txt")?;
+        assert!(writer.flush().is_err());
+        storage.delete("/moved.txt")?;
+        drop(writer);
+        assert_eq!(vfs(moved.read_to_string())?, "!");
+
+        let drafts = vfs(root.join("drafts"))?;
+        vfs(drafts.create_dir())?;
+        vfs(vfs(drafts.join("b.txt"))?.create_file())?.write_all(b"draft")?;
+        let published = vfs(root.join("docs/published"))?;
+        vfs(drafts.move_dir(&published))?;
+        assert!(!vfs(drafts.exists())?);
+        assert_eq!(
+            vfs(vfs(published.join("b.txt"))?.read_to_string())?,
+            "draft"
+        );
+        assert!(vfs(root.join("missing.txt"))?.open_file().is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn it_exposes_a_c_interface() -> io::Result<()> {
+        use crate::ffi::*;
+        use std::ffi::{CStr, CString};
+
+        let path = std::env::temp_dir().join("ifs-test-ffi");
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        let file = CString::new("/docs/a.txt").unwrap();
+        let dir = CString::new("/docs").unwrap();
+        unsafe {
+            let mut storage = std::ptr::null_mut();
+            assert_eq!(ifs_open(path.as_ptr(), &mut storage), IFS_OK);
+            assert_eq!(
+                ifs_store(storage, file.as_ptr(), b"hello".as_ptr(), 5),
+                IFS_OK
+            );
+
+            let mut data = std::ptr::null_mut();
+            let mut length = 0;
+            assert_eq!(
+                ifs_get(storage, file.as_ptr(), &mut data, &mut length),
+                IFS_OK
+            );
+            assert_eq!(std::slice::from_raw_parts(data, length), b"hello");
+            ifs_free_buffer(data, length);
+
+            let mut list = std::ptr::null_mut();
+            assert_eq!(ifs_list(storage, dir.as_ptr(), &mut list), IFS_OK);
+            assert_eq!(ifs_list_len(list), 1);
+            assert_eq!(CStr::from_ptr(ifs_list_name(list, 0)).to_bytes(), b"a.txt");
+            assert!(ifs_list_name(list, 1).is_null());
+            ifs_list_free(list);
+
+            assert_eq!(
+                ifs_delete(storage, dir.as_ptr()),
+                IFS_ERROR_DIRECTORY_NOT_EMPTY
+            );
+            assert_eq!(ifs_delete(storage, file.as_ptr()), IFS_OK);
+            assert_eq!(
+                ifs_get(storage, file.as_ptr(), &mut data, &mut length),
+                IFS_ERROR_NOT_FOUND
+            );
+            assert_eq!(
+                ifs_delete(storage, std::ptr::null()),
+                IFS_ERROR_INVALID_ARGUMENT
+            );
+            ifs_close(storage);
+        }
+
+        // the header declares every exported function
+        let header = include_str!("../include/indexed_file_storage.h");
+        for line in include_str!("ffi.rs").lines() {
+            if let Some(name) = line
+                .strip_prefix("pub unsafe extern \"C\" fn ")
+                .and_then(|rest| rest.split('(').next())
+            {
+                assert!(header.contains(&format!("{}(", name)), "{}", name);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_reads_containers_from_memory() -> io::Result<()> {
+        let storage = test_storage("memory-container")?;
+        storage.set_inline_threshold(8);
+        storage.store_at("/docs/a.txt", &b"inline"[..])?;
+        storage.store_at("/docs/b.txt", &[7u8; 1000][..])?;
+        drop(storage);
+
+        let path = std::env::temp_dir().join("ifs-test-memory-container");
+        let mut files = HashMap::new();
+        files.insert(0, fs::read(path.join("data-0.bin"))?);
+        let data = MemoryDataBackend::from_files(files);
+        let mut container = MemoryContainer::open(
+            fs::read(path.join("tree.dft"))?,
+            fs::read(path.join("index.meta"))?,
+            Arc::new(data),
+        )?;
+        assert_eq!(container.read_dir("/docs")?.len(), 2);
+        assert!(container.entry("/docs")?.is_dir());
+        let mut content = Vec::new();
+        container.get("/docs/a.txt")?.read_to_end(&mut content)?;
+        assert_eq!(content, b"inline");
+        content.clear();
+        container.get("/docs/b.txt")?.read_to_end(&mut content)?;
+        assert_eq!(content, vec![7u8; 1000]);
+        assert!(container.get("/docs/c.txt").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_parses_formats_without_std() -> io::Result<()> {
+        use crate::format::meta::MetaTable;
+        use crate::format::tree::{read_dir, TreeHeader};
+        use crate::format::SliceReader;
+
+        let storage = test_storage("format-core")?;
+        storage.store_at("/docs/a.txt", &[7u8; 1000][..])?;
+        drop(storage);
+
+        let path = std::env::temp_dir().join("ifs-test-format-core");
+        let tree = fs::read(path.join("tree.dft"))?;
+        let mut reader = SliceReader(&tree);
+        let header = TreeHeader::read(&mut reader).map_err(Error::from)?;
+        let root = read_dir(&mut reader, header.root).map_err(Error::from)?;
+        assert_eq!(root.len(), 1);
+        assert!(root[0].is_dir());
+        let docs = read_dir(&mut reader, root[0].child_pointer()).map_err(Error::from)?;
+        assert_eq!(docs[0].name, "a.txt");
+
+        let meta = fs::read(path.join("index.meta"))?;
+        let table = MetaTable::parse(&meta, u32::MAX as u64).map_err(Error::from)?;
+        let id = HashAlgorithm::Sha256.hash_id("/docs/a.txt");
+        assert_eq!(table.entries.get(&id).map(|e| (e.0, e.2)), Some((0, 1000)));
+        assert!(MetaTable::parse(&meta[..meta.len() - 1], u32::MAX as u64).is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "serve")]
+    #[test]
+    fn it_serves_files_over_http() -> io::Result<()> {
+        use crate::serve::Server;
+        use std::net::TcpStream;
+
+        let storage = Arc::new(test_storage("serve")?);
+        let mut server = Server::bind(Arc::clone(&storage), "127.0.0.1:0")?;
+        server.set_timeout(Duration::from_millis(500));
+        let address = server.local_addr()?;
+        std::thread::spawn(move || server.run());
+        let request = |request: &str| -> io::Result<String> {
+            let mut stream = TcpStream::connect(address)?;
+            let (line, rest) = request.split_once("\r\n").unwrap();
+            write!(stream, "{}\r\nConnection: close\r\n{}", line, rest)?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok(response)
+        };
+
+        let response =
+            request("PUT /docs/a%20b.txt HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world")?;
+        assert!(response.starts_with("HTTP/1.1 201 "));
+        assert_eq!(storage.read_path("/docs/a b.txt")?, b"hello world");
+        let response = request("GET /docs/a%20b.txt HTTP/1.1\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 200 "));
+        assert!(response.ends_with("\r\n\r\nhello world"));
+        let response = request("GET /docs/a%20b.txt HTTP/1.1\r\nRange: bytes=6-\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 206 "));
+        assert!(response.contains("Content-Range: bytes 6-10/11\r\n"));
+        assert!(response.ends_with("\r\n\r\nworld"));
+        let response = request("GET /docs/a%20b.txt HTTP/1.1\r\nRange: bytes=-3\r\n\r\n")?;
+        assert!(response.ends_with("\r\n\r\nrld"));
+        let response = request("GET /docs/a%20b.txt HTTP/1.1\r\nRange: bytes=20-\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 416 "));
+        assert!(response.contains("Content-Range: bytes */11\r\n"));
+        let response = request("GET /docs/a%20b.txt HTTP/1.1\r\nRange: bytes=-0\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 416 "));
+        for range in ["bytes=0-1,4-5", "bytes=5-2", "bytes=x-", "items=0-1"] {
+            let response = request(&format!(
+                "GET /docs/a%20b.txt HTTP/1.1\r\nRange: {}\r\n\r\n",
+                range
+            ))?;
+            assert!(response.starts_with("HTTP/1.1 200 "));
+            assert!(response.ends_with("\r\n\r\nhello world"));
+        }
+        let response = request("HEAD /docs/a%20b.txt HTTP/1.1\r\n\r\n")?;
+        assert!(response.contains("\r\nContent-Length: 11\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+        let response = request("GET /docs HTTP/1.1\r\n\r\n")?;
+        assert!(response.ends_with("\r\n\r\na b.txt\n"));
+        let response = request("DELETE /docs HTTP/1.1\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 409 "));
+        let response = request("DELETE /docs/a%20b.txt HTTP/1.1\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 204 "));
+        let response = request("GET /docs/a%20b.txt HTTP/1.1\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 404 "));
+        let response = request("nonsense\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 400 "));
+        let response = request("PUT /docs/b.txt HTTP/1.1\r\n\r\nhello")?;
+        assert!(response.starts_with("HTTP/1.1 411 "));
+
+        // a body shorter than its length isn't stored
+        let mut stream = TcpStream::connect(address)?;
+        stream.write_all(b"PUT /docs/b.txt HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello")?;
+        stream.shutdown(std::net::Shutdown::Write)?;
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        assert!(response.is_empty() || response.starts_with("HTTP/1.1 400 "));
+        assert!(matches!(
+            storage.entry("/docs/b.txt"),
+            Err(Error::NotFound { .. })
+        ));
+        // stalled connections are closed after the timeout
+        let mut stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let started = std::time::Instant::now();
+        stream.write_all(b"PUT /docs/b.txt HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello")?;
+        // the storage isn't locked while the body is received
+        let response = request("GET /docs HTTP/1.1\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 200 "));
+        let _ = stream.read_to_string(&mut String::new());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(matches!(
+            storage.entry("/docs/b.txt"),
+            Err(Error::NotFound { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_reports_progress() -> io::Result<()> {
+        let storage = test_storage("progress")?;
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        zip.add_directory("dir/", options)?;
+        zip.start_file("dir/a.txt", options)?;
+        zip.write_all(b"hello")?;
+        zip.start_file("b.txt", options)?;
+        zip.write_all(b"world!")?;
+        let zip = zip.finish()?.into_inner();
+
+        let mut reports = Vec::new();
+        storage.import_archive_with(
+            Cursor::new(zip),
+            ArchiveFormat::Zip,
+            "/",
+            OperationOptions::new().progress(|p| reports.push(p)),
+        )?;
+        assert_eq!(
+            reports.last(),
+            Some(&Progress {
+                items: 2,
+                total: Some(2),
+                bytes: 11
+            })
+        );
+
+        let mut checked = Progress::default();
+        assert!(storage
+            .check_with(OperationOptions::new().progress(|p| checked = p))?
+            .is_ok());
+        assert_eq!(
+            (checked.items, checked.total, checked.bytes),
+            (2, Some(2), 11)
+        );
+        let mut copied = 0;
+        storage.compact_with(OperationOptions::new().progress(|p| copied = p.items))?;
+        assert_eq!(copied, 3);
+
+        let mut exported = Progress::default();
+        let mut json = Vec::new();
+        storage.tree().to_json_with(
+            &mut json,
+            OperationOptions::new().progress(|p| exported = p),
+        )?;
+        assert_eq!(exported.items, 3);
+        let mut imported = 0;
+        let mut copy = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        copy.from_json_with(
+            &mut &json[..],
+            OperationOptions::new().progress(|p| imported = p.items),
+        )?;
+        assert_eq!(imported, 3);
+
+        storage.delete("/dir/a.txt")?;
+        let mut moved = Progress::default();
+        storage.vacuum_with(OperationOptions::new().progress(|p| moved = p))?;
+        assert_eq!((moved.items, moved.bytes), (1, 14));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_cancels_long_operations() -> io::Result<()> {
+        let storage = test_storage("cancel")?;
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        for name in &["a.txt", "b.txt", "c.txt"] {
+            zip.start_file(*name, options)?;
+            zip.write_all(name.as_bytes())?;
+        }
+        let zip = zip.finish()?.into_inner();
+
+        let cancel = CancelToken::new();
+        let result = storage.import_archive_with(
+            Cursor::new(zip),
+            ArchiveFormat::Zip,
+            "/",
+            OperationOptions::new()
+                .progress(|_| cancel.cancel())
+                .cancel(&cancel),
+        );
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert!(storage.get("/a.txt").is_ok());
+        assert!(storage.get("/b.txt").is_err());
+        assert!(matches!(
+            storage.check_with(OperationOptions::new().cancel(&cancel)),
+            Err(Error::Cancelled)
+        ));
+        assert!(matches!(
+            storage.compact_with(OperationOptions::new().cancel(&cancel)),
+            Err(Error::Cancelled)
+        ));
+        let mut json = Vec::new();
+        assert!(matches!(
+            storage
+                .tree()
+                .to_json_with(&mut json, OperationOptions::new().cancel(&cancel)),
+            Err(Error::Cancelled)
+        ));
+        assert!(json.is_empty());
+        storage.tree().to_json(&mut json)?;
+        let mut copy = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        assert!(matches!(
+            copy.from_json_with(&mut &json[..], OperationOptions::new().cancel(&cancel)),
+            Err(Error::Cancelled)
+        ));
+        assert!(copy.entries()?.is_empty());
+        storage.store("/big.txt", &vec![0u8; 1000][..])?;
+        storage.store("/b.txt", &b"b.txt"[..])?;
+        storage.delete("/big.txt")?;
+        assert!(matches!(
+            storage.vacuum_with(OperationOptions::new().cancel(&cancel)),
+            Err(Error::Cancelled)
+        ));
+        assert!(storage.check()?.is_ok());
+        assert!(storage.check()?.is_ok());
+        let mut content = String::new();
+        storage.get("/a.txt")?.read_to_string(&mut content)?;
+        assert_eq!(content, "a.txt");
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_imports_archives() -> io::Result<()> {
+        let storage = test_storage("import")?;
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(3);
+        header.set_cksum();
+        tar.append_data(&mut header, "a/b/c.txt", &b"tar"[..])?;
+        let tar = tar.into_inner()?;
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        zip.start_file("d.txt", options)?;
+        zip.write_all(b"zip")?;
+        let zip = zip.finish()?.into_inner();
+
+        assert_eq!(
+            storage.import_archive(Cursor::new(tar), ArchiveFormat::Tar, "/t")?,
+            1
+        );
+        assert_eq!(
+            storage.import_archive(Cursor::new(zip), ArchiveFormat::Zip, "/z")?,
+            1
+        );
+        let mut content = String::new();
+        storage.get("/t/a/b/c.txt")?.read_to_string(&mut content)?;
+        storage.get("/z/d.txt")?.read_to_string(&mut content)?;
+        assert_eq!(content, "tarzip");
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_checks_and_repairs_storages() -> io::Result<()> {
+        let storage = test_storage("check")?;
+        storage.store("/a.txt", &b"a"[..])?;
+        storage.store("/b.txt", &b"b"[..])?;
+        storage.create_dir("/empty")?;
+        assert!(storage.check()?.is_ok());
+
+        // deleted directories free their chunks so the leak is simulated
+        let tree_path = std::env::temp_dir().join("ifs-test-check/tree.dft");
+        fs::OpenOptions::new()
+            .append(true)
+            .open(tree_path)?
+            .write_all(&[0u8; 1038])?;
+        storage.tree().cd("/")?;
+        storage.tree().delete_entry("a.txt")?;
+        storage.tree().create_entry("c.txt", false)?;
+        let report = storage.check()?;
+        assert_eq!(report.unreachable_chunks.len(), 1);
+        assert_eq!(report.dangling_entries.len(), 1);
+        assert_eq!(report.missing_entries, vec!["/c.txt".to_string()]);
+
+        storage.repair()?;
+        assert!(storage.check()?.is_ok());
+        assert!(storage.get("/b.txt").is_ok());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn it_serializes_public_types() -> io::Result<()> {
+        use crate::dirtreefile::{DirEntry, DirStats};
+        use crate::metafile::MetaEntry;
+        use crate::storage::{CheckReport, StorageStats};
+
+        let report = CheckReport {
+            unreachable_chunks: vec![(16, 1038)],
+            dangling_entries: vec![hash_id("a")],
+            missing_entries: vec!["/c.txt".to_string()],
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&report)?;
+        let read: CheckReport = serde_json::from_str(&json)?;
+        assert_eq!(read.unreachable_chunks, report.unreachable_chunks);
+        assert_eq!(read.dangling_entries, report.dangling_entries);
+        assert_eq!(read.missing_entries, report.missing_entries);
+        assert!(read.overlapping_chunks.is_empty());
+        assert!(serde_json::from_str::<DirStats>("{\"files\": 1}").is_err());
+
+        let storage = test_storage("serde")?;
+        storage.store("/a.txt", &b"hello"[..])?;
+        let stats = storage.stats()?;
+        let read: StorageStats = serde_json::from_str(&serde_json::to_string(&stats)?)?;
+        assert_eq!(read, stats);
+        let stats = storage.tree().dir_stats("/")?;
+        let read: DirStats = serde_json::from_value(serde_json::to_value(stats)?)?;
+        assert_eq!(read, stats);
+
+        let entry = storage.entry("/a.txt")?;
+        let read: DirEntry = serde_json::from_str(&serde_json::to_string(&entry)?)?;
+        assert_eq!(read.name, "a.txt");
+        assert_eq!(read.blob_id(), entry.blob_id());
+        assert_eq!(read.metadata(), entry.metadata());
+        let location = *storage
+            .meta()
+            .get_entry_raw(&entry.blob_id().unwrap())
+            .unwrap();
+        let read: MetaEntry = serde_json::from_str(&serde_json::to_string(&location)?)?;
+        assert_eq!(read, location);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_expires_files() -> io::Result<()> {
+        let storage = test_storage("expire")?;
+        storage.store("/a.txt", &b"a"[..])?;
+        storage.store("/b.txt", &b"b"[..])?;
+        storage.store("/c.txt", &b"c"[..])?;
+        let past = UNIX_EPOCH + Duration::from_secs(1);
+        let future = UNIX_EPOCH + Duration::from_secs(u32::MAX as u64);
+        storage.set_expiry("/a.txt", Some(past))?;
+        storage.set_expiry("/b.txt", Some(future))?;
+        storage.set_expiry("/c.txt", Some(past))?;
+        storage.set_expiry("/c.txt", None)?;
+        assert_eq!(storage.meta().expiry("/b.txt"), Some(future));
+        assert_eq!(storage.meta().expiry("/c.txt"), None);
+        assert!(storage.set_expiry("/d.txt", Some(past)).is_err());
+
+        assert_eq!(storage.expire_now()?, 1);
+        assert!(matches!(storage.get("/a.txt"), Err(Error::NotFound { .. })));
+        assert_eq!(storage.read_dir("/")?.len(), 2);
+        assert!(storage.check()?.is_ok());
+        assert_eq!(storage.expire_now()?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_reports_typed_errors() -> io::Result<()> {
+        let storage = test_storage("errors")?;
+        storage.store("/a.txt", &b"a"[..])?;
+
+        match storage.get("/missing.txt") {
+            Err(Error::NotFound { path }) => assert_eq!(path, "/missing.txt"),
+            _ => panic!("expected a not found error"),
+        }
+        assert!(matches!(
+            storage.read_dir("/a.txt"),
+            Err(Error::NotADirectory { .. })
+        ));
+        assert!(matches!(
+            storage.create_dir(&format!("/{}", "a".repeat(2048))),
+            Err(Error::NameTooLong { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_locks_storages() -> io::Result<()> {
+        let storage = test_storage("lock")?;
+        storage.store("/a.txt", &b"a"[..])?;
+        let path = std::env::temp_dir().join("ifs-test-lock");
+        assert!(matches!(
+            Storage::try_open(path.clone()),
+            Err(Error::Locked { .. })
+        ));
+        drop(storage);
+
+        let reader = Storage::open_read_only(path.clone())?;
+        let other_reader = Storage::open_read_only(path.clone())?;
+        assert_eq!(other_reader.get("/a.txt")?.remaining(), 1);
+        assert!(matches!(
+            reader.store("/b.txt", &b"b"[..]),
+            Err(Error::ReadOnly { .. })
+        ));
+        assert!(matches!(Storage::try_open(path), Err(Error::Locked { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_keeps_trees_in_memory() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_entry("dir", true)?;
+        tree.cd("dir")?;
+        for i in 0..100 {
+            tree.create_entry(&format!("file-{}", i), false)?;
+        }
+        let mut tree = DirTreeFile::from_backend(tree.into_inner())?;
+        tree.cd("/dir")?;
+        assert_eq!(tree.entries()?.len(), 100);
+        assert!(tree.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_renames_entries() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_entry("dir", true)?;
+        tree.cd("dir")?;
+        tree.create_entry("file", false)?;
+        tree.cd("/")?;
+        tree.rename_entry("dir", "renamed")?;
+        tree.set_metadata("renamed", EntryMetadata::new(0))?;
+        // fills the root chunk so that the long name has to be moved to a new chunk
+        for i in 0..60 {
+            tree.create_entry(&format!("file-{}", i), false)?;
+        }
+        tree.rename_entry("renamed", &"a".repeat(200))?;
+        assert!(matches!(
+            tree.rename_entry("file-0", "file-1"),
+            Err(Error::AlreadyExists { .. })
+        ));
+        assert!(!tree.has_entry("dir")?);
+        assert!(tree
+            .lookup(&"a".repeat(200))?
+            .is_some_and(|e| e.metadata().is_some()));
+        tree.cd(&format!("/{}", "a".repeat(200)))?;
+        assert!(tree.has_entry("file")?);
+        assert!(tree.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_moves_entries() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_entry("a", true)?;
+        tree.create_entry("b", true)?;
+        tree.cd("a")?;
+        tree.create_entry("c", true)?;
+        tree.cd("c")?;
+        tree.create_entry("file", false)?;
+        tree.move_entry("/a/c", "/b")?;
+        assert_eq!(tree.dir(), "/b/c");
+        assert!(tree.has_entry("file")?);
+        tree.cd("/")?;
+        assert!(tree.lookup("/b/c")?.is_some_and(|e| e.is_dir()));
+        assert!(tree.lookup("b/c/file")?.is_some());
+        assert!(tree.lookup("/a/c")?.is_none());
+        assert!(matches!(
+            tree.lookup("/b/c/file/x"),
+            Err(Error::NotADirectory { .. })
+        ));
+        assert_eq!(tree.dir(), "/");
+        assert!(matches!(
+            tree.move_entry("/b", "/b/c"),
+            Err(Error::InvalidName { .. })
+        ));
+        tree.cd("/a")?;
+        assert!(tree.entries()?.is_empty());
+        assert!(tree.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_rejects_moves_into_the_own_subtree() -> io::Result<()> {
+        let options = TreeOptions {
+            case_insensitive: true,
+            ..TreeOptions::default()
+        };
+        let mut tree = DirTreeFile::from_backend_with_options(Cursor::new(Vec::new()), options)?;
+        tree.create_dir_all("/a/b")?;
+        assert!(matches!(
+            tree.move_entry("/A", "/a/b"),
+            Err(Error::InvalidName { .. })
+        ));
+        assert!(tree.lookup("/a/b")?.is_some());
+        assert_eq!(tree.dir(), "/");
+        assert!(tree.check()?.is_ok());
+
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_dir_all("/a/b")?;
+        tree.create_symlink("l", "/a")?;
+        assert!(matches!(
+            tree.move_entry("/a", "/l/b"),
+            Err(Error::InvalidName { .. })
+        ));
+        assert!(matches!(
+            tree.move_entry("/a", "/l"),
+            Err(Error::InvalidName { .. })
+        ));
+        assert!(matches!(
+            tree.copy_entry("/a", "/l/b/c"),
+            Err(Error::InvalidName { .. })
+        ));
+        assert!(tree.lookup("/a/b")?.is_some());
+        assert!(tree.lookup("/a/b/c")?.is_none());
+        tree.move_entry("/l", "/a/b")?;
+        assert!(tree.lookup("/a/b/l")?.is_some_and(|e| e.is_symlink()));
+        assert!(tree.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_opens_directory_handles() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_dir_all("/a/b")?;
+        tree.create_dir_all("/c")?;
+        let mut a = tree.open_dir("/a")?;
+        let mut c = tree.open_dir("c")?;
+        let mut b = a.open_dir(&mut tree, "b")?;
+        assert_eq!(b.path(), "/a/b");
+        assert_eq!(tree.dir(), "/");
+
+        assert_eq!(a.entries(&mut tree)?.len(), 1);
+        a.create_entry(&mut tree, "file", false)?;
+        c.create_entry(&mut tree, "other", false)?;
+        b.create_entry(&mut tree, "nested", true)?;
+        assert!(a.has_entry(&mut tree, "file")?);
+        assert!(!c.has_entry(&mut tree, "file")?);
+        let mut again = tree.open_dir("/a")?;
+        assert!(again.delete_entry(&mut tree, "file")?);
+        assert_eq!(a.entries(&mut tree)?.len(), 1);
+        assert_eq!(tree.entries()?.len(), 2);
+        tree.with_dir(&mut c, |t| t.cd(".."))?;
+        assert_eq!(c.path(), "/");
+        assert!(tree.open_dir("/missing").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_detects_pointer_cycles() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_entry("a", true)?;
+        let mut data = tree.into_inner().into_inner();
+        // points the child pointer of the only entry of the root back to the root
+        data[41..49].copy_from_slice(&32u64.to_be_bytes());
+        let mut tree = DirTreeFile::from_backend(Cursor::new(data))?;
+        assert!(tree.lookup("/a/a/a")?.unwrap().is_dir());
+        assert!(tree.walk("/")?.any(|item| item.is_err()));
+        assert!(matches!(tree.delete_entry("a"), Err(Error::Corrupt { .. })));
+        assert!(tree.copy_entry("/a", "/b").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_copies_subtrees() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_dir_all("/src/a/b")?;
+        tree.cd("/src/a")?;
+        tree.create_entry("file", false)?;
+        tree.set_xattr("file", "key", b"value")?;
+        tree.create_symlink("link", "b")?;
+        tree.cd("/")?;
+
+        let stats = tree.copy_entry("src", "/dest")?;
+        assert_eq!(stats.dirs, 3);
+        assert_eq!(stats.files, 1);
+        assert_eq!(stats.symlinks, 1);
+        assert_eq!(tree.dir(), "/");
+        assert_eq!(tree.get_xattr("dest", "key")?, None);
+        tree.cd("/dest/a")?;
+        assert_eq!(tree.get_xattr("file", "key")?, Some(b"value".to_vec()));
+        tree.cd("/")?;
+        tree.delete_recursive("src")?;
+        assert!(tree.lookup("/dest/a/link/")?.is_some());
+        assert!(tree.lookup("/dest/a/b")?.unwrap().is_dir());
+        assert!(matches!(
+            tree.copy_entry("/dest", "/dest/a/x"),
+            Err(Error::InvalidName { .. })
+        ));
+        assert!(matches!(
+            tree.copy_entry("/dest/a", "/dest"),
+            Err(Error::AlreadyExists { .. })
+        ));
+        assert!(tree.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_deletes_directories_recursively() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_entry("a", true)?;
+        tree.cd("a")?;
+        tree.create_entry("b", true)?;
+        tree.create_entry("file", false)?;
+        tree.cd("b")?;
+        tree.create_entry("c", true)?;
+        tree.cd("/")?;
+        assert!(tree.delete_recursive("a")?);
+        assert!(!tree.delete_recursive("a")?);
+        assert!(tree.entries()?.is_empty());
+        assert_eq!(tree.get_size()?, 1070);
+        assert!(tree.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_walks_trees() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_dir_all("/a/b")?;
+        tree.create_dir_all("/c")?;
+        tree.cd("/a/b")?;
+        tree.create_entry("file", false)?;
+        let walked = tree
+            .walk("/")?
+            .map(|item| item.map(|(depth, path, _)| (depth, path)))
+            .collect::<crate::error::Result<Vec<_>>>()?;
+        assert_eq!(
+            walked,
+            vec![
+                (1, "/a".to_string()),
+                (2, "/a/b".to_string()),
+                (3, "/a/b/file".to_string()),
+                (1, "/c".to_string()),
+            ]
+        );
+        assert_eq!(tree.walk("/a")?.count(), 2);
+        assert_eq!(tree.dir(), "/a/b");
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_matches_glob_patterns() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_dir_all("/logs/2024-01/app")?;
+        tree.create_dir_all("/logs/2024-02")?;
+        tree.create_dir_all("/logs/2023-12")?;
+        tree.cd("/logs/2024-01/app")?;
+        tree.create_entry("a.json", false)?;
+        tree.create_entry("b.txt", false)?;
+        tree.cd("/logs/2024-02")?;
+        tree.create_entry("c.json", false)?;
+        tree.cd("/logs/2023-12")?;
+        tree.create_entry("d.json", false)?;
+
+        let mut paths = tree.glob("/logs/2024-*/**/*.json")?;
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec!["/logs/2024-01/app/a.json", "/logs/2024-02/c.json"]
+        );
+        assert_eq!(tree.glob("../2024-0?")?.len(), 2);
+        assert_eq!(tree.glob("/logs/2023-12/d.json")?.len(), 1);
+        assert!(tree.glob("/missing/*")?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_matches_patterns_with_many_stars_in_linear_time() -> io::Result<()> {
+        let name = "a".repeat(200);
+        assert!(!glob_match(&format!("{}b", "a*".repeat(30)), &name));
+        assert!(glob_match(&"a*".repeat(30), &name));
+        assert!(glob_match("*a?c*", "xxabcxx"));
+        assert!(!glob_match("a*b", "ab/b"));
+
+        let path = "/a".repeat(200);
+        assert!(!glob_match(&format!("{}/b", "/**".repeat(30)), &path));
+        assert!(glob_match(&"/**/a".repeat(30), &path));
+        assert!(glob_match("/**", "/"));
+        assert!(glob_match("/a/**/**/b", "/a/b"));
+        assert!(!glob_match("/a/**/b", "/a/c"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_sums_up_directories() -> io::Result<()> {
+        let storage = test_storage("stats")?;
+        storage.create_dir_all("/a/b")?;
+        storage.store("/a/one.txt", &b"one"[..])?;
+        storage.store("/a/b/two.txt", &b"two two"[..])?;
+        storage.store("/three.txt", &b"3"[..])?;
+        storage.create_dir("/a/empty")?;
+
+        let mut tree = storage.tree();
+        tree.cd("/a")?;
+        tree.create_symlink("link", "/three.txt")?;
+        let stats = tree.dir_stats("/a")?;
+        assert_eq!((stats.files, stats.dirs, stats.symlinks), (2, 2, 1));
+        assert_eq!(stats.bytes, 10);
+        assert_eq!(tree.dir_stats("/")?.bytes, 11);
+        assert!(tree.dir_stats("/three.txt").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_finds_entries() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_dir_all("/a/b/c")?;
+        tree.cd("/a/b")?;
+        tree.create_entry("target", false)?;
+        tree.cd("/a/b/c")?;
+        tree.create_entry("target", true)?;
+        tree.create_entry("other", false)?;
+
+        let mut paths: Vec<String> = tree
+            .find("/", |e| e.name == "target")?
+            .map(|item| item.map(|(path, _)| path))
+            .collect::<Result<_, _>>()?;
+        paths.sort();
+        assert_eq!(paths, vec!["/a/b/c/target", "/a/b/target"]);
+        let (path, _) = tree.find("/a", |e| !e.is_dir())?.next().unwrap()?;
+        assert!(path.ends_with("target") || path.ends_with("other"));
+        assert_eq!(tree.find(".", |_| true)?.count(), 2);
+        assert!(tree.find("/missing", |_| true).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_exports_trees_as_json() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_dir_all("/a/b")?;
+        tree.create_dir_all("/empty")?;
+        tree.cd("/a")?;
+        tree.create_entry("file \"quoted\"", false)?;
+        tree.set_metadata("file \"quoted\"", EntryMetadata::new(12))?;
+        tree.set_xattr("file \"quoted\"", "user.key", &[0, 255])?;
+        tree.create_symlink("link", "../empty")?;
+        let mut json = Vec::new();
+        tree.to_json(&mut json)?;
+
+        let mut copy = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        let stats = copy.from_json(&mut &json[..])?;
+        assert_eq!((stats.files, stats.dirs, stats.symlinks), (1, 3, 1));
+        let mut exported = Vec::new();
+        copy.to_json(&mut exported)?;
+        assert_eq!(String::from_utf8(exported), String::from_utf8(json.clone()));
+        assert!(copy.lookup("/a/link")?.unwrap().is_symlink());
+        assert_eq!(
+            copy.lookup("/a/file \"quoted\"")?
+                .unwrap()
+                .metadata()
+                .unwrap()
+                .size,
+            12
+        );
+        assert!(copy.check()?.is_ok());
+
+        assert!(matches!(
+            copy.from_json(&mut &json[..]),
+            Err(Error::AlreadyExists { .. })
+        ));
+        assert!(matches!(
+            copy.from_json(&mut &b"{\"entries\": [1.5]}"[..]),
+            Err(Error::InvalidArchive { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_links_entries_to_blobs() -> io::Result<()> {
+        let storage = test_storage("blob-ids")?;
+        storage.store("/a.txt", &b"a"[..])?;
+        let id = storage.entry("/a.txt")?.blob_id().unwrap();
+        assert_eq!(id, hash_id("/a.txt"));
+        assert!(storage.meta().get_entry_raw(&id).is_some());
+
+        storage.rename("/a.txt", "/b.txt")?;
+        storage.hard_link("/b.txt", "/c.txt")?;
+        for path in ["/b.txt", "/c.txt"].iter() {
+            let id = storage.entry(path)?.blob_id().unwrap();
+            assert_eq!(
+                storage.meta().get_entry_raw(&id),
+                storage.meta().get_entry(path)
+            );
+        }
+
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_file_entry("file", [7; 32])?;
+        let mut tree = DirTreeFile::from_backend(tree.into_inner())?;
+        assert_eq!(tree.lookup("file")?.unwrap().blob_id(), Some([7; 32]));
+        assert!(!tree.lookup("file")?.unwrap().is_dir());
+
+        Ok(())
+    }
+
+    /// A backend that fails all writes after a number of flushes like a crashed process
+    struct CrashingBackend {
+        inner: Cursor<Vec<u8>>,
+        flushes: usize,
+    }
+
+    impl Read for CrashingBackend {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for CrashingBackend {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.flushes == 0 {
+                return Err(io::Error::other("crashed"));
+            }
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes = self.flushes.saturating_sub(1);
+            Ok(())
+        }
+    }
+
+    impl io::Seek for CrashingBackend {
+        fn seek(&mut self, position: io::SeekFrom) -> io::Result<u64> {
+            self.inner.seek(position)
+        }
+    }
+
+    impl Backend for CrashingBackend {
+        fn set_len(&mut self, size: u64) -> io::Result<()> {
+            self.inner.set_len(size)
+        }
+    }
+
+    /// Counts how often it's synced
+    struct SyncCountingBackend {
+        inner: Cursor<Vec<u8>>,
+        syncs: usize,
+    }
+
+    impl Read for SyncCountingBackend {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for SyncCountingBackend {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl io::Seek for SyncCountingBackend {
+        fn seek(&mut self, position: io::SeekFrom) -> io::Result<u64> {
+            self.inner.seek(position)
+        }
+    }
+
+    impl Backend for SyncCountingBackend {
+        fn set_len(&mut self, size: u64) -> io::Result<()> {
+            self.inner.set_len(size)
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.syncs += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_syncs_by_policy() -> io::Result<()> {
+        let backend = SyncCountingBackend {
+            inner: Cursor::new(Vec::new()),
+            syncs: 0,
+        };
+        let mut tree = DirTreeFile::from_backend(backend)?;
+        tree.create_entry("a", true)?;
+        let mut backend = tree.into_inner();
+        assert_eq!(backend.syncs, 0);
+
+        backend.syncs = 0;
+        let mut tree = DirTreeFile::from_backend(backend)?;
+        tree.set_sync_policy(SyncPolicy::OnCommit);
+        tree.set_journaled(false);
+        tree.create_entry("b", true)?;
+        let backend = tree.into_inner();
+        assert_eq!(backend.syncs, 0);
+        let mut tree = DirTreeFile::from_backend(backend)?;
+        tree.set_sync_policy(SyncPolicy::OnCommit);
+        tree.create_entry("c", true)?;
+        let mut backend = tree.into_inner();
+        assert!(backend.syncs > 0);
+
+        backend.syncs = 0;
+        let mut tree = DirTreeFile::from_backend(backend)?;
+        tree.set_sync_policy(SyncPolicy::Always);
+        tree.set_journaled(false);
+        tree.create_entry("d", true)?;
+        assert_eq!(tree.into_inner().syncs, 1);
+
+        let storage = test_storage("sync")?;
+        storage.set_sync_policy(SyncPolicy::Always);
+        storage.store("/a.txt", &b"a"[..])?;
+        assert_eq!(storage.meta().get_entry("/a.txt"), Some(&(0, 0, 1)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_commits_batches_at_once() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.begin_batch()?;
+        assert!(tree.begin_batch().is_err());
+        for i in 0..100 {
+            tree.create_entry(&i.to_string(), false)?;
+        }
+        assert_eq!(tree.entries()?.len(), 100);
+        tree.commit()?;
+        assert!(!tree.in_batch());
+        assert!(tree.check()?.is_ok());
+
+        tree.begin_batch()?;
+        tree.create_entry("a", false)?;
+        assert!(tree.create_entry("a", false).is_err());
+        assert!(!tree.in_batch());
+        assert!(tree.lookup("a")?.is_none());
+        let mut tree = DirTreeFile::from_backend(tree.into_inner())?;
+        assert_eq!(tree.entries()?.len(), 100);
+
+        let storage = test_storage("batch")?;
+        let meta_path = std::env::temp_dir().join("ifs-test-batch/index.meta");
+        storage.begin_batch()?;
+        for i in 0..20 {
+            storage.store(&format!("/{}.txt", i), &b"data"[..])?;
+        }
+        assert!(IndexedMetaFile::open(&meta_path)?.is_empty());
+        storage.commit()?;
+        assert_eq!(IndexedMetaFile::open(&meta_path)?.len(), 20);
+        assert_eq!(storage.read_dir("/")?.len(), 20);
+
+        storage.begin_batch()?;
+        storage.store("/discarded.txt", &b"data"[..])?;
+        storage.rollback()?;
+        assert!(storage.get("/discarded.txt").is_err());
+        assert!(storage.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_applies_transactions_atomically() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_entry("existing", true)?;
+        tree.transaction(|tx| {
+            tx.create("a", true)?;
+            tx.create("/a/file", false)?;
+            tx.create_symlink("a/link", "file")?;
+            tx.delete("existing")?;
+            assert!(tx.lookup("a/file")?.is_some());
+            Ok(())
+        })?;
+        assert!(tree.lookup("/a/link")?.unwrap().is_symlink());
+        assert!(tree.lookup("existing")?.is_none());
+        assert_eq!(tree.dir(), "/");
+        assert!(tree.check()?.is_ok());
+
+        let size = tree.get_size()?;
+        let result = tree.transaction(|tx| {
+            tx.create("b", true)?;
+            tx.create_dir_all("/c/d")?;
+            tx.create("a", false)
+        });
+        assert!(matches!(result, Err(Error::AlreadyExists { .. })));
+        assert!(tree.lookup("b")?.is_none());
+        assert!(tree.lookup("c")?.is_none());
+        assert_eq!(tree.get_size()?, size);
+        assert!(tree.check()?.is_ok());
+
+        // the process stops after the journal was recorded in the header
+        let backend = CrashingBackend {
+            inner: tree.into_inner(),
+            flushes: 2,
+        };
+        let mut tree = DirTreeFile::from_backend(backend)?;
+        let result = tree.transaction(|tx| {
+            tx.create_dir_all("/x/y")?;
+            tx.delete_recursive("a")
+        });
+        assert!(result.is_err());
+        let mut tree = DirTreeFile::from_backend(tree.into_inner().inner)?;
+        assert!(tree.lookup("/x/y")?.unwrap().is_dir());
+        assert!(tree.lookup("a")?.is_none());
+        assert!(tree.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_replays_journaled_mutations() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        for i in 0..20 {
+            tree.create_entry(&format!("dir-{}", i), true)?;
+        }
+        let backend = CrashingBackend {
+            inner: tree.into_inner(),
+            flushes: 2,
+        };
+        let mut tree = DirTreeFile::from_backend(backend)?;
+        assert!(tree.delete_entry("dir-3").is_err());
+        assert!(tree.has_entry("dir-3")?);
+
+        let mut tree = DirTreeFile::from_backend(tree.into_inner().inner)?;
+        assert!(!tree.has_entry("dir-3")?);
+        assert_eq!(tree.entries()?.len(), 19);
+        assert!(tree.check()?.is_ok());
+
+        tree.set_journaled(false);
+        tree.create_entry("unjournaled", false)?;
+        assert!(tree.has_entry("unjournaled")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_keeps_cached_entries_coherent() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_dir_all("/a")?;
+        tree.create_entry("x", false)?;
+        tree.cd("/a")?;
+        for i in 0..10 {
+            tree.create_entry(&format!("f{}", i), false)?;
+        }
+        tree.cd("/")?;
+        assert_eq!(tree.entries()?.len(), 2);
+        tree.delete_entry("x")?;
+        assert_eq!(tree.entries()?.len(), 1);
+
+        assert!(tree.lookup("/a/f1")?.is_some());
+        assert_eq!(tree.walk("/a")?.count(), 10);
+        let mut handle = tree.open_dir("/a")?;
+        handle.delete_entry(&mut tree, "f1")?;
+        assert!(tree.lookup("/a/f1")?.is_none());
+        assert_eq!(tree.walk("/a")?.count(), 9);
+
+        let result: Result<(), _> = tree.transaction(|tx| {
+            tx.create("/a/staged", false)?;
+            assert!(tx.lookup("/a/staged")?.is_some());
+            Err(Error::InvalidName {
+                name: "staged".to_string(),
+            })
+        });
+        assert!(result.is_err());
+        assert!(tree.lookup("/a/staged")?.is_none());
+        assert_eq!(tree.walk("/")?.count(), 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_keeps_recently_read_chunks_cached() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        for i in 0..8 {
+            tree.create_dir_all(&format!("/d{}", i))?;
+            tree.cd(&format!("/d{}", i))?;
+            tree.create_entry("file", false)?;
+        }
+        tree.set_chunk_cache_capacity(4);
+        for i in 0..8 {
+            tree.cd(&format!("/d{}", i))?;
+            assert_eq!(tree.entries()?.len(), 1);
+        }
+        assert_eq!(tree.cached_chunks(), 4);
+        assert!(tree.lookup("/d7/file")?.is_some());
+        assert!(tree.cached_chunks() <= 4);
+        tree.cd("/d7")?;
+        tree.create_entry("other", false)?;
+        assert_eq!(tree.entries()?.len(), 2);
+
+        tree.set_chunk_cache_capacity(0);
+        assert_eq!(tree.cached_chunks(), 0);
+        tree.cd("/d0")?;
+        assert_eq!(tree.entries()?.len(), 1);
+        assert_eq!(tree.cached_chunks(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_resolves_symlinks() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_dir_all("/a/b")?;
+        tree.create_symlink("abs", "/a")?;
+        tree.create_symlink("loop", "loop")?;
+        tree.cd("/a")?;
+        tree.create_symlink("rel", "b")?;
+        tree.cd("b")?;
+        tree.create_entry("file", false)?;
+        assert!(tree.lookup("/abs/rel/file")?.is_some());
+        assert!(tree.lookup("/abs")?.is_some_and(|e| e.is_symlink()));
+        tree.cd("/abs/rel")?;
+        assert_eq!(tree.dir(), "/a/b");
+        assert!(matches!(tree.cd("/loop"), Err(Error::SymlinkLoop { .. })));
+        assert!(matches!(
+            tree.lookup("/loop/file"),
+            Err(Error::SymlinkLoop { .. })
+        ));
+        assert!(tree.check()?.files.iter().all(|f| f == "/a/b/file"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_persists_chunk_sizes() -> io::Result<()> {
+        let options = TreeOptions {
+            chunk_size: 128,
+            ..TreeOptions::default()
+        };
+        let mut tree = DirTreeFile::from_backend_with_options(Cursor::new(Vec::new()), options)?;
+        for i in 0..20 {
+            tree.create_entry(&format!("file-{}", i), false)?;
+        }
+        let mut tree = DirTreeFile::from_backend(tree.into_inner())?;
+        assert_eq!(tree.chunk_size(), 128);
+        assert_eq!(tree.entries()?.len(), 20);
+        assert!(tree.check()?.is_ok());
+
+        // trees written before the header existed start with the root chunk
+        let mut legacy = vec![0, 0, 4, 0, 0, 0];
+        legacy.resize(1038, 0);
+        let mut tree = DirTreeFile::from_backend(Cursor::new(legacy))?;
+        assert_eq!(tree.chunk_size(), 1024);
+        tree.create_entry("dir", true)?;
+        tree.cd("dir")?;
+        assert!(tree.check()?.is_ok());
+
+        // the first header had no version and kept the root after 16 bytes
+        let mut v1 = b"IFST".to_vec();
+        v1.extend_from_slice(&[0, 0, 0, 128, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 128, 0, 0]);
+        v1.resize(16 + 142, 0);
+        let mut tree = DirTreeFile::from_backend(Cursor::new(v1))?;
+        assert_eq!(tree.chunk_size(), 128);
+        tree.create_entry("file", false)?;
+        assert!(tree.check()?.is_ok());
+
+        let mut data = DirTreeFile::from_backend(Cursor::new(Vec::new()))?
+            .into_inner()
+            .into_inner();
+        assert_eq!(&data[..6], b"IFSV\0\x02");
+        data[5] = 3;
+        assert!(matches!(
+            DirTreeFile::from_backend(Cursor::new(data)),
+            Err(Error::UnsupportedFormat { version: 3, .. })
+        ));
+        assert!(matches!(
+            DirTreeFile::from_backend(Cursor::new(b"not a tree".to_vec())),
+            Err(Error::Corrupt { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_verifies_chunk_checksums() -> io::Result<()> {
+        let options = TreeOptions {
+            chunk_size: 128,
+            checksums: true,
+            ..TreeOptions::default()
+        };
+        let mut tree =
+            DirTreeFile::from_backend_with_options(Cursor::new(Vec::new()), options.clone())?;
+        for i in 0..60 {
+            tree.create_entry(&format!("file-{:02}", i), i % 3 == 0)?;
+        }
+        tree.delete_entry("file-01")?;
+        let mut tree = DirTreeFile::from_backend(tree.into_inner())?;
+        assert!(tree.has_checksums());
+        assert_eq!(tree.entries()?.len(), 59);
+        assert!(tree.check()?.is_ok());
+
+        let mut tree = DirTreeFile::from_backend_with_options(Cursor::new(Vec::new()), options)?;
+        tree.create_entry("a", false)?;
+        let mut data = tree.into_inner().into_inner();
+        let name = 38 + data[38..].iter().position(|b| *b == b'a').unwrap();
+        data[name] = b'b';
+        let mut tree = DirTreeFile::from_backend(Cursor::new(data))?;
+        assert!(matches!(
+            tree.entries(),
+            Err(Error::Corrupt { offset: 32, .. })
+        ));
+        tree.set_verify_checksums(false);
+        assert_eq!(tree.entries()?[0].name, "b");
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_reclaims_orphan_chunks() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_entry("a", true)?;
+        // a chunk leaked by an interrupted operation in the middle of the file
+        let mut data = tree.into_inner().into_inner();
+        data.extend_from_slice(&[0, 0, 4, 0]);
+        data.resize(data.len() + 1034, 0);
+        let mut tree = DirTreeFile::from_backend(Cursor::new(data))?;
+        tree.create_entry("b", true)?;
+        let size = tree.get_size()?;
+        assert_eq!(tree.check()?.unreachable.len(), 1);
+
+        assert_eq!(tree.reclaim_orphans()?, 1);
+        assert!(tree.check()?.is_ok());
+        assert_eq!(tree.reclaim_orphans()?, 0);
+        tree.create_entry("c", true)?;
+        assert_eq!(tree.get_size()?, size);
+        assert!(tree.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_sorts_entries_in_chunks() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        for i in (0..40).rev() {
+            tree.create_entry(&format!("file-{:02}", i), i % 2 == 0)?;
+        }
+        let mut tree = DirTreeFile::from_backend(tree.into_inner())?;
+        let names: Vec<String> = tree.entries()?.into_iter().map(|e| e.name).collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted);
+        assert!(tree.lookup("file-00")?.unwrap().is_dir());
+        assert!(tree.lookup("file-39")?.is_some());
+        assert!(tree.lookup("file-40")?.is_none());
+        tree.cd("file-32")?;
+        tree.cd("/")?;
+        assert!(tree.check()?.is_ok());
+
+        // chunks written before sorting existed are converted when they change
+        let mut legacy = vec![0, 0, 4, 0, 0, 2];
+        legacy.extend_from_slice(&[0, 9, b'b', 0, 0, 0, 0, 0, 0, 0, 0]);
+        legacy.extend_from_slice(&[0, 9, b'a', 0, 0, 0, 0, 0, 0, 0, 0]);
+        legacy.resize(1038, 0);
+        let mut tree = DirTreeFile::from_backend(Cursor::new(legacy))?;
+        assert!(tree.has_entry("a")?);
+        tree.create_entry("c", false)?;
+        let mut tree = DirTreeFile::from_backend(tree.into_inner())?;
+        let names: Vec<String> = tree.entries()?.into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec!["a", "b", "c"]);
+        assert!(tree.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_indexes_large_directories() -> io::Result<()> {
+        let options = TreeOptions {
+            chunk_size: 128,
+            ..TreeOptions::default()
+        };
+        let mut tree = DirTreeFile::from_backend_with_options(Cursor::new(Vec::new()), options)?;
+        tree.create_entry("big", true)?;
+        tree.cd("big")?;
+        for i in 0..500 {
+            tree.create_entry(&format!("file-{}", i), i % 50 == 0)?;
+        }
+        let mut tree = DirTreeFile::from_backend(tree.into_inner())?;
+        tree.cd("/big")?;
+        assert_eq!(tree.entries()?.len(), 500);
+        for i in 0..500 {
+            assert!(tree.has_entry(&format!("file-{}", i))?);
+        }
+        assert!(!tree.has_entry("file-500")?);
+        tree.cd("file-100")?;
+        tree.create_entry("nested", false)?;
+        assert!(tree.lookup("/big/file-100/nested")?.is_some());
+        tree.cd("/big")?;
+        tree.rename_entry("file-1", "renamed")?;
+        assert!(tree.delete_entry("file-2")?);
+        assert!(tree.lookup("/big/renamed")?.is_some());
+        assert!(tree.lookup("/big/file-2")?.is_none());
+        assert_eq!(tree.walk("/")?.count(), 501);
+        assert!(tree.check()?.is_ok());
+
+        tree.cd("/")?;
+        let size = tree.get_size()?;
+        tree.delete_recursive("big")?;
+        tree.create_entry("other", true)?;
+        assert!(tree.get_size()? <= size);
+        assert!(tree.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_compares_names_ignoring_case() -> io::Result<()> {
+        let options = TreeOptions {
+            case_insensitive: true,
+            ..TreeOptions::default()
+        };
+        let mut tree = DirTreeFile::from_backend_with_options(Cursor::new(Vec::new()), options)?;
+        tree.create_entry("Docs", true)?;
+        tree.create_entry("README.md", false)?;
+        assert!(matches!(
+            tree.create_entry("readme.MD", false),
+            Err(Error::AlreadyExists { .. })
+        ));
+        let mut tree = DirTreeFile::from_backend(tree.into_inner())?;
+        assert!(tree.is_case_insensitive());
+        assert!(tree.has_entry("readme.md")?);
+        assert_eq!(tree.lookup("/DOCS")?.unwrap().name, "Docs");
+        tree.cd("docs")?;
+        assert_eq!(tree.dir(), "/Docs");
+        tree.cd("/")?;
+        tree.rename_entry("readme.md", "ReadMe.md")?;
+        assert_eq!(tree.lookup("README.MD")?.unwrap().name, "ReadMe.md");
+        assert!(tree.delete_entry("DOCS")?);
+        assert_eq!(tree.entries()?.len(), 1);
+
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_entry("a", false)?;
+        tree.create_entry("A", false)?;
+        assert!(!tree.has_entry("b")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_applies_name_policies() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_entry("a:b", false)?;
+        tree.set_name_policy(NamePolicy::Windows);
+        assert!(matches!(
+            tree.create_entry("c:d", false),
+            Err(Error::InvalidName { .. })
+        ));
+        assert!(tree.create_entry("nul.txt", false).is_err());
+        assert!(tree.create_entry("name.", false).is_err());
+        tree.create_entry("null.txt", false)?;
+        tree.set_name_policy(NamePolicy::Posix);
+        assert!(tree.create_entry("-flag", false).is_err());
+        assert!(tree.create_entry("ünicode", false).is_err());
+        tree.create_entry("file_1.tar-gz", false)?;
+        tree.set_name_policy(NamePolicy::Custom(Arc::new(|name| name.len() <= 4)));
+        assert!(tree.rename_entry("a:b", "long-name").is_err());
+        tree.rename_entry("a:b", "abc")?;
+        assert!(tree.create_entry("", false).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_compacts_trees() -> io::Result<()> {
+        let options = TreeOptions {
+            chunk_size: 128,
+            ..TreeOptions::default()
+        };
+        let mut tree = DirTreeFile::from_backend_with_options(Cursor::new(Vec::new()), options)?;
+        tree.create_dir_all("/a/b")?;
+        tree.cd("/a")?;
+        for i in 0..40 {
+            tree.create_entry(&format!("file-{}", i), false)?;
+        }
+        for i in 0..40 {
+            if i % 8 != 0 {
+                tree.delete_entry(&format!("file-{}", i))?;
+            }
+        }
+        tree.set_xattr("file-8", "key", b"value")?;
+        let size = tree.get_size()?;
+
+        let reclaimed = tree.compact()?;
+        assert!(reclaimed > 0);
+        assert_eq!(tree.get_size()?, size - reclaimed);
+        assert_eq!(tree.dir(), "/a");
+        assert_eq!(tree.entries()?.len(), 6);
+        assert_eq!(tree.get_xattr("file-8", "key")?, Some(b"value".to_vec()));
+        assert!(tree.lookup("/a/b")?.unwrap().is_dir());
+        assert!(tree.check()?.is_ok());
+        assert_eq!(tree.compact()?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_reuses_freed_chunks() -> io::Result<()> {
+        let mut tree = DirTreeFile::from_backend(Cursor::new(Vec::new()))?;
+        tree.create_entry("a", true)?;
+        tree.create_entry("b", true)?;
+        let size = tree.get_size()?;
+        tree.delete_entry("a")?;
+        assert_eq!(tree.get_size()?, size);
+        assert!(tree.check()?.is_ok());
+        // the free list is stored in the file
+        let mut tree = DirTreeFile::from_backend(tree.into_inner())?;
+        tree.create_entry("c", true)?;
+        assert_eq!(tree.get_size()?, size);
+        assert!(tree.check()?.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_stores_data_in_custom_backends() -> io::Result<()> {
+        let path = std::env::temp_dir().join("ifs-test-backend");
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        let backend = Arc::new(MemoryDataBackend::default());
+        let storage = Storage::open_with_backend(path, backend.clone())?;
+        storage.store("/a.txt", &b"remote"[..])?;
+        let mut content = String::new();
+        storage.get("/a.txt")?.read_to_string(&mut content)?;
+        assert_eq!(content, "remote");
+        assert_eq!(backend.len(0)?, 14);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn it_stores_files_asynchronously() -> io::Result<()> {
+        use crate::asynchronous::AsyncStorage;
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        runtime.block_on(async {
+            let storage = AsyncStorage::from_storage(test_storage("async")?);
+            storage.create_dir("/docs").await?;
+            storage.store("/docs/a.txt", b"async".to_vec()).await?;
+            assert_eq!(storage.get("/docs/a.txt").await?, b"async");
+            assert_eq!(storage.read_dir("/docs").await?.len(), 1);
+
+            Ok(())
+        })
+    }
+
+    #[cfg(feature = "object_store")]
+    #[test]
+    fn it_implements_the_object_store() -> io::Result<()> {
+        use crate::object_store::StorageObjectStore;
+        use ::object_store::path::Path;
+        use ::object_store::{
+            Attribute, Attributes, GetOptions, GetRange, ObjectStore, ObjectStoreExt, PutMode,
+            PutOptions, UpdateVersion,
+        };
+        use futures_util::TryStreamExt;
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        runtime.block_on(async {
+            let storage = Arc::new(test_storage("object-store")?);
+            let store = StorageObjectStore::new(Arc::clone(&storage));
+            let a = Path::from("data/2024/a.parquet");
+            let b = Path::from("data/b.parquet");
+            let mut attributes = Attributes::new();
+            attributes.insert(Attribute::ContentType, "text/plain".into());
+            let options = PutOptions {
+                attributes,
+                ..Default::default()
+            };
+            let put = store
+                .put_opts(&a, b"hello world".to_vec().into(), options)
+                .await?;
+            store.put(&b, b"other".to_vec().into()).await?;
+            assert_eq!(storage.read_path("/data/2024/a.parquet")?, b"hello world");
+
+            let result = store.get(&a).await?;
+            assert_eq!(result.meta.size, 11);
+            assert_eq!(result.meta.e_tag, put.e_tag);
+            assert_eq!(
+                result
+                    .attributes
+                    .get(&Attribute::ContentType)
+                    .map(|v| v.as_ref()),
+                Some("text/plain")
+            );
+            assert_eq!(&result.bytes().await?[..], b"hello world");
+            let range = store
+                .get_opts(&a, GetOptions::new().with_range(Some(GetRange::Suffix(5))))
+                .await?;
+            assert_eq!(&range.bytes().await?[..], b"world");
+            assert_eq!(&store.get_range(&a, 0..5).await?[..], b"hello");
+
+            // conditional puts check the current object
+            let create = PutOptions::from(PutMode::Create);
+            assert!(store
+                .put_opts(&a, b"x".to_vec().into(), create)
+                .await
+                .is_err());
+            let stale = PutMode::Update(UpdateVersion {
+                e_tag: Some(String::from("\"stale\"")),
+                version: None,
+            });
+            assert!(store
+                .put_opts(&a, b"x".to_vec().into(), stale.into())
+                .await
+                .is_err());
+            let current = PutMode::Update(UpdateVersion {
+                e_tag: put.e_tag,
+                version: None,
+            });
+            store
+                .put_opts(&a, b"updated".to_vec().into(), current.into())
+                .await?;
+
+            let mut upload = store.put_multipart(&Path::from("data/c.bin")).await?;
+            upload.put_part(b"multi".to_vec().into()).await?;
+            upload.put_part(b"part".to_vec().into()).await?;
+            upload.complete().await?;
+            assert_eq!(storage.read_path("/data/c.bin")?, b"multipart");
+
+            let listed: Vec<String> = store
+                .list(Some(&Path::from("data")))
+                .map_ok(|meta| meta.location.to_string())
+                .try_collect()
+                .await?;
+            assert_eq!(
+                listed,
+                vec!["data/2024/a.parquet", "data/b.parquet", "data/c.bin"]
+            );
+            let listed = store.list_with_delimiter(Some(&Path::from("data"))).await?;
+            assert_eq!(listed.common_prefixes, vec![Path::from("data/2024")]);
+            assert_eq!(listed.objects.len(), 2);
+            let missing: Vec<_> = store
+                .list(Some(&Path::from("missing")))
+                .try_collect()
+                .await?;
+            assert!(missing.is_empty());
+
+            store.copy(&b, &Path::from("copy/b.parquet")).await?;
+            store.rename(&a, &Path::from("moved/a.parquet")).await?;
+            assert!(store.head(&a).await.is_err());
+            assert!(storage.entry("/data/2024").is_err());
+            assert_eq!(storage.read_path("/moved/a.parquet")?, b"updated");
+            store.delete(&b).await?;
+            store.delete(&b).await?;
+            assert_eq!(storage.read_path("/copy/b.parquet")?, b"other");
+            assert!(store.get(&b).await.is_err());
+
+            Ok(())
+        })
+    }
 }
@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// A cache keyed by offsets in a file that drops the least recently used
+/// value when it's full. Keys are kept in order so that all values in a
+/// written range can be dropped at once
+pub(crate) struct LruCache<V> {
+    capacity: usize,
+    /// The values with the tick of their last use
+    values: BTreeMap<u64, (u64, V)>,
+    /// The keys by the tick of their last use
+    uses: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl<V> LruCache<V> {
+    /// Creates a cache holding up to the given number of values. A capacity
+    /// of 0 disables the cache
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            values: BTreeMap::new(),
+            uses: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Changes the capacity and drops the least recently used values that don't fit
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.values.len() > capacity {
+            self.evict();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns the value for a key and marks it as used
+    pub fn get_mut(&mut self, key: u64) -> Option<&mut V> {
+        let tick = self.next_tick();
+        let (used, value) = self.values.get_mut(&key)?;
+        self.uses.remove(used);
+        self.uses.insert(tick, key);
+        *used = tick;
+
+        Some(value)
+    }
+
+    /// Inserts a value, dropping the least recently used one if the cache is full
+    pub fn insert(&mut self, key: u64, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(key);
+        while self.values.len() >= self.capacity {
+            self.evict();
+        }
+        let tick = self.next_tick();
+        self.uses.insert(tick, key);
+        self.values.insert(key, (tick, value));
+    }
+
+    pub fn remove(&mut self, key: u64) -> Option<V> {
+        let (used, value) = self.values.remove(&key)?;
+        self.uses.remove(&used);
+
+        Some(value)
+    }
+
+    /// Removes all values with a key in the range
+    pub fn remove_range(&mut self, range: Range<u64>) {
+        let keys: Vec<u64> = self.values.range(range).map(|(key, _)| *key).collect();
+        for key in keys {
+            self.remove(key);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.uses.clear();
+    }
+
+    /// Drops the least recently used value
+    fn evict(&mut self) {
+        if let Some((_, key)) = self.uses.pop_first() {
+            self.values.remove(&key);
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
@@ -0,0 +1,578 @@
+use crate::bloom::BloomFilter;
+use crate::error::{Error, Result};
+use crate::metafile::{EntryID, HashAlgorithm, MetaEntry};
+#[cfg(feature = "mmap")]
+use crate::mmap::Mapping;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+/// Marks a file as a sorted run
+const MAGIC: [u8; 4] = *b"IFSR";
+const VERSION: u16 = 1;
+/// magic, version, hash algorithm, reserved, count
+const HEADER_SIZE: u64 = 16;
+/// id, state, data file, data pointer, blob length
+const RECORD_SIZE: u64 = 32 + 1 + 4 + 8 + 8;
+/// The number of changes kept in memory before they are written as a run
+const DEFAULT_MEMTABLE_LIMIT: usize = 4096;
+/// The number of runs after which all runs are merged into one
+const DEFAULT_MAX_RUNS: usize = 8;
+const RUN_EXTENSION: &str = "run";
+
+const REMOVED: u8 = 0;
+const USED: u8 = 1;
+
+/// An entry or a marker that it was removed
+type Record = Option<MetaEntry>;
+
+/// A meta file for write heavy workloads. Changes are collected in memory and
+/// written as runs sorted by id. Lookups check the changes in memory first and
+/// then the runs from the newest to the oldest. Once there are too many runs
+/// they are merged into one on a worker thread while new runs can still be
+/// written. Like [crate::hashtable::HashTableFile] reference counts aren't tracked
+pub struct LsmMetaFile {
+    directory: PathBuf,
+    memtable: BTreeMap<EntryID, Record>,
+    /// The runs from the oldest to the newest
+    runs: Vec<Run>,
+    next_sequence: u64,
+    memtable_limit: usize,
+    max_runs: usize,
+    algorithm: HashAlgorithm,
+    merge: Option<BackgroundMerge>,
+}
+
+/// A merge of the oldest runs running on a worker thread
+struct BackgroundMerge {
+    handle: JoinHandle<Result<Run>>,
+    /// The number of runs at the start of the list that are merged
+    runs: usize,
+}
+
+/// A file of records sorted by id
+struct Run {
+    path: PathBuf,
+    #[cfg(not(feature = "mmap"))]
+    file: File,
+    count: u64,
+    bloom: BloomFilter,
+    /// If the run has records of removed entries
+    removals: bool,
+    /// Lookups search the mapping instead of reading records
+    #[cfg(feature = "mmap")]
+    mapping: Mapping,
+}
+
+impl LsmMetaFile {
+    /// Opens the runs in the given directory and creates it if it doesn't exist
+    pub fn open<P: AsRef<Path>>(directory: P) -> Result<Self> {
+        Self::open_with_algorithm(directory, HashAlgorithm::default())
+    }
+
+    /// Opens the runs like [LsmMetaFile::open]. The algorithm is used if there
+    /// are no runs yet, otherwise the one of the runs is used
+    pub fn open_with_algorithm<P: AsRef<Path>>(
+        directory: P,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)?;
+        let mut sequences = Vec::new();
+        for entry in fs::read_dir(&directory)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(RUN_EXTENSION) {
+                continue;
+            }
+            let sequence = path
+                .file_stem()
+                .and_then(|s| s.to_str()?.parse::<u64>().ok());
+            if let Some(sequence) = sequence {
+                sequences.push(sequence);
+            }
+        }
+        sequences.sort_unstable();
+
+        let mut runs = Vec::with_capacity(sequences.len());
+        let mut run_algorithm = None;
+        for sequence in &sequences {
+            let path = run_path(&directory, *sequence);
+            let (run, algorithm) = Run::open(&path).map_err(|e| e.in_file(&path))?;
+            if *run_algorithm.get_or_insert(algorithm) != algorithm {
+                return Err(
+                    Error::corrupt(6, "the run uses a different hash algorithm").in_file(&path)
+                );
+            }
+            runs.push(run);
+        }
+
+        Ok(Self {
+            directory,
+            memtable: BTreeMap::new(),
+            runs,
+            next_sequence: sequences.last().map(|s| s + 1).unwrap_or(0),
+            memtable_limit: DEFAULT_MEMTABLE_LIMIT,
+            max_runs: DEFAULT_MAX_RUNS,
+            algorithm: run_algorithm.unwrap_or(algorithm),
+            merge: None,
+        })
+    }
+
+    /// Returns the algorithm ids are hashed with
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Sets the number of changes kept in memory before they are written as a run
+    pub fn set_memtable_limit(&mut self, limit: usize) {
+        self.memtable_limit = limit.max(1);
+    }
+
+    /// Sets the number of runs after which all runs are merged into one
+    pub fn set_max_runs(&mut self, max_runs: usize) {
+        self.max_runs = max_runs.max(1);
+    }
+
+    /// Returns the number of runs on disk including the ones that are being merged
+    pub fn run_count(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Returns if runs are being merged on a worker thread
+    pub fn is_merging(&self) -> bool {
+        self.merge.is_some()
+    }
+
+    /// Waits for a running merge and replaces the merged runs with its result
+    pub fn wait_for_merge(&mut self) -> Result<()> {
+        let merge = match self.merge.take() {
+            Some(merge) => merge,
+            None => return Ok(()),
+        };
+        let merged = merge
+            .handle
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+        let sources: Vec<Run> = self
+            .runs
+            .splice(..merge.runs, std::iter::once(merged))
+            .collect();
+        for run in sources {
+            fs::remove_file(&run.path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of changes that haven't been written yet
+    pub fn pending(&self) -> usize {
+        self.memtable.len()
+    }
+
+    /// Returns an entry by id
+    pub fn get_entry<K: AsRef<[u8]> + ?Sized>(&mut self, id: &K) -> Result<Option<MetaEntry>> {
+        self.get_entry_raw(&self.algorithm.hash_id(id))
+    }
+
+    /// Returns an entry by its hashed id as recorded in the tree entries
+    pub fn get_entry_raw(&mut self, id: &EntryID) -> Result<Option<MetaEntry>> {
+        if let Some(record) = self.memtable.get(id) {
+            return Ok(*record);
+        }
+        for run in self.runs.iter_mut().rev() {
+            if let Some(record) = run.get(id).map_err(|e| e.in_file(&run.path))? {
+                return Ok(record);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Adds a file entry. The change is written with the next run
+    pub fn add_entry<K: AsRef<[u8]> + ?Sized>(&mut self, id: &K, entry: MetaEntry) -> Result<()> {
+        self.add_entry_raw(self.algorithm.hash_id(id), entry)
+    }
+
+    /// Adds an entry by an id that is already hashed
+    pub fn add_entry_raw(&mut self, id: EntryID, entry: MetaEntry) -> Result<()> {
+        self.change(id, Some(entry))
+    }
+
+    /// Removes an entry. The removal is written with the next run
+    pub fn remove_entry<K: AsRef<[u8]> + ?Sized>(&mut self, id: &K) -> Result<()> {
+        self.remove_entry_raw(self.algorithm.hash_id(id))
+    }
+
+    /// Removes an entry by its hashed id
+    pub fn remove_entry_raw(&mut self, id: EntryID) -> Result<()> {
+        self.change(id, None)
+    }
+
+    /// Writes the changes in memory as a new run. Starts merging all runs on a
+    /// worker thread if there are too many and no merge is running. A finished
+    /// merge is applied first
+    pub fn flush(&mut self) -> Result<()> {
+        if self.merge.as_ref().map(|m| m.handle.is_finished()) == Some(true) {
+            self.wait_for_merge()?;
+        }
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let memtable = std::mem::take(&mut self.memtable);
+        let run = self.write_run(memtable.into_iter().map(Ok))?;
+        self.runs.push(run);
+        if self.runs.len() > self.max_runs && self.merge.is_none() {
+            self.start_merge();
+        }
+
+        Ok(())
+    }
+
+    /// Writes the changes in memory and merges all runs into one dropping
+    /// removed and replaced entries. Waits for a running merge first
+    pub fn compact(&mut self) -> Result<()> {
+        self.wait_for_merge()?;
+        if !self.memtable.is_empty() {
+            let memtable = std::mem::take(&mut self.memtable);
+            let run = self.write_run(memtable.into_iter().map(Ok))?;
+            self.runs.push(run);
+        }
+        if self.runs.len() < 2 && self.runs.iter().all(|run| !run.removals) {
+            return Ok(());
+        }
+        self.start_merge();
+
+        self.wait_for_merge()
+    }
+
+    /// Merges all current runs on a worker thread. The merged run gets the next
+    /// sequence so that it's older than the runs written while it's merged
+    fn start_merge(&mut self) {
+        let sources: Vec<(PathBuf, u64)> = self
+            .runs
+            .iter()
+            .map(|run| (run.path.clone(), run.count))
+            .collect();
+        let path = run_path(&self.directory, self.next_sequence);
+        self.next_sequence += 1;
+        let algorithm = self.algorithm;
+        let handle = thread::spawn(move || {
+            let mut readers = Vec::with_capacity(sources.len());
+            for (source, count) in &sources {
+                readers.push(RunReader::new(source, *count)?);
+            }
+            let merge = Merge::new(readers)?.filter(|record| !matches!(record, Ok((_, None))));
+
+            write_run(&path, algorithm, merge)
+        });
+        self.merge = Some(BackgroundMerge {
+            handle,
+            runs: self.runs.len(),
+        });
+    }
+
+    fn change(&mut self, id: EntryID, record: Record) -> Result<()> {
+        self.memtable.insert(id, record);
+        if self.memtable.len() >= self.memtable_limit {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes sorted records as the next run
+    fn write_run<I: Iterator<Item = Result<(EntryID, Record)>>>(
+        &mut self,
+        records: I,
+    ) -> Result<Run> {
+        let path = run_path(&self.directory, self.next_sequence);
+        let run = write_run(&path, self.algorithm, records)?;
+        self.next_sequence += 1;
+
+        Ok(run)
+    }
+}
+
+impl Drop for LsmMetaFile {
+    fn drop(&mut self) {
+        let _ = self.wait_for_merge();
+    }
+}
+
+/// Writes sorted records as a run. The run is written to a temporary file
+/// first so that a partially written run is never read
+fn write_run<I: Iterator<Item = Result<(EntryID, Record)>>>(
+    path: &Path,
+    algorithm: HashAlgorithm,
+    records: I,
+) -> Result<Run> {
+    let temp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temp)?);
+    writer.write_all(&MAGIC)?;
+    writer.write_u16::<BigEndian>(VERSION)?;
+    writer.write_u8(algorithm.to_u8())?;
+    writer.write_u8(0)?;
+    writer.write_u64::<BigEndian>(0)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut count = 0u64;
+    for record in records {
+        let (id, record) = record?;
+        let data = encode_record(&id, record)?;
+        hasher.update(&data);
+        writer.write_all(&data)?;
+        count += 1;
+    }
+    writer.write_u32::<BigEndian>(hasher.finalize())?;
+    writer.seek(SeekFrom::Start(8))?;
+    writer.write_u64::<BigEndian>(count)?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+
+    Run::open(path)
+        .map(|(run, _)| run)
+        .map_err(|e| e.in_file(path))
+}
+
+impl Run {
+    /// Opens a run verifying its size, order and checksum and builds its bloom filter
+    fn open(path: &Path) -> Result<(Self, HashAlgorithm)> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut header = [0u8; HEADER_SIZE as usize];
+        file.read_exact(&mut header).map_err(|e| truncated(e, 0))?;
+        let version = u16::from_be_bytes([header[4], header[5]]);
+        let algorithm = match HashAlgorithm::from_u8(header[6]) {
+            Some(algorithm) if header[..4] == MAGIC && version == VERSION && header[7] == 0 => {
+                algorithm
+            }
+            _ => {
+                return Err(Error::UnsupportedFormat {
+                    file: PathBuf::new(),
+                    version,
+                    flags: u16::from_be_bytes([header[6], header[7]]),
+                })
+            }
+        };
+        let mut count = [0u8; 8];
+        count.copy_from_slice(&header[8..]);
+        let count = u64::from_be_bytes(count);
+        let expected = count
+            .checked_mul(RECORD_SIZE)
+            .and_then(|s| s.checked_add(HEADER_SIZE + 4));
+        if expected != Some(size) {
+            return Err(Error::corrupt(8, "the run doesn't match the file size"));
+        }
+
+        let mut bloom = BloomFilter::with_capacity(count);
+        let mut hasher = crc32fast::Hasher::new();
+        let mut removals = false;
+        let mut reader = BufReader::new(&mut file);
+        let mut previous: Option<EntryID> = None;
+        let mut data = [0u8; RECORD_SIZE as usize];
+        for i in 0..count {
+            let offset = HEADER_SIZE + i * RECORD_SIZE;
+            reader.read_exact(&mut data)?;
+            hasher.update(&data);
+            let (id, record) = decode_record(&data, offset)?;
+            if previous.map(|p| p >= id).unwrap_or(false) {
+                return Err(Error::corrupt(offset, "the run isn't sorted"));
+            }
+            removals |= record.is_none();
+            bloom.insert(&id);
+            previous = Some(id);
+        }
+        let checksum = reader.read_u32::<BigEndian>()?;
+        if checksum != hasher.finalize() {
+            return Err(Error::corrupt(size - 4, "run checksum mismatch"));
+        }
+        drop(reader);
+        let run = Self {
+            path: path.to_path_buf(),
+            #[cfg(feature = "mmap")]
+            mapping: Mapping::new(&file)?,
+            #[cfg(not(feature = "mmap"))]
+            file,
+            count,
+            bloom,
+            removals,
+        };
+
+        Ok((run, algorithm))
+    }
+
+    /// Looks up the record of an id by a binary search over the run
+    fn get(&mut self, id: &EntryID) -> Result<Option<Record>> {
+        if !self.bloom.may_contain(id) {
+            return Ok(None);
+        }
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let offset = HEADER_SIZE + middle * RECORD_SIZE;
+            let data = self.record_at(offset)?;
+            let (found, record) = decode_record(&data, offset)?;
+            match found.cmp(id) {
+                std::cmp::Ordering::Equal => return Ok(Some(record)),
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+            }
+        }
+
+        Ok(None)
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    fn record_at(&mut self, offset: u64) -> Result<[u8; RECORD_SIZE as usize]> {
+        let mut data = [0u8; RECORD_SIZE as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut data)?;
+
+        Ok(data)
+    }
+
+    #[cfg(feature = "mmap")]
+    fn record_at(&mut self, offset: u64) -> Result<[u8; RECORD_SIZE as usize]> {
+        let mut data = [0u8; RECORD_SIZE as usize];
+        let start = offset as usize;
+        data.copy_from_slice(&self.mapping.as_slice()[start..start + RECORD_SIZE as usize]);
+
+        Ok(data)
+    }
+}
+
+/// Reads the records of a run in order
+struct RunReader {
+    reader: BufReader<File>,
+    /// The offset of the next record
+    offset: u64,
+    remaining: u64,
+}
+
+impl RunReader {
+    fn new(path: &Path, count: u64) -> Result<Self> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(HEADER_SIZE))?;
+
+        Ok(Self {
+            reader: BufReader::new(file),
+            offset: HEADER_SIZE,
+            remaining: count,
+        })
+    }
+
+    fn next_record(&mut self) -> Result<Option<(EntryID, Record)>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        let mut data = [0u8; RECORD_SIZE as usize];
+        self.reader.read_exact(&mut data)?;
+        let offset = self.offset;
+        self.offset += RECORD_SIZE;
+
+        Ok(Some(decode_record(&data, offset)?))
+    }
+}
+
+/// Merges runs ordered from the oldest to the newest. The record of the
+/// newest run is kept for ids that are in several runs
+struct Merge {
+    readers: Vec<RunReader>,
+    heads: Vec<Option<(EntryID, Record)>>,
+}
+
+impl Merge {
+    fn new(mut readers: Vec<RunReader>) -> Result<Self> {
+        let mut heads = Vec::with_capacity(readers.len());
+        for reader in &mut readers {
+            heads.push(reader.next_record()?);
+        }
+
+        Ok(Self { readers, heads })
+    }
+
+    fn next_record(&mut self) -> Result<Option<(EntryID, Record)>> {
+        let mut next: Option<(EntryID, Record)> = None;
+        for head in self.heads.iter().flatten() {
+            // later runs are newer so they replace records with the same id
+            if next.map(|(id, _)| head.0 <= id).unwrap_or(true) {
+                next = Some(*head);
+            }
+        }
+        if let Some((id, _)) = next {
+            for (head, reader) in self.heads.iter_mut().zip(&mut self.readers) {
+                if head.map(|(head, _)| head == id).unwrap_or(false) {
+                    *head = reader.next_record()?;
+                }
+            }
+        }
+
+        Ok(next)
+    }
+}
+
+impl Iterator for Merge {
+    type Item = Result<(EntryID, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+fn run_path(directory: &Path, sequence: u64) -> PathBuf {
+    directory.join(format!("{:016}.{}", sequence, RUN_EXTENSION))
+}
+
+fn encode_record(id: &EntryID, record: Record) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(RECORD_SIZE as usize);
+    data.write_all(id)?;
+    let (state, (file, pointer, length)) = match record {
+        Some(entry) => (USED, entry),
+        None => (REMOVED, (0, 0, 0)),
+    };
+    data.write_u8(state)?;
+    data.write_u32::<BigEndian>(file)?;
+    data.write_u64::<BigEndian>(pointer)?;
+    data.write_u64::<BigEndian>(length)?;
+
+    Ok(data)
+}
+
+/// Decodes the record at the offset of the run
+fn decode_record(data: &[u8], offset: u64) -> Result<(EntryID, Record)> {
+    let mut id = [0u8; 32];
+    id.copy_from_slice(&data[..32]);
+    let mut reader = &data[32..];
+    let (state, entry) = reader
+        .read_u8()
+        .and_then(|state| {
+            let entry = (
+                reader.read_u32::<BigEndian>()?,
+                reader.read_u64::<BigEndian>()?,
+                reader.read_u64::<BigEndian>()?,
+            );
+            Ok((state, entry))
+        })
+        .map_err(|e| truncated(e, offset))?;
+    match state {
+        USED => Ok((id, Some(entry))),
+        REMOVED => Ok((id, None)),
+        state => Err(Error::corrupt(
+            offset,
+            format!("invalid record state {}", state),
+        )),
+    }
+}
+
+/// Turns the end of the data into a corruption error at the given offset
+fn truncated(error: io::Error, offset: u64) -> Error {
+    if error.kind() == io::ErrorKind::UnexpectedEof {
+        Error::corrupt(offset, "the run is truncated")
+    } else {
+        error.into()
+    }
+}
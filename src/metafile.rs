@@ -1,83 +1,1073 @@
+use crate::backend::SyncPolicy;
+use crate::error::{Error, Result};
+pub use crate::format::meta::{EntryID, MetaEntry, MAX_VALUE_LENGTH, UNKNOWN_LENGTH};
+use crate::format::meta::{
+    MetaTable, Values, FORMAT_VERSION, HASH_SIZE, LOG_INSERT, LOG_REMOVE, LOG_VALUES, MAGIC,
+};
+use crate::trace::{event, span};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use sha2::{Digest, Sha256};
-use std::collections::HashMap;
-use std::io::{self, Read, Write};
+use sha2::{Digest, Sha256, Sha512Trunc256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use xxhash_rust::xxh3::{xxh3_128, xxh3_128_with_seed};
 
-const HASH_SIZE: usize = 256 / 8;
+/// The number of entries [IndexedMetaFile::from_reader] accepts
+pub const DEFAULT_MAX_ENTRIES: u64 = u32::MAX as u64;
+/// The seed of the second half of [HashAlgorithm::Xxh3] ids
+const XXH3_SEED: u64 = 0x6966_735f_6964_7332;
+/// The size of the smallest entry of all versions
+const MIN_ENTRY_SIZE: u64 = HASH_SIZE as u64 + 12;
+/// The tag of the value that holds the namespace of an entry
+pub const NAMESPACE_TAG: u8 = u8::MAX;
+/// The first byte of the keys ids in a namespace are hashed from
+pub const NAMESPACE_PREFIX: u8 = 0xff;
+/// The tag of the value that holds the expiry of an entry in milliseconds since the epoch
+pub const EXPIRY_TAG: u8 = u8::MAX - 1;
+/// The tag of the value that holds the content of a blob stored inline
+pub const INLINE_TAG: u8 = u8::MAX - 2;
+/// The data file of entries whose blob is stored inline in the [INLINE_TAG] value.
+/// The pointer of these entries only tells different blobs apart
+pub const INLINE_FILE: u32 = u32::MAX;
+/// The tag of the value that lists the chunks of a blob spanning multiple data files
+pub const CHUNKS_TAG: u8 = u8::MAX - 3;
+/// The tag of the value that holds the version number of an entry
+pub const VERSION_TAG: u8 = u8::MAX - 4;
+/// The tag of the value that records when and from where an entry was deleted
+pub const TRASH_TAG: u8 = u8::MAX - 5;
+/// The tag of the value that marks an entry as pinned
+pub const PIN_TAG: u8 = u8::MAX - 6;
+/// The tag of the value that holds the content type and source name of a blob
+pub const BLOB_META_TAG: u8 = u8::MAX - 7;
+/// The tag of the value that lists the tags of an entry
+pub const TAGS_TAG: u8 = u8::MAX - 8;
+/// The data file of entries that only mark their id, e.g. in a secondary index.
+/// They don't reference a blob and aren't counted as references
+pub const MARKER_FILE: u32 = u32::MAX - 1;
+/// The tags of values that describe the blob of an entry and are shared by all
+/// ids referencing it
+const BLOB_TAGS: [u8; 2] = [INLINE_TAG, CHUNKS_TAG];
+/// The size of a chunk in the [CHUNKS_TAG] value
+const CHUNK_SIZE: usize = 20;
+/// The number of appended records that are always allowed before the file is compacted
+const DEFAULT_COMPACT_THRESHOLD: usize = 4096;
 
-pub type EntryID = [u8; HASH_SIZE];
-pub type MetaEntry = (u32, u64);
+/// Decides which entry is kept when two merged meta files have different
+/// entries for the same id
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the entry of the meta file that is merged into
+    KeepSelf,
+    /// Take the entry of the other meta file
+    KeepOther,
+    /// Fail with [Error::AlreadyExists] without changing anything
+    Error,
+}
+
+/// A change that is appended to the file
+enum Change {
+    Insert(MetaEntry),
+    Remove,
+    /// The encoded values of the entry
+    Values(Vec<u8>),
+}
+
+/// The algorithm that turns ids into entry ids. It's recorded in the meta file header
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    /// Sha512 truncated to 256 bits which is faster than Sha256 on 64 bit cpus
+    /// without sha extensions
+    Sha512Trunc256,
+    Blake3,
+    /// Two xxh3 128 bit hashes with different seeds. It's much faster but not
+    /// cryptographic, so it should only be used with keys that aren't chosen by
+    /// untrusted users
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    /// Returns the hashed id of a key. Keys can be any bytes like paths, binary ids or uuids
+    pub fn hash_id<K: AsRef<[u8]> + ?Sized>(&self, id: &K) -> EntryID {
+        let id = id.as_ref();
+        let mut array_result = [0u8; HASH_SIZE];
+        match self {
+            HashAlgorithm::Sha256 => array_result.copy_from_slice(&Sha256::digest(id)),
+            HashAlgorithm::Sha512Trunc256 => {
+                array_result.copy_from_slice(&Sha512Trunc256::digest(id))
+            }
+            HashAlgorithm::Blake3 => array_result = *blake3::hash(id).as_bytes(),
+            HashAlgorithm::Xxh3 => {
+                array_result[..16].copy_from_slice(&xxh3_128(id).to_be_bytes());
+                array_result[16..]
+                    .copy_from_slice(&xxh3_128_with_seed(id, XXH3_SEED).to_be_bytes());
+            }
+        }
+
+        array_result
+    }
+
+    /// Returns the number stored in headers
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            HashAlgorithm::Sha256 => 0,
+            HashAlgorithm::Sha512Trunc256 => 1,
+            HashAlgorithm::Blake3 => 2,
+            HashAlgorithm::Xxh3 => 3,
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(HashAlgorithm::Sha256),
+            1 => Some(HashAlgorithm::Sha512Trunc256),
+            2 => Some(HashAlgorithm::Blake3),
+            3 => Some(HashAlgorithm::Xxh3),
+            _ => None,
+        }
+    }
+}
 
 pub struct IndexedMetaFile {
     entries: HashMap<EntryID, MetaEntry>,
+    /// The ids referencing the extent at each data file and pointer. The number
+    /// of ids is the reference count of the extent, which is rebuilt from the
+    /// entries when the index is loaded
+    locations: BTreeMap<(u32, u64), Vec<EntryID>>,
+    /// Values that applications stored next to entries by their tag
+    values: HashMap<EntryID, Values>,
+    /// The file the table is saved to if it was opened from a path
+    path: Option<PathBuf>,
+    /// If the table changed since it was read or saved
+    dirty: bool,
+    /// If unsaved changes are written when the meta file is dropped
+    autosave: bool,
+    sync_policy: SyncPolicy,
+    /// Changes that haven't been appended to the file yet
+    log: Vec<(EntryID, Change)>,
+    /// The number of records appended after the table in the file
+    logged: usize,
+    /// If the file has to be rewritten instead of appended to
+    rewrite: bool,
+    compact_threshold: usize,
+    algorithm: HashAlgorithm,
 }
 
 impl IndexedMetaFile {
     /// Creates a new indexed meta file assuming it already exists
-    pub fn new() -> io::Result<Self> {
+    pub fn new() -> Result<Self> {
+        Self::with_algorithm(HashAlgorithm::default())
+    }
+
+    /// Creates a new indexed meta file that hashes ids with the given algorithm
+    pub fn with_algorithm(algorithm: HashAlgorithm) -> Result<Self> {
         Ok(Self {
             entries: HashMap::new(),
+            locations: BTreeMap::new(),
+            values: HashMap::new(),
+            path: None,
+            dirty: false,
+            autosave: false,
+            sync_policy: SyncPolicy::default(),
+            log: Vec::new(),
+            logged: 0,
+            rewrite: true,
+            compact_threshold: DEFAULT_COMPACT_THRESHOLD,
+            algorithm,
         })
     }
 
-    /// Creates a new MetaFile from a reader
-    pub fn from_reader<R: Read>(mut reader: R) -> io::Result<Self> {
-        let table_size = reader.read_u64::<BigEndian>()?;
-        let entries = Self::read_entries(table_size, reader)?;
+    /// Opens the meta file at the given path or creates an empty one if it doesn't
+    /// exist. The table is written back to the path with [IndexedMetaFile::save]
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut meta = if path.exists() {
+            let file = File::open(path)?;
+            // a table can't have more entries than fit into the file
+            let max_entries = file.metadata()?.len().saturating_sub(8) / MIN_ENTRY_SIZE;
+            Self::from_reader_limited(BufReader::new(file), max_entries.min(DEFAULT_MAX_ENTRIES))
+                .map_err(|e| e.in_file(path))?
+        } else {
+            Self::new()?
+        };
+        meta.path = Some(path.to_path_buf());
+
+        Ok(meta)
+    }
+
+    /// Returns the algorithm ids are hashed with
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Returns the hashed id of a key
+    pub fn hash_id<K: AsRef<[u8]> + ?Sized>(&self, id: &K) -> EntryID {
+        self.algorithm.hash_id(id)
+    }
+
+    /// Returns the path the meta file is saved to
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns if the table changed since it was read or saved
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
 
-        Ok(Self { entries })
+    /// Sets if unsaved changes are written when the meta file is dropped.
+    /// Errors on drop are ignored, so call [IndexedMetaFile::flush] where they matter
+    pub fn set_autosave(&mut self, autosave: bool) {
+        self.autosave = autosave;
     }
 
-    fn read_entries<R: Read>(
-        number: u64,
-        mut reader: R,
-    ) -> io::Result<HashMap<EntryID, MetaEntry>> {
-        let mut entries = HashMap::new();
-        for _ in 0..number {
-            let mut id = [0u8; HASH_SIZE];
-            reader.read(&mut id)?;
-            let data_file = reader.read_u32::<BigEndian>()?;
-            let data_pointer = reader.read_u64::<BigEndian>()?;
-            entries.insert(id, (data_file, data_pointer));
+    /// Sets when saved and appended changes are synced to the disk
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.sync_policy = policy;
+    }
+
+    /// Sets the number of appended records that are always allowed before the
+    /// file is rewritten. Beyond that the file is compacted once it holds more
+    /// records than the table has entries
+    pub fn set_compact_threshold(&mut self, records: usize) {
+        self.compact_threshold = records;
+    }
+
+    /// Returns the number of changes appended to the file since it was last compacted
+    pub fn log_length(&self) -> usize {
+        self.logged
+    }
+
+    /// Drops the changes that weren't flushed by reading the file again
+    pub fn reload(&mut self) -> Result<()> {
+        let path = self
+            .path
+            .clone()
+            .ok_or_else(|| Error::invalid_argument("the meta file has no path"))?;
+        let mut loaded = Self::open(&path)?;
+        loaded.autosave = self.autosave;
+        loaded.sync_policy = self.sync_policy;
+        loaded.compact_threshold = self.compact_threshold;
+        // the dropped changes must not be saved
+        self.autosave = false;
+        *self = loaded;
+
+        Ok(())
+    }
+
+    /// Writes the whole table to the path it was opened from
+    pub fn save(&mut self) -> Result<()> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| Error::invalid_argument("the meta file has no path"))?;
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()?;
+        if self.sync_policy != SyncPolicy::Never {
+            writer.get_ref().sync_data()?;
         }
+        self.dirty = false;
+        self.rewrite = false;
+        self.log.clear();
+        self.logged = 0;
 
-        Ok(entries)
+        Ok(())
     }
 
-    /// Writes the lookup table
-    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+    /// Writes the changes since the table was read or saved. They are appended to
+    /// the file unless it has to be compacted
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let _span = span!("index flush");
+        if self.rewrite {
+            return self.save();
+        }
+        event!(Debug, "appending {} changes to the index", self.log.len());
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| Error::invalid_argument("the meta file has no path"))?;
+        let mut writer = BufWriter::new(OpenOptions::new().append(true).open(path)?);
+        for (id, change) in &self.log {
+            let mut record = Vec::new();
+            match change {
+                Change::Insert(entry) => {
+                    record.write_u8(LOG_INSERT)?;
+                    record.write_all(id)?;
+                    write_entry(&mut record, entry)?;
+                }
+                Change::Remove => {
+                    record.write_u8(LOG_REMOVE)?;
+                    record.write_all(id)?;
+                }
+                Change::Values(values) => {
+                    record.write_u8(LOG_VALUES)?;
+                    record.write_all(id)?;
+                    record.write_u32::<BigEndian>(values.len() as u32)?;
+                    record.write_all(values)?;
+                }
+            }
+            writer.write_all(&record)?;
+            writer.write_u32::<BigEndian>(crc32fast::hash(&record))?;
+        }
+        writer.flush()?;
+        if self.sync_policy != SyncPolicy::Never {
+            writer.get_ref().sync_data()?;
+        }
+        self.logged += self.log.len();
+        self.log.clear();
+        self.dirty = false;
+
+        Ok(())
+    }
+
+    /// Rewrites the file without the appended changes
+    pub fn compact(&mut self) -> Result<()> {
+        if self.dirty || self.logged > 0 {
+            self.save()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Remembers a change so that it can be appended to the file
+    fn record(&mut self, id: EntryID, change: Change) {
+        self.dirty = true;
+        if self.rewrite {
+            return;
+        }
+        self.log.push((id, change));
+        if self.logged + self.log.len() > self.compact_threshold.max(self.entries.len()) {
+            self.rewrite = true;
+            self.log.clear();
+        }
+    }
+
+    /// Creates a new MetaFile from a reader applying the changes appended after the table.
+    /// Files without a header use Sha256. Files of version 1 are rewritten on the next
+    /// flush and their entries have an [UNKNOWN_LENGTH]. A truncated table or one that
+    /// doesn't match its checksum is reported as [Error::Corrupt]
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        Self::from_reader_limited(reader, DEFAULT_MAX_ENTRIES)
+    }
+
+    /// Creates a new MetaFile like [IndexedMetaFile::from_reader] but fails with
+    /// [Error::Corrupt] if the file has more than the given number of entries.
+    /// Untrusted files should be read with a limit that matches their length
+    pub fn from_reader_limited<R: Read>(mut reader: R, max_entries: u64) -> Result<Self> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let table = MetaTable::parse(&data, max_entries)?;
+        let entries: HashMap<EntryID, MetaEntry> = table.entries.into_iter().collect();
+        let mut locations: BTreeMap<(u32, u64), Vec<EntryID>> = BTreeMap::new();
+        for (id, entry) in &entries {
+            if entry.0 != MARKER_FILE {
+                locations.entry((entry.0, entry.1)).or_default().push(*id);
+            }
+        }
+
+        Ok(Self {
+            entries,
+            locations,
+            values: table.values.into_iter().collect(),
+            path: None,
+            dirty: table.rewrite,
+            autosave: false,
+            sync_policy: SyncPolicy::default(),
+            log: Vec::new(),
+            logged: table.logged,
+            rewrite: table.rewrite,
+            compact_threshold: DEFAULT_COMPACT_THRESHOLD,
+            algorithm: HashAlgorithm::from_u8(table.algorithm).unwrap_or_default(),
+        })
+    }
+
+    /// Writes the lookup table without a log
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut writer = Checksummed::new(writer);
+        writer.write_all(&MAGIC)?;
+        writer.write_u16::<BigEndian>(FORMAT_VERSION)?;
+        writer.write_u8(self.algorithm.to_u8())?;
+        writer.write_u8(0)?;
         writer.write_u64::<BigEndian>(self.entries.len() as u64)?;
-        for (k, (df, dp)) in &self.entries {
-            writer.write(k)?;
-            writer.write_u32::<BigEndian>(*df)?;
-            writer.write_u64::<BigEndian>(*dp)?;
+        let empty = BTreeMap::new();
+        for (id, entry) in &self.entries {
+            writer.write_all(id)?;
+            write_entry(&mut writer, entry)?;
+            let values = encode_values(self.values.get(id).unwrap_or(&empty));
+            writer.write_u32::<BigEndian>(values.len() as u32)?;
+            writer.write_all(&values)?;
+        }
+        let checksum = writer.checksum();
+        writer.write_u32::<BigEndian>(checksum)?;
+
+        Ok(())
+    }
+
+    /// Adds a file entry and returns the entry it replaced
+    pub fn add_entry<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        id: &K,
+        entry: MetaEntry,
+    ) -> Option<MetaEntry> {
+        self.add_entry_raw(self.hash_id(id), entry)
+    }
+
+    /// Adds an entry by an id that is already hashed, e.g. a content hash,
+    /// and returns the entry it replaced
+    pub fn add_entry_raw(&mut self, id: EntryID, entry: MetaEntry) -> Option<MetaEntry> {
+        // ids sharing a blob each hold a copy of the values describing it
+        let shared: Vec<(u8, Option<Vec<u8>>)> = BLOB_TAGS
+            .iter()
+            .map(|tag| {
+                let value = self
+                    .locations
+                    .get(&(entry.0, entry.1))
+                    .and_then(|ids| ids.iter().find(|other| **other != id))
+                    .and_then(|other| self.values.get(other)?.get(tag))
+                    .cloned();
+                (*tag, value)
+            })
+            .collect();
+        self.record(id, Change::Insert(entry));
+        let previous = self.entries.insert(id, entry);
+        if let Some(previous) = previous {
+            self.release(&id, previous);
+        }
+        if entry.0 != MARKER_FILE {
+            self.locations
+                .entry((entry.0, entry.1))
+                .or_default()
+                .push(id);
+        }
+        for (tag, value) in shared {
+            let own = self.values.get(&id).and_then(|values| values.get(&tag));
+            match value {
+                Some(value) if own != Some(&value) => {
+                    self.set_value(id, tag, &value);
+                }
+                None if own.is_some() => {
+                    self.remove_value(id, tag);
+                }
+                _ => {}
+            }
+        }
+
+        previous
+    }
+
+    /// Adds an entry with the content of a small blob stored in the meta file
+    /// instead of a data file and returns the entry it replaced
+    pub fn add_inline_entry<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        id: &K,
+        content: &[u8],
+    ) -> Result<Option<MetaEntry>> {
+        if content.len() > MAX_VALUE_LENGTH {
+            return Err(Error::ValueTooLarge {
+                size: content.len(),
+                max: MAX_VALUE_LENGTH,
+            });
+        }
+        let id = self.hash_id(id);
+        let pointer = self
+            .locations
+            .range((INLINE_FILE, 0)..=(INLINE_FILE, u64::MAX))
+            .next_back()
+            .map_or(0, |((_, pointer), _)| pointer + 1);
+        let previous = self.add_entry_raw(id, (INLINE_FILE, pointer, content.len() as u64));
+        self.set_value(id, INLINE_TAG, content);
+
+        Ok(previous)
+    }
+
+    /// Returns the content of an entry whose blob is stored inline
+    pub fn inline_content<K: AsRef<[u8]> + ?Sized>(&self, id: &K) -> Option<&[u8]> {
+        self.inline_content_raw(&self.hash_id(id))
+    }
+
+    /// Returns the inline content of an entry by its hashed id
+    pub fn inline_content_raw(&self, id: &EntryID) -> Option<&[u8]> {
+        self.values
+            .get(id)?
+            .get(&INLINE_TAG)
+            .map(|value| value.as_slice())
+    }
+
+    /// Records the chunks of a blob that spans multiple data files. The entry
+    /// points to the first chunk and has the length of the whole blob
+    pub fn set_chunks<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        id: &K,
+        chunks: &[MetaEntry],
+    ) -> Result<()> {
+        if chunks.len() * CHUNK_SIZE > MAX_VALUE_LENGTH {
+            return Err(Error::ValueTooLarge {
+                size: chunks.len() * CHUNK_SIZE,
+                max: MAX_VALUE_LENGTH,
+            });
+        }
+        let id = self.entry_id(id)?;
+        let mut value = Vec::with_capacity(chunks.len() * CHUNK_SIZE);
+        for (file, pointer, length) in chunks {
+            value.extend_from_slice(&file.to_be_bytes());
+            value.extend_from_slice(&pointer.to_be_bytes());
+            value.extend_from_slice(&length.to_be_bytes());
         }
+        self.set_value(id, CHUNKS_TAG, &value);
 
         Ok(())
     }
 
-    /// Adds a file entry
-    pub fn add_entry(&mut self, id: &str, file: u32, pointer: u64) {
-        self.entries.insert(hash_id(id), (file, pointer));
+    /// Returns the chunks of an entry whose blob spans multiple data files
+    /// or an empty list if the blob is stored in one piece
+    pub fn chunks<K: AsRef<[u8]> + ?Sized>(&self, id: &K) -> Vec<MetaEntry> {
+        self.chunks_raw(&self.hash_id(id))
+    }
+
+    /// Returns the chunks of a blob by the hashed id of its entry
+    pub fn chunks_raw(&self, id: &EntryID) -> Vec<MetaEntry> {
+        let value = match self
+            .values
+            .get(id)
+            .and_then(|values| values.get(&CHUNKS_TAG))
+        {
+            Some(value) => value,
+            None => return Vec::new(),
+        };
+        value
+            .chunks_exact(CHUNK_SIZE)
+            .map(|mut chunk| {
+                // reading from a slice of the right size can't fail
+                (
+                    chunk.read_u32::<BigEndian>().unwrap_or(0),
+                    chunk.read_u64::<BigEndian>().unwrap_or(0),
+                    chunk.read_u64::<BigEndian>().unwrap_or(0),
+                )
+            })
+            .collect()
     }
 
     /// Returns an entry by id
-    pub fn get_entry(&self, id: &str) -> Option<&MetaEntry> {
-        self.entries.get(&hash_id(id))
+    pub fn get_entry<K: AsRef<[u8]> + ?Sized>(&self, id: &K) -> Option<&MetaEntry> {
+        self.entries.get(&self.hash_id(id))
+    }
+
+    /// Returns an entry by its hashed id as recorded in the tree entries
+    pub fn get_entry_raw(&self, id: &EntryID) -> Option<&MetaEntry> {
+        self.entries.get(id)
+    }
+
+    /// Returns the number of entries
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns if the meta file has no entries
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns if there's an entry for the id
+    pub fn contains<K: AsRef<[u8]> + ?Sized>(&self, id: &K) -> bool {
+        self.contains_raw(&self.hash_id(id))
+    }
+
+    /// Returns if there's an entry for the hashed id
+    pub fn contains_raw(&self, id: &EntryID) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    /// Removes an entry from the meta file and returns it
+    pub fn remove_entry<K: AsRef<[u8]> + ?Sized>(&mut self, id: &K) -> Option<MetaEntry> {
+        self.remove_entry_raw(&self.hash_id(id))
+    }
+
+    /// Removes an entry by its hashed id together with its values
+    pub fn remove_entry_raw(&mut self, id: &EntryID) -> Option<MetaEntry> {
+        let entry = self.entries.remove(id)?;
+        self.release(id, entry);
+        self.values.remove(id);
+        self.record(*id, Change::Remove);
+
+        Some(entry)
+    }
+
+    /// Stores a value with the given tag next to an entry, e.g. a content type
+    /// or flags, and returns the value it replaced. Values stay when the entry is
+    /// replaced and are removed with it
+    pub fn set_meta<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        id: &K,
+        tag: u8,
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        let id = self.entry_id(id)?;
+
+        self.set_meta_raw(&id, tag, value)
+    }
+
+    /// Stores a value next to an entry by its hashed id
+    pub fn set_meta_raw(&mut self, id: &EntryID, tag: u8, value: &[u8]) -> Result<Option<Vec<u8>>> {
+        if value.len() > MAX_VALUE_LENGTH {
+            return Err(Error::ValueTooLarge {
+                size: value.len(),
+                max: MAX_VALUE_LENGTH,
+            });
+        }
+        if !self.entries.contains_key(id) {
+            return Err(Error::NotFound {
+                path: id.iter().map(|b| format!("{:02x}", b)).collect(),
+            });
+        }
+
+        Ok(self.set_value(*id, tag, value))
     }
 
-    /// Removes an entry from the meta file
-    pub fn remove_entry(&mut self, id: &str) {
-        self.entries.remove(&hash_id(id));
+    fn set_value(&mut self, id: EntryID, tag: u8, value: &[u8]) -> Option<Vec<u8>> {
+        let values = self.values.entry(id).or_default();
+        let previous = values.insert(tag, value.to_vec());
+        let encoded = encode_values(values);
+        self.record(id, Change::Values(encoded));
+
+        previous
+    }
+
+    /// Returns the value with the given tag stored next to an entry
+    pub fn get_meta<K: AsRef<[u8]> + ?Sized>(&self, id: &K, tag: u8) -> Option<&[u8]> {
+        self.get_meta_raw(&self.hash_id(id), tag)
+    }
+
+    /// Returns the value with the given tag stored next to an entry by its hashed id
+    pub fn get_meta_raw(&self, id: &EntryID, tag: u8) -> Option<&[u8]> {
+        self.values.get(id)?.get(&tag).map(|value| value.as_slice())
+    }
+
+    /// Removes the value with the given tag from an entry and returns it
+    pub fn remove_meta<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        id: &K,
+        tag: u8,
+    ) -> Result<Option<Vec<u8>>> {
+        let id = self.entry_id(id)?;
+
+        Ok(self.remove_value(id, tag))
+    }
+
+    fn remove_value(&mut self, id: EntryID, tag: u8) -> Option<Vec<u8>> {
+        let values = self.values.get_mut(&id)?;
+        let previous = values.remove(&tag);
+        if previous.is_some() {
+            let encoded = encode_values(values);
+            if values.is_empty() {
+                self.values.remove(&id);
+            }
+            self.record(id, Change::Values(encoded));
+        }
+
+        previous
+    }
+
+    /// Returns the tags of all values stored next to an entry
+    pub fn meta_tags<K: AsRef<[u8]> + ?Sized>(&self, id: &K) -> Vec<u8> {
+        self.values
+            .get(&self.hash_id(id))
+            .map(|values| values.keys().copied().collect())
+            .unwrap_or_default()
     }
+
+    /// Returns the hashed id of a key that has an entry
+    fn entry_id<K: AsRef<[u8]> + ?Sized>(&self, id: &K) -> Result<EntryID> {
+        let hashed = self.hash_id(id);
+        if self.entries.contains_key(&hashed) {
+            Ok(hashed)
+        } else {
+            Err(Error::NotFound {
+                path: String::from_utf8_lossy(id.as_ref()).into_owned(),
+            })
+        }
+    }
+
+    /// Returns the number of ids that reference the extent of the blob. Entries at
+    /// the same location count together even if they were written with different
+    /// lengths, e.g. before and after the blob was extended
+    pub fn ref_count(&self, entry: &MetaEntry) -> u32 {
+        self.extent_refs(entry.0, entry.1)
+    }
+
+    /// Returns the number of ids that reference the extent at the given location.
+    /// The chunks of a blob are shared by the ids sharing its first chunk
+    pub fn extent_refs(&self, file: u32, pointer: u64) -> u32 {
+        self.locations
+            .get(&(file, pointer))
+            .map_or(0, |ids| ids.len() as u32)
+    }
+
+    /// Returns the locations of the extents referenced by more than one id
+    /// together with their reference count
+    pub fn shared_extents(&self) -> impl Iterator<Item = ((u32, u64), u32)> + '_ {
+        self.locations
+            .iter()
+            .filter(|(_, ids)| ids.len() > 1)
+            .map(|(location, ids)| (*location, ids.len() as u32))
+    }
+
+    fn release(&mut self, id: &EntryID, entry: MetaEntry) {
+        let location = (entry.0, entry.1);
+        if let Some(ids) = self.locations.get_mut(&location) {
+            ids.retain(|other| other != id);
+            if ids.is_empty() {
+                self.locations.remove(&location);
+            }
+        }
+    }
+
+    /// Adds the entries of another meta file that uses the same hash algorithm and
+    /// returns the number of entries that were added or replaced. Values are taken
+    /// along with the entries
+    pub fn merge(&mut self, other: &IndexedMetaFile, policy: ConflictPolicy) -> Result<usize> {
+        if other.algorithm != self.algorithm {
+            return Err(Error::invalid_argument(
+                "the meta files use different hash algorithms",
+            ));
+        }
+        if policy == ConflictPolicy::Error {
+            let conflict = other
+                .entries
+                .iter()
+                .find(|(id, entry)| self.entries.get(*id).is_some_and(|own| own != *entry));
+            if let Some((id, _)) = conflict {
+                return Err(Error::AlreadyExists {
+                    path: id.iter().map(|b| format!("{:02x}", b)).collect(),
+                });
+            }
+        }
+        let mut merged = 0;
+        for (id, entry) in &other.entries {
+            let taken = match self.entries.get(id) {
+                None => true,
+                Some(own) => own != entry && policy == ConflictPolicy::KeepOther,
+            };
+            if !taken {
+                continue;
+            }
+            self.add_entry_raw(*id, *entry);
+            let values = other.values.get(id).cloned().unwrap_or_default();
+            self.record(*id, Change::Values(encode_values(&values)));
+            set_values(&mut self.values, *id, values);
+            merged += 1;
+        }
+
+        Ok(merged)
+    }
+
+    /// Returns the entries with blobs in the given data file ordered by their pointer
+    pub fn entries_in_file(&self, file: u32) -> impl Iterator<Item = (&EntryID, &MetaEntry)> {
+        let entries = &self.entries;
+        self.locations
+            .range((file, 0)..=(file, u64::MAX))
+            .flat_map(|(_, ids)| ids.iter())
+            .filter_map(move |id| Some((id, entries.get(id)?)))
+    }
+
+    /// Points the entries and chunks at the old pointers of the data file to their
+    /// new pointers after the blobs were moved, e.g. to fill gaps. Values stay
+    /// with their entries. The new pointers must not be used by other blobs
+    pub fn relocate(&mut self, file: u32, moved: &BTreeMap<u64, u64>) {
+        let mut relocated = Vec::new();
+        for (pointer, new_pointer) in moved {
+            if let Some(ids) = self.locations.remove(&(file, *pointer)) {
+                relocated.push((*new_pointer, ids));
+            }
+        }
+        for (pointer, ids) in relocated {
+            for id in &ids {
+                if let Some(entry) = self.entries.get_mut(id) {
+                    entry.1 = pointer;
+                    let entry = *entry;
+                    self.record(*id, Change::Insert(entry));
+                }
+            }
+            self.locations.insert((file, pointer), ids);
+        }
+
+        let chunked: Vec<EntryID> = self
+            .values
+            .iter()
+            .filter(|(_, values)| values.contains_key(&CHUNKS_TAG))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in chunked {
+            let chunks = self.chunks_raw(&id);
+            if !chunks
+                .iter()
+                .any(|(f, pointer, _)| *f == file && moved.contains_key(pointer))
+            {
+                continue;
+            }
+            let mut value = Vec::with_capacity(chunks.len() * CHUNK_SIZE);
+            for (f, pointer, length) in chunks {
+                let pointer = match f == file {
+                    true => *moved.get(&pointer).unwrap_or(&pointer),
+                    false => pointer,
+                };
+                value.extend_from_slice(&f.to_be_bytes());
+                value.extend_from_slice(&pointer.to_be_bytes());
+                value.extend_from_slice(&length.to_be_bytes());
+            }
+            self.set_value(id, CHUNKS_TAG, &value);
+        }
+    }
+
+    /// Returns the entry of the blob at the given location and the ids referencing it
+    pub fn entry_at(&self, file: u32, pointer: u64) -> Option<(&MetaEntry, &[EntryID])> {
+        let ids = self.locations.get(&(file, pointer))?;
+        let entry = self.entries.get(ids.first()?)?;
+
+        Some((entry, ids))
+    }
+
+    /// Returns an iterator over all hashed ids and their entries in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&EntryID, &MetaEntry)> {
+        self.entries.iter()
+    }
+
+    /// Sets the time after which [IndexedMetaFile::expire] removes the entry. The
+    /// expiry is stored as the value with the [EXPIRY_TAG]
+    pub fn set_expiry<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        id: &K,
+        expiry: Option<SystemTime>,
+    ) -> Result<()> {
+        match expiry {
+            Some(expiry) => {
+                let id = self.entry_id(id)?;
+                let millis = expiry
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis() as u64)
+                    .unwrap_or(0);
+                self.set_value(id, EXPIRY_TAG, &millis.to_be_bytes());
+            }
+            None => {
+                self.remove_meta(id, EXPIRY_TAG)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the time after which the entry expires
+    pub fn expiry<K: AsRef<[u8]> + ?Sized>(&self, id: &K) -> Option<SystemTime> {
+        self.values.get(&self.hash_id(id)).and_then(expiry)
+    }
+
+    /// Returns the ids of the entries that expire at or before the given time
+    pub fn expired(&self, now: SystemTime) -> Vec<EntryID> {
+        self.values
+            .iter()
+            .filter(|(id, values)| {
+                self.entries.contains_key(*id) && expiry(values).is_some_and(|e| e <= now)
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Removes the entries that expire at or before the given time and returns them
+    pub fn expire(&mut self, now: SystemTime) -> Vec<(EntryID, MetaEntry)> {
+        self.expired(now)
+            .into_iter()
+            .filter_map(|id| Some((id, self.remove_entry_raw(&id)?)))
+            .collect()
+    }
+
+    /// Removes the entries whose expiry has passed and returns them
+    pub fn expire_now(&mut self) -> Vec<(EntryID, MetaEntry)> {
+        self.expire(SystemTime::now())
+    }
+
+    /// Adds an entry to a namespace and returns the entry it replaced. The same id
+    /// can be used in different namespaces. The namespace is stored as the value
+    /// with the [NAMESPACE_TAG]
+    pub fn add_entry_in<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        namespace: &str,
+        id: &K,
+        entry: MetaEntry,
+    ) -> Result<Option<MetaEntry>> {
+        if namespace.len() > MAX_VALUE_LENGTH {
+            return Err(Error::NameTooLong {
+                name: namespace.to_string(),
+                max: MAX_VALUE_LENGTH,
+            });
+        }
+        let id = self.hash_id_in(namespace, id);
+        let previous = self.add_entry_raw(id, entry);
+        let recorded = self.values.get(&id).and_then(|v| v.get(&NAMESPACE_TAG));
+        if recorded.map(|v| v.as_slice()) != Some(namespace.as_bytes()) {
+            self.set_value(id, NAMESPACE_TAG, namespace.as_bytes());
+        }
+
+        Ok(previous)
+    }
+
+    /// Returns an entry of a namespace
+    pub fn get_entry_in<K: AsRef<[u8]> + ?Sized>(
+        &self,
+        namespace: &str,
+        id: &K,
+    ) -> Option<&MetaEntry> {
+        self.entries.get(&self.hash_id_in(namespace, id))
+    }
+
+    /// Removes an entry from a namespace and returns it
+    pub fn remove_entry_in<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        namespace: &str,
+        id: &K,
+    ) -> Option<MetaEntry> {
+        self.remove_entry_raw(&self.hash_id_in(namespace, id))
+    }
+
+    /// Returns the entries of a namespace in no particular order
+    pub fn namespace_entries<'a>(
+        &'a self,
+        namespace: &'a str,
+    ) -> impl Iterator<Item = (&'a EntryID, &'a MetaEntry)> {
+        let entries = &self.entries;
+        self.values
+            .iter()
+            .filter(move |(_, values)| {
+                values.get(&NAMESPACE_TAG).map(|v| v.as_slice()) == Some(namespace.as_bytes())
+            })
+            .filter_map(move |(id, _)| Some((id, entries.get(id)?)))
+    }
+
+    /// Returns the names of all namespaces with entries sorted by name
+    pub fn namespaces(&self) -> Vec<String> {
+        let names: BTreeSet<String> = self
+            .values
+            .values()
+            .filter_map(|values| values.get(&NAMESPACE_TAG))
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect();
+
+        names.into_iter().collect()
+    }
+
+    /// Removes all entries of a namespace and returns them
+    pub fn remove_namespace(&mut self, namespace: &str) -> Vec<MetaEntry> {
+        let ids: Vec<EntryID> = self
+            .namespace_entries(namespace)
+            .map(|(id, _)| *id)
+            .collect();
+
+        ids.iter()
+            .filter_map(|id| self.remove_entry_raw(id))
+            .collect()
+    }
+
+    /// Returns the hashed id of a key in a namespace. The namespace is prefixed
+    /// with its length so that namespaces and keys can't be shifted into each other.
+    /// The key starts with [NAMESPACE_PREFIX] which never occurs in UTF-8, so it
+    /// can't collide with a path or any other string key outside of namespaces
+    pub fn hash_id_in<K: AsRef<[u8]> + ?Sized>(&self, namespace: &str, id: &K) -> EntryID {
+        let mut key = Vec::with_capacity(3 + namespace.len() + id.as_ref().len());
+        key.push(NAMESPACE_PREFIX);
+        key.extend_from_slice(&(namespace.len() as u16).to_be_bytes());
+        key.extend_from_slice(namespace.as_bytes());
+        key.extend_from_slice(id.as_ref());
+
+        self.hash_id(&key)
+    }
+
+    /// Returns an iterator over all hashed ids in no particular order
+    pub fn ids(&self) -> impl Iterator<Item = &EntryID> {
+        self.entries.keys()
+    }
+}
+
+impl Drop for IndexedMetaFile {
+    fn drop(&mut self) {
+        if self.autosave && self.path.is_some() {
+            let _ = self.flush();
+        }
+    }
+}
+
+fn write_entry<W: Write>(writer: &mut W, (file, pointer, length): &MetaEntry) -> io::Result<()> {
+    writer.write_u32::<BigEndian>(*file)?;
+    writer.write_u64::<BigEndian>(*pointer)?;
+    writer.write_u64::<BigEndian>(*length)
+}
+
+/// Encodes values as a sequence of tag, length and value
+fn encode_values(values: &Values) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for (tag, value) in values {
+        encoded.push(*tag);
+        encoded.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        encoded.extend_from_slice(value);
+    }
+
+    encoded
+}
+
+/// Computes the checksum of the data written through it
+struct Checksummed<T> {
+    inner: T,
+    hasher: crc32fast::Hasher,
 }
 
-fn hash_id(id: &str) -> [u8; HASH_SIZE] {
-    let mut hasher = Sha256::default();
-    hasher.update(&id.as_bytes());
-    let result = hasher.finalize();
-    let mut array_result = [0u8; HASH_SIZE];
-    array_result.copy_from_slice(&result[..]);
+impl<T> Checksummed<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    /// Returns the checksum of the data written so far
+    fn checksum(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn set_values(all: &mut HashMap<EntryID, Values>, id: EntryID, values: Values) {
+    if values.is_empty() {
+        all.remove(&id);
+    } else {
+        all.insert(id, values);
+    }
+}
+
+/// Returns the expiry stored in the values of an entry
+fn expiry(values: &Values) -> Option<SystemTime> {
+    let millis: [u8; 8] = values.get(&EXPIRY_TAG)?.as_slice().try_into().ok()?;
+
+    Some(UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(millis)))
+}
 
-    array_result
+/// Returns the hashed id of a key using the default algorithm
+pub fn hash_id<K: AsRef<[u8]> + ?Sized>(id: &K) -> [u8; HASH_SIZE] {
+    HashAlgorithm::default().hash_id(id)
 }
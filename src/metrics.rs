@@ -0,0 +1,35 @@
+//! Hooks that report measurements of the storage to a metrics system like
+//! prometheus. Implement [Metrics] and pass it to [Storage::set_metrics]
+//!
+//! [Storage::set_metrics]: crate::storage::Storage::set_metrics
+
+/// Counts the bytes of file content written to the data files and the index
+pub const BYTES_WRITTEN: &str = "ifs_bytes_written";
+/// Counts the blobs written to the data files and the content stored inline
+pub const BLOBS_STORED: &str = "ifs_blobs_stored";
+/// The seconds it took to find the content of a file in the index
+pub const LOOKUP_SECONDS: &str = "ifs_lookup_seconds";
+/// The seconds it took to resolve a path in the directory tree
+pub const TREE_LOOKUP_SECONDS: &str = "ifs_tree_lookup_seconds";
+/// The percentage of the data files that isn't used by any blob
+pub const FRAGMENTATION: &str = "ifs_fragmentation";
+/// The percentage of the tree file that isn't used by any entry
+pub const TREE_FRAGMENTATION: &str = "ifs_tree_fragmentation";
+
+/// Receives measurements by the names above. All methods do nothing by default
+pub trait Metrics: Send + Sync {
+    /// Adds the value to a counter
+    fn counter(&self, _name: &'static str, _value: u64) {}
+
+    /// Sets a gauge to the value
+    fn gauge(&self, _name: &'static str, _value: f64) {}
+
+    /// Records an observation in a histogram
+    fn histogram(&self, _name: &'static str, _value: f64) {}
+}
+
+/// Discards all measurements
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
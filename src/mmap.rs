@@ -0,0 +1,60 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+/// A read only memory mapping of a whole file. The file must not shrink while
+/// it's mapped as reading past its new end raises a signal
+pub(crate) struct Mapping {
+    pointer: *mut libc::c_void,
+    length: usize,
+}
+
+// the mapping is read only and not tied to a thread
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    pub fn new(file: &File) -> io::Result<Self> {
+        let length = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "the file is too large"))?;
+        if length == 0 {
+            return Ok(Self {
+                pointer: std::ptr::null_mut(),
+                length,
+            });
+        }
+        let pointer = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                length,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if pointer == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { pointer, length })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        if self.length == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.pointer as *const u8, self.length) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.length > 0 {
+            unsafe {
+                libc::munmap(self.pointer, self.length);
+            }
+        }
+    }
+}
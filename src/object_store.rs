@@ -0,0 +1,572 @@
+//! An implementation of [object_store::ObjectStore] over a storage, so data
+//! pipelines written against the `object_store` crate can read and write a storage.
+//! The storage is used from the blocking thread pool of tokio
+
+use crate::error::Error;
+use crate::storage::{BlobReader, EntryInfo, ListCursor, Storage};
+use crate::utils::{join_path, split_path};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::future;
+use futures_util::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use object_store::path::Path;
+use object_store::{
+    Attribute, Attributes, CopyMode, CopyOptions, GetOptions, GetResult, GetResultPayload,
+    ListResult, MultipartUpload, ObjectMeta, ObjectStore, PutMode, PutMultipartOptions, PutOptions,
+    PutPayload, PutResult, RenameOptions, RenameTargetMode, Result, UploadPart,
+};
+use std::collections::VecDeque;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::io::{self, Read};
+use std::mem;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The name of the store in generic errors
+const STORE: &str = "IndexedFileStorage";
+/// The number of bytes read from the storage for each chunk of a get stream
+const CHUNK_SIZE: u64 = 64 * 1024;
+/// The number of files listed from the storage at a time
+const LIST_PAGE_SIZE: usize = 1000;
+
+/// Exposes a storage as an [ObjectStore]. The location `a/b.txt` is the file
+/// `/a/b.txt` and the parent directories are created when an object is put and
+/// removed when they become empty. Directories and symlinks aren't objects.
+///
+/// Writes through the store are serialized so conditional puts, copies and
+/// renames can check their target first. They're only atomic against other
+/// writes through the same store
+pub struct StorageObjectStore {
+    storage: Arc<Storage>,
+    writes: Arc<Mutex<()>>,
+}
+
+impl StorageObjectStore {
+    pub fn new(storage: Arc<Storage>) -> Self {
+        Self {
+            storage,
+            writes: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Returns the storage the store reads from and writes to
+    pub fn storage(&self) -> &Arc<Storage> {
+        &self.storage
+    }
+
+    /// Runs a write in the blocking thread pool while no other write of the store runs
+    async fn write<T, F>(&self, operation: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Storage) -> Result<T> + Send + 'static,
+    {
+        let writes = Arc::clone(&self.writes);
+        blocking(&self.storage, move |storage| {
+            let _guard = writes.lock().unwrap_or_else(PoisonError::into_inner);
+            operation(storage)
+        })
+        .await
+    }
+}
+
+impl Debug for StorageObjectStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StorageObjectStore").finish_non_exhaustive()
+    }
+}
+
+impl Display for StorageObjectStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", STORE)
+    }
+}
+
+/// Runs an operation on the shared storage in the blocking thread pool of tokio
+async fn blocking<T, F>(storage: &Arc<Storage>, operation: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Storage) -> Result<T> + Send + 'static,
+{
+    let storage = Arc::clone(storage);
+    tokio::task::spawn_blocking(move || operation(&storage))
+        .await
+        .map_err(generic)?
+}
+
+fn generic<E: std::error::Error + Send + Sync + 'static>(error: E) -> object_store::Error {
+    object_store::Error::Generic {
+        store: STORE,
+        source: Box::new(error),
+    }
+}
+
+/// Maps the errors with a counterpart in the `object_store` crate and wraps the others
+fn store_error(path: &str, error: Error) -> object_store::Error {
+    let path = path.trim_start_matches('/').to_string();
+    match error {
+        Error::NotFound { .. } | Error::IsADirectory { .. } | Error::NotADirectory { .. } => {
+            object_store::Error::NotFound {
+                path,
+                source: Box::new(error),
+            }
+        }
+        Error::AlreadyExists { .. } => object_store::Error::AlreadyExists {
+            path,
+            source: Box::new(error),
+        },
+        Error::ReadOnly { .. } => object_store::Error::PermissionDenied {
+            path,
+            source: Box::new(error),
+        },
+        error => generic(error),
+    }
+}
+
+fn storage_path(location: &Path) -> String {
+    format!("/{}", location)
+}
+
+fn object_path(path: &str) -> Result<Path> {
+    Ok(Path::parse(path.trim_start_matches('/'))?)
+}
+
+/// Describes an object. The e-tag changes with the modification time and the size
+fn object_meta(location: Path, size: u64, modified: Option<SystemTime>) -> ObjectMeta {
+    let modified = modified.unwrap_or(UNIX_EPOCH);
+    let nanos = modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+
+    ObjectMeta {
+        location,
+        last_modified: DateTime::<Utc>::from(modified),
+        size,
+        e_tag: Some(format!("\"{:x}-{:x}\"", nanos, size)),
+        version: None,
+    }
+}
+
+/// Returns the description of the object at the location
+fn head(storage: &Storage, location: &Path) -> Result<ObjectMeta> {
+    let path = storage_path(location);
+    let entry = storage.entry(&path).map_err(|e| store_error(&path, e))?;
+    if entry.is_dir() || entry.is_symlink() {
+        return Err(store_error(&path, Error::NotFound { path: path.clone() }));
+    }
+    let size = match entry.metadata() {
+        Some(metadata) => metadata.size,
+        None => storage
+            .get(&path)
+            .map_err(|e| store_error(&path, e))?
+            .remaining(),
+    };
+
+    Ok(object_meta(
+        location.clone(),
+        size,
+        entry.metadata().map(|m| m.modified),
+    ))
+}
+
+fn exists(storage: &Storage, path: &str) -> Result<bool> {
+    match storage.entry(path) {
+        Ok(_) => Ok(true),
+        Err(Error::NotFound { .. }) => Ok(false),
+        Err(e) => Err(store_error(path, e)),
+    }
+}
+
+/// Stores the parts at the location if the mode allows it
+fn put(
+    storage: &Storage,
+    location: &Path,
+    parts: VecDeque<Bytes>,
+    mode: PutMode,
+    attributes: &Attributes,
+) -> Result<PutResult> {
+    let path = storage_path(location);
+    match mode {
+        PutMode::Overwrite => {}
+        PutMode::Create => {
+            if exists(storage, &path)? {
+                return Err(store_error(
+                    &path,
+                    Error::AlreadyExists { path: path.clone() },
+                ));
+            }
+        }
+        PutMode::Update(version) => {
+            let current = head(storage, location)?;
+            if version.e_tag.is_none() || current.e_tag != version.e_tag {
+                return Err(object_store::Error::Precondition {
+                    path: location.to_string(),
+                    source: format!("the e-tag {:?} doesn't match", version.e_tag).into(),
+                });
+            }
+        }
+    }
+    storage
+        .store_at(&path, PartsReader { parts })
+        .map_err(|e| store_error(&path, e))?;
+    set_content_type(storage, &path, attributes.get(&Attribute::ContentType))?;
+
+    Ok(PutResult {
+        e_tag: head(storage, location)?.e_tag,
+        version: None,
+        extensions: Default::default(),
+    })
+}
+
+/// Sets the content type of the file if it differs
+fn set_content_type<S: AsRef<str>>(
+    storage: &Storage,
+    path: &str,
+    content_type: Option<S>,
+) -> Result<()> {
+    let mut blob_meta = storage.blob_meta(path).map_err(|e| store_error(path, e))?;
+    let content_type = content_type.map(|s| s.as_ref().to_string());
+    if blob_meta.content_type != content_type {
+        blob_meta.content_type = content_type;
+        storage
+            .set_blob_meta(path, &blob_meta)
+            .map_err(|e| store_error(path, e))?;
+    }
+
+    Ok(())
+}
+
+/// Removes the directory and its parents while they're empty
+fn remove_empty_dirs(storage: &Storage, mut dir: String) -> Result<()> {
+    while dir != "/" {
+        let empty = match storage.read_dir(&dir) {
+            Ok(entries) => entries.is_empty(),
+            Err(Error::NotFound { .. }) => false,
+            Err(e) => return Err(store_error(&dir, e)),
+        };
+        if !empty {
+            break;
+        }
+        storage.delete(&dir).map_err(|e| store_error(&dir, e))?;
+        dir = split_path(&dir).map_err(|e| store_error(&dir, e))?.0;
+    }
+
+    Ok(())
+}
+
+/// Deletes the object and the directories that become empty. Missing objects
+/// are ignored like in other stores
+fn delete(storage: &Storage, location: &Path) -> Result<()> {
+    let path = storage_path(location);
+    match storage.entry(&path) {
+        Ok(entry) if !entry.is_dir() => {}
+        Ok(_) | Err(Error::NotFound { .. }) | Err(Error::NotADirectory { .. }) => return Ok(()),
+        Err(e) => return Err(store_error(&path, e)),
+    }
+    storage.delete(&path).map_err(|e| store_error(&path, e))?;
+    let (parent, _) = split_path(&path).map_err(|e| store_error(&path, e))?;
+
+    remove_empty_dirs(storage, parent)
+}
+
+/// Returns a page of the files below the directory. A missing directory has no files
+async fn list_page(
+    storage: &Arc<Storage>,
+    dir: String,
+    cursor: Option<ListCursor>,
+) -> Result<(Vec<EntryInfo>, Option<ListCursor>)> {
+    blocking(storage, move |storage| {
+        match storage.list_in(&dir, cursor, LIST_PAGE_SIZE) {
+            Ok(page) => Ok(page),
+            Err(Error::NotFound { .. }) | Err(Error::NotADirectory { .. }) => {
+                Ok((Vec::new(), None))
+            }
+            Err(e) => Err(store_error(&dir, e)),
+        }
+    })
+    .await
+}
+
+/// Streams the content of a reader in chunks read in the blocking thread pool
+fn read_chunks(reader: BlobReader) -> BoxStream<'static, Result<Bytes>> {
+    stream::try_unfold(reader, |mut reader| async move {
+        let (reader, chunk) = tokio::task::spawn_blocking(move || {
+            let mut chunk = Vec::new();
+            (&mut reader)
+                .take(CHUNK_SIZE)
+                .read_to_end(&mut chunk)
+                .map(|_| (reader, chunk))
+        })
+        .await
+        .map_err(generic)?
+        .map_err(generic)?;
+
+        Ok(match chunk.is_empty() {
+            true => None,
+            false => Some((Bytes::from(chunk), reader)),
+        })
+    })
+    .boxed()
+}
+
+/// Reads the parts of a payload one after another without copying them together
+struct PartsReader {
+    parts: VecDeque<Bytes>,
+}
+
+impl Read for PartsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(part) = self.parts.front_mut() {
+            if part.is_empty() {
+                self.parts.pop_front();
+                continue;
+            }
+            let length = part.len().min(buf.len());
+            buf[..length].copy_from_slice(&part.split_to(length));
+            return Ok(length);
+        }
+
+        Ok(0)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for StorageObjectStore {
+    async fn put_opts(
+        &self,
+        location: &Path,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> Result<PutResult> {
+        let location = location.clone();
+        let parts = payload.into_iter().collect();
+        self.write(move |storage| put(storage, &location, parts, opts.mode, &opts.attributes))
+            .await
+    }
+
+    /// Collects the parts in memory and stores them when the upload is completed
+    async fn put_multipart_opts(
+        &self,
+        location: &Path,
+        opts: PutMultipartOptions,
+    ) -> Result<Box<dyn MultipartUpload>> {
+        Ok(Box::new(StorageUpload {
+            storage: Arc::clone(&self.storage),
+            writes: Arc::clone(&self.writes),
+            location: location.clone(),
+            attributes: opts.attributes,
+            parts: Vec::new(),
+        }))
+    }
+
+    async fn get_opts(&self, location: &Path, options: GetOptions) -> Result<GetResult> {
+        let location = location.clone();
+        let (meta, range, attributes, reader) = blocking(&self.storage, move |storage| {
+            let meta = head(storage, &location)?;
+            options.check_preconditions(&meta)?;
+            let range = match &options.range {
+                Some(range) => range.as_range(meta.size).map_err(generic)?,
+                None => 0..meta.size,
+            };
+            let path = storage_path(&location);
+            let mut attributes = Attributes::new();
+            let blob_meta = storage
+                .blob_meta(&path)
+                .map_err(|e| store_error(&path, e))?;
+            if let Some(content_type) = blob_meta.content_type {
+                attributes.insert(Attribute::ContentType, content_type.into());
+            }
+            let reader = storage
+                .get_range(&path, range.start, range.end - range.start)
+                .map_err(|e| store_error(&path, e))?;
+
+            Ok((meta, range, attributes, reader))
+        })
+        .await?;
+
+        Ok(GetResult {
+            payload: GetResultPayload::Stream(read_chunks(reader)),
+            meta,
+            range,
+            attributes,
+            extensions: Default::default(),
+        })
+    }
+
+    fn delete_stream(
+        &self,
+        locations: BoxStream<'static, Result<Path>>,
+    ) -> BoxStream<'static, Result<Path>> {
+        let storage = Arc::clone(&self.storage);
+        let writes = Arc::clone(&self.writes);
+        locations
+            .and_then(move |location| {
+                let store = Self {
+                    storage: Arc::clone(&storage),
+                    writes: Arc::clone(&writes),
+                };
+                async move {
+                    store
+                        .write(move |storage| delete(storage, &location).map(|_| location))
+                        .await
+                }
+            })
+            .boxed()
+    }
+
+    /// Lists the files below the prefix a page at a time in the order of their paths
+    fn list(&self, prefix: Option<&Path>) -> BoxStream<'static, Result<ObjectMeta>> {
+        let storage = Arc::clone(&self.storage);
+        let dir = prefix.map_or_else(|| String::from("/"), storage_path);
+        // the outer option is None after the last page
+        stream::try_unfold(Some(None), move |cursor: Option<Option<ListCursor>>| {
+            let storage = Arc::clone(&storage);
+            let dir = dir.clone();
+            async move {
+                let cursor = match cursor {
+                    Some(cursor) => cursor,
+                    None => return Ok(None),
+                };
+                let (files, next) = list_page(&storage, dir, cursor).await?;
+                let objects: Vec<Result<ObjectMeta>> = files
+                    .into_iter()
+                    .map(|file| {
+                        Ok(object_meta(
+                            object_path(&file.path)?,
+                            file.size,
+                            file.modified,
+                        ))
+                    })
+                    .collect();
+
+                Ok::<_, object_store::Error>(Some((stream::iter(objects), next.map(Some))))
+            }
+        })
+        .try_flatten()
+        .boxed()
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> Result<ListResult> {
+        let dir = prefix.map_or_else(|| String::from("/"), storage_path);
+        blocking(&self.storage, move |storage| {
+            let entries = match storage.read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(Error::NotFound { .. }) | Err(Error::NotADirectory { .. }) => Vec::new(),
+                Err(e) => return Err(store_error(&dir, e)),
+            };
+            let mut result = ListResult {
+                common_prefixes: Vec::new(),
+                objects: Vec::new(),
+                extensions: Default::default(),
+            };
+            for entry in entries {
+                let location = object_path(&join_path(&dir, &entry.name))?;
+                if entry.is_dir() {
+                    result.common_prefixes.push(location);
+                } else if !entry.is_symlink() {
+                    result.objects.push(head(storage, &location)?);
+                }
+            }
+            result.common_prefixes.sort();
+            result.objects.sort_by(|a, b| a.location.cmp(&b.location));
+
+            Ok(result)
+        })
+        .await
+    }
+
+    async fn copy_opts(&self, from: &Path, to: &Path, options: CopyOptions) -> Result<()> {
+        let (from, to) = (from.clone(), to.clone());
+        self.write(move |storage| {
+            let (from, to) = (storage_path(&from), storage_path(&to));
+            if let CopyMode::Create = options.mode {
+                if exists(storage, &to)? {
+                    return Err(store_error(&to, Error::AlreadyExists { path: to.clone() }));
+                }
+            }
+            if from == to {
+                return Ok(());
+            }
+            let reader = storage.get(&from).map_err(|e| store_error(&from, e))?;
+            storage
+                .store_at(&to, reader)
+                .map_err(|e| store_error(&to, e))?;
+            let blob_meta = storage
+                .blob_meta(&from)
+                .map_err(|e| store_error(&from, e))?;
+
+            set_content_type(storage, &to, blob_meta.content_type)
+        })
+        .await
+    }
+
+    async fn rename_opts(&self, from: &Path, to: &Path, options: RenameOptions) -> Result<()> {
+        let (from, to) = (from.clone(), to.clone());
+        self.write(move |storage| {
+            let (from, to) = (storage_path(&from), storage_path(&to));
+            if let RenameTargetMode::Create = options.target_mode {
+                if exists(storage, &to)? {
+                    return Err(store_error(&to, Error::AlreadyExists { path: to.clone() }));
+                }
+            }
+            let (parent, _) = split_path(&to).map_err(|e| store_error(&to, e))?;
+            storage
+                .create_dir_all(&parent)
+                .map_err(|e| store_error(&to, e))?;
+            storage
+                .rename(&from, &to)
+                .map_err(|e| store_error(&from, e))?;
+            let (parent, _) = split_path(&from).map_err(|e| store_error(&from, e))?;
+
+            remove_empty_dirs(storage, parent)
+        })
+        .await
+    }
+}
+
+/// A multipart upload that keeps the parts in memory until it's completed
+struct StorageUpload {
+    storage: Arc<Storage>,
+    writes: Arc<Mutex<()>>,
+    location: Path,
+    attributes: Attributes,
+    parts: Vec<PutPayload>,
+}
+
+impl Debug for StorageUpload {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StorageUpload")
+            .field("location", &self.location)
+            .field("parts", &self.parts.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl MultipartUpload for StorageUpload {
+    fn put_part(&mut self, data: PutPayload) -> UploadPart {
+        self.parts.push(data);
+        Box::pin(future::ready(Ok(())))
+    }
+
+    async fn complete(&mut self) -> Result<PutResult> {
+        let parts = mem::take(&mut self.parts)
+            .into_iter()
+            .flat_map(PutPayload::into_iter)
+            .collect();
+        let location = self.location.clone();
+        let attributes = self.attributes.clone();
+        let store = StorageObjectStore {
+            storage: Arc::clone(&self.storage),
+            writes: Arc::clone(&self.writes),
+        };
+        store
+            .write(move |storage| put(storage, &location, parts, PutMode::Overwrite, &attributes))
+            .await
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        self.parts.clear();
+        Ok(())
+    }
+}
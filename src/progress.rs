@@ -0,0 +1,116 @@
+use crate::error::{Error, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The state of a long running operation that is passed to its progress callback
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// The number of items processed so far, e.g. files, entries or chunks
+    pub items: u64,
+    /// The number of items the operation processes if it's known up front
+    pub total: Option<u64>,
+    /// The number of bytes read, written or moved so far
+    pub bytes: u64,
+}
+
+impl Progress {
+    /// Creates the progress of an operation that processes the given number of items
+    pub fn new(total: Option<u64>) -> Self {
+        Self {
+            items: 0,
+            total,
+            bytes: 0,
+        }
+    }
+
+    /// Counts a processed item of the given size
+    pub(crate) fn advance(&mut self, bytes: u64) {
+        self.items += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// Stops a long running operation from another thread. Operations check the token
+/// between items and fail with [Error::Cancelled] leaving the storage consistent
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the operations using the token to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Fails with [Error::Cancelled] if the token was cancelled
+    pub(crate) fn check(&self) -> Result<()> {
+        match self.is_cancelled() {
+            true => Err(Error::Cancelled),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Uses a flag that is already shared with other threads as a token
+impl From<Arc<AtomicBool>> for CancelToken {
+    fn from(flag: Arc<AtomicBool>) -> Self {
+        Self(flag)
+    }
+}
+
+/// The options of a long running operation: an optional callback that is called
+/// with the progress after every item and an optional token that cancels it
+#[derive(Default)]
+pub struct OperationOptions<'a> {
+    progress: Option<Box<dyn FnMut(Progress) + 'a>>,
+    cancel: Option<CancelToken>,
+}
+
+impl<'a> OperationOptions<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the callback that is called with the progress after every item
+    pub fn progress<F: FnMut(Progress) + 'a>(mut self, progress: F) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Sets the token that stops the operation with [Error::Cancelled]
+    pub fn cancel(mut self, token: &CancelToken) -> Self {
+        self.cancel = Some(token.clone());
+        self
+    }
+
+    /// Returns options with the same token but without a callback, e.g. for a
+    /// step of the operation that reports its progress differently
+    pub(crate) fn without_progress(&self) -> OperationOptions<'static> {
+        OperationOptions {
+            progress: None,
+            cancel: self.cancel.clone(),
+        }
+    }
+
+    /// Passes the progress to the callback
+    pub(crate) fn report(&mut self, progress: Progress) {
+        if let Some(callback) = &mut self.progress {
+            callback(progress);
+        }
+    }
+
+    /// Fails with [Error::Cancelled] if the token was cancelled
+    pub(crate) fn check(&self) -> Result<()> {
+        match &self.cancel {
+            Some(token) => token.check(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,339 @@
+//! An ordered log of the operations made through a storage, enabled with
+//! [Storage::enable_replication_log]. A replica reads it with
+//! [Storage::replication_log] and applies it with [Storage::apply_replication]
+//!
+//! [Storage::enable_replication_log]: crate::storage::Storage::enable_replication_log
+//! [Storage::replication_log]: crate::storage::Storage::replication_log
+//! [Storage::apply_replication]: crate::storage::Storage::apply_replication
+
+use crate::error::{Error, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crc32fast::Hasher;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// The name of the log in the storage directory
+pub const REPLICATION_FILE_NAME: &str = "replication.log";
+
+const CREATE_DIR: u8 = 0;
+const SYMLINK: u8 = 1;
+const STORE: u8 = 2;
+const DELETE: u8 = 3;
+const RENAME: u8 = 4;
+
+/// An operation that brings a replica to the state of the storage after a change.
+/// Stores are followed by the content of the file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Operation {
+    CreateDir { path: String },
+    Symlink { path: String, target: String },
+    Store { path: String },
+    Delete { path: String },
+    Rename { from: String, to: String },
+}
+
+/// Appends numbered records of operations. The records of a batch are kept
+/// in memory until it's committed
+pub(crate) struct ReplicationLog {
+    file: File,
+    /// The sequence number of the last written record
+    sequence: u64,
+    pending: Vec<u8>,
+    pending_count: u64,
+}
+
+impl ReplicationLog {
+    /// Opens the log at the path and continues after its last record. An
+    /// incomplete record of an interrupted write is dropped
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let scan = scan_records(path, &file, u64::MAX)?;
+        if file.metadata()?.len() > scan.end {
+            file.set_len(scan.end)?;
+        }
+
+        Ok(Self {
+            file,
+            sequence: scan.sequence,
+            pending: Vec::new(),
+            pending_count: 0,
+        })
+    }
+
+    /// Writes a record of the operation and the content of stores. Records of a
+    /// batch are kept until [ReplicationLog::commit]
+    pub fn record(
+        &mut self,
+        in_batch: bool,
+        sync: bool,
+        operation: &Operation,
+        content: Option<(&mut dyn Read, u64)>,
+    ) -> Result<()> {
+        self.pending_count += 1;
+        let sequence = self.sequence + self.pending_count;
+        write_record(&mut self.pending, sequence, operation, content)?;
+        if !in_batch {
+            self.commit(sync)?;
+        }
+
+        Ok(())
+    }
+
+    /// Appends the records of the current batch
+    pub fn commit(&mut self, sync: bool) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(&self.pending)?;
+        if sync {
+            self.file.sync_data()?;
+        }
+        self.sequence += self.pending_count;
+        self.discard();
+
+        Ok(())
+    }
+
+    /// Drops the records of the current batch
+    pub fn discard(&mut self) {
+        self.pending.clear();
+        self.pending_count = 0;
+    }
+}
+
+/// Opens the log at the path with the records after the sequence number. Only
+/// records complete at the time of the call are returned
+pub(crate) fn read_after(path: &Path, after: u64) -> Result<io::Take<File>> {
+    let mut file = File::open(path)?;
+    let scan = scan_records(path, &file, after)?;
+    file.seek(SeekFrom::Start(scan.start))?;
+
+    Ok(file.take(scan.end - scan.start))
+}
+
+/// The complete records of a log
+struct Scan {
+    /// The sequence number of the last record
+    sequence: u64,
+    /// The offset of the first record after the requested sequence number
+    start: u64,
+    /// The offset after the last record
+    end: u64,
+}
+
+/// Reads the records of the log up to an incomplete one that is still written or
+/// was interrupted
+fn scan_records(path: &Path, file: &File, after: u64) -> Result<Scan> {
+    let mut reader = BufReader::new(file);
+    let mut scan = Scan {
+        sequence: 0,
+        start: 0,
+        end: 0,
+    };
+    let mut found = false;
+    loop {
+        match skip_record(&mut reader) {
+            Ok(Some((sequence, length))) => {
+                if sequence > after && !found {
+                    scan.start = scan.end;
+                    found = true;
+                }
+                scan.sequence = sequence;
+                scan.end += length;
+            }
+            Ok(None) => break,
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.in_file(path)),
+        }
+    }
+    if !found {
+        scan.start = scan.end;
+    }
+
+    Ok(scan)
+}
+
+/// Writes a record with the sequence number, the operation, the length and data
+/// of the content and a checksum of all of it
+fn write_record<W: Write>(
+    writer: W,
+    sequence: u64,
+    operation: &Operation,
+    content: Option<(&mut dyn Read, u64)>,
+) -> Result<()> {
+    let mut writer = HashingWriter {
+        inner: writer,
+        hasher: Hasher::new(),
+    };
+    writer.write_u64::<BigEndian>(sequence)?;
+    match operation {
+        Operation::CreateDir { path } => {
+            writer.write_u8(CREATE_DIR)?;
+            write_string(&mut writer, path)?;
+        }
+        Operation::Symlink { path, target } => {
+            writer.write_u8(SYMLINK)?;
+            write_string(&mut writer, path)?;
+            write_string(&mut writer, target)?;
+        }
+        Operation::Store { path } => {
+            writer.write_u8(STORE)?;
+            write_string(&mut writer, path)?;
+        }
+        Operation::Delete { path } => {
+            writer.write_u8(DELETE)?;
+            write_string(&mut writer, path)?;
+        }
+        Operation::Rename { from, to } => {
+            writer.write_u8(RENAME)?;
+            write_string(&mut writer, from)?;
+            write_string(&mut writer, to)?;
+        }
+    }
+    let (reader, length) = match content {
+        Some(content) => content,
+        None => (&mut io::empty() as &mut dyn Read, 0),
+    };
+    writer.write_u64::<BigEndian>(length)?;
+    let copied = io::copy(&mut reader.take(length), &mut writer)?;
+    if copied != length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the content is shorter than its length",
+        )
+        .into());
+    }
+    let checksum = writer.hasher.finalize();
+    writer.inner.write_u32::<BigEndian>(checksum)?;
+
+    Ok(())
+}
+
+/// Reads the records of a replication log and checks their checksums
+pub(crate) struct RecordReader<R: Read> {
+    inner: R,
+    hasher: Hasher,
+    offset: u64,
+}
+
+impl<R: Read> RecordReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Hasher::new(),
+            offset: 0,
+        }
+    }
+
+    /// Reads the sequence number, operation and content length of the next record.
+    /// Returns None at the end of the log. The content has to be read from the
+    /// reader before the record is finished
+    pub fn next_operation(&mut self) -> Result<Option<(u64, Operation, u64)>> {
+        self.hasher = Hasher::new();
+        let sequence = match self.read_u64::<BigEndian>() {
+            Ok(sequence) => sequence,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let operation = match self.read_u8()? {
+            CREATE_DIR => Operation::CreateDir {
+                path: read_string(self)?,
+            },
+            SYMLINK => Operation::Symlink {
+                path: read_string(self)?,
+                target: read_string(self)?,
+            },
+            STORE => Operation::Store {
+                path: read_string(self)?,
+            },
+            DELETE => Operation::Delete {
+                path: read_string(self)?,
+            },
+            RENAME => Operation::Rename {
+                from: read_string(self)?,
+                to: read_string(self)?,
+            },
+            _ => return Err(Error::corrupt(self.offset, "unknown replication operation")),
+        };
+        let length = self.read_u64::<BigEndian>()?;
+
+        Ok(Some((sequence, operation, length)))
+    }
+
+    /// Reads the checksum after the content and compares it to the read record
+    pub fn finish_record(&mut self) -> Result<()> {
+        let checksum = self.inner.read_u32::<BigEndian>()?;
+        if checksum != self.hasher.clone().finalize() {
+            return Err(Error::corrupt(self.offset, "invalid replication record"));
+        }
+        self.offset += 4;
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for RecordReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.offset += read as u64;
+
+        Ok(read)
+    }
+}
+
+/// Returns the sequence number and length of the next record without keeping
+/// its content
+fn skip_record<R: Read>(reader: R) -> Result<Option<(u64, u64)>> {
+    let mut reader = RecordReader::new(reader);
+    let (sequence, _, length) = match reader.next_operation()? {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+    let skipped = io::copy(&mut (&mut reader).take(length), &mut io::sink())?;
+    if skipped != length {
+        return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+    reader.finish_record()?;
+
+    Ok(Some((sequence, reader.offset)))
+}
+
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write_string<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    writer.write_u32::<BigEndian>(value.len() as u32)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Result<String> {
+    let length = reader.read_u32::<BigEndian>()?;
+    let mut value = Vec::new();
+    reader.take(length as u64).read_to_end(&mut value)?;
+    if value.len() != length as usize {
+        return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+
+    String::from_utf8(value).map_err(|_| Error::corrupt(0, "a path isn't valid UTF-8"))
+}
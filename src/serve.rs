@@ -0,0 +1,398 @@
+//! A small HTTP/1.1 server for the files of a storage built on hyper. `GET` and
+//! `HEAD` read a file with support for single range requests or list a directory,
+//! `PUT` stores the request body and `DELETE` removes a file or an empty
+//! directory. The storage is used from the blocking thread pool of tokio
+
+use crate::error::{Error, Result};
+use crate::storage::{BlobReader, Storage};
+use crate::trace::event;
+use crate::utils::normalize_path;
+use bytes::Bytes;
+use futures_util::stream;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, Full, StreamBody};
+use hyper::body::{Frame, Incoming};
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use std::convert::{Infallible, TryFrom};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::SpooledTempFile;
+use tokio_io_timeout::TimeoutStream;
+
+/// How long reading from or writing to a connection may stall by default
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// The size up to which request bodies are buffered in memory instead of a temporary file
+const MAX_MEMORY_BODY: usize = 1024 * 1024;
+/// The size of the chunks the content of files is sent in
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+type Body = BoxBody<Bytes, io::Error>;
+
+/// Serves the files of a storage over HTTP
+pub struct Server {
+    storage: Arc<Storage>,
+    listener: TcpListener,
+    timeout: Duration,
+}
+
+impl Server {
+    /// Binds the server to the address, e.g. `127.0.0.1:8080`. Port 0 picks a free port
+    pub fn bind<A: ToSocketAddrs>(storage: Arc<Storage>, address: A) -> Result<Self> {
+        Ok(Self {
+            storage,
+            listener: TcpListener::bind(address)?,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// Returns the address the server is listening on
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Sets how long reading from or writing to a connection may stall before
+    /// the connection is closed
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Accepts connections on a runtime of its own until accepting fails
+    pub fn run(&self) -> Result<()> {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(self.serve())
+    }
+
+    /// Accepts connections on the current tokio runtime until accepting fails
+    pub async fn serve(&self) -> Result<()> {
+        let listener = self.listener.try_clone()?;
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = TimeoutStream::new(stream);
+            stream.set_read_timeout(Some(self.timeout));
+            stream.set_write_timeout(Some(self.timeout));
+            let storage = Arc::clone(&self.storage);
+            let service = service_fn(move |request| respond(Arc::clone(&storage), request));
+            let connection = http1::Builder::new()
+                .timer(TokioTimer::new())
+                .header_read_timeout(self.timeout)
+                .title_case_headers(true)
+                .serve_connection(TokioIo::new(Box::pin(stream)), service);
+            tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    event!(Warn, "failed to answer a request: {}", e);
+                }
+            });
+        }
+    }
+}
+
+async fn respond(
+    storage: Arc<Storage>,
+    request: Request<Incoming>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    let path = match percent_decode(request.uri().path()) {
+        Some(path) => normalize_path(&path),
+        None => return Ok(status(StatusCode::BAD_REQUEST)),
+    };
+    let head = request.method() == Method::HEAD;
+    let response = handle(storage, path, request)
+        .await
+        .unwrap_or_else(|e| status(error_status(&e)));
+    if head {
+        // the headers describe the body that isn't sent
+        let (parts, _) = response.into_parts();
+        return Ok(Response::from_parts(parts, empty()));
+    }
+
+    Ok(response)
+}
+
+async fn handle(
+    storage: Arc<Storage>,
+    path: String,
+    request: Request<Incoming>,
+) -> Result<Response<Body>> {
+    match *request.method() {
+        Method::GET | Method::HEAD => {
+            let range = request
+                .headers()
+                .get(header::RANGE)
+                .map(|range| range.to_str().unwrap_or_default().to_string());
+            blocking(storage, move |storage| {
+                if storage.entry(&path)?.is_dir() {
+                    list_dir(storage, &path)
+                } else {
+                    read_file(storage, &path, range.as_deref())
+                }
+            })
+            .await
+        }
+        Method::PUT => put(storage, path, request).await,
+        Method::DELETE => {
+            blocking(storage, move |storage| storage.delete(&path)).await?;
+            Ok(response(StatusCode::NO_CONTENT, empty()))
+        }
+        _ => Ok(with_header(
+            status(StatusCode::METHOD_NOT_ALLOWED),
+            header::ALLOW,
+            "GET, HEAD, PUT, DELETE",
+        )),
+    }
+}
+
+/// Runs the operation on the blocking thread pool
+async fn blocking<T, F>(storage: Arc<Storage>, operation: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Storage) -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || operation(&storage))
+        .await
+        .map_err(|e| Error::Io(io::Error::other(e)))?
+}
+
+/// Stores the request body. The body is received completely before it's stored
+/// so that a slow client doesn't hold the locks of the storage
+async fn put(
+    storage: Arc<Storage>,
+    path: String,
+    request: Request<Incoming>,
+) -> Result<Response<Body>> {
+    let length = match request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok())
+    {
+        Some(length) => length,
+        None => return Ok(status(StatusCode::LENGTH_REQUIRED)),
+    };
+    let mut body = SpooledTempFile::new(MAX_MEMORY_BODY);
+    let mut received = 0u64;
+    let mut incoming = request.into_body();
+    while let Some(frame) = incoming.frame().await {
+        let frame =
+            frame.map_err(|e| Error::invalid_argument(format!("incomplete body: {}", e)))?;
+        if let Ok(data) = frame.into_data() {
+            received += data.len() as u64;
+            body.write_all(&data)?;
+        }
+    }
+    if received != length {
+        return Ok(status(StatusCode::BAD_REQUEST));
+    }
+
+    blocking(storage, move |storage| {
+        body.seek(SeekFrom::Start(0))?;
+        if storage.store_at(&path, body.take(length))? != length {
+            storage.delete(&path)?;
+            return Ok(status(StatusCode::BAD_REQUEST));
+        }
+
+        Ok(status(StatusCode::CREATED))
+    })
+    .await
+}
+
+/// Lists the names of the entries of a directory, one per line. Directories end with a slash
+fn list_dir(storage: &Storage, path: &str) -> Result<Response<Body>> {
+    let mut listing = String::new();
+    for entry in storage.read_dir(path)? {
+        listing.push_str(&entry.name);
+        if entry.is_dir() {
+            listing.push('/');
+        }
+        listing.push('\n');
+    }
+
+    Ok(text(StatusCode::OK, listing))
+}
+
+fn read_file(storage: &Storage, path: &str, range: Option<&str>) -> Result<Response<Body>> {
+    let content_type = storage
+        .blob_meta(path)?
+        .content_type
+        .and_then(|content_type| HeaderValue::from_str(&content_type).ok())
+        .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+    let reader = storage.get(path)?;
+    let size = reader.remaining();
+    // ranges that can't be served are ignored and the whole file is sent
+    let range = match range.and_then(|range| parse_range(range, size)) {
+        None => None,
+        Some(ByteRange::Satisfiable(offset, length)) => Some((offset, length)),
+        Some(ByteRange::Unsatisfiable) => {
+            return Ok(with_header(
+                status(StatusCode::RANGE_NOT_SATISFIABLE),
+                header::CONTENT_RANGE,
+                format!("bytes */{}", size),
+            ))
+        }
+    };
+    let (response, length) = match range {
+        Some((offset, length)) => {
+            let body = read_chunks(storage.get_range(path, offset, length)?);
+            let response = with_header(
+                response(StatusCode::PARTIAL_CONTENT, body),
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", offset, offset + length - 1, size),
+            );
+            (response, length)
+        }
+        None => (response(StatusCode::OK, read_chunks(reader)), size),
+    };
+    let response = with_header(response, header::CONTENT_LENGTH, length);
+    let response = with_header(response, header::CONTENT_TYPE, content_type);
+
+    Ok(with_header(response, header::ACCEPT_RANGES, "bytes"))
+}
+
+/// Streams the content of the reader in chunks that are read on the blocking thread pool
+fn read_chunks(reader: BlobReader) -> Body {
+    let chunks = stream::try_unfold(reader, |mut reader| async move {
+        let (reader, chunk) = tokio::task::spawn_blocking(move || {
+            let mut chunk = Vec::new();
+            (&mut reader)
+                .take(CHUNK_SIZE)
+                .read_to_end(&mut chunk)
+                .map(|_| (reader, chunk))
+        })
+        .await
+        .map_err(io::Error::other)??;
+
+        Ok(match chunk.is_empty() {
+            true => None,
+            false => Some((Frame::data(Bytes::from(chunk)), reader)),
+        })
+    });
+
+    StreamBody::new(chunks).boxed()
+}
+
+fn response(status: StatusCode, body: Body) -> Response<Body> {
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+
+    response
+}
+
+fn with_header<V>(mut response: Response<Body>, name: HeaderName, value: V) -> Response<Body>
+where
+    HeaderValue: TryFrom<V>,
+{
+    // the values are numbers, ranges or were checked before
+    if let Ok(value) = HeaderValue::try_from(value) {
+        response.headers_mut().insert(name, value);
+    }
+
+    response
+}
+
+fn text(status: StatusCode, text: String) -> Response<Body> {
+    let body = Full::new(Bytes::from(text))
+        .map_err(|never| match never {})
+        .boxed();
+
+    with_header(
+        response(status, body),
+        header::CONTENT_TYPE,
+        "text/plain; charset=utf-8",
+    )
+}
+
+/// Returns a response with the status and its reason as plain text
+fn status(status: StatusCode) -> Response<Body> {
+    let reason = status.canonical_reason().unwrap_or_default();
+
+    text(status, format!("{}\n", reason))
+}
+
+fn empty() -> Body {
+    Empty::new().map_err(|never| match never {}).boxed()
+}
+
+/// A single range of a `Range` header
+enum ByteRange {
+    /// The offset and length of the range within the file
+    Satisfiable(u64, u64),
+    /// A valid range that starts after the end of the file
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header with a single byte range for a file of the size.
+/// Returns None for other units, multiple ranges and invalid ranges, which are
+/// ignored like RFC 9110 allows
+fn parse_range(range: &str, size: u64) -> Option<ByteRange> {
+    let (unit, spec) = range.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") || spec.contains(',') {
+        return None;
+    }
+    let number = |value: &str| match !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit())
+    {
+        true => value.parse::<u64>().ok(),
+        false => None,
+    };
+    let (start, end) = spec.trim().split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => match number(suffix)?.min(size) {
+            0 => return Some(ByteRange::Unsatisfiable),
+            suffix => (size - suffix, size - 1),
+        },
+        (start, "") => (number(start)?, u64::MAX),
+        (start, end) => (number(start)?, number(end)?),
+    };
+    if start > end {
+        return None;
+    }
+    if start >= size {
+        return Some(ByteRange::Unsatisfiable);
+    }
+
+    Some(ByteRange::Satisfiable(start, end.min(size - 1) - start + 1))
+}
+
+/// Decodes the percent escapes of a path. Returns None for malformed escapes or
+/// if the result isn't valid UTF-8
+fn percent_decode(path: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let high = (bytes.next()? as char).to_digit(16)?;
+            let low = (bytes.next()? as char).to_digit(16)?;
+            decoded.push((high * 16 + low) as u8);
+        } else {
+            decoded.push(byte);
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Returns the status that matches the error best
+fn error_status(error: &Error) -> StatusCode {
+    match error {
+        Error::NotFound { .. } | Error::NotADirectory { .. } => StatusCode::NOT_FOUND,
+        Error::InvalidName { .. }
+        | Error::NameTooLong { .. }
+        | Error::EntryTooLarge { .. }
+        | Error::ValueTooLarge { .. }
+        | Error::InvalidArgument { .. } => StatusCode::BAD_REQUEST,
+        Error::AlreadyExists { .. }
+        | Error::IsADirectory { .. }
+        | Error::DirectoryNotEmpty { .. } => StatusCode::CONFLICT,
+        Error::InvalidRange { .. } => StatusCode::RANGE_NOT_SATISFIABLE,
+        Error::ReadOnly { .. } => StatusCode::FORBIDDEN,
+        Error::Locked { .. } => StatusCode::LOCKED,
+        Error::QuotaExceeded { .. } => StatusCode::INSUFFICIENT_STORAGE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
@@ -0,0 +1,166 @@
+use crate::error::{Error, Result};
+use crate::metafile::{EntryID, HashAlgorithm, IndexedMetaFile, MetaEntry};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// The file the number of shards is recorded in
+const SHARDS_FILE: &str = "shards";
+/// The most shards an index can be split into
+pub const MAX_SHARDS: usize = 1 << 16;
+
+/// A meta file split into shards by the prefix of the hashed ids. Every shard
+/// is a separate [IndexedMetaFile] behind its own lock so that threads adding
+/// entries to different shards don't wait for each other and a shard stays
+/// small enough to be rewritten quickly
+pub struct ShardedMetaFile {
+    directory: PathBuf,
+    shards: Vec<RwLock<IndexedMetaFile>>,
+    algorithm: HashAlgorithm,
+}
+
+impl ShardedMetaFile {
+    /// Opens the shards in the given directory and creates it if it doesn't exist.
+    /// The number of shards has to match the one the directory was created with
+    pub fn open<P: AsRef<Path>>(directory: P, shards: usize) -> Result<Self> {
+        if shards == 0 || shards > MAX_SHARDS {
+            return Err(Error::invalid_argument(format!(
+                "the number of shards has to be between 1 and {}",
+                MAX_SHARDS
+            )));
+        }
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)?;
+        let shards_path = directory.join(SHARDS_FILE);
+        if shards_path.exists() {
+            let recorded = File::open(&shards_path)?.read_u32::<BigEndian>()? as usize;
+            if recorded != shards {
+                return Err(Error::invalid_argument(format!(
+                    "the index has {} shards",
+                    recorded
+                )));
+            }
+        } else {
+            File::create(&shards_path)?.write_u32::<BigEndian>(shards as u32)?;
+        }
+
+        let mut files = Vec::with_capacity(shards);
+        for index in 0..shards {
+            files.push(RwLock::new(IndexedMetaFile::open(shard_path(
+                &directory, index,
+            ))?));
+        }
+        let algorithm = files
+            .first()
+            .map(|shard| read(shard).algorithm())
+            .unwrap_or_default();
+
+        Ok(Self {
+            directory,
+            shards: files,
+            algorithm,
+        })
+    }
+
+    /// Returns the directory the shards are stored in
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Returns the number of shards
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the algorithm ids are hashed with
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Returns the number of entries in all shards
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| read(shard).len()).sum()
+    }
+
+    /// Returns if no shard has entries
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| read(shard).is_empty())
+    }
+
+    /// Returns an entry by id
+    pub fn get_entry<K: AsRef<[u8]> + ?Sized>(&self, id: &K) -> Option<MetaEntry> {
+        self.get_entry_raw(&self.algorithm.hash_id(id))
+    }
+
+    /// Returns an entry by its hashed id as recorded in the tree entries
+    pub fn get_entry_raw(&self, id: &EntryID) -> Option<MetaEntry> {
+        read(self.shard(id)).get_entry_raw(id).copied()
+    }
+
+    /// Returns if an entry with the given id exists
+    pub fn contains<K: AsRef<[u8]> + ?Sized>(&self, id: &K) -> bool {
+        self.get_entry(id).is_some()
+    }
+
+    /// Adds a file entry and returns the entry it replaced
+    pub fn add_entry<K: AsRef<[u8]> + ?Sized>(
+        &self,
+        id: &K,
+        entry: MetaEntry,
+    ) -> Option<MetaEntry> {
+        self.add_entry_raw(self.algorithm.hash_id(id), entry)
+    }
+
+    /// Adds an entry by an id that is already hashed and returns the entry it replaced
+    pub fn add_entry_raw(&self, id: EntryID, entry: MetaEntry) -> Option<MetaEntry> {
+        write(self.shard(&id)).add_entry_raw(id, entry)
+    }
+
+    /// Removes an entry and returns it
+    pub fn remove_entry<K: AsRef<[u8]> + ?Sized>(&self, id: &K) -> Option<MetaEntry> {
+        self.remove_entry_raw(&self.algorithm.hash_id(id))
+    }
+
+    /// Removes an entry by its hashed id
+    pub fn remove_entry_raw(&self, id: &EntryID) -> Option<MetaEntry> {
+        write(self.shard(id)).remove_entry_raw(id)
+    }
+
+    /// Writes the changes of every shard
+    pub fn flush(&self) -> Result<()> {
+        for shard in &self.shards {
+            write(shard).flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites every shard without appended changes
+    pub fn compact(&self) -> Result<()> {
+        for shard in &self.shards {
+            write(shard).compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the shard an id belongs to
+    fn shard(&self, id: &EntryID) -> &RwLock<IndexedMetaFile> {
+        let prefix = u16::from_be_bytes([id[0], id[1]]) as usize;
+
+        &self.shards[prefix % self.shards.len()]
+    }
+}
+
+fn shard_path(directory: &Path, index: usize) -> PathBuf {
+    directory.join(format!("{:04x}.meta", index))
+}
+
+fn read(shard: &RwLock<IndexedMetaFile>) -> RwLockReadGuard<'_, IndexedMetaFile> {
+    shard.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write(shard: &RwLock<IndexedMetaFile>) -> RwLockWriteGuard<'_, IndexedMetaFile> {
+    shard.write().unwrap_or_else(PoisonError::into_inner)
+}
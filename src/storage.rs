@@ -1,3 +1,3781 @@
-pub struct IndexedFileStorage {
+use crate::audit::{AuditLog, AuditRecords, AUDIT_FILE_NAME};
+use crate::backend::{Backend, BatchRead, DataBackend, LocalDataBackend, SyncPolicy, BLOCK_SIZE};
+use crate::dirtreefile::{DirEntry, DirStats, DirTreeFile, EntryMetadata, TreeStats};
+use crate::error::{Error, Result};
+use crate::metafile::{
+    EntryID, HashAlgorithm, IndexedMetaFile, MetaEntry, BLOB_META_TAG, INLINE_FILE, MARKER_FILE,
+    MAX_VALUE_LENGTH, PIN_TAG, TAGS_TAG, TRASH_TAG, UNKNOWN_LENGTH, VERSION_TAG,
+};
+use crate::metrics::{
+    Metrics, NoMetrics, BLOBS_STORED, BYTES_WRITTEN, FRAGMENTATION, LOOKUP_SECONDS,
+};
+use crate::progress::{OperationOptions, Progress};
+use crate::replication::{self, Operation, RecordReader, ReplicationLog, REPLICATION_FILE_NAME};
+use crate::trace::{event, span};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::{Ring, UringDataBackend, UringFile};
+use crate::utils::{join_path, normalize_path, split_path};
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::{btree_map, hash_map, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{
+    Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak,
+};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-}
\ No newline at end of file
+const TREE_FILE_NAME: &str = "tree.dft";
+const META_FILE_NAME: &str = "index.meta";
+/// The index namespace holding the previous versions of files
+pub const VERSIONS_NAMESPACE: &str = "versions";
+/// The index namespace holding deleted files until the trash is emptied
+pub const TRASH_NAMESPACE: &str = "trash";
+/// The index namespace holding the paths of the files with each tag
+pub const TAGS_NAMESPACE: &str = "tags";
+/// The directory holding the root directories of the [Namespace]s of a storage
+pub const NAMESPACES_DIR: &str = "/.namespaces";
+/// The size after which blobs continue in the next data file by default
+pub const DEFAULT_MAX_DATA_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+/// The size up to which paths with the same tag are added to one bucket
+const TAG_BUCKET_SIZE: usize = 4 * 1024;
+
+/// The backend the tree of a storage is stored in, which is the tree file unless
+/// the storage was opened with a different one like [Storage::open_uring]
+pub type TreeBackend = Box<dyn Backend + Send>;
+
+/// The format of an archive that can be imported into the storage
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    Zip,
+}
+
+/// Describes the content of a file. The times are those of the entry in the tree
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlobMeta {
+    /// The MIME type of the content, e.g. `image/png`
+    pub content_type: Option<String>,
+    /// The name of the file the content was taken from
+    pub source_name: Option<String>,
+    pub created: Option<SystemTime>,
+    pub modified: Option<SystemTime>,
+}
+
+/// The space used by a data file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DataFileStats {
+    pub file: u32,
+    /// The size of the data file in bytes
+    pub size: u64,
+    /// The bytes taken by blobs referenced by the index and their length prefixes
+    pub used: u64,
+}
+
+impl DataFileStats {
+    /// Returns the percentage of the data file that is used by blobs
+    pub fn utilization(&self) -> f64 {
+        match self.size {
+            0 => 100.0,
+            size => self.used as f64 * 100.0 / size as f64,
+        }
+    }
+}
+
+/// The number of entries and the space used by a storage
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StorageStats {
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    /// The sum of the sizes of all files. Content shared by hard links counts once per link
+    pub logical_bytes: u64,
+    /// The size of all data files and the content stored inline in the index
+    pub physical_bytes: u64,
+    /// The number of entries in the index including versions and the trash
+    pub index_entries: u64,
+    /// The number of entries with their content stored in the index
+    pub inline_entries: u64,
+    /// The number of entries with their content split across data files
+    pub chunked_entries: u64,
+    /// The number of distinct blobs and blob chunks in the data files
+    pub data_chunks: u64,
+    pub data_files: Vec<DataFileStats>,
+    pub tree: TreeStats,
+}
+
+impl StorageStats {
+    /// Returns the percentage of the data files that isn't used by any blob
+    pub fn fragmentation(&self) -> f64 {
+        let size: u64 = self.data_files.iter().map(|f| f.size).sum();
+        let used: u64 = self.data_files.iter().map(|f| f.used).sum();
+        match size {
+            0 => 0.0,
+            size => size.saturating_sub(used) as f64 * 100.0 / size as f64,
+        }
+    }
+}
+
+/// A file returned by [Storage::list]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryInfo {
+    pub path: String,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// The position after the last file of a page returned by [Storage::list].
+/// It's the path of that file so it can be passed to clients and back
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListCursor(String);
+
+impl ListCursor {
+    /// Creates a cursor that continues after the file at the given path
+    pub fn new(path: &str) -> Self {
+        Self(normalize_path(path))
+    }
+
+    /// Returns the path of the last file of the previous page
+    pub fn path(&self) -> &str {
+        &self.0
+    }
+}
+
+/// A change made through a storage handle, sent to the receivers of [Storage::subscribe]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangeEvent {
+    /// A file, directory or symlink was created
+    Created {
+        path: String,
+    },
+    /// The content, times or attributes of a file changed
+    Modified {
+        path: String,
+    },
+    Deleted {
+        path: String,
+    },
+    /// A file was moved, replacing an existing file at the destination, or a
+    /// directory was moved with its descendants
+    Moved {
+        from: String,
+        to: String,
+    },
+}
+
+/// The senders of [Storage::subscribe], the audit and replication logs and the
+/// events of the current batch that are only sent once it's committed
+#[derive(Default)]
+struct Subscribers {
+    senders: Vec<Sender<ChangeEvent>>,
+    audit: Option<AuditLog>,
+    replication: Option<ReplicationLog>,
+    pending: Vec<ChangeEvent>,
+}
+
+impl Subscribers {
+    fn notify(&mut self, in_batch: bool, event: ChangeEvent) -> Result<()> {
+        if self.senders.is_empty() && self.audit.is_none() {
+            return Ok(());
+        }
+        match in_batch {
+            true => self.pending.push(event),
+            false => self.send(event)?,
+        }
+
+        Ok(())
+    }
+
+    /// Sends the event and records it in the audit log. Senders whose receiver
+    /// was dropped are forgotten. The event is sent even if its audit record
+    /// can't be written, because the change was already made
+    fn send(&mut self, event: ChangeEvent) -> Result<()> {
+        self.senders
+            .retain(|sender| sender.send(event.clone()).is_ok());
+        match &mut self.audit {
+            Some(audit) => audit.record(&event),
+            None => Ok(()),
+        }
+    }
+
+    /// Appends the replication records of the committed batch and sends its events
+    fn commit(&mut self, sync: bool) -> Result<()> {
+        if let Some(replication) = &mut self.replication {
+            replication.commit(sync)?;
+        }
+        let mut result = Ok(());
+        for event in mem::take(&mut self.pending) {
+            result = result.and(self.send(event));
+        }
+
+        result
+    }
+
+    /// Returns an error if changes are refused because of a failed audit record
+    fn check_audit_log(&self) -> Result<()> {
+        match &self.audit {
+            Some(audit) => audit.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces the audit log and writes the records the previous one couldn't
+    fn set_audit_log(&mut self, mut log: AuditLog) -> Result<()> {
+        if let Some(previous) = &self.audit {
+            log.write_unwritten(previous.unwritten())?;
+        }
+        self.audit = Some(log);
+
+        Ok(())
+    }
+
+    fn discard(&mut self) {
+        if let Some(replication) = &mut self.replication {
+            replication.discard();
+        }
+        self.pending.clear();
+    }
+}
+
+/// The order in which files are evicted when the storage exceeds its limits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionStrategy {
+    /// Evicts the files that were read or written the longest time ago
+    Lru,
+    /// Evicts the files that were created first
+    Fifo,
+}
+
+/// Limits for the files in the storage that are enforced by
+/// [Storage::enforce_policies]. Limits that are `None` aren't enforced
+#[derive(Clone, Debug)]
+pub struct EvictionPolicy {
+    /// The maximum total size of all files
+    pub max_size: Option<u64>,
+    /// The maximum number of files
+    pub max_entries: Option<u64>,
+    /// The time after its last modification a file is evicted
+    pub max_age: Option<Duration>,
+    pub strategy: EvictionStrategy,
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            max_entries: None,
+            max_age: None,
+            strategy: EvictionStrategy::Lru,
+        }
+    }
+}
+
+/// What happens when a store would exceed the quota of the storage
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LowSpacePolicy {
+    /// The store fails with [Error::QuotaExceeded]
+    Reject,
+    /// Files are evicted in the order of the eviction strategy until the new
+    /// content fits. The store fails if it doesn't fit without pinned files
+    Evict,
+}
+
+/// Limits for the files in the storage that are checked when files are stored
+/// or imported. Limits that are `None` aren't checked
+#[derive(Clone, Debug)]
+pub struct Quota {
+    /// The maximum total size of all files
+    pub max_bytes: Option<u64>,
+    /// The maximum number of files
+    pub max_entries: Option<u64>,
+    pub policy: LowSpacePolicy,
+}
+
+/// A thread that enforces the eviction policy of a storage in an interval.
+/// The thread stops when the task is dropped or the storage is closed
+pub struct EvictionTask {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl EvictionTask {
+    /// Stops the thread and waits for it to finish
+    pub fn stop(self) {
+        drop(self);
+    }
+}
+
+impl Drop for EvictionTask {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+/// The result of a consistency check of the storage
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CheckReport {
+    /// Ranges of the tree file that don't belong to any reachable chunk
+    pub unreachable_chunks: Vec<(u64, u64)>,
+    /// Locations of tree chunks that overlap with another chunk
+    pub overlapping_chunks: Vec<u64>,
+    /// Locations of tree chunks with invalid lengths
+    pub bad_lengths: Vec<u64>,
+    /// Index entries that aren't referenced by the tree or point outside of the data files
+    pub dangling_entries: Vec<EntryID>,
+    /// Files in the tree that don't have an index entry
+    pub missing_entries: Vec<String>,
+}
+
+impl CheckReport {
+    /// Returns if no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.unreachable_chunks.is_empty()
+            && self.overlapping_chunks.is_empty()
+            && self.bad_lengths.is_empty()
+            && self.dangling_entries.is_empty()
+            && self.missing_entries.is_empty()
+    }
+}
+
+/// An isolated logical store inside a storage, returned by [Storage::namespace].
+/// Its paths are resolved below its own root directory and can't leave it. The
+/// data files are shared with the rest of the storage. Unrelated to the index
+/// namespaces like [VERSIONS_NAMESPACE]
+pub struct Namespace<'a> {
+    storage: &'a Storage,
+    name: String,
+    root: String,
+}
+
+impl Namespace<'_> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the path of the root directory in the storage
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    /// Returns the path in the storage of a path in the namespace
+    pub fn storage_path(&self, path: &str) -> String {
+        match normalize_path(path).as_str() {
+            "/" => self.root.clone(),
+            path => format!("{}{}", self.root, path),
+        }
+    }
+
+    /// Returns the path in the namespace of a path in the storage
+    fn namespace_path(&self, path: &str) -> String {
+        match path.strip_prefix(&self.root) {
+            Some("") | None => String::from("/"),
+            Some(path) => path.to_string(),
+        }
+    }
+
+    /// Sets the limits for the files of the namespace. They are checked in addition
+    /// to the quota of the storage
+    pub fn set_quota(&self, quota: Option<Quota>) {
+        let mut quotas = self
+            .storage
+            .namespace_quotas
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match quota {
+            Some(quota) => quotas.insert(self.root.clone(), quota),
+            None => quotas.remove(&self.root),
+        };
+    }
+
+    /// Returns the number of entries and bytes in the namespace
+    pub fn usage(&self) -> Result<DirStats> {
+        self.storage.tree().dir_stats(&self.root)
+    }
+
+    pub fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>> {
+        self.storage.read_dir(&self.storage_path(path))
+    }
+
+    /// Lists the files of the namespace like [Storage::list] with paths in the namespace
+    pub fn list(
+        &self,
+        cursor: Option<ListCursor>,
+        limit: usize,
+    ) -> Result<(Vec<EntryInfo>, Option<ListCursor>)> {
+        let cursor = cursor.map(|cursor| ListCursor(self.storage_path(&cursor.0)));
+        let (mut files, next) = self.storage.list_in(&self.root, cursor, limit)?;
+        for file in &mut files {
+            file.path = self.namespace_path(&file.path);
+        }
+        let next = next.map(|cursor| ListCursor(self.namespace_path(&cursor.0)));
+
+        Ok((files, next))
+    }
+
+    pub fn entry(&self, path: &str) -> Result<DirEntry> {
+        self.storage.entry(&self.storage_path(path))
+    }
+
+    pub fn create_dir(&self, path: &str) -> Result<()> {
+        self.storage.create_dir(&self.storage_path(path))
+    }
+
+    pub fn create_dir_all(&self, path: &str) -> Result<()> {
+        self.storage.create_dir_all(&self.storage_path(path))
+    }
+
+    pub fn store<R: Read>(&self, path: &str, reader: R) -> Result<u64> {
+        self.storage.store(&self.storage_path(path), reader)
+    }
+
+    pub fn append<R: Read>(&self, path: &str, reader: R) -> Result<u64> {
+        self.storage.append(&self.storage_path(path), reader)
+    }
+
+    pub fn get(&self, path: &str) -> Result<BlobReader> {
+        self.storage.get(&self.storage_path(path))
+    }
+
+    pub fn delete(&self, path: &str) -> Result<()> {
+        self.storage.delete(&self.storage_path(path))
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.storage
+            .rename(&self.storage_path(from), &self.storage_path(to))
+    }
+}
+
+/// A storage directory combining the directory tree, the metafile index
+/// and the data files that contain the actual file contents.
+///
+/// The storage can be shared between threads. Blob reads only take a read lock
+/// on the index so they can run concurrently while mutations are serialized by
+/// the lock on the tree and appends to the data file by the append lock.
+/// Locks are always taken in the order tree, append, index. Open readers pin the
+/// data they point at so that deletes and vacuums never overwrite it.
+pub struct Storage {
+    path: PathBuf,
+    read_only: bool,
+    tree: Mutex<DirTreeFile<TreeBackend>>,
+    meta: RwLock<IndexedMetaFile>,
+    /// The algorithm of the index so that ids can be hashed without locking it
+    algorithm: HashAlgorithm,
+    data: Arc<dyn DataBackend>,
+    /// The data file new blobs are appended to
+    data_file: Mutex<u32>,
+    /// Data files with blobs of the current batch that still have to be synced
+    unsynced: Mutex<BTreeSet<u32>>,
+    /// If new blobs start at a block boundary
+    aligned: AtomicBool,
+    /// Blobs smaller than this are stored in the index instead of a data file
+    inline_threshold: AtomicU64,
+    /// The size after which blobs continue in the next data file
+    max_data_file_size: AtomicU64,
+    /// The number of previous versions kept of each file
+    version_retention: AtomicU32,
+    /// If deleted files are moved to the trash
+    trash: AtomicBool,
+    eviction: Mutex<Option<EvictionPolicy>>,
+    quota: Mutex<Option<Quota>>,
+    /// The quotas of namespaces by their root directory
+    namespace_quotas: Mutex<HashMap<String, Quota>>,
+    /// The time files were last read since the storage was opened
+    accessed: Mutex<HashMap<String, SystemTime>>,
+    /// The data file ranges of the open readers
+    pins: Arc<ReadPins>,
+    metrics: RwLock<Arc<dyn Metrics>>,
+    subscribers: Mutex<Subscribers>,
+}
+
+/// The new content of a file before it's added to the index
+enum Content {
+    /// Content that is small enough to be stored in the index
+    Inline(Vec<u8>),
+    /// A blob in the data files and its chunks if it spans multiple ones
+    Stored(MetaEntry, Vec<MetaEntry>),
+}
+
+impl Content {
+    fn len(&self) -> u64 {
+        match self {
+            Content::Inline(data) => data.len() as u64,
+            Content::Stored((_, _, length), _) => *length,
+        }
+    }
+}
+
+/// The ranges of the data files that open readers point at, by their data file
+/// and start with their end and the number of readers. Pinned ranges aren't
+/// truncated when their blob is freed and vacuums don't move blobs over them
+#[derive(Default)]
+pub(crate) struct ReadPins {
+    ranges: Mutex<BTreeMap<(u32, u64), (u64, usize)>>,
+}
+
+impl ReadPins {
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<(u32, u64), (u64, usize)>> {
+        self.ranges.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Pins the blob or chunk extents with their length prefixes until the
+    /// returned guard is dropped
+    fn pin(self: &Arc<Self>, extents: Vec<MetaEntry>) -> ReadPin {
+        let mut ranges = self.lock();
+        for &(file, pointer, length) in &extents {
+            let (end, readers) = ranges.entry((file, pointer)).or_insert((0, 0));
+            *end = (*end).max(pointer + 8 + length);
+            *readers += 1;
+        }
+
+        ReadPin {
+            pins: Arc::clone(self),
+            extents,
+        }
+    }
+}
+
+/// Returns the end of the last pinned range in the data file that overlaps the
+/// range from start to end
+fn pinned_end(
+    ranges: &BTreeMap<(u32, u64), (u64, usize)>,
+    file: u32,
+    start: u64,
+    end: u64,
+) -> Option<u64> {
+    ranges
+        .range((file, 0)..(file, end))
+        .map(|(_, (pinned_end, _))| *pinned_end)
+        .filter(|pinned_end| *pinned_end > start)
+        .max()
+}
+
+/// Keeps the extents of a reader pinned while it's alive
+struct ReadPin {
+    pins: Arc<ReadPins>,
+    extents: Vec<MetaEntry>,
+}
+
+impl Drop for ReadPin {
+    fn drop(&mut self) {
+        let mut ranges = self.pins.lock();
+        for (file, pointer, _) in &self.extents {
+            if let btree_map::Entry::Occupied(mut entry) = ranges.entry((*file, *pointer)) {
+                entry.get_mut().1 -= 1;
+                if entry.get().1 == 0 {
+                    entry.remove();
+                }
+            }
+        }
+    }
+}
+
+/// Reads the content of a single stored file. The data the reader points at
+/// stays in place until it's dropped, even if the file is deleted or the data
+/// files are vacuumed in the meantime
+pub struct BlobReader {
+    data: Arc<dyn DataBackend>,
+    file: u32,
+    offset: u64,
+    remaining: u64,
+    /// The bytes left in the current chunk
+    chunk_remaining: u64,
+    /// The chunks after the current one of a blob spanning data files
+    chunks: VecDeque<MetaEntry>,
+    /// The content of a blob stored in the index
+    inline: Option<Vec<u8>>,
+    _pin: Option<ReadPin>,
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.chunk_remaining == 0 {
+            self.next_chunk();
+        }
+        let length = buf
+            .len()
+            .min(self.chunk_remaining.min(self.remaining) as usize);
+        if length == 0 {
+            return Ok(0);
+        }
+        let read = match &self.inline {
+            Some(content) => {
+                let start = self.offset as usize;
+                buf[..length].copy_from_slice(&content[start..start + length]);
+                length
+            }
+            None => self
+                .data
+                .read_at(self.file, self.offset, &mut buf[..length])?,
+        };
+        self.offset += read as u64;
+        self.remaining -= read as u64;
+        self.chunk_remaining -= read as u64;
+
+        Ok(read)
+    }
+}
+
+impl BlobReader {
+    /// Returns a reader for the blob of the entry with the hashed id in the index.
+    /// The blob is pinned while the reader is alive if pins are given
+    pub(crate) fn open(
+        meta: &IndexedMetaFile,
+        data: &Arc<dyn DataBackend>,
+        id: &EntryID,
+        pins: Option<&Arc<ReadPins>>,
+    ) -> Result<Option<Self>> {
+        let (file, pointer, length) = match meta.get_entry_raw(id) {
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+        if file == INLINE_FILE {
+            let content = meta
+                .inline_content_raw(id)
+                .filter(|content| content.len() as u64 == length)
+                .ok_or_else(|| Error::corrupt(pointer, "missing inline content"))?;
+            return Ok(Some(BlobReader {
+                data: Arc::clone(data),
+                file,
+                offset: 0,
+                remaining: length,
+                chunk_remaining: length,
+                chunks: VecDeque::new(),
+                inline: Some(content.to_vec()),
+                _pin: None,
+            }));
+        }
+        let mut chunks: VecDeque<MetaEntry> = meta.chunks_raw(id).into();
+        if let Some((file, pointer, chunk_length)) = chunks.pop_front() {
+            let extents = std::iter::once((file, pointer, chunk_length))
+                .chain(chunks.iter().copied())
+                .collect();
+            return Ok(Some(BlobReader {
+                data: Arc::clone(data),
+                file,
+                offset: pointer + 8,
+                remaining: length,
+                chunk_remaining: chunk_length,
+                chunks,
+                inline: None,
+                _pin: pins.map(|pins| pins.pin(extents)),
+            }));
+        }
+        let remaining = match length {
+            UNKNOWN_LENGTH => {
+                let mut length = [0u8; 8];
+                data.read_exact_at(file, pointer, &mut length)?;
+                BigEndian::read_u64(&length)
+            }
+            length => length,
+        };
+
+        Ok(Some(BlobReader {
+            data: Arc::clone(data),
+            file,
+            offset: pointer + 8,
+            remaining,
+            chunk_remaining: remaining,
+            chunks: VecDeque::new(),
+            inline: None,
+            _pin: pins.map(|pins| pins.pin(vec![(file, pointer, remaining)])),
+        }))
+    }
+
+    /// Returns the number of bytes that haven't been read yet
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Moves the reader forward without reading the skipped bytes
+    fn skip(&mut self, bytes: u64) {
+        let mut bytes = bytes.min(self.remaining);
+        self.remaining -= bytes;
+        while bytes > 0 {
+            if self.chunk_remaining == 0 {
+                self.next_chunk();
+            }
+            let step = bytes.min(self.chunk_remaining);
+            self.offset += step;
+            self.chunk_remaining -= step;
+            bytes -= step;
+        }
+    }
+
+    /// Continues with the next chunk of a blob spanning data files
+    fn next_chunk(&mut self) {
+        if let Some((file, pointer, length)) = self.chunks.pop_front() {
+            self.file = file;
+            self.offset = pointer + 8;
+            self.chunk_remaining = length;
+        }
+    }
+}
+
+/// Writes inside the content of a stored file without changing its size. The
+/// writer holds the lock on the tree so that the content can't be moved or freed
+/// while it's written. Mutations of the storage wait until it's dropped while
+/// reads continue
+pub struct BlobWriter<'a> {
+    tree: MutexGuard<'a, DirTreeFile<TreeBackend>>,
+    data: Arc<dyn DataBackend>,
+    metrics: Arc<dyn Metrics>,
+    storage: &'a Storage,
+    path: String,
+    /// The chunks of the blob in the data files
+    chunks: Vec<MetaEntry>,
+    length: u64,
+    position: u64,
+    /// If data was written since the last flush
+    modified: bool,
+}
+
+impl Write for BlobWriter<'_> {
+    /// Writes at the current position. Nothing is written at the end of the content
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = buf
+            .len()
+            .min(self.length.saturating_sub(self.position) as usize);
+        self.write_chunks(self.position, &buf[..length])?;
+        self.position += length as u64;
+
+        Ok(length)
+    }
+
+    /// Syncs the written data as the sync policy of the storage requires and
+    /// updates the modification time of the file
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.modified {
+            return Ok(());
+        }
+        if self.tree.sync_policy() != SyncPolicy::Never {
+            let files: BTreeSet<u32> = self.chunks.iter().map(|(file, _, _)| *file).collect();
+            for file in files {
+                self.data.sync(file)?;
+            }
+        }
+        let (parent, name) = split_path(&self.path)?;
+        let entry = find_entry(&mut self.tree, &parent, &name)?;
+        let mut metadata = entry
+            .metadata()
+            .unwrap_or_else(|| EntryMetadata::new(self.length));
+        metadata.modified = SystemTime::now();
+        self.tree.set_metadata(&name, metadata)?;
+        self.modified = false;
+        let path = self.path.clone();
+        self.storage
+            .notify(&mut self.tree, ChangeEvent::Modified { path })?;
+
+        Ok(())
+    }
+}
+
+impl Seek for BlobWriter<'_> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )
+        })?;
+
+        Ok(self.position)
+    }
+}
+
+impl BlobWriter<'_> {
+    /// Returns the size of the content which can't change through the writer
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Writes the data at the offset without moving the position. Fails with
+    /// [Error::InvalidRange] if the data doesn't fit into the content
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let length = data.len() as u64;
+        if offset
+            .checked_add(length)
+            .is_none_or(|end| end > self.length)
+        {
+            return Err(Error::InvalidRange {
+                path: self.path.clone(),
+                offset,
+                length,
+                size: self.length,
+            });
+        }
+
+        Ok(self.write_chunks(offset, data)?)
+    }
+
+    /// Writes the data to the chunks covering the range starting at the offset
+    fn write_chunks(&mut self, mut offset: u64, mut data: &[u8]) -> io::Result<()> {
+        let mut start = 0;
+        for &(file, pointer, length) in &self.chunks {
+            if data.is_empty() {
+                break;
+            }
+            if offset < start + length {
+                let part = data.len().min((start + length - offset) as usize);
+                self.data
+                    .write_at(file, pointer + 8 + offset - start, &data[..part])?;
+                self.modified = true;
+                self.metrics.counter(BYTES_WRITTEN, part as u64);
+                offset += part as u64;
+                data = &data[part..];
+            }
+            start += length;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for BlobWriter<'_> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl Storage {
+    /// Opens the storage in the given directory and creates it if it doesn't exist.
+    /// Waits until no other process has the storage opened
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let data = Arc::new(LocalDataBackend::new(path.clone()));
+
+        Self::open_with_backend(path, data)
+    }
+
+    /// Opens the storage like [Storage::open] but fails with [Error::Locked]
+    /// instead of waiting if another process has the storage opened
+    pub fn try_open(path: PathBuf) -> Result<Self> {
+        create_storage_dir(&path)?;
+        let tree = open_tree(&path, false, false)?;
+        let data = Arc::new(LocalDataBackend::new(path.clone()));
+
+        Self::from_parts(path, tree, data, false)
+    }
+
+    /// Opens an existing storage for reading. Multiple processes can open the
+    /// storage read-only at the same time but not while it is opened for writing
+    pub fn open_read_only(path: PathBuf) -> Result<Self> {
+        let tree = open_tree(&path, true, true)?;
+        let data = Arc::new(LocalDataBackend::new(path.clone()));
+
+        Self::from_parts(path, tree, data, true)
+    }
+
+    /// Opens the storage with the tree and index in the given directory and the
+    /// blob data stored in the given backend
+    pub fn open_with_backend(path: PathBuf, data: Arc<dyn DataBackend>) -> Result<Self> {
+        create_storage_dir(&path)?;
+        let tree = open_tree(&path, false, true)?;
+
+        Self::from_parts(path, tree, data, false)
+    }
+
+    /// Opens the storage like [Storage::open] with the data files opened for
+    /// direct I/O and new blobs aligned to blocks
+    pub fn open_direct(path: PathBuf) -> Result<Self> {
+        let data = Arc::new(LocalDataBackend::with_direct_io(path.clone()));
+        let storage = Self::open_with_backend(path, data)?;
+        storage.set_aligned_blobs(true);
+
+        Ok(storage)
+    }
+
+    /// Opens the storage like [Storage::open] with the tree file and the data
+    /// files read and written through one io_uring instance
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub fn open_uring(path: PathBuf) -> Result<Self> {
+        create_storage_dir(&path)?;
+        let ring = Arc::new(Ring::new()?);
+        let tree = DirTreeFile::open_locked_with(path.join(TREE_FILE_NAME), false, true, |file| {
+            Ok(Box::new(UringFile::new(file, Arc::clone(&ring))) as TreeBackend)
+        })?;
+        let data = Arc::new(UringDataBackend::with_ring(path.clone(), ring));
+
+        Self::from_parts(path, tree, data, false)
+    }
+
+    fn from_parts(
+        path: PathBuf,
+        tree: DirTreeFile<TreeBackend>,
+        data: Arc<dyn DataBackend>,
+        read_only: bool,
+    ) -> Result<Self> {
+        let mut meta = IndexedMetaFile::open(path.join(META_FILE_NAME))?;
+        let algorithm = meta.algorithm();
+        // indexes written before lengths were recorded get them from the data files
+        let unknown: Vec<(EntryID, MetaEntry)> = meta
+            .iter()
+            .filter(|(_, entry)| entry.2 == UNKNOWN_LENGTH)
+            .map(|(id, entry)| (*id, *entry))
+            .collect();
+        let blobs: Vec<(u32, u64)> = unknown.iter().map(|(_, e)| (e.0, e.1)).collect();
+        let lengths = stored_lengths(data.as_ref(), &blobs)?;
+        for ((id, (file, pointer, _)), length) in unknown.into_iter().zip(lengths) {
+            if let Some(length) = length {
+                meta.add_entry_raw(id, (file, pointer, length));
+            }
+        }
+        let mut data_file = 0;
+        while data.len(data_file + 1)? > 0 {
+            data_file += 1;
+        }
+
+        Ok(Self {
+            path,
+            read_only,
+            tree: Mutex::new(tree),
+            meta: RwLock::new(meta),
+            algorithm,
+            data,
+            data_file: Mutex::new(data_file),
+            unsynced: Mutex::new(BTreeSet::new()),
+            aligned: AtomicBool::new(false),
+            inline_threshold: AtomicU64::new(0),
+            max_data_file_size: AtomicU64::new(DEFAULT_MAX_DATA_FILE_SIZE),
+            version_retention: AtomicU32::new(0),
+            trash: AtomicBool::new(false),
+            eviction: Mutex::new(None),
+            quota: Mutex::new(None),
+            namespace_quotas: Mutex::new(HashMap::new()),
+            accessed: Mutex::new(HashMap::new()),
+            pins: Arc::new(ReadPins::default()),
+            metrics: RwLock::new(Arc::new(NoMetrics)),
+            subscribers: Mutex::new(Subscribers::default()),
+        })
+    }
+
+    /// Returns if the storage was opened read-only
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        self.check_not_read_only()?;
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .check_audit_log()
+    }
+
+    fn check_not_read_only(&self) -> Result<()> {
+        if self.read_only {
+            Err(Error::ReadOnly {
+                path: self.path.clone(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sets when the tree, the index and stored blobs are synced to the disk
+    pub fn set_sync_policy(&self, policy: SyncPolicy) {
+        self.tree().set_sync_policy(policy);
+        self.meta_mut().set_sync_policy(policy);
+    }
+
+    /// Sets if new blobs start at a multiple of [BLOCK_SIZE] in the data files.
+    /// The space in front of an aligned blob stays unused
+    pub fn set_aligned_blobs(&self, aligned: bool) {
+        self.aligned.store(aligned, Ordering::Relaxed);
+    }
+
+    /// Sets the size below which the content of stored files is kept in the index
+    /// instead of a data file. This saves a read of the data file for small files.
+    /// The threshold is 0 by default and limited to the maximum length of index values
+    pub fn set_inline_threshold(&self, bytes: u64) {
+        let bytes = bytes.min(MAX_VALUE_LENGTH as u64 + 1);
+        self.inline_threshold.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Sets the size after which new blobs continue in the next data file. Blobs
+    /// larger than that are split into chunks spanning multiple data files.
+    /// The size is at least [BLOCK_SIZE]
+    pub fn set_max_data_file_size(&self, bytes: u64) {
+        self.max_data_file_size
+            .store(bytes.max(BLOCK_SIZE), Ordering::Relaxed);
+    }
+
+    /// Sets the number of previous versions that are kept when a file is replaced
+    /// by [Storage::store]. Older versions are removed when the file is stored
+    /// again or by [Storage::prune_versions]. Versioning is off with 0, the default
+    pub fn set_version_retention(&self, versions: u32) {
+        self.version_retention.store(versions, Ordering::Relaxed);
+    }
+
+    /// Sets if [Storage::delete] moves files to the trash instead of freeing their
+    /// content. Trashed files can be brought back with [Storage::restore] until
+    /// [Storage::empty_trash] removes them
+    pub fn set_trash(&self, enabled: bool) {
+        self.trash.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Sets the limits that [Storage::enforce_policies] evicts files for, e.g. to
+    /// use the storage as a bounded cache
+    pub fn set_eviction_policy(&self, policy: Option<EvictionPolicy>) {
+        *self.eviction.lock().unwrap_or_else(PoisonError::into_inner) = policy;
+    }
+
+    /// Sets the limits that stores and imports are checked against. Checking
+    /// walks the tree, so quotas slow down stores into large storages
+    pub fn set_quota(&self, quota: Option<Quota>) {
+        *self.quota.lock().unwrap_or_else(PoisonError::into_inner) = quota;
+    }
+
+    /// Sets where the storage and its tree report written bytes, stored blobs,
+    /// lookup latencies and fragmentation
+    pub fn set_metrics(&self, metrics: Arc<dyn Metrics>) {
+        self.tree().set_metrics(Arc::clone(&metrics));
+        *self.metrics.write().unwrap_or_else(PoisonError::into_inner) = metrics;
+    }
+
+    fn metrics(&self) -> Arc<dyn Metrics> {
+        Arc::clone(&self.metrics.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Returns a receiver for the changes made through this storage from now on.
+    /// Changes of a batch are sent when it's committed. Dropping the receiver
+    /// ends the subscription
+    pub fn subscribe(&self) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .senders
+            .push(sender);
+
+        receiver
+    }
+
+    /// Starts recording every change made through this storage in the append-only
+    /// [AUDIT_FILE_NAME] log in the storage directory. The actor is called for
+    /// each change to tell who made it.
+    ///
+    /// A change whose record can't be written stays applied and fails with
+    /// [Error::AuditLogFailed]. Further changes are refused until this is called
+    /// again, which appends the records that couldn't be written
+    pub fn enable_audit_log<F: Fn() -> String + Send + Sync + 'static>(
+        &self,
+        actor: F,
+    ) -> Result<()> {
+        self.check_not_read_only()?;
+        let log = AuditLog::open(&self.path.join(AUDIT_FILE_NAME), Box::new(actor))?;
+        self.set_audit_log(log)
+    }
+
+    pub(crate) fn set_audit_log(&self, log: AuditLog) -> Result<()> {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .set_audit_log(log)
+    }
+
+    /// Returns the records of the audit log from the oldest one
+    pub fn audit_log(&self) -> Result<AuditRecords> {
+        AuditRecords::open(self.path.join(AUDIT_FILE_NAME))
+    }
+
+    /// Starts recording the operations made through this storage in the
+    /// [REPLICATION_FILE_NAME] log in the storage directory. Only the content and
+    /// structure of the tree is replicated, not metadata or extended attributes
+    pub fn enable_replication_log(&self) -> Result<()> {
+        self.check_writable()?;
+        let log = ReplicationLog::open(&self.path.join(REPLICATION_FILE_NAME))?;
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .replication = Some(log);
+
+        Ok(())
+    }
+
+    /// Returns a reader for the records of the replication log after the sequence
+    /// number that was returned by the last [Storage::apply_replication] of a replica
+    pub fn replication_log(&self, after: u64) -> Result<io::Take<fs::File>> {
+        replication::read_after(&self.path.join(REPLICATION_FILE_NAME), after)
+    }
+
+    /// Applies the records of the replication log of another storage in order and
+    /// returns the sequence number of the last one, or 0 if there was none.
+    /// Operations are idempotent, so records can be applied again after a failure
+    pub fn apply_replication<R: Read>(&self, reader: R) -> Result<u64> {
+        self.check_writable()?;
+        let mut records = RecordReader::new(reader);
+        let mut sequence = 0;
+        while let Some((number, operation, length)) = records.next_operation()? {
+            event!(Trace, "applying replication record {}", number);
+            match operation {
+                Operation::CreateDir { path } => self.create_dir_all(&path)?,
+                Operation::Symlink { path, target } => {
+                    ignore_not_found(self.delete(&path))?;
+                    self.create_symlink(&path, &target)?;
+                }
+                Operation::Store { path } => {
+                    self.store_at(&path, (&mut records).take(length))?;
+                    // the stored content is only known to be valid after the checksum
+                    if let Err(e) = records.finish_record() {
+                        ignore_not_found(self.delete(&path))?;
+                        return Err(e);
+                    }
+                    sequence = number;
+                    continue;
+                }
+                Operation::Delete { path } => ignore_not_found(self.delete(&path))?,
+                Operation::Rename { from, to } => ignore_not_found(self.rename(&from, &to))?,
+            }
+            records.finish_record()?;
+            sequence = number;
+        }
+
+        Ok(sequence)
+    }
+
+    /// Sends the event to the subscribers and records the operation that replicates
+    /// it, or keeps both until the batch of the tree is committed
+    fn notify(&self, tree: &mut DirTreeFile<TreeBackend>, event: ChangeEvent) -> Result<()> {
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if subscribers.replication.is_some() {
+            self.replicate(&mut subscribers, tree, &event)?;
+        }
+        subscribers.notify(tree.in_batch(), event)
+    }
+
+    /// Records the operation that brings a replica to the state of the tree after
+    /// the event
+    fn replicate(
+        &self,
+        subscribers: &mut Subscribers,
+        tree: &mut DirTreeFile<TreeBackend>,
+        event: &ChangeEvent,
+    ) -> Result<()> {
+        let operation = match event {
+            ChangeEvent::Created { path } | ChangeEvent::Modified { path } => {
+                match tree.lookup(path)? {
+                    Some(entry) if entry.is_dir() => Operation::CreateDir { path: path.clone() },
+                    Some(entry) => match entry.symlink_target() {
+                        Some(target) => Operation::Symlink {
+                            path: path.clone(),
+                            target: target.to_string(),
+                        },
+                        None => Operation::Store { path: path.clone() },
+                    },
+                    None => return Ok(()),
+                }
+            }
+            ChangeEvent::Deleted { path } => Operation::Delete { path: path.clone() },
+            ChangeEvent::Moved { from, to } => Operation::Rename {
+                from: from.clone(),
+                to: to.clone(),
+            },
+        };
+        let mut content = match &operation {
+            Operation::Store { path } => self.read_blob(&self.algorithm.hash_id(path))?,
+            _ => None,
+        };
+        let content = content.as_mut().map(|reader| {
+            let length = reader.remaining();
+            (reader as &mut dyn Read, length)
+        });
+        let sync = tree.sync_policy() != SyncPolicy::Never;
+        match &mut subscribers.replication {
+            Some(log) => log.record(tree.in_batch(), sync, &operation, content),
+            None => Ok(()),
+        }
+    }
+
+    /// Locks and returns the directory tree of the storage
+    pub fn tree(&self) -> MutexGuard<'_, DirTreeFile<TreeBackend>> {
+        self.tree.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the index of the storage
+    pub fn meta(&self) -> RwLockReadGuard<'_, IndexedMetaFile> {
+        self.meta.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn meta_mut(&self) -> RwLockWriteGuard<'_, IndexedMetaFile> {
+        self.meta.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the entries of the directory at the given path
+    pub fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>> {
+        let mut tree = self.tree();
+        tree.cd(&normalize_path(path))?;
+        tree.entries()
+    }
+
+    /// Returns up to `limit` files of the whole storage ordered by their path,
+    /// starting after the cursor. The returned cursor continues with the next
+    /// page and is `None` after the last page. Only one directory is read into
+    /// memory at a time
+    pub fn list(
+        &self,
+        cursor: Option<ListCursor>,
+        limit: usize,
+    ) -> Result<(Vec<EntryInfo>, Option<ListCursor>)> {
+        self.list_in("/", cursor, limit)
+    }
+
+    /// Lists the files below the directory like [Storage::list]
+    pub(crate) fn list_in(
+        &self,
+        dir: &str,
+        cursor: Option<ListCursor>,
+        limit: usize,
+    ) -> Result<(Vec<EntryInfo>, Option<ListCursor>)> {
+        let depth = dir.split('/').filter(|part| !part.is_empty()).count();
+        let after: Vec<String> = cursor
+            .map(|cursor| {
+                cursor
+                    .0
+                    .split('/')
+                    .filter(|part| !part.is_empty())
+                    .skip(depth)
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut files = Vec::new();
+        // one more file than requested tells if there's another page
+        list_files(
+            &mut self.tree(),
+            dir,
+            &after,
+            limit.saturating_add(1),
+            &mut files,
+        )?;
+        let next = match files.len() > limit {
+            true => {
+                files.truncate(limit);
+                files.last().map(|file| ListCursor(file.path.clone()))
+            }
+            false => None,
+        };
+
+        Ok((files, next))
+    }
+
+    /// Returns the namespace with the name and creates its root directory in
+    /// [NAMESPACES_DIR] if it doesn't exist yet
+    pub fn namespace(&self, name: &str) -> Result<Namespace<'_>> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(Error::InvalidName {
+                name: name.to_string(),
+            });
+        }
+        let root = join_path(NAMESPACES_DIR, name);
+        if !self.read_only {
+            self.create_dir_all(&root)?;
+        }
+
+        Ok(Namespace {
+            storage: self,
+            name: name.to_string(),
+            root,
+        })
+    }
+
+    /// Returns the names of all namespaces
+    pub fn namespaces(&self) -> Result<Vec<String>> {
+        let mut tree = self.tree();
+        if tree.lookup(NAMESPACES_DIR)?.is_none() {
+            return Ok(Vec::new());
+        }
+        tree.cd(NAMESPACES_DIR)?;
+        let mut names: Vec<String> = tree
+            .entries()?
+            .into_iter()
+            .filter(|entry| entry.is_dir())
+            .map(|entry| entry.name)
+            .collect();
+        names.sort();
+
+        Ok(names)
+    }
+
+    /// Creates a directory. The parent directory must already exist
+    pub fn create_dir(&self, path: &str) -> Result<()> {
+        self.check_writable()?;
+        let path = normalize_path(path);
+        let (parent, name) = split_path(&path)?;
+        let mut tree = self.tree();
+        tree.cd(&parent)?;
+        tree.create_entry(&name, true)?;
+        self.notify(&mut tree, ChangeEvent::Created { path })?;
+
+        Ok(())
+    }
+
+    /// Creates the directory and all its missing parents
+    pub fn create_dir_all(&self, path: &str) -> Result<()> {
+        self.check_writable()?;
+        self.create_dirs(&mut self.tree(), path)
+    }
+
+    /// Creates the directory and its missing parents in the tree and reports them
+    fn create_dirs(&self, tree: &mut DirTreeFile<TreeBackend>, path: &str) -> Result<()> {
+        let mut missing = Vec::new();
+        let mut current = normalize_path(path);
+        while current != "/" && tree.lookup(&current)?.is_none() {
+            let (parent, _) = split_path(&current)?;
+            missing.push(mem::replace(&mut current, parent));
+        }
+        tree.create_dir_all(&normalize_path(path))?;
+        for path in missing.into_iter().rev() {
+            self.notify(tree, ChangeEvent::Created { path })?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates a symlink at the given path pointing to the target path
+    pub fn create_symlink(&self, path: &str, target: &str) -> Result<()> {
+        self.check_writable()?;
+        let path = normalize_path(path);
+        let (parent, name) = split_path(&path)?;
+        let mut tree = self.tree();
+        tree.cd(&parent)?;
+        tree.create_symlink(&name, target)?;
+        self.notify(&mut tree, ChangeEvent::Created { path })?;
+
+        Ok(())
+    }
+
+    /// Stores the content of the reader at the given path replacing an existing file
+    /// and returns the number of bytes written
+    pub fn store<R: Read>(&self, path: &str, reader: R) -> Result<u64> {
+        self.check_writable()?;
+        let mut tree = self.tree();
+        if tree.in_batch() {
+            let result = self.insert(&mut tree, path, reader);
+            if result.is_err() {
+                self.discard_batch(&mut tree)?;
+            }
+            return result;
+        }
+        let length = self.insert(&mut tree, path, reader)?;
+        self.meta_mut().flush()?;
+
+        Ok(length)
+    }
+
+    /// Stores the content of the reader at the given path like [Storage::store]
+    /// and creates the missing parent directories first
+    pub fn store_at<R: Read>(&self, path: &str, reader: R) -> Result<u64> {
+        self.check_writable()?;
+        let (parent, _) = split_path(&normalize_path(path))?;
+        self.create_dirs(&mut self.tree(), &parent)?;
+
+        self.store(path, reader)
+    }
+
+    /// Appends the content of the reader to the file at the given path and returns
+    /// the new length. The content is extended in place if it's at the end of the
+    /// current data file and copied to the end otherwise. The length in front of
+    /// the content is only updated after the appended data is written. Files that
+    /// don't exist are created and hard linked files get their own copy
+    pub fn append<R: Read>(&self, path: &str, mut reader: R) -> Result<u64> {
+        self.check_writable()?;
+        let path = normalize_path(path);
+        let (parent, name) = split_path(&path)?;
+        let mut tree = self.tree();
+        let entry = match find_entry(&mut tree, &parent, &name) {
+            Ok(entry) => entry,
+            Err(Error::NotFound { .. }) => {
+                let length = self.insert(&mut tree, &path, reader)?;
+                self.meta_mut().flush()?;
+                return Ok(length);
+            }
+            Err(e) => return Err(e),
+        };
+        if entry.is_dir() {
+            return Err(Error::IsADirectory { path });
+        }
+        let (blob, chunks, shared) = {
+            let meta = self.meta();
+            let blob = *meta
+                .get_entry(&path)
+                .ok_or_else(|| Error::NotFound { path: path.clone() })?;
+            (blob, meta.chunks(&path), meta.ref_count(&blob) > 1)
+        };
+        let in_place = match shared {
+            true => None,
+            false => self.append_in_place(blob, chunks, &mut reader)?,
+        };
+        let content = match in_place {
+            Some((blob, chunks)) => {
+                self.sync_blob(&tree, blob, &chunks)?;
+                Content::Stored(blob, chunks)
+            }
+            None => self.write_content(&tree, self.get(&path)?.chain(reader))?,
+        };
+        let length = content.len();
+        let mut metadata = EntryMetadata::new(length);
+        if let Some(previous) = entry.metadata() {
+            metadata.created = previous.created;
+        }
+        tree.set_metadata(&name, metadata)?;
+        self.index_content(&tree, &path, content)?;
+        self.meta_mut().flush()?;
+        self.notify(&mut tree, ChangeEvent::Modified { path })?;
+
+        Ok(length)
+    }
+
+    /// Changes the size of the file at the given path. Shrunk content stays in place
+    /// and the space after it is freed if it's at the end of its data file. Grown
+    /// content is filled with zeros like [Storage::append] does. Content shared
+    /// by hard links or stored in the index is copied
+    pub fn truncate(&self, path: &str, length: u64) -> Result<()> {
+        self.check_writable()?;
+        let path = normalize_path(path);
+        let (parent, name) = split_path(&path)?;
+        let size = self.get(&path)?.remaining();
+        if length >= size {
+            self.append(&path, io::repeat(0).take(length - size))?;
+            return Ok(());
+        }
+        let mut tree = self.tree();
+        let entry = find_entry(&mut tree, &parent, &name)?;
+        let (blob, chunks, shared) = {
+            let meta = self.meta();
+            let blob = *meta
+                .get_entry(&path)
+                .ok_or_else(|| Error::NotFound { path: path.clone() })?;
+            (blob, meta.chunks(&path), meta.ref_count(&blob) > 1)
+        };
+        let (shrunk, dropped) = if shared || blob.0 == INLINE_FILE || blob.2 == UNKNOWN_LENGTH {
+            let content = self.write_content(&tree, self.get(&path)?.take(length))?;
+            self.index_content(&tree, &path, content)?;
+            (None, Vec::new())
+        } else {
+            let mut kept = Vec::new();
+            let mut dropped = Vec::new();
+            let mut remaining = length;
+            for (file, pointer, chunk_length) in blob_chunks(blob, chunks) {
+                if remaining > 0 || kept.is_empty() {
+                    kept.push((file, pointer, chunk_length.min(remaining)));
+                    remaining -= chunk_length.min(remaining);
+                } else {
+                    dropped.push((file, pointer, chunk_length));
+                }
+            }
+            let last = kept.last().copied();
+            if kept.len() == 1 {
+                kept.clear();
+            }
+            self.index_content(
+                &tree,
+                &path,
+                Content::Stored((blob.0, blob.1, length), kept),
+            )?;
+            (last, dropped)
+        };
+        let mut metadata = EntryMetadata::new(length);
+        if let Some(previous) = entry.metadata() {
+            metadata.created = previous.created;
+        }
+        tree.set_metadata(&name, metadata)?;
+        self.meta_mut().flush()?;
+        // the shorter lengths are written after the index so that the stored
+        // lengths are never shorter than the ones in the index
+        if let Some(chunk) = shrunk {
+            self.shrink_chunk(chunk)?;
+        }
+        for chunk in dropped {
+            self.free_blob(chunk)?;
+        }
+        self.notify(&mut tree, ChangeEvent::Modified { path })?;
+
+        Ok(())
+    }
+
+    /// Opens the content of the file at the given path for writes in place, e.g. to
+    /// update records of a fixed size. Content stored in the index is moved to a
+    /// data file first. Hard linked files share the written data
+    pub fn open_writer(&self, path: &str) -> Result<BlobWriter<'_>> {
+        self.check_writable()?;
+        let path = normalize_path(path);
+        let (parent, name) = split_path(&path)?;
+        let mut tree = self.tree();
+        if find_entry(&mut tree, &parent, &name)?.is_dir() {
+            return Err(Error::IsADirectory { path });
+        }
+        let (mut blob, mut chunks) = {
+            let meta = self.meta();
+            let blob = *meta
+                .get_entry(&path)
+                .ok_or_else(|| Error::NotFound { path: path.clone() })?;
+            (blob, meta.chunks(&path))
+        };
+        if blob.0 == INLINE_FILE {
+            let (stored, stored_chunks) = self.write_blob(&mut self.get(&path)?)?;
+            self.sync_blob(&tree, stored, &stored_chunks)?;
+            self.index_content(&tree, &path, Content::Stored(stored, stored_chunks.clone()))?;
+            self.meta_mut().flush()?;
+            blob = stored;
+            chunks = stored_chunks;
+        }
+        let length = self.get(&path)?.remaining();
+
+        Ok(BlobWriter {
+            tree,
+            data: Arc::clone(&self.data),
+            metrics: self.metrics(),
+            storage: self,
+            path,
+            chunks: blob_chunks((blob.0, blob.1, length), chunks),
+            length,
+            position: 0,
+            modified: false,
+        })
+    }
+
+    /// Starts a batch of stores. The tree changes of the batch are applied
+    /// together and the index and data files are written and synced once by
+    /// [Storage::commit]. If a store in the batch fails the whole batch is
+    /// discarded. Other mutations write the index right away
+    pub fn begin_batch(&self) -> Result<()> {
+        self.check_writable()?;
+        self.tree().begin_batch()
+    }
+
+    /// Applies the changes of the current batch
+    pub fn commit(&self) -> Result<()> {
+        let mut tree = self.tree();
+        if !tree.in_batch() {
+            return Ok(());
+        }
+        let unsynced =
+            mem::take(&mut *self.unsynced.lock().unwrap_or_else(PoisonError::into_inner));
+        if tree.sync_policy() != SyncPolicy::Never {
+            for file in unsynced {
+                self.data.sync(file)?;
+            }
+        }
+        let subscribers = || {
+            self.subscribers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+        };
+        if let Err(e) = tree.commit() {
+            subscribers().discard();
+            self.meta_mut().reload()?;
+            return Err(e);
+        }
+        self.meta_mut().flush()?;
+        subscribers().commit(tree.sync_policy() != SyncPolicy::Never)?;
+
+        Ok(())
+    }
+
+    /// Discards the changes of the current batch. Blobs that were already
+    /// written stay in the data files
+    pub fn rollback(&self) -> Result<()> {
+        self.discard_batch(&mut self.tree())
+    }
+
+    fn discard_batch(&self, tree: &mut DirTreeFile<TreeBackend>) -> Result<()> {
+        tree.rollback();
+        self.unsynced
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .discard();
+
+        self.meta_mut().reload()
+    }
+
+    /// Returns the whole content of the file at the given path
+    pub fn read_path(&self, path: &str) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.get(path)?.read_to_end(&mut content)?;
+
+        Ok(content)
+    }
+
+    /// Returns a reader for the content of the file at the given path
+    pub fn get(&self, path: &str) -> Result<BlobReader> {
+        let start = Instant::now();
+        let path = normalize_path(path);
+        let reader = self.read_blob(&self.algorithm.hash_id(&path))?;
+        self.metrics()
+            .histogram(LOOKUP_SECONDS, start.elapsed().as_secs_f64());
+        if reader.is_some()
+            && self
+                .eviction
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .is_some()
+        {
+            self.accessed
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(path.clone(), SystemTime::now());
+        }
+
+        reader.ok_or(Error::NotFound { path })
+    }
+
+    /// Returns a reader for the blob of the entry with the hashed id
+    fn read_blob(&self, id: &EntryID) -> Result<Option<BlobReader>> {
+        BlobReader::open(&self.meta(), &self.data, id, Some(&self.pins))
+            .map_err(|e| e.in_file(&self.path.join(META_FILE_NAME)))
+    }
+
+    /// Returns a reader for `length` bytes of the file at the given path starting
+    /// at `offset`. Fails with [Error::InvalidRange] if the range exceeds the file
+    pub fn get_range(&self, path: &str, offset: u64, length: u64) -> Result<BlobReader> {
+        let mut reader = self.get(path)?;
+        let size = reader.remaining();
+        if offset.checked_add(length).is_none_or(|end| end > size) {
+            return Err(Error::InvalidRange {
+                path: normalize_path(path),
+                offset,
+                length,
+                size,
+            });
+        }
+        reader.skip(offset);
+        reader.remaining = length;
+
+        Ok(reader)
+    }
+
+    /// Reads `length` bytes of the file at the given path starting at `offset`,
+    /// e.g. to answer a range request
+    pub fn read_range(&self, path: &str, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let mut reader = self.get_range(path, offset, length)?;
+        let mut data = vec![0u8; length as usize];
+        reader.read_exact(&mut data)?;
+
+        Ok(data)
+    }
+
+    /// Deletes the file or empty directory at the given path
+    pub fn delete(&self, path: &str) -> Result<()> {
+        self.check_writable()?;
+        self.delete_in(&mut self.tree(), path)
+    }
+
+    /// Deletes the file or the directory with everything below it at the given path
+    pub fn remove_path(&self, path: &str) -> Result<()> {
+        self.check_writable()?;
+        let path = normalize_path(path);
+        let mut tree = self.tree();
+        let entry = tree
+            .lookup(&path)?
+            .ok_or_else(|| Error::NotFound { path: path.clone() })?;
+        let mut paths = vec![path.clone()];
+        if entry.is_dir() {
+            for item in tree.walk(&path)? {
+                let (_, path, _) = item?;
+                paths.push(normalize_path(&path));
+            }
+        }
+        // the paths below a directory sort after it, so they're deleted first
+        paths.sort_unstable_by(|a, b| b.cmp(a));
+        for path in paths {
+            self.delete_in(&mut tree, &path)?;
+        }
+
+        Ok(())
+    }
+
+    fn delete_in(&self, tree: &mut DirTreeFile<TreeBackend>, path: &str) -> Result<()> {
+        let path = normalize_path(path);
+        let (parent, name) = split_path(&path)?;
+        let entry = find_entry(tree, &parent, &name)?;
+
+        if entry.is_dir() {
+            tree.cd(&path)?;
+            if !tree.entries()?.is_empty() {
+                return Err(Error::DirectoryNotEmpty { path });
+            }
+            tree.cd(&parent)?;
+        }
+        tree.delete_entry(&name)?;
+        let removed = {
+            let mut meta = self.meta_mut();
+            let mut removed = remove_versions(&mut meta, &path, 0);
+            remove_tags(&mut meta, &path)?;
+            if self.trash.load(Ordering::Relaxed) {
+                if let (Some(metadata), Some(blob)) = (entry.metadata(), meta.get_entry(&path)) {
+                    let blob = *blob;
+                    removed.extend(trash_entry(&mut meta, &path, blob, metadata)?);
+                }
+            }
+            let chunks = meta.chunks(&path);
+            if let Some(entry) = meta.remove_entry(&path) {
+                removed.push((entry, chunks));
+            }
+            meta.flush()?;
+            removed
+        };
+        self.notify(tree, ChangeEvent::Deleted { path })?;
+
+        self.free_removed(removed)
+    }
+
+    /// Returns the paths of the files in the trash with the time they were deleted
+    pub fn list_trash(&self) -> Vec<(String, SystemTime)> {
+        let meta = self.meta();
+        let mut files: Vec<(String, SystemTime)> = meta
+            .namespace_entries(TRASH_NAMESPACE)
+            .filter_map(|(id, _)| parse_trash_record(meta.get_meta_raw(id, TRASH_TAG)?))
+            .map(|(path, _, deleted)| (path, deleted))
+            .collect();
+        files.sort();
+
+        files
+    }
+
+    /// Brings a deleted file back from the trash to its previous path. The
+    /// parent directory has to exist and the path must be free
+    pub fn restore(&self, path: &str) -> Result<()> {
+        self.check_writable()?;
+        let path = normalize_path(path);
+        let (parent, name) = split_path(&path)?;
+        let mut tree = self.tree();
+        let trash_id = self.meta().hash_id_in(TRASH_NAMESPACE, &path);
+        let (blob, metadata) = {
+            let meta = self.meta();
+            match (
+                meta.get_entry_raw(&trash_id),
+                meta.get_meta_raw(&trash_id, TRASH_TAG)
+                    .and_then(parse_trash_record),
+            ) {
+                (Some(blob), Some((_, metadata, _))) => (*blob, metadata),
+                _ => return Err(Error::NotFound { path }),
+            }
+        };
+        tree.cd(&parent)?;
+        if tree.entries()?.iter().any(|e| e.name == name) {
+            return Err(Error::AlreadyExists { path });
+        }
+        tree.create_file_entry(&name, self.algorithm.hash_id(&path))?;
+        tree.set_metadata(&name, metadata)?;
+
+        let mut meta = self.meta_mut();
+        // the blob values are copied from the trashed entry before it's removed
+        meta.add_entry(&path, blob);
+        meta.remove_entry_raw(&trash_id);
+        meta.flush()?;
+        drop(meta);
+        self.notify(&mut tree, ChangeEvent::Created { path })?;
+
+        Ok(())
+    }
+
+    /// Removes the files that were moved to the trash at least the given time ago
+    /// and frees their content. Returns the number of removed files
+    pub fn empty_trash(&self, older_than: Duration) -> Result<usize> {
+        self.check_writable()?;
+        let _span = span!("emptying the trash");
+        let _tree = self.tree();
+        let cutoff = SystemTime::now()
+            .checked_sub(older_than)
+            .unwrap_or(UNIX_EPOCH);
+        let removed = {
+            let mut meta = self.meta_mut();
+            let ids: Vec<EntryID> = meta
+                .namespace_entries(TRASH_NAMESPACE)
+                .filter(|(id, _)| {
+                    meta.get_meta_raw(id, TRASH_TAG)
+                        .and_then(parse_trash_record)
+                        .is_none_or(|(_, _, deleted)| deleted <= cutoff)
+                })
+                .map(|(id, _)| *id)
+                .collect();
+            let mut removed = Vec::new();
+            for id in ids {
+                let chunks = meta.chunks_raw(&id);
+                if let Some(entry) = meta.remove_entry_raw(&id) {
+                    removed.push((entry, chunks));
+                }
+            }
+            meta.flush()?;
+            removed
+        };
+        let count = removed.len();
+        event!(Debug, "removed {} files from the trash", count);
+        self.free_removed(removed)?;
+
+        Ok(count)
+    }
+
+    /// Deletes files until the storage is within the limits of its eviction
+    /// policy and returns the number of deleted files. Files older than the
+    /// maximum age are deleted first, then files in the order of the strategy.
+    /// Pinned files count towards the limits but are never deleted. Read times are
+    /// only known since the storage was opened, files that weren't read since then
+    /// are ordered by their modification time
+    pub fn enforce_policies(&self) -> Result<usize> {
+        let policy = match self
+            .eviction
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+        {
+            Some(policy) => policy,
+            None => return Ok(0),
+        };
+        self.check_writable()?;
+        let _span = span!("eviction pass");
+        let mut tree = self.tree();
+        let evicted = self.evict(&mut tree, &policy, "/", None)?;
+        event!(Debug, "evicted {} files", evicted);
+
+        Ok(evicted)
+    }
+
+    /// Deletes files below the directory until the others are within the limits of
+    /// the policy. The file at the `keep` path is neither deleted nor counted
+    fn evict(
+        &self,
+        tree: &mut DirTreeFile<TreeBackend>,
+        policy: &EvictionPolicy,
+        dir: &str,
+        keep: Option<&str>,
+    ) -> Result<usize> {
+        let now = SystemTime::now();
+        let mut files = Vec::new();
+        {
+            let meta = self.meta();
+            let mut accessed = self.accessed.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut known = HashMap::new();
+            for item in tree.walk(dir)? {
+                let (_, path, entry) = item?;
+                let metadata = match entry.metadata() {
+                    Some(metadata) if !entry.is_dir() && !entry.is_symlink() => metadata,
+                    _ => continue,
+                };
+                let used = match accessed.remove(&path) {
+                    Some(time) => {
+                        known.insert(path.clone(), time);
+                        time.max(metadata.modified)
+                    }
+                    None => metadata.modified,
+                };
+                let order = match policy.strategy {
+                    EvictionStrategy::Lru => used,
+                    EvictionStrategy::Fifo => metadata.created,
+                };
+                if keep == Some(path.as_str()) {
+                    continue;
+                }
+                let pinned = meta.get_meta(&path, PIN_TAG).is_some();
+                files.push((order, path, metadata, pinned));
+            }
+            // forget deleted files
+            *accessed = known;
+        }
+        files.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+
+        let mut count = files.len() as u64;
+        let mut size: u64 = files.iter().map(|(_, _, metadata, _)| metadata.size).sum();
+        let mut evicted = 0;
+        for (_, path, metadata, pinned) in files {
+            let expired = policy
+                .max_age
+                .is_some_and(|age| metadata.modified.checked_add(age).is_some_and(|t| t <= now));
+            let exceeded = policy.max_entries.is_some_and(|max| count > max)
+                || policy.max_size.is_some_and(|max| size > max);
+            if pinned || (!expired && !exceeded) {
+                continue;
+            }
+            match self.delete_in(tree, &path) {
+                Ok(()) => evicted += 1,
+                Err(Error::NotFound { .. }) => {}
+                Err(e) => return Err(e),
+            }
+            count -= 1;
+            size -= metadata.size;
+        }
+
+        Ok(evicted)
+    }
+
+    /// Starts a thread that calls [Storage::enforce_policies] in the given interval.
+    /// Failed runs are retried in the next interval
+    pub fn spawn_eviction(self: &Arc<Self>, interval: Duration) -> EvictionTask {
+        let storage: Weak<Self> = Arc::downgrade(self);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let handle = thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                thread::park_timeout(interval);
+                if stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                match storage.upgrade() {
+                    Some(storage) => {
+                        let _ = storage.enforce_policies();
+                    }
+                    None => break,
+                }
+            }
+        });
+
+        EvictionTask {
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    /// Returns the versions of the file at the given path that can be read with
+    /// [Storage::get_version] from the oldest to the current one
+    pub fn list_versions(&self, path: &str) -> Result<Vec<u64>> {
+        let path = normalize_path(path);
+        let meta = self.meta();
+        if !meta.contains(&path) {
+            return Err(Error::NotFound { path });
+        }
+        let current = current_version(&meta, &path);
+        let mut versions: Vec<u64> = (1..current)
+            .rev()
+            .take_while(|v| {
+                meta.get_entry_in(VERSIONS_NAMESPACE, &version_key(&path, *v))
+                    .is_some()
+            })
+            .collect();
+        versions.reverse();
+        versions.push(current);
+
+        Ok(versions)
+    }
+
+    /// Returns a reader for a version of the file at the given path
+    pub fn get_version(&self, path: &str, version: u64) -> Result<BlobReader> {
+        let path = normalize_path(path);
+        let id = {
+            let meta = self.meta();
+            match meta.contains(&path) && current_version(&meta, &path) == version {
+                true => self.algorithm.hash_id(&path),
+                false => meta.hash_id_in(VERSIONS_NAMESPACE, &version_key(&path, version)),
+            }
+        };
+
+        self.read_blob(&id)?.ok_or_else(|| Error::NotFound {
+            path: format!("{}@{}", path, version),
+        })
+    }
+
+    /// Removes the versions of all files that exceed the retention and returns
+    /// the number of removed versions
+    pub fn prune_versions(&self) -> Result<usize> {
+        self.check_writable()?;
+        let mut tree = self.tree();
+        let keep = self.version_retention.load(Ordering::Relaxed);
+        let mut removed = Vec::new();
+        {
+            let mut meta = self.meta_mut();
+            for item in tree.walk("/")? {
+                let (_, path, entry) = item?;
+                if !entry.is_dir() && meta.get_meta(&path, PIN_TAG).is_none() {
+                    removed.extend(remove_versions(&mut meta, &path, keep));
+                }
+            }
+            meta.flush()?;
+        }
+        let count = removed.len();
+        self.free_removed(removed)?;
+
+        Ok(count)
+    }
+
+    /// Creates a hard link at `link` that shares the content of the file at `existing`.
+    /// The content stays stored until the last link is deleted
+    pub fn hard_link(&self, existing: &str, link: &str) -> Result<()> {
+        self.check_writable()?;
+        let existing = normalize_path(existing);
+        let link = normalize_path(link);
+        let (parent, name) = split_path(&link)?;
+        let mut tree = self.tree();
+        let entry = tree.lookup(&existing)?.ok_or_else(|| Error::NotFound {
+            path: existing.clone(),
+        })?;
+        if entry.is_dir() {
+            return Err(Error::IsADirectory { path: existing });
+        }
+        let blob = *self
+            .meta()
+            .get_entry(&existing)
+            .ok_or(Error::NotFound { path: existing })?;
+        tree.cd(&parent)?;
+        tree.create_file_entry(&name, self.algorithm.hash_id(&link))?;
+        if let Some(metadata) = entry.metadata() {
+            tree.set_metadata(&name, metadata)?;
+        }
+        let mut meta = self.meta_mut();
+        meta.add_entry(&link, blob);
+        meta.flush()?;
+        drop(meta);
+        self.notify(&mut tree, ChangeEvent::Created { path: link })?;
+
+        Ok(())
+    }
+
+    /// Returns the number of paths that share the content of the file
+    pub fn link_count(&self, path: &str) -> Result<u32> {
+        let path = normalize_path(path);
+        let meta = self.meta();
+        let entry = meta.get_entry(&path).ok_or(Error::NotFound { path })?;
+
+        Ok(meta.ref_count(entry))
+    }
+
+    /// Returns the tree entry at the given path
+    pub fn entry(&self, path: &str) -> Result<DirEntry> {
+        let path = normalize_path(path);
+
+        self.tree().lookup(&path)?.ok_or(Error::NotFound { path })
+    }
+
+    /// Sets an extended attribute of the entry at the given path
+    pub fn set_xattr(&self, path: &str, key: &str, value: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        let path = normalize_path(path);
+        let (parent, name) = split_path(&path)?;
+        let mut tree = self.tree();
+        tree.cd(&parent)?;
+        tree.set_xattr(&name, key, value)?;
+        self.notify(&mut tree, ChangeEvent::Modified { path })?;
+
+        Ok(())
+    }
+
+    /// Returns the content type, source name and times of the file at the given path
+    pub fn blob_meta(&self, path: &str) -> Result<BlobMeta> {
+        let path = normalize_path(path);
+        let entry = self.entry(&path)?;
+        if entry.is_dir() {
+            return Err(Error::IsADirectory { path });
+        }
+        let mut blob_meta = self
+            .meta()
+            .get_meta(&path, BLOB_META_TAG)
+            .and_then(decode_blob_meta)
+            .unwrap_or_default();
+        if let Some(metadata) = entry.metadata() {
+            blob_meta.created = Some(metadata.created);
+            blob_meta.modified = Some(metadata.modified);
+        }
+
+        Ok(blob_meta)
+    }
+
+    /// Sets the content type and source name of the file at the given path.
+    /// Times that are `None` are left unchanged. The content type and source
+    /// name stay when the file is replaced
+    pub fn set_blob_meta(&self, path: &str, blob_meta: &BlobMeta) -> Result<()> {
+        self.check_writable()?;
+        let path = normalize_path(path);
+        let (parent, name) = split_path(&path)?;
+        let mut tree = self.tree();
+        let entry = find_entry(&mut tree, &parent, &name)?;
+        if entry.is_dir() {
+            return Err(Error::IsADirectory { path });
+        }
+        if blob_meta.created.is_some() || blob_meta.modified.is_some() {
+            let mut metadata = entry.metadata().unwrap_or_else(|| EntryMetadata::new(0));
+            metadata.created = blob_meta.created.unwrap_or(metadata.created);
+            metadata.modified = blob_meta.modified.unwrap_or(metadata.modified);
+            tree.set_metadata(&name, metadata)?;
+        }
+        let mut meta = self.meta_mut();
+        match encode_blob_meta(blob_meta) {
+            Some(value) => meta.set_meta(&path, BLOB_META_TAG, &value)?,
+            None => meta.remove_meta(&path, BLOB_META_TAG)?,
+        };
+        meta.flush()?;
+        drop(meta);
+        self.notify(&mut tree, ChangeEvent::Modified { path })?;
+
+        Ok(())
+    }
+
+    /// Returns the value of an extended attribute of the entry at the given path
+    pub fn get_xattr(&self, path: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.entry(path)?.xattr(key).map(|v| v.to_vec()))
+    }
+
+    /// Returns the keys of all extended attributes of the entry at the given path
+    pub fn list_xattrs(&self, path: &str) -> Result<Vec<String>> {
+        Ok(self
+            .entry(path)?
+            .xattrs()
+            .map(|(key, _)| key.to_string())
+            .collect())
+    }
+
+    /// Removes an extended attribute of the entry at the given path
+    /// and returns if it existed
+    pub fn remove_xattr(&self, path: &str, key: &str) -> Result<bool> {
+        self.check_writable()?;
+        let path = normalize_path(path);
+        let (parent, name) = split_path(&path)?;
+        let mut tree = self.tree();
+        tree.cd(&parent)?;
+        let removed = tree.remove_xattr(&name, key)?;
+        if removed {
+            self.notify(&mut tree, ChangeEvent::Modified { path })?;
+        }
+
+        Ok(removed)
+    }
+
+    /// Moves the file at `from` to `to` replacing an existing file at the destination.
+    /// Directories are moved with all their descendants but only to a path that
+    /// doesn't exist yet
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.check_writable()?;
+        let from = normalize_path(from);
+        let to = normalize_path(to);
+        let (from_parent, from_name) = split_path(&from)?;
+        let (to_parent, to_name) = split_path(&to)?;
+        let mut tree = self.tree();
+
+        let source = find_entry(&mut tree, &from_parent, &from_name)?;
+        if source.is_dir() {
+            return self.rename_dir(&mut tree, from, to);
+        }
+        if from == to {
+            return Ok(());
+        }
+        let blob = *self
+            .meta()
+            .get_entry(&from)
+            .ok_or_else(|| Error::NotFound { path: from.clone() })?;
+        tree.cd(&to_parent)?;
+        match tree.entries()?.into_iter().find(|e| e.name == to_name) {
+            Some(entry) if entry.is_dir() => {
+                return Err(Error::IsADirectory { path: to });
+            }
+            Some(_) => tree.set_blob_id(&to_name, self.algorithm.hash_id(&to))?,
+            None => tree.create_file_entry(&to_name, self.algorithm.hash_id(&to))?,
+        }
+        // the times move with the file as well
+        if let Some(metadata) = source.metadata() {
+            tree.set_metadata(&to_name, metadata)?;
+        }
+        tree.cd(&from_parent)?;
+        tree.delete_entry(&from_name)?;
+        let mut meta = self.meta_mut();
+        // the replaced file is removed with its values
+        let mut replaced = remove_versions(&mut meta, &to, 0);
+        remove_tags(&mut meta, &to)?;
+        let chunks = meta.chunks(&to);
+        if let Some(entry) = meta.remove_entry(&to) {
+            replaced.push((entry, chunks));
+        }
+        move_file_meta(&mut meta, &from, &to, blob)?;
+        meta.flush()?;
+        drop(meta);
+        self.notify(&mut tree, ChangeEvent::Moved { from, to })?;
+
+        self.free_removed(replaced)
+    }
+
+    /// Moves a directory with its descendants to a path that doesn't exist yet.
+    /// Only the directory entry is moved in the tree while the index entries of
+    /// the files below it are moved to their new paths
+    fn rename_dir(
+        &self,
+        tree: &mut DirTreeFile<TreeBackend>,
+        from: String,
+        to: String,
+    ) -> Result<()> {
+        if from == to {
+            return Ok(());
+        }
+        let (from_parent, from_name) = split_path(&from)?;
+        let (to_parent, to_name) = split_path(&to)?;
+        if to.starts_with(&format!("{}/", from)) {
+            return Err(Error::InvalidName { name: to });
+        }
+        tree.cd(&to_parent)?;
+        if tree.has_entry(&to_name)? {
+            return Err(Error::AlreadyExists { path: to });
+        }
+        let mut files = Vec::new();
+        for item in tree.walk(&from)? {
+            let (_, path, entry) = item?;
+            if !entry.is_dir() && !entry.is_symlink() {
+                files.push(path);
+            }
+        }
+        // the entry is renamed in the directory where the new name is still free
+        tree.cd(&from_parent)?;
+        if from_parent == to_parent {
+            tree.rename_entry(&from_name, &to_name)?;
+        } else if !tree.has_entry(&to_name)? {
+            tree.rename_entry(&from_name, &to_name)?;
+            tree.move_entry(&join_path(&from_parent, &to_name), &to_parent)?;
+        } else {
+            tree.move_entry(&from, &to_parent)?;
+            tree.cd(&to_parent)?;
+            tree.rename_entry(&from_name, &to_name)?;
+        }
+
+        let mut meta = self.meta_mut();
+        for path in files {
+            let moved = format!("{}{}", to, &path[from.len()..]);
+            let (parent, name) = split_path(&moved)?;
+            tree.cd(&parent)?;
+            tree.set_blob_id(&name, self.algorithm.hash_id(&moved))?;
+            if let Some(blob) = meta.get_entry(&path).copied() {
+                move_file_meta(&mut meta, &path, &moved, blob)?;
+            }
+        }
+        meta.flush()?;
+        drop(meta);
+
+        self.notify(tree, ChangeEvent::Moved { from, to })
+    }
+
+    /// Adds a tag to the file at the given path, e.g. to find all thumbnails with
+    /// [Storage::find_by_tag]. Tags stay when the file is replaced or renamed
+    pub fn tag(&self, path: &str, tag: &str) -> Result<()> {
+        self.check_writable()?;
+        check_tag(tag)?;
+        let path = normalize_path(path);
+        let mut meta = self.meta_mut();
+        if !meta.contains(&path) {
+            return Err(Error::NotFound { path });
+        }
+        let mut tags = file_tags(&meta, &path);
+        if tags.iter().any(|t| t == tag) {
+            return Ok(());
+        }
+        tags.push(tag.to_string());
+        meta.set_meta(&path, TAGS_TAG, tags.join("\0").as_bytes())?;
+        add_tagged_path(&mut meta, tag, &path)?;
+
+        meta.flush()
+    }
+
+    /// Removes a tag from the file at the given path and returns if it had the tag
+    pub fn untag(&self, path: &str, tag: &str) -> Result<bool> {
+        self.check_writable()?;
+        let path = normalize_path(path);
+        let mut meta = self.meta_mut();
+        let mut tags = file_tags(&meta, &path);
+        let count = tags.len();
+        tags.retain(|t| t != tag);
+        if tags.len() == count {
+            return Ok(false);
+        }
+        match tags.is_empty() {
+            true => meta.remove_meta(&path, TAGS_TAG)?,
+            false => meta.set_meta(&path, TAGS_TAG, tags.join("\0").as_bytes())?,
+        };
+        remove_tagged_path(&mut meta, tag, &path)?;
+        meta.flush()?;
+
+        Ok(true)
+    }
+
+    /// Returns the tags of the file at the given path in the order they were added
+    pub fn tags(&self, path: &str) -> Result<Vec<String>> {
+        let path = normalize_path(path);
+        let meta = self.meta();
+        if !meta.contains(&path) {
+            return Err(Error::NotFound { path });
+        }
+
+        Ok(file_tags(&meta, &path))
+    }
+
+    /// Returns the paths of the files with the given tag sorted by path. The paths
+    /// are looked up in the buckets of the tag without scanning other tags
+    pub fn find_by_tag(&self, tag: &str) -> Vec<String> {
+        let mut paths: Vec<String> = tag_buckets(&self.meta(), tag).concat();
+        paths.sort();
+
+        paths
+    }
+
+    /// Sets the time after which [Storage::expire_now] deletes the file at the given path
+    pub fn set_expiry(&self, path: &str, expiry: Option<SystemTime>) -> Result<()> {
+        self.check_writable()?;
+        let path = normalize_path(path);
+        let mut meta = self.meta_mut();
+        meta.set_expiry(&path, expiry)?;
+
+        meta.flush()
+    }
+
+    /// Pins the file at the given path so that it's never evicted, expired or
+    /// pruned by [Storage::enforce_policies], [Storage::expire_now] and
+    /// [Storage::prune_versions]. The pin stays when the file is replaced
+    pub fn pin(&self, path: &str) -> Result<()> {
+        self.check_writable()?;
+        let path = normalize_path(path);
+        let mut meta = self.meta_mut();
+        meta.set_meta(&path, PIN_TAG, &[])?;
+
+        meta.flush()
+    }
+
+    /// Removes the pin of the file at the given path and returns if it was pinned
+    pub fn unpin(&self, path: &str) -> Result<bool> {
+        self.check_writable()?;
+        let path = normalize_path(path);
+        let mut meta = self.meta_mut();
+        let pinned = meta.remove_meta(&path, PIN_TAG)?.is_some();
+        meta.flush()?;
+
+        Ok(pinned)
+    }
+
+    /// Returns if the file at the given path is pinned
+    pub fn is_pinned(&self, path: &str) -> bool {
+        self.meta()
+            .get_meta(&normalize_path(path), PIN_TAG)
+            .is_some()
+    }
+
+    /// Deletes the files whose expiry has passed and frees their content.
+    /// Pinned files are kept. Returns the number of deleted files
+    pub fn expire_now(&self) -> Result<usize> {
+        self.check_writable()?;
+        let _span = span!("expiry pass");
+        let mut tree = self.tree();
+        let expired: HashSet<EntryID> = {
+            let meta = self.meta();
+            meta.expired(SystemTime::now())
+                .into_iter()
+                .filter(|id| meta.get_meta_raw(id, PIN_TAG).is_none())
+                .collect()
+        };
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        let mut paths = Vec::new();
+        for item in tree.walk("/")? {
+            let (_, path, entry) = item?;
+            let id = entry
+                .blob_id()
+                .unwrap_or_else(|| self.algorithm.hash_id(&path));
+            if !entry.is_dir() && expired.contains(&id) {
+                paths.push(path);
+            }
+        }
+        for path in &paths {
+            let (parent, name) = split_path(path)?;
+            tree.cd(&parent)?;
+            tree.delete_entry(&name)?;
+        }
+        tree.cd("/")?;
+        let removed = {
+            let mut meta = self.meta_mut();
+            let mut removed: Vec<(MetaEntry, Vec<MetaEntry>)> = Vec::new();
+            for path in &paths {
+                removed.extend(remove_versions(&mut meta, path, 0));
+                remove_tags(&mut meta, path)?;
+            }
+            for id in &expired {
+                let chunks = meta.chunks_raw(id);
+                if let Some(entry) = meta.remove_entry_raw(id) {
+                    removed.push((entry, chunks));
+                }
+            }
+            meta.flush()?;
+            removed
+        };
+        event!(Debug, "expired {} files", expired.len());
+        for path in paths {
+            self.notify(&mut tree, ChangeEvent::Deleted { path })?;
+        }
+        self.free_removed(removed)?;
+
+        Ok(expired.len())
+    }
+
+    /// Checks the tree file and the index for corruption and inconsistencies
+    pub fn check(&self) -> Result<CheckReport> {
+        self.check_with(OperationOptions::new())
+    }
+
+    /// Checks the storage like [Storage::check], reports every checked index entry
+    /// with the bytes of its content and stops when the token of the options is cancelled
+    pub fn check_with(&self, mut options: OperationOptions) -> Result<CheckReport> {
+        self.check_tree(&mut self.tree(), &mut options)
+    }
+
+    fn check_tree(
+        &self,
+        tree: &mut DirTreeFile<TreeBackend>,
+        options: &mut OperationOptions,
+    ) -> Result<CheckReport> {
+        let tree_check = tree.check_with(options.without_progress())?;
+        let mut report = CheckReport {
+            unreachable_chunks: tree_check.unreachable,
+            overlapping_chunks: tree_check.overlapping,
+            bad_lengths: tree_check.bad_lengths,
+            ..Default::default()
+        };
+        let meta = self.meta();
+        let mut referenced = HashSet::new();
+
+        for path in tree_check.files {
+            let id = self.algorithm.hash_id(&path);
+            if !meta.contains(&path) {
+                report.missing_entries.push(path);
+            }
+            referenced.insert(id);
+        }
+        for namespace in &[VERSIONS_NAMESPACE, TRASH_NAMESPACE, TAGS_NAMESPACE] {
+            referenced.extend(meta.namespace_entries(namespace).map(|(id, _)| *id));
+        }
+        let mut state = Progress::new(Some(meta.len() as u64));
+        for (id, entry) in meta.iter() {
+            options.check()?;
+            state.advance(match entry.0 {
+                MARKER_FILE => 0,
+                _ => entry.2,
+            });
+            options.report(state);
+            let intact = match entry.0 {
+                MARKER_FILE => true,
+                INLINE_FILE => meta
+                    .inline_content_raw(id)
+                    .is_some_and(|content| content.len() as u64 == entry.2),
+                _ => self.chunks_in_bounds(entry, &meta.chunks_raw(id))?,
+            };
+            if !referenced.contains(id) || !intact {
+                report.dangling_entries.push(*id);
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Returns the number of entries and the space used by the storage, e.g. for
+    /// monitoring. All data files and the whole tree are read
+    pub fn stats(&self) -> Result<StorageStats> {
+        let mut tree = self.tree();
+        let dir_stats = tree.dir_stats("/")?;
+        let tree_stats = tree.stats()?;
+        let last_file = *self
+            .data_file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let meta = self.meta();
+        let mut stats = StorageStats {
+            files: dir_stats.files,
+            dirs: dir_stats.dirs,
+            symlinks: dir_stats.symlinks,
+            logical_bytes: dir_stats.bytes,
+            index_entries: meta.len() as u64,
+            tree: tree_stats,
+            ..Default::default()
+        };
+        stats.data_files = (0..=last_file)
+            .map(|file| {
+                Ok(DataFileStats {
+                    file,
+                    size: self.data.len(file)?,
+                    used: 0,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let mut seen = HashSet::new();
+        for (id, entry) in meta.iter() {
+            match entry.0 {
+                MARKER_FILE => continue,
+                INLINE_FILE => {
+                    stats.inline_entries += 1;
+                    if seen.insert((entry.0, entry.1)) {
+                        stats.physical_bytes += entry.2;
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            let chunks = meta.chunks_raw(id);
+            if !chunks.is_empty() {
+                stats.chunked_entries += 1;
+            }
+            if !seen.insert((entry.0, entry.1)) {
+                continue;
+            }
+            for (file, pointer, length) in blob_chunks(*entry, chunks) {
+                let length = match length {
+                    UNKNOWN_LENGTH => {
+                        stored_length(self.data.as_ref(), file, pointer)?.unwrap_or(0)
+                    }
+                    length => length,
+                };
+                stats.data_chunks += 1;
+                if let Some(data_file) = stats.data_files.get_mut(file as usize) {
+                    data_file.used += 8 + length;
+                }
+            }
+        }
+        stats.physical_bytes += stats.data_files.iter().map(|f| f.size).sum::<u64>();
+        self.metrics().gauge(FRAGMENTATION, stats.fragmentation());
+
+        Ok(stats)
+    }
+
+    /// Fixes the problems that can be solved without losing intact data by removing
+    /// dangling index entries, removing files without content from the tree,
+    /// reclaiming leaked chunks and truncating unused space at the end of the tree file.
+    /// Returns the report of the problems found before the repair
+    pub fn repair(&self) -> Result<CheckReport> {
+        self.check_writable()?;
+        let _span = span!("repair");
+        let mut tree = self.tree();
+        let report = self.check_tree(&mut tree, &mut OperationOptions::new())?;
+
+        for path in &report.missing_entries {
+            let (parent, name) = split_path(path)?;
+            tree.cd(&parent)?;
+            tree.delete_entry(&name)?;
+        }
+        if report.overlapping_chunks.is_empty() && report.bad_lengths.is_empty() {
+            tree.reclaim_orphans()?;
+        }
+        tree.truncate_unreachable()?;
+        tree.cd("/")?;
+        let mut meta = self.meta_mut();
+        for id in &report.dangling_entries {
+            meta.remove_entry_raw(id);
+        }
+        meta.flush()?;
+        if !report.is_ok() {
+            event!(
+                Warn,
+                "repaired {} missing and {} dangling index entries",
+                report.missing_entries.len(),
+                report.dangling_entries.len()
+            );
+        }
+
+        Ok(report)
+    }
+
+    /// Rewrites the tree file without fragmented directories and the index without
+    /// appended changes and vacuums the data files like [Storage::vacuum]. Returns
+    /// the number of bytes reclaimed in the tree file and the data files
+    pub fn compact(&self) -> Result<u64> {
+        self.compact_with(OperationOptions::new())
+    }
+
+    /// Compacts the storage like [Storage::compact], reports every copied tree entry
+    /// and stops when the token of the options is cancelled. A cancelled compaction
+    /// leaves the tree and the index unchanged
+    pub fn compact_with(&self, options: OperationOptions) -> Result<u64> {
+        self.check_writable()?;
+        let _span = span!("compaction");
+        let mut tree = self.tree();
+        let mut vacuum_options = options.without_progress();
+        let reclaimed = tree.compact_with(options)?;
+        let vacuumed = self.vacuum_in(&tree, &mut vacuum_options)?;
+        self.meta_mut().compact()?;
+
+        Ok(reclaimed + vacuumed)
+    }
+
+    /// Moves blobs into the gaps that deleted blobs left in the data files and
+    /// truncates the space after the last blob of each file. Returns the number
+    /// of reclaimed bytes. Nothing is moved while a batch is open and the data
+    /// open readers point at is kept until they are dropped
+    pub fn vacuum(&self) -> Result<u64> {
+        self.vacuum_with(OperationOptions::new())
+    }
+
+    /// Vacuums the data files like [Storage::vacuum], reports every moved blob and
+    /// stops when the token of the options is cancelled. The blobs moved before the
+    /// cancellation stay at their new place
+    pub fn vacuum_with(&self, mut options: OperationOptions) -> Result<u64> {
+        self.check_writable()?;
+        let _span = span!("vacuum");
+        let tree = self.tree();
+
+        self.vacuum_in(&tree, &mut options)
+    }
+
+    fn vacuum_in(
+        &self,
+        tree: &DirTreeFile<TreeBackend>,
+        options: &mut OperationOptions,
+    ) -> Result<u64> {
+        // a rollback could restore references to the blobs removed in the batch
+        if tree.in_batch() {
+            return Ok(0);
+        }
+        let data_file = self
+            .data_file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut reclaimed = 0;
+        let mut state = Progress::new(None);
+        for file in 0..=*data_file {
+            reclaimed += self.vacuum_file(file, options, &mut state)?;
+        }
+        if reclaimed > 0 {
+            event!(Debug, "vacuumed {} bytes of the data files", reclaimed);
+        }
+
+        Ok(reclaimed)
+    }
+
+    /// Moves the blobs of a data file down so that no gaps are left between them.
+    /// Space left by a moved blob is only reused after the index was flushed, so an
+    /// interrupted vacuum never overwrites a blob the index on disk points to. A blob
+    /// that would overlap itself is copied behind the last blob first. Ranges that
+    /// open readers point at are never overwritten, blobs are moved past them
+    fn vacuum_file(
+        &self,
+        file: u32,
+        options: &mut OperationOptions,
+        state: &mut Progress,
+    ) -> Result<u64> {
+        let aligned = self.aligned.load(Ordering::Relaxed);
+        let align = |pointer: u64| match aligned {
+            true => pointer.div_ceil(BLOCK_SIZE) * BLOCK_SIZE,
+            false => pointer,
+        };
+        // the first aligned position after the start that no reader points at
+        let unpinned = |start: u64, length: u64| {
+            let pins = self.pins.lock();
+            let mut target = align(start);
+            while let Some(pinned) = pinned_end(&pins, file, target, target + length) {
+                target = align(pinned);
+            }
+            target
+        };
+        let size = self.data.len(file)?;
+        let extents = match self.extents_in_file(file)? {
+            Some(extents) => extents,
+            None => return Ok(0),
+        };
+        let mut moved = BTreeMap::new();
+        let mut end = 0;
+        for (pointer, length) in extents {
+            if let Err(e) = options.check() {
+                self.relocate(file, &mut moved)?;
+                return Err(e);
+            }
+            // readers can only pin vacated space until the index was flushed
+            let vacated = moved.keys().next().copied().unwrap_or(u64::MAX);
+            let mut target = unpinned(end, length);
+            if target < pointer && target + length > vacated {
+                self.relocate(file, &mut moved)?;
+                target = unpinned(end, length);
+            }
+            if target >= pointer {
+                end = pointer + length;
+                continue;
+            }
+            if target + length > pointer {
+                let behind = unpinned(size.max(pointer + length), length);
+                self.copy_extent(file, pointer, behind, length)?;
+                moved.insert(pointer, behind);
+                self.relocate(file, &mut moved)?;
+                // the blob stays in place if a reader pinned it before the index was flushed
+                if unpinned(end, length) == target {
+                    self.copy_extent(file, behind, target, length)?;
+                } else {
+                    target = pointer;
+                }
+                moved.insert(behind, target);
+                self.relocate(file, &mut moved)?;
+            } else {
+                self.copy_extent(file, pointer, target, length)?;
+                moved.insert(pointer, target);
+            }
+            state.advance(length);
+            options.report(*state);
+            end = target + length;
+        }
+        self.relocate(file, &mut moved)?;
+        let pins = self.pins.lock();
+        let end = pinned_end(&pins, file, end, u64::MAX).map_or(end, |pinned| pinned.max(end));
+        if end < self.data.len(file)? {
+            self.data.truncate(file, end)?;
+        }
+
+        Ok(size.saturating_sub(end))
+    }
+
+    /// Syncs the moved blobs and points the index to their new pointers
+    fn relocate(&self, file: u32, moved: &mut BTreeMap<u64, u64>) -> Result<()> {
+        if moved.is_empty() {
+            return Ok(());
+        }
+        self.data.sync(file)?;
+        let mut meta = self.meta_mut();
+        meta.relocate(file, moved);
+        meta.flush()?;
+        moved.clear();
+
+        Ok(())
+    }
+
+    /// Returns the pointers and sizes of the blobs and chunks in a data file with
+    /// their length prefixes ordered by their pointer. Returns None if a blob
+    /// doesn't fit into the file
+    fn extents_in_file(&self, file: u32) -> Result<Option<Vec<(u64, u64)>>> {
+        let meta = self.meta();
+        let mut pointers = BTreeSet::new();
+        for (id, entry) in meta.iter() {
+            if entry.0 == INLINE_FILE || entry.0 == MARKER_FILE {
+                continue;
+            }
+            for (chunk_file, pointer, _) in blob_chunks(*entry, meta.chunks_raw(id)) {
+                if chunk_file == file {
+                    pointers.insert(pointer);
+                }
+            }
+        }
+        let blobs: Vec<(u32, u64)> = pointers.into_iter().map(|p| (file, p)).collect();
+        let mut extents = Vec::with_capacity(blobs.len());
+        for ((_, pointer), length) in blobs
+            .iter()
+            .zip(stored_lengths(self.data.as_ref(), &blobs)?)
+        {
+            match length {
+                Some(length) => extents.push((*pointer, 8 + length)),
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(extents))
+    }
+
+    /// Copies the bytes of a blob to a lower position in its data file that doesn't overlap it
+    fn copy_extent(&self, file: u32, from: u64, to: u64, length: u64) -> Result<()> {
+        let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+        let mut copied = 0;
+        while copied < length {
+            let part = (length - copied).min(COPY_BUFFER_SIZE as u64) as usize;
+            self.data
+                .read_exact_at(file, from + copied, &mut buffer[..part])?;
+            self.data.write_at(file, to + copied, &buffer[..part])?;
+            copied += part as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Imports all files of an archive into the directory `dest` without
+    /// extracting them to the disk first. Returns the number of imported files
+    pub fn import_archive<R: Read + Seek>(
+        &self,
+        reader: R,
+        format: ArchiveFormat,
+        dest: &str,
+    ) -> Result<usize> {
+        self.import_archive_with(reader, format, dest, OperationOptions::new())
+    }
+
+    /// Imports an archive like [Storage::import_archive], reports every imported file
+    /// with its size and stops when the token of the options is cancelled. The total
+    /// is only known for zip archives. The files imported before the cancellation are kept
+    pub fn import_archive_with<R: Read + Seek>(
+        &self,
+        reader: R,
+        format: ArchiveFormat,
+        dest: &str,
+        mut options: OperationOptions,
+    ) -> Result<usize> {
+        self.check_writable()?;
+        let mut tree = self.tree();
+        self.create_dirs(&mut tree, dest)?;
+        let count = match format {
+            ArchiveFormat::Tar => self.import_tar(&mut tree, reader, dest, &mut options),
+            ArchiveFormat::Zip => self.import_zip(&mut tree, reader, dest, &mut options),
+        };
+        // the index entries of the files imported before an error are flushed as well
+        self.meta_mut().flush()?;
+
+        count
+    }
+
+    fn import_tar<R: Read>(
+        &self,
+        tree: &mut DirTreeFile<TreeBackend>,
+        reader: R,
+        dest: &str,
+        options: &mut OperationOptions,
+    ) -> Result<usize> {
+        let mut archive = tar::Archive::new(reader);
+        let mut count = 0;
+        let mut state = Progress::new(None);
+
+        for entry in archive.entries()? {
+            options.check()?;
+            let entry = entry?;
+            let path = match archive_path(dest, &entry.path()?) {
+                Some(path) => path,
+                None => continue,
+            };
+            let entry_type = entry.header().entry_type();
+
+            if entry_type.is_dir() {
+                self.create_dirs(tree, &path)?;
+            } else if entry_type.is_file() {
+                let (parent, _) = split_path(&path)?;
+                self.create_dirs(tree, &parent)?;
+                state.advance(self.insert(tree, &path, entry)?);
+                options.report(state);
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn import_zip<R: Read + Seek>(
+        &self,
+        tree: &mut DirTreeFile<TreeBackend>,
+        reader: R,
+        dest: &str,
+        options: &mut OperationOptions,
+    ) -> Result<usize> {
+        let mut archive = zip::ZipArchive::new(reader).map_err(zip_error)?;
+        let mut count = 0;
+        let files = (0..archive.len()).filter(|i| {
+            archive
+                .name_for_index(*i)
+                .is_some_and(|name| !name.ends_with('/'))
+        });
+        let mut state = Progress::new(Some(files.count() as u64));
+
+        for i in 0..archive.len() {
+            options.check()?;
+            let file = archive.by_index(i).map_err(zip_error)?;
+            let path = match file.enclosed_name().and_then(|p| archive_path(dest, &p)) {
+                Some(path) => path,
+                None => continue,
+            };
+
+            if file.is_dir() {
+                self.create_dirs(tree, &path)?;
+            } else {
+                let (parent, _) = split_path(&path)?;
+                self.create_dirs(tree, &parent)?;
+                state.advance(self.insert(tree, &path, file)?);
+                options.report(state);
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Writes the file content and adds the tree and index entries without saving the index
+    fn insert<R: Read>(
+        &self,
+        tree: &mut DirTreeFile<TreeBackend>,
+        path: &str,
+        reader: R,
+    ) -> Result<u64> {
+        let path = normalize_path(path);
+        let (parent, name) = split_path(&path)?;
+        tree.cd(&parent)?;
+        let existing = tree.entries()?.into_iter().find(|e| e.name == name);
+        let event = match existing {
+            Some(_) => ChangeEvent::Modified { path: path.clone() },
+            None => ChangeEvent::Created { path: path.clone() },
+        };
+
+        if let Some(entry) = &existing {
+            if entry.is_dir() {
+                return Err(Error::IsADirectory { path });
+            }
+        }
+        let content = self.write_content(tree, reader)?;
+        let length = content.len();
+        if let Err(e) = self.check_quota(tree, &path, existing.as_ref(), length) {
+            if let Content::Stored(blob, chunks) = content {
+                for chunk in blob_chunks(blob, chunks) {
+                    self.free_blob(chunk)?;
+                }
+            }
+            return Err(e);
+        }
+        tree.cd(&parent)?;
+        let mut metadata = EntryMetadata::new(length);
+        match existing.as_ref().and_then(|e| e.metadata()) {
+            Some(previous) => metadata.created = previous.created,
+            None if existing.is_none() => {
+                tree.create_file_entry(&name, self.algorithm.hash_id(&path))?
+            }
+            None => {}
+        }
+        // entries written before ids were recorded get them when they are replaced
+        if existing.is_some_and(|e| e.blob_id().is_none()) {
+            tree.set_blob_id(&name, self.algorithm.hash_id(&path))?;
+        }
+        tree.set_metadata(&name, metadata)?;
+        let keep = self.version_retention.load(Ordering::Relaxed);
+        if keep > 0 {
+            keep_version(&mut self.meta_mut(), &path)?;
+        }
+        self.index_content(tree, &path, content)?;
+        // removed versions are kept while a batch can still be discarded
+        if keep > 0 && !tree.in_batch() {
+            let removed = {
+                let mut meta = self.meta_mut();
+                match meta.get_meta(&path, PIN_TAG) {
+                    Some(_) => Vec::new(),
+                    None => remove_versions(&mut meta, &path, keep),
+                }
+            };
+            self.free_removed(removed)?;
+        }
+        self.notify(tree, event)?;
+
+        Ok(length)
+    }
+
+    /// Checks if the files fit into the quotas of the storage and the namespace of
+    /// the path when the file at the path gets the new length
+    fn check_quota(
+        &self,
+        tree: &mut DirTreeFile<TreeBackend>,
+        path: &str,
+        existing: Option<&DirEntry>,
+        length: u64,
+    ) -> Result<()> {
+        let mut quotas: Vec<(String, Quota)> = self
+            .namespace_quotas
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|(root, _)| path.starts_with(&format!("{}/", root)))
+            .map(|(root, quota)| (root.clone(), quota.clone()))
+            .collect();
+        if let Some(quota) = self
+            .quota
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+        {
+            quotas.push((String::from("/"), quota));
+        }
+        for (dir, quota) in quotas {
+            self.check_quota_in(tree, &dir, &quota, path, existing, length)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks if the files below the directory fit into the quota and evicts other
+    /// files there if the low space policy allows it
+    fn check_quota_in(
+        &self,
+        tree: &mut DirTreeFile<TreeBackend>,
+        dir: &str,
+        quota: &Quota,
+        path: &str,
+        existing: Option<&DirEntry>,
+        length: u64,
+    ) -> Result<()> {
+        let previous = existing.and_then(|e| e.metadata()).map_or(0, |m| m.size);
+        let added = existing.is_none() as u64;
+        let exceeded = |tree: &mut DirTreeFile<TreeBackend>| -> Result<bool> {
+            let stats = tree.dir_stats(dir)?;
+            let bytes = stats.bytes - previous + length;
+            let files = stats.files + added;
+            Ok(quota.max_bytes.is_some_and(|max| bytes > max)
+                || quota.max_entries.is_some_and(|max| files > max))
+        };
+        if !exceeded(tree)? {
+            return Ok(());
+        }
+        if quota.policy == LowSpacePolicy::Evict {
+            let strategy = self
+                .eviction
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .as_ref()
+                .map_or(EvictionStrategy::Lru, |policy| policy.strategy);
+            let policy = EvictionPolicy {
+                max_size: quota.max_bytes.map(|max| max.saturating_sub(length)),
+                max_entries: quota.max_entries.map(|max| max.saturating_sub(1)),
+                max_age: None,
+                strategy,
+            };
+            self.evict(tree, &policy, dir, Some(path))?;
+            if !exceeded(tree)? {
+                return Ok(());
+            }
+        }
+
+        Err(Error::QuotaExceeded {
+            path: path.to_string(),
+        })
+    }
+
+    /// Frees the blobs that were removed from the index if nothing references them anymore
+    fn free_removed(&self, removed: Vec<(MetaEntry, Vec<MetaEntry>)>) -> Result<()> {
+        let chunks: Vec<MetaEntry> = {
+            let meta = self.meta();
+            removed
+                .into_iter()
+                .filter(|(entry, _)| meta.ref_count(entry) == 0)
+                .flat_map(|(entry, chunks)| blob_chunks(entry, chunks))
+                .collect()
+        };
+        if !chunks.is_empty() {
+            event!(Debug, "freeing {} unreferenced blobs", chunks.len());
+        }
+        for chunk in chunks {
+            self.free_blob(chunk)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the content of the reader to the index if it's small enough or a
+    /// new blob in the data files otherwise. The blob is synced as the tree's
+    /// policy requires
+    fn write_content<R: Read>(
+        &self,
+        tree: &DirTreeFile<TreeBackend>,
+        mut reader: R,
+    ) -> Result<Content> {
+        let threshold = self.inline_threshold.load(Ordering::Relaxed);
+        let mut head = Vec::new();
+        (&mut reader).take(threshold).read_to_end(&mut head)?;
+        if (head.len() as u64) < threshold {
+            let metrics = self.metrics();
+            metrics.counter(BYTES_WRITTEN, head.len() as u64);
+            metrics.counter(BLOBS_STORED, 1);
+            return Ok(Content::Inline(head));
+        }
+        let (blob, chunks) = self.write_blob(&mut (&head[..]).chain(reader))?;
+        self.sync_blob(tree, blob, &chunks)?;
+
+        Ok(Content::Stored(blob, chunks))
+    }
+
+    /// Syncs the data files of a blob, which has to be on the disk before the index
+    /// references it. In a batch the files are synced on commit
+    fn sync_blob(
+        &self,
+        tree: &DirTreeFile<TreeBackend>,
+        blob: MetaEntry,
+        chunks: &[MetaEntry],
+    ) -> Result<()> {
+        let files: BTreeSet<u32> = blob_chunks(blob, chunks.to_vec())
+            .into_iter()
+            .map(|(file, _, _)| file)
+            .collect();
+        if tree.in_batch() {
+            self.unsynced
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .extend(files);
+        } else if tree.sync_policy() != SyncPolicy::Never {
+            for file in files {
+                self.data.sync(file)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Points the index entry of the path to the new content and frees the
+    /// replaced blob if nothing else references it
+    fn index_content(
+        &self,
+        tree: &DirTreeFile<TreeBackend>,
+        path: &str,
+        content: Content,
+    ) -> Result<()> {
+        let (previous, previous_chunks, blob) = {
+            let mut meta = self.meta_mut();
+            let previous_chunks = meta.chunks(path);
+            let (previous, blob) = match content {
+                Content::Inline(data) => (meta.add_inline_entry(path, &data)?, None),
+                Content::Stored(blob, chunks) => {
+                    let previous = meta.add_entry(path, blob);
+                    if !chunks.is_empty() {
+                        meta.set_chunks(path, &chunks)?;
+                    }
+                    (previous, Some(blob))
+                }
+            };
+            (previous, previous_chunks, blob)
+        };
+        // blobs that were extended in place keep their location
+        let moved = |previous: &MetaEntry| {
+            blob.is_none_or(|(file, pointer, _)| (file, pointer) != (previous.0, previous.1))
+        };
+        // replaced blobs are kept while a batch can still be discarded
+        if let Some(previous) = previous.filter(|p| !tree.in_batch() && moved(p)) {
+            if self.meta().ref_count(&previous) == 0 {
+                for chunk in blob_chunks(previous, previous_chunks) {
+                    self.free_blob(chunk)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Appends the content of the reader to the last chunk of a blob if it's at the
+    /// end of the current data file. Returns None without reading if it isn't
+    fn append_in_place<R: Read>(
+        &self,
+        blob: MetaEntry,
+        mut chunks: Vec<MetaEntry>,
+        reader: &mut R,
+    ) -> Result<Option<(MetaEntry, Vec<MetaEntry>)>> {
+        let mut data_file = self
+            .data_file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let last = chunks.pop().unwrap_or(blob);
+        let (file, pointer, length) = last;
+        if blob.0 == INLINE_FILE
+            || length == UNKNOWN_LENGTH
+            || file != *data_file
+            || stored_length(self.data.as_ref(), file, pointer)? != Some(length)
+            || pointer + 8 + length != self.data.len(file)?
+        {
+            return Ok(None);
+        }
+
+        self.write_chunks(&mut data_file, last, chunks, reader)
+            .map(Some)
+    }
+
+    /// Appends a blob to the current data file and returns the file, pointer and length.
+    /// A blob that doesn't fit into the data file continues in chunks in the next ones,
+    /// which are returned as well
+    fn write_blob<R: Read>(&self, reader: &mut R) -> Result<(MetaEntry, Vec<MetaEntry>)> {
+        let mut data_file = self
+            .data_file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let max_size = self.max_data_file_size.load(Ordering::Relaxed);
+        let chunk = self.start_chunk(&mut data_file, max_size)?;
+        let blob = self.write_chunks(&mut data_file, chunk, Vec::new(), reader)?;
+        self.metrics().counter(BLOBS_STORED, 1);
+
+        Ok(blob)
+    }
+
+    /// Writes the content of the reader after the data of a chunk and continues in
+    /// new chunks when the data file is full. Returns the entry of the blob made
+    /// up of all chunks and the chunks if there's more than one
+    fn write_chunks<R: Read>(
+        &self,
+        data_file: &mut u32,
+        mut chunk: MetaEntry,
+        mut chunks: Vec<MetaEntry>,
+        reader: &mut R,
+    ) -> Result<(MetaEntry, Vec<MetaEntry>)> {
+        let max_size = self.max_data_file_size.load(Ordering::Relaxed);
+        let metrics = self.metrics();
+        let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let mut written = 0;
+            while written < read {
+                let (file, pointer, chunk_length) = chunk;
+                let room = max_size.saturating_sub(pointer + 8 + chunk_length);
+                if room == 0 {
+                    self.finish_chunk(chunk)?;
+                    chunks.push(chunk);
+                    chunk = self.start_chunk(data_file, max_size)?;
+                    continue;
+                }
+                let part = (read - written).min(room as usize);
+                self.data.write_at(
+                    file,
+                    pointer + 8 + chunk_length,
+                    &buffer[written..written + part],
+                )?;
+                chunk.2 += part as u64;
+                written += part;
+            }
+            metrics.counter(BYTES_WRITTEN, read as u64);
+        }
+        self.finish_chunk(chunk)?;
+        if chunks.is_empty() {
+            return Ok((chunk, chunks));
+        }
+        chunks.push(chunk);
+        let (file, pointer, _) = chunks[0];
+        let length = chunks.iter().map(|(_, _, length)| length).sum();
+
+        Ok(((file, pointer, length), chunks))
+    }
+
+    /// Starts a chunk of a blob in the current data file or the next one if the
+    /// current one is full
+    fn start_chunk(&self, data_file: &mut u32, max_size: u64) -> Result<MetaEntry> {
+        let aligned = self.aligned.load(Ordering::Relaxed);
+        let align = |pointer: u64| match aligned {
+            true => pointer.div_ceil(BLOCK_SIZE) * BLOCK_SIZE,
+            false => pointer,
+        };
+        let mut pointer = align(self.data.len(*data_file)?);
+        if pointer + 8 >= max_size {
+            *data_file += 1;
+            pointer = align(self.data.len(*data_file)?);
+        }
+        // the length is written last so that an interrupted write isn't readable
+        self.data.write_at(*data_file, pointer, &[0u8; 8])?;
+
+        Ok((*data_file, pointer, 0))
+    }
+
+    /// Writes the length in front of a chunk
+    fn finish_chunk(&self, (file, pointer, length): MetaEntry) -> Result<()> {
+        let mut length_raw = [0u8; 8];
+        BigEndian::write_u64(&mut length_raw, length);
+        self.data.write_at(file, pointer, &length_raw)?;
+
+        Ok(())
+    }
+
+    /// Releases the space of a blob that isn't referenced anymore. Only a blob at
+    /// the end of its data file can be truncated, other blobs and blobs that are
+    /// still read leave a gap
+    fn free_blob(&self, entry: MetaEntry) -> Result<()> {
+        let _data_file = self
+            .data_file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if entry.0 == INLINE_FILE || !self.blob_in_bounds(&entry)? {
+            return Ok(());
+        }
+        let (file, pointer, _) = entry;
+        let length = stored_length(self.data.as_ref(), file, pointer)?.unwrap_or(0);
+        let end = pointer + 8 + length;
+        let pins = self.pins.lock();
+        if end == self.data.len(file)? && pinned_end(&pins, file, pointer, end).is_none() {
+            self.data.truncate(file, pointer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the shorter length in front of a chunk and releases the space after
+    /// it if it's at the end of its data file
+    fn shrink_chunk(&self, (file, pointer, length): MetaEntry) -> Result<()> {
+        let _data_file = self
+            .data_file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let stored = match stored_length(self.data.as_ref(), file, pointer)? {
+            Some(stored) if stored > length => stored,
+            _ => return Ok(()),
+        };
+        self.finish_chunk((file, pointer, length))?;
+        let end = pointer + 8 + stored;
+        let pins = self.pins.lock();
+        if end == self.data.len(file)?
+            && pinned_end(&pins, file, pointer + 8 + length, end).is_none()
+        {
+            self.data.truncate(file, pointer + 8 + length)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns if all chunks of a blob fit into their data files and add up to the
+    /// length of the entry
+    fn chunks_in_bounds(&self, entry: &MetaEntry, chunks: &[MetaEntry]) -> Result<bool> {
+        if chunks.is_empty() {
+            return self.blob_in_bounds(entry);
+        }
+        if chunks[0].0 != entry.0 || chunks[0].1 != entry.1 {
+            return Ok(false);
+        }
+        let mut length = 0u64;
+        for chunk in chunks {
+            if !self.blob_in_bounds(chunk)? {
+                return Ok(false);
+            }
+            length = length.saturating_add(chunk.2);
+        }
+
+        Ok(length == entry.2)
+    }
+
+    /// Returns if the blob fits into its data file and has at least the length of
+    /// the entry. An append that was interrupted before the index was saved leaves
+    /// a longer length in front of the blob
+    fn blob_in_bounds(&self, &(file, pointer, length): &MetaEntry) -> Result<bool> {
+        let stored = stored_length(self.data.as_ref(), file, pointer)?;
+
+        Ok(stored.is_some_and(|stored| length == UNKNOWN_LENGTH || stored >= length))
+    }
+}
+
+/// Returns the key of a version of a file in the [VERSIONS_NAMESPACE]
+fn version_key(path: &str, version: u64) -> Vec<u8> {
+    let mut key = path.as_bytes().to_vec();
+    key.push(0);
+    key.extend_from_slice(&version.to_be_bytes());
+
+    key
+}
+
+/// Returns the version of the current content of a file. Files that were
+/// never replaced with versioning on are at version 1
+fn current_version(meta: &IndexedMetaFile, path: &str) -> u64 {
+    meta.get_meta(path, VERSION_TAG)
+        .and_then(|value| value.try_into().ok())
+        .map_or(1, u64::from_be_bytes)
+}
+
+/// Adds the current content of a file to its history and bumps its version
+fn keep_version(meta: &mut IndexedMetaFile, path: &str) -> Result<()> {
+    let entry = match meta.get_entry(path) {
+        Some(entry) => *entry,
+        None => return Ok(()),
+    };
+    let version = current_version(meta, path);
+    meta.add_entry_in(VERSIONS_NAMESPACE, &version_key(path, version), entry)?;
+    meta.set_meta(path, VERSION_TAG, &(version + 1).to_be_bytes())?;
+
+    Ok(())
+}
+
+/// Removes the versions of a file except for the given number of most recent
+/// ones and returns the removed blobs with their chunks
+fn remove_versions(
+    meta: &mut IndexedMetaFile,
+    path: &str,
+    keep: u32,
+) -> Vec<(MetaEntry, Vec<MetaEntry>)> {
+    let current = current_version(meta, path);
+    let mut removed = Vec::new();
+    for version in (1..current.saturating_sub(u64::from(keep))).rev() {
+        let id = meta.hash_id_in(VERSIONS_NAMESPACE, &version_key(path, version));
+        let chunks = meta.chunks_raw(&id);
+        match meta.remove_entry_raw(&id) {
+            Some(entry) => removed.push((entry, chunks)),
+            None => break,
+        }
+    }
+
+    removed
+}
+
+/// Moves the blob of a file into the [TRASH_NAMESPACE] and returns the blob of
+/// a file that was trashed at the same path before
+/// Moves the index entry of a file to another path together with the values
+/// stored next to it, its previous versions and its paths in the tag buckets
+fn move_file_meta(meta: &mut IndexedMetaFile, from: &str, to: &str, blob: MetaEntry) -> Result<()> {
+    let values: Vec<(u8, Vec<u8>)> = meta
+        .meta_tags(from)
+        .into_iter()
+        .filter_map(|tag| Some((tag, meta.get_meta(from, tag)?.to_vec())))
+        .collect();
+    let current = current_version(meta, from);
+    for version in (1..current).rev() {
+        let key = version_key(from, version);
+        let entry = match meta.get_entry_in(VERSIONS_NAMESPACE, &key) {
+            Some(entry) => *entry,
+            None => break,
+        };
+        meta.add_entry_in(VERSIONS_NAMESPACE, &version_key(to, version), entry)?;
+        meta.remove_entry_in(VERSIONS_NAMESPACE, &key);
+    }
+    meta.remove_entry(from);
+    meta.add_entry(to, blob);
+    for (tag, value) in values {
+        meta.set_meta(to, tag, &value)?;
+    }
+    for tag in file_tags(meta, to) {
+        remove_tagged_path(meta, &tag, from)?;
+        add_tagged_path(meta, &tag, to)?;
+    }
+
+    Ok(())
+}
+
+fn trash_entry(
+    meta: &mut IndexedMetaFile,
+    path: &str,
+    blob: MetaEntry,
+    metadata: EntryMetadata,
+) -> Result<Option<(MetaEntry, Vec<MetaEntry>)>> {
+    let id = meta.hash_id_in(TRASH_NAMESPACE, path);
+    let chunks = meta.chunks_raw(&id);
+    let previous = meta.remove_entry_raw(&id).map(|entry| (entry, chunks));
+    meta.add_entry_in(TRASH_NAMESPACE, path, blob)?;
+    let deleted = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let mut record = deleted.to_be_bytes().to_vec();
+    record.extend(metadata.to_bytes());
+    record.extend_from_slice(path.as_bytes());
+    meta.set_meta_raw(&id, TRASH_TAG, &record)?;
+
+    Ok(previous)
+}
+
+/// Returns the path, metadata and deletion time of a trashed file
+fn parse_trash_record(record: &[u8]) -> Option<(String, EntryMetadata, SystemTime)> {
+    if record.len() < 32 {
+        return None;
+    }
+    let deleted = UNIX_EPOCH + Duration::from_millis(BigEndian::read_u64(&record[..8]));
+    let metadata = EntryMetadata::from_bytes(&record[8..32])?;
+    let path = String::from_utf8(record[32..].to_vec()).ok()?;
+
+    Some((path, metadata, deleted))
+}
+
+/// Encodes the content type and source name of a blob as a flags byte, the
+/// length of the content type, the content type and the source name
+fn encode_blob_meta(blob_meta: &BlobMeta) -> Option<Vec<u8>> {
+    if blob_meta.content_type.is_none() && blob_meta.source_name.is_none() {
+        return None;
+    }
+    let content_type = blob_meta.content_type.as_deref().unwrap_or("");
+    let source_name = blob_meta.source_name.as_deref().unwrap_or("");
+    let flags =
+        blob_meta.content_type.is_some() as u8 | (blob_meta.source_name.is_some() as u8) << 1;
+    let mut value = vec![flags];
+    value.extend_from_slice(&(content_type.len() as u16).to_be_bytes());
+    value.extend_from_slice(content_type.as_bytes());
+    value.extend_from_slice(source_name.as_bytes());
+
+    Some(value)
+}
+
+fn decode_blob_meta(value: &[u8]) -> Option<BlobMeta> {
+    let flags = *value.first()?;
+    let length = BigEndian::read_u16(value.get(1..3)?) as usize;
+    let content_type = String::from_utf8(value.get(3..3 + length)?.to_vec()).ok()?;
+    let source_name = String::from_utf8(value[3 + length..].to_vec()).ok()?;
+
+    Some(BlobMeta {
+        content_type: Some(content_type).filter(|_| flags & 1 != 0),
+        source_name: Some(source_name).filter(|_| flags & 2 != 0),
+        ..Default::default()
+    })
+}
+
+/// Tags must not be empty or contain null bytes as they separate tags in the index
+fn check_tag(tag: &str) -> Result<()> {
+    if tag.is_empty() || tag.contains('\0') {
+        return Err(Error::InvalidName {
+            name: tag.to_string(),
+        });
+    }
+    if tag.len() > u8::MAX as usize {
+        return Err(Error::NameTooLong {
+            name: tag.to_string(),
+            max: u8::MAX as usize,
+        });
+    }
+
+    Ok(())
+}
+
+/// Returns the key of a bucket of the paths with a tag in the [TAGS_NAMESPACE]
+fn tag_key(tag: &str, bucket: u32) -> Vec<u8> {
+    let mut key = tag.as_bytes().to_vec();
+    key.push(0);
+    key.extend_from_slice(&bucket.to_be_bytes());
+
+    key
+}
+
+/// Returns the paths in each bucket of a tag. The buckets are numbered without
+/// gaps so that they are found by their keys
+fn tag_buckets(meta: &IndexedMetaFile, tag: &str) -> Vec<Vec<String>> {
+    let mut buckets = Vec::new();
+    for bucket in 0.. {
+        let id = meta.hash_id_in(TAGS_NAMESPACE, &tag_key(tag, bucket));
+        match meta.get_meta_raw(&id, TAGS_TAG) {
+            Some(value) => buckets.push(
+                value
+                    .split(|b| *b == 0)
+                    .map(|path| String::from_utf8_lossy(path).into_owned())
+                    .collect(),
+            ),
+            None => break,
+        }
+    }
+
+    buckets
+}
+
+/// Writes the paths of a bucket of a tag or removes the bucket if it's empty
+fn write_tag_bucket(
+    meta: &mut IndexedMetaFile,
+    tag: &str,
+    bucket: u32,
+    paths: &[String],
+) -> Result<()> {
+    let key = tag_key(tag, bucket);
+    if paths.is_empty() {
+        meta.remove_entry_in(TAGS_NAMESPACE, &key);
+        return Ok(());
+    }
+    if meta.get_entry_in(TAGS_NAMESPACE, &key).is_none() {
+        meta.add_entry_in(TAGS_NAMESPACE, &key, (MARKER_FILE, 0, 0))?;
+    }
+    let id = meta.hash_id_in(TAGS_NAMESPACE, &key);
+    meta.set_meta_raw(&id, TAGS_TAG, paths.join("\0").as_bytes())?;
+
+    Ok(())
+}
+
+/// Returns the tags of a file that are stored next to its entry
+fn file_tags(meta: &IndexedMetaFile, path: &str) -> Vec<String> {
+    meta.get_meta(path, TAGS_TAG)
+        .map(|value| {
+            value
+                .split(|b| *b == 0)
+                .map(|tag| String::from_utf8_lossy(tag).into_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Adds the path of a tagged file to the last bucket of the tag that
+/// [Storage::find_by_tag] reads or to a new one if the last bucket is full
+fn add_tagged_path(meta: &mut IndexedMetaFile, tag: &str, path: &str) -> Result<()> {
+    let mut buckets = tag_buckets(meta, tag);
+    let fits = |paths: &Vec<String>| {
+        paths.iter().map(|p| p.len() + 1).sum::<usize>() + path.len() <= TAG_BUCKET_SIZE
+    };
+    let bucket = match buckets.last() {
+        Some(paths) if fits(paths) => buckets.len() - 1,
+        _ => {
+            buckets.push(Vec::new());
+            buckets.len() - 1
+        }
+    };
+    buckets[bucket].push(path.to_string());
+
+    write_tag_bucket(meta, tag, bucket as u32, &buckets[bucket])
+}
+
+/// Removes the path of a file from the buckets of a tag. An emptied bucket is
+/// replaced with the last one so that no gaps are left
+fn remove_tagged_path(meta: &mut IndexedMetaFile, tag: &str, path: &str) -> Result<()> {
+    let mut buckets = tag_buckets(meta, tag);
+    let bucket = match buckets
+        .iter()
+        .position(|paths| paths.iter().any(|p| p == path))
+    {
+        Some(bucket) => bucket,
+        None => return Ok(()),
+    };
+    buckets[bucket].retain(|p| p != path);
+    let last = buckets.len() - 1;
+    if buckets[bucket].is_empty() && bucket < last {
+        write_tag_bucket(meta, tag, last as u32, &[])?;
+        return write_tag_bucket(meta, tag, bucket as u32, &buckets[last]);
+    }
+
+    write_tag_bucket(meta, tag, bucket as u32, &buckets[bucket])
+}
+
+/// Removes the paths of a file from the buckets of all its tags
+fn remove_tags(meta: &mut IndexedMetaFile, path: &str) -> Result<()> {
+    for tag in file_tags(meta, path) {
+        remove_tagged_path(meta, &tag, path)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the chunks of a blob in the data files, which is the blob itself
+/// unless it spans multiple data files
+fn blob_chunks(entry: MetaEntry, chunks: Vec<MetaEntry>) -> Vec<MetaEntry> {
+    if chunks.is_empty() {
+        vec![entry]
+    } else {
+        chunks
+    }
+}
+
+/// Returns the length written in front of a blob if the blob fits into its data file
+fn stored_length(data: &dyn DataBackend, file: u32, pointer: u64) -> Result<Option<u64>> {
+    Ok(stored_lengths(data, &[(file, pointer)])?.remove(0))
+}
+
+/// Returns the lengths of the blobs at the pointers like [stored_length]. The
+/// lengths are read in one batch
+fn stored_lengths(data: &dyn DataBackend, blobs: &[(u32, u64)]) -> Result<Vec<Option<u64>>> {
+    let mut sizes = HashMap::new();
+    for (file, _) in blobs {
+        if let hash_map::Entry::Vacant(entry) = sizes.entry(*file) {
+            entry.insert(data.len(*file)?);
+        }
+    }
+    let fits =
+        |(file, pointer): &(u32, u64)| pointer.checked_add(8).is_some_and(|end| end <= sizes[file]);
+    let mut lengths = vec![[0u8; 8]; blobs.len()];
+    let mut reads: Vec<BatchRead<'_>> = blobs
+        .iter()
+        .zip(lengths.iter_mut())
+        .filter(|(blob, _)| fits(blob))
+        .map(|((file, pointer), length)| BatchRead {
+            file: *file,
+            offset: *pointer,
+            buf: length,
+        })
+        .collect();
+    data.read_exact_batch(&mut reads)?;
+    drop(reads);
+
+    Ok(blobs
+        .iter()
+        .zip(lengths)
+        .map(|(blob, length)| {
+            let length = BigEndian::read_u64(&length);
+            fits(blob)
+                .then(|| (blob.1 + 8).checked_add(length))
+                .flatten()
+                .filter(|end| *end <= sizes[&blob.0])
+                .map(|_| length)
+        })
+        .collect())
+}
+
+/// Opens the tree file of the storage in the directory with the file as its backend
+fn open_tree(path: &Path, read_only: bool, wait: bool) -> Result<DirTreeFile<TreeBackend>> {
+    DirTreeFile::open_locked_with(path.join(TREE_FILE_NAME), read_only, wait, |file| {
+        Ok(Box::new(file) as TreeBackend)
+    })
+}
+
+fn create_storage_dir(path: &Path) -> Result<()> {
+    if !path.exists() {
+        fs::create_dir_all(path)?;
+    }
+
+    Ok(())
+}
+
+/// Adds the files below the directory ordered by their path to the list until it
+/// has `limit` files. Files up to the path given as components in `after` are skipped
+fn list_files(
+    tree: &mut DirTreeFile<TreeBackend>,
+    dir: &str,
+    after: &[String],
+    limit: usize,
+    files: &mut Vec<EntryInfo>,
+) -> Result<()> {
+    tree.cd(dir)?;
+    let mut entries = tree.entries()?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    let start = after
+        .first()
+        .map(|first| entries.partition_point(|e| e.name < *first));
+    for entry in entries.into_iter().skip(start.unwrap_or(0)) {
+        if files.len() >= limit {
+            break;
+        }
+        let path = join_path(dir, &entry.name);
+        let resumed = after.first() == Some(&entry.name);
+        if entry.is_dir() {
+            let after = if resumed { &after[1..] } else { &[] };
+            list_files(tree, &path, after, limit, files)?;
+        } else if !resumed && !entry.is_symlink() {
+            let metadata = entry.metadata();
+            files.push(EntryInfo {
+                path,
+                size: metadata.map_or(0, |m| m.size),
+                modified: metadata.map(|m| m.modified),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Treats a missing entry as success for operations that may already be applied
+fn ignore_not_found(result: Result<()>) -> Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn find_entry(tree: &mut DirTreeFile<TreeBackend>, parent: &str, name: &str) -> Result<DirEntry> {
+    tree.cd(parent)?;
+    tree.entries()?
+        .into_iter()
+        .find(|e| e.name == name)
+        .ok_or_else(|| Error::NotFound {
+            path: join_path(parent, name),
+        })
+}
+
+/// Maps the path of an archive entry into the destination directory
+/// ignoring entries that would escape it
+fn archive_path(dest: &str, path: &Path) -> Option<String> {
+    let mut parts = Vec::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().to_string()),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    if parts.is_empty() {
+        return None;
+    }
+
+    Some(join_path(dest, &parts.join("/")))
+}
+
+fn zip_error(error: zip::result::ZipError) -> Error {
+    match error {
+        zip::result::ZipError::Io(e) => Error::Io(e),
+        error => Error::InvalidArchive {
+            reason: error.to_string(),
+        },
+    }
+}
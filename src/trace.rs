@@ -0,0 +1,57 @@
+//! Diagnostic events and spans for operators. With the `tracing` feature they are
+//! emitted through the `tracing` crate with the module as the target, without it
+//! they compile to nothing
+
+/// Emits an event at the given level, e.g. `event!(Debug, "flushed {} changes", n)`
+#[cfg(feature = "tracing")]
+macro_rules! event {
+    (Trace, $($arg:tt)+) => {
+        tracing::trace!($($arg)+)
+    };
+    (Debug, $($arg:tt)+) => {
+        tracing::debug!($($arg)+)
+    };
+    (Info, $($arg:tt)+) => {
+        tracing::info!($($arg)+)
+    };
+    (Warn, $($arg:tt)+) => {
+        tracing::warn!($($arg)+)
+    };
+    (Error, $($arg:tt)+) => {
+        tracing::error!($($arg)+)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    };
+}
+
+/// Enters a debug span that lasts until the returned guard is dropped at the end
+/// of the enclosing scope. Subscribers get the duration of the operation from it,
+/// e.g. `tracing_subscriber::fmt` with `FmtSpan::CLOSE`
+#[cfg(feature = "tracing")]
+macro_rules! span {
+    ($name:expr) => {
+        tracing::debug_span!($name).entered()
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! span {
+    ($name:expr) => {
+        crate::trace::Span
+    };
+}
+
+pub(crate) use event;
+pub(crate) use span;
+
+/// The guard of a span without the `tracing` feature
+#[cfg(not(feature = "tracing"))]
+#[must_use]
+pub(crate) struct Span;
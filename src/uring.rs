@@ -0,0 +1,299 @@
+//! Reads and writes through io_uring on Linux. The tree file and the data files of
+//! a storage share one ring, and batches of reads are submitted together so that
+//! they take a single system call instead of a seek and a read each
+
+use crate::backend::{Backend, BatchRead, DataBackend, LocalDataBackend};
+use io_uring::{opcode, squeue, types, IoUring};
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// The number of operations that can be submitted at once. Larger batches are
+/// submitted in parts
+const RING_ENTRIES: u32 = 64;
+
+/// A submission and completion queue shared by the files of a storage
+pub struct Ring {
+    ring: Mutex<IoUring>,
+}
+
+impl Ring {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            ring: Mutex::new(IoUring::new(RING_ENTRIES)?),
+        })
+    }
+
+    /// Reads from the file at the offset and returns the number of bytes read
+    pub fn read_at(&self, fd: RawFd, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let entry = read_entry(fd, offset, buf);
+
+        // the buffer is borrowed until the read completed
+        unsafe { self.submit(&[entry]) }?.remove(0)
+    }
+
+    /// Writes to the file at the offset and returns the number of bytes written
+    pub fn write_at(&self, fd: RawFd, offset: u64, data: &[u8]) -> io::Result<usize> {
+        let length = data.len().min(u32::MAX as usize) as u32;
+        let entry = opcode::Write::new(types::Fd(fd), data.as_ptr(), length)
+            .offset(offset)
+            .build();
+
+        // the data is borrowed until the write completed
+        unsafe { self.submit(&[entry]) }?.remove(0)
+    }
+
+    /// Writes all data to the file at the offset
+    pub fn write_all_at(&self, fd: RawFd, mut offset: u64, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            match self.write_at(fd, offset, data) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+                Ok(n) => {
+                    offset += n as u64;
+                    data = &data[n..];
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the data of the file through to the storage medium
+    pub fn sync_data(&self, fd: RawFd) -> io::Result<()> {
+        let entry = opcode::Fsync::new(types::Fd(fd))
+            .flags(types::FsyncFlags::DATASYNC)
+            .build();
+
+        unsafe { self.submit(&[entry]) }?.remove(0).map(|_| ())
+    }
+
+    /// Submits all reads at once and returns the number of bytes read into each buffer
+    pub fn read_batch(&self, reads: &mut [(RawFd, u64, &mut [u8])]) -> io::Result<Vec<usize>> {
+        let entries: Vec<squeue::Entry> = reads
+            .iter_mut()
+            .map(|(fd, offset, buf)| read_entry(*fd, *offset, buf))
+            .collect();
+
+        // the buffers are borrowed until all reads completed
+        unsafe { self.submit(&entries) }?.into_iter().collect()
+    }
+
+    /// Submits the entries and waits until all of them completed. Returns the
+    /// result of each entry in the same order
+    ///
+    /// # Safety
+    ///
+    /// The buffers the entries point to must stay valid until the function returns
+    unsafe fn submit(&self, entries: &[squeue::Entry]) -> io::Result<Vec<io::Result<usize>>> {
+        let mut ring = self.ring.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut results = vec![0; entries.len()];
+        for (group, part) in entries.chunks(RING_ENTRIES as usize).enumerate() {
+            let first = group * RING_ENTRIES as usize;
+            {
+                let mut submission = ring.submission();
+                for (index, entry) in part.iter().enumerate() {
+                    let entry = entry.clone().user_data((first + index) as u64);
+                    // the queue is empty as every part is waited for
+                    submission
+                        .push(&entry)
+                        .map_err(|_| io::Error::other("the submission queue is full"))?;
+                }
+            }
+            let mut completed = 0;
+            while completed < part.len() {
+                match ring.submit_and_wait(part.len() - completed) {
+                    Ok(_) => {}
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            ErrorKind::Interrupted
+                                | ErrorKind::WouldBlock
+                                | ErrorKind::ResourceBusy
+                        ) => {}
+                    Err(e) => {
+                        // other errors are returned before anything is submitted, a new
+                        // ring drops the entries that still point to the buffers
+                        *ring = IoUring::new(RING_ENTRIES)?;
+                        return Err(e);
+                    }
+                }
+                for completion in ring.completion() {
+                    results[completion.user_data() as usize] = completion.result();
+                    completed += 1;
+                }
+            }
+        }
+
+        Ok(results
+            .into_iter()
+            .map(|result| {
+                if result < 0 {
+                    Err(io::Error::from_raw_os_error(-result))
+                } else {
+                    Ok(result as usize)
+                }
+            })
+            .collect())
+    }
+}
+
+fn read_entry(fd: RawFd, offset: u64, buf: &mut [u8]) -> squeue::Entry {
+    let length = buf.len().min(u32::MAX as usize) as u32;
+
+    opcode::Read::new(types::Fd(fd), buf.as_mut_ptr(), length)
+        .offset(offset)
+        .build()
+}
+
+/// Stores data files as `data-<n>.bin` in a local directory like
+/// [LocalDataBackend] and reads and writes them through a [Ring]
+pub struct UringDataBackend {
+    files: LocalDataBackend,
+    ring: Arc<Ring>,
+}
+
+impl UringDataBackend {
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        Ok(Self::with_ring(dir, Arc::new(Ring::new()?)))
+    }
+
+    /// Creates a backend that submits to a ring shared with other files
+    pub fn with_ring(dir: PathBuf, ring: Arc<Ring>) -> Self {
+        Self {
+            files: LocalDataBackend::new(dir),
+            ring,
+        }
+    }
+}
+
+impl DataBackend for UringDataBackend {
+    fn read_at(&self, file: u32, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.files.with_file(file, false, |f| {
+            self.ring.read_at(f.as_raw_fd(), offset, buf)
+        })
+    }
+
+    fn write_at(&self, file: u32, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.files.with_file(file, true, |f| {
+            self.ring.write_all_at(f.as_raw_fd(), offset, data)
+        })
+    }
+
+    fn len(&self, file: u32) -> io::Result<u64> {
+        self.files.len(file)
+    }
+
+    fn truncate(&self, file: u32, size: u64) -> io::Result<()> {
+        self.files.truncate(file, size)
+    }
+
+    fn sync(&self, file: u32) -> io::Result<()> {
+        self.files
+            .with_file(file, true, |f| self.ring.sync_data(f.as_raw_fd()))
+    }
+
+    /// Submits all reads at once and reads the rest of short reads one by one
+    fn read_exact_batch(&self, reads: &mut [BatchRead<'_>]) -> io::Result<()> {
+        let files: Vec<u32> = reads.iter().map(|read| read.file).collect();
+        let read = self.files.with_files(&files, |opened| {
+            let mut batch: Vec<(RawFd, u64, &mut [u8])> = reads
+                .iter_mut()
+                .zip(opened)
+                .map(|(read, f)| (f.as_raw_fd(), read.offset, &mut *read.buf))
+                .collect();
+
+            self.ring.read_batch(&mut batch)
+        })?;
+        for (request, read) in reads.iter_mut().zip(read) {
+            if read < request.buf.len() {
+                let offset = request.offset + read as u64;
+                self.read_exact_at(request.file, offset, &mut request.buf[read..])?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A file that is read and written through a [Ring], e.g. as the backend of a
+/// [DirTreeFile](crate::dirtreefile::DirTreeFile). It keeps its own position so
+/// that a seek followed by a read is a single positional read
+pub struct UringFile {
+    file: File,
+    ring: Arc<Ring>,
+    position: u64,
+}
+
+impl UringFile {
+    pub fn new(file: File, ring: Arc<Ring>) -> Self {
+        Self {
+            file,
+            ring,
+            position: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+}
+
+impl Read for UringFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self
+            .ring
+            .read_at(self.file.as_raw_fd(), self.position, buf)?;
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl Write for UringFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self
+            .ring
+            .write_at(self.file.as_raw_fd(), self.position, buf)?;
+        self.position += written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for UringFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size()?.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        self.position = position;
+
+        Ok(position)
+    }
+}
+
+impl Backend for UringFile {
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.ring.sync_data(self.file.as_raw_fd())
+    }
+}
@@ -1 +1,123 @@
+use crate::error::{Error, Result};
 
+/// Normalizes a virtual path to the form `/a/b/c` removing empty and `.` segments
+/// and resolving `..`
+pub fn normalize_path(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+
+    format!("/{}", parts.join("/"))
+}
+
+/// Splits a path into the normalized parent directory and the name of the entry
+pub fn split_path(path: &str) -> Result<(String, String)> {
+    let path = normalize_path(path);
+    let index = path.rfind('/').unwrap_or(0);
+    let name = &path[index + 1..];
+
+    if name.is_empty() {
+        return Err(Error::InvalidName { name: path });
+    }
+    let parent = if index == 0 { "/" } else { &path[..index] };
+
+    Ok((parent.to_string(), name.to_string()))
+}
+
+/// Joins a path onto a directory path
+pub fn join_path(dir: &str, path: &str) -> String {
+    normalize_path(&format!("{}/{}", dir, path))
+}
+
+/// Matches a normalized path against a glob pattern. `*` and `?` match within a
+/// single segment and a `**` segment matches any number of segments
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').filter(|p| !p.is_empty()).collect();
+    let path: Vec<&str> = path.split('/').filter(|p| !p.is_empty()).collect();
+
+    match_segments(&pattern, &path)
+}
+
+/// Returns if the pattern contains wildcards
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// Matches the segments by tracking which pattern positions can be reached
+/// after each segment of the path, so that every segment is compared with every
+/// pattern segment at most once
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+    let pattern: Vec<Vec<char>> = pattern.iter().map(|s| s.chars().collect()).collect();
+    let mut reached = vec![false; pattern.len() + 1];
+    reached[0] = true;
+    close_over_globstars(&pattern, &mut reached);
+
+    for name in path {
+        let name: Vec<char> = name.chars().collect();
+        let mut next = vec![false; pattern.len() + 1];
+        for (i, segment) in pattern.iter().enumerate() {
+            if !reached[i] {
+                continue;
+            }
+            if segment[..] == ['*', '*'] {
+                next[i] = true;
+            } else if match_name(segment, &name) {
+                next[i + 1] = true;
+            }
+        }
+        close_over_globstars(&pattern, &mut next);
+        if !next.contains(&true) {
+            return false;
+        }
+        reached = next;
+    }
+
+    reached[pattern.len()]
+}
+
+/// Marks the positions after reached `**` segments as reached since they may match no segment
+fn close_over_globstars(pattern: &[Vec<char>], reached: &mut [bool]) {
+    for (i, segment) in pattern.iter().enumerate() {
+        if reached[i] && segment[..] == ['*', '*'] {
+            reached[i + 1] = true;
+        }
+    }
+}
+
+/// Matches a name greedily. A failed match after a `*` only retries from the
+/// last star with one more character consumed by it, which takes linear time
+/// per star instead of backtracking through every earlier star
+fn match_name(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
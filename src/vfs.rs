@@ -0,0 +1,316 @@
+//! An implementation of [vfs::FileSystem] over a storage, so code written against
+//! the `vfs` crate can use a storage as its backend
+
+use crate::error::Error;
+use crate::storage::{BlobReader, Storage};
+use crate::trace::event;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use vfs::error::VfsErrorKind;
+use vfs::{FileSystem, SeekAndRead, SeekAndWrite, VfsError, VfsFileType, VfsMetadata, VfsResult};
+
+/// Exposes a storage as a [FileSystem]. Paths of the `vfs` crate are empty for
+/// the root and start with a slash otherwise, which matches the storage paths
+pub struct StorageVfs {
+    storage: Arc<Storage>,
+}
+
+impl StorageVfs {
+    pub fn new(storage: Arc<Storage>) -> Self {
+        Self { storage }
+    }
+
+    /// Returns the storage the file system reads from and writes to
+    pub fn storage(&self) -> &Arc<Storage> {
+        &self.storage
+    }
+
+    /// Returns if the path is the root or a directory
+    fn is_dir(&self, path: &str) -> VfsResult<bool> {
+        if path.is_empty() || path == "/" {
+            return Ok(true);
+        }
+
+        Ok(self.storage.entry(path).map_err(vfs_error)?.is_dir())
+    }
+}
+
+impl Debug for StorageVfs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StorageVfs").finish_non_exhaustive()
+    }
+}
+
+/// Maps the errors with a counterpart in the `vfs` crate and wraps the others
+fn vfs_error(error: Error) -> VfsError {
+    match error {
+        Error::NotFound { .. } => VfsErrorKind::FileNotFound.into(),
+        Error::AlreadyExists { .. } => VfsErrorKind::FileExists.into(),
+        Error::InvalidName { .. } | Error::NameTooLong { .. } => VfsErrorKind::InvalidPath.into(),
+        error => VfsErrorKind::IoError(error.into()).into(),
+    }
+}
+
+impl FileSystem for StorageVfs {
+    fn read_dir(&self, path: &str) -> VfsResult<Box<dyn Iterator<Item = String> + Send>> {
+        let entries = self.storage.read_dir(path).map_err(vfs_error)?;
+
+        Ok(Box::new(entries.into_iter().map(|entry| entry.name)))
+    }
+
+    fn create_dir(&self, path: &str) -> VfsResult<()> {
+        match self.storage.create_dir(path) {
+            Err(Error::AlreadyExists { .. }) if self.is_dir(path)? => {
+                Err(VfsErrorKind::DirectoryExists.into())
+            }
+            result => result.map_err(vfs_error),
+        }
+    }
+
+    fn open_file(&self, path: &str) -> VfsResult<Box<dyn SeekAndRead + Send>> {
+        if self.is_dir(path)? {
+            return Err(VfsErrorKind::Other(format!("{} is a directory", path)).into());
+        }
+        let reader = self.storage.get(path).map_err(vfs_error)?;
+
+        Ok(Box::new(StorageVfsReader {
+            storage: Arc::clone(&self.storage),
+            path: path.to_string(),
+            size: reader.remaining(),
+            position: 0,
+            reader: Some(reader),
+        }))
+    }
+
+    fn create_file(&self, path: &str) -> VfsResult<Box<dyn SeekAndWrite + Send>> {
+        self.storage.store(path, io::empty()).map_err(vfs_error)?;
+
+        Ok(Box::new(StorageVfsWriter::new(
+            Arc::clone(&self.storage),
+            path,
+            None,
+        )))
+    }
+
+    fn append_file(&self, path: &str) -> VfsResult<Box<dyn SeekAndWrite + Send>> {
+        if self.is_dir(path)? {
+            return Err(VfsErrorKind::Other(format!("{} is a directory", path)).into());
+        }
+        let length = self.storage.get(path).map_err(vfs_error)?.remaining();
+
+        Ok(Box::new(StorageVfsWriter::new(
+            Arc::clone(&self.storage),
+            path,
+            Some(length),
+        )))
+    }
+
+    fn metadata(&self, path: &str) -> VfsResult<VfsMetadata> {
+        if path.is_empty() || path == "/" {
+            return Ok(VfsMetadata {
+                file_type: VfsFileType::Directory,
+                len: 0,
+                created: None,
+                modified: None,
+                accessed: None,
+            });
+        }
+        let entry = self.storage.entry(path).map_err(vfs_error)?;
+        let metadata = entry.metadata();
+        let (file_type, len) = if entry.is_dir() {
+            (VfsFileType::Directory, 0)
+        } else {
+            let len = match metadata {
+                Some(metadata) => metadata.size,
+                None => self.storage.get(path).map_err(vfs_error)?.remaining(),
+            };
+            (VfsFileType::File, len)
+        };
+
+        Ok(VfsMetadata {
+            file_type,
+            len,
+            created: metadata.map(|m| m.created),
+            modified: metadata.map(|m| m.modified),
+            accessed: None,
+        })
+    }
+
+    fn exists(&self, path: &str) -> VfsResult<bool> {
+        if path.is_empty() || path == "/" {
+            return Ok(true);
+        }
+        match self.storage.entry(path) {
+            Ok(_) => Ok(true),
+            Err(Error::NotFound { .. }) => Ok(false),
+            Err(e) => Err(vfs_error(e)),
+        }
+    }
+
+    fn remove_file(&self, path: &str) -> VfsResult<()> {
+        if self.is_dir(path)? {
+            return Err(VfsErrorKind::Other(format!("{} is a directory", path)).into());
+        }
+
+        self.storage.delete(path).map_err(vfs_error)
+    }
+
+    fn remove_dir(&self, path: &str) -> VfsResult<()> {
+        if !self.is_dir(path)? {
+            return Err(VfsErrorKind::Other(format!("{} is not a directory", path)).into());
+        }
+
+        self.storage.delete(path).map_err(vfs_error)
+    }
+
+    fn copy_file(&self, src: &str, dest: &str) -> VfsResult<()> {
+        let reader = self.storage.get(src).map_err(vfs_error)?;
+        self.storage.store(dest, reader).map_err(vfs_error)?;
+
+        Ok(())
+    }
+
+    fn move_file(&self, src: &str, dest: &str) -> VfsResult<()> {
+        self.storage.rename(src, dest).map_err(vfs_error)
+    }
+
+    fn move_dir(&self, src: &str, dest: &str) -> VfsResult<()> {
+        self.storage.rename(src, dest).map_err(vfs_error)
+    }
+}
+
+/// Reads a stored file from the position it was sought to. Seeking only moves
+/// the position and the content after it is requested again on the next read
+struct StorageVfsReader {
+    storage: Arc<Storage>,
+    path: String,
+    size: u64,
+    position: u64,
+    reader: Option<BlobReader>,
+}
+
+impl Read for StorageVfsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size {
+            return Ok(0);
+        }
+        let reader = match &mut self.reader {
+            Some(reader) => reader,
+            None => self.reader.insert(self.storage.get_range(
+                &self.path,
+                self.position,
+                self.size - self.position,
+            )?),
+        };
+        let read = reader.read(buf)?;
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl Seek for StorageVfsReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        if position != self.position {
+            self.position = position;
+            self.reader = None;
+        }
+
+        Ok(position)
+    }
+}
+
+/// Collects the written data in memory and writes it when the writer is flushed
+/// or dropped. A created file is stored with its whole content while an appending
+/// writer only appends the new data with [Storage::append], so its writes always
+/// go to the end like with `O_APPEND`. Errors on drop can only be logged, so
+/// callers should flush first
+struct StorageVfsWriter {
+    storage: Arc<Storage>,
+    path: String,
+    content: Cursor<Vec<u8>>,
+    /// The length of the file without the data that wasn't appended yet
+    appended_to: Option<u64>,
+    changed: bool,
+}
+
+impl StorageVfsWriter {
+    fn new(storage: Arc<Storage>, path: &str, appended_to: Option<u64>) -> Self {
+        Self {
+            storage,
+            path: path.to_string(),
+            content: Cursor::new(Vec::new()),
+            appended_to,
+            changed: false,
+        }
+    }
+}
+
+impl Write for StorageVfsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.appended_to.is_some() {
+            self.content.seek(SeekFrom::End(0))?;
+        }
+        self.changed = true;
+        self.content.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        match &mut self.appended_to {
+            Some(length) => {
+                *length = self
+                    .storage
+                    .append(&self.path, &self.content.get_ref()[..])?;
+                self.content = Cursor::new(Vec::new());
+            }
+            None => {
+                self.storage
+                    .store(&self.path, &self.content.get_ref()[..])?;
+            }
+        }
+        self.changed = false;
+
+        Ok(())
+    }
+}
+
+impl Seek for StorageVfsWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let length = match self.appended_to {
+            Some(length) => length,
+            None => return self.content.seek(pos),
+        };
+        // writes of an appending writer go to the end wherever it was sought to
+        let end = length + self.content.get_ref().len() as u64;
+        match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) | SeekFrom::Current(offset) => end.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"))
+    }
+}
+
+impl Drop for StorageVfsWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            event!(
+                Warn,
+                "failed to write {} when the writer was dropped: {}",
+                self.path,
+                e
+            );
+        }
+    }
+}